@@ -1,11 +1,15 @@
 use serde::{Serialize, Deserialize};
+use crate::debug::DebugInfo;
+use std::collections::HashMap;
 
+/// The offset of an instruction in the encoded code of a chunk.
 pub type InstructionIndex = usize;
 pub type ConstantIndex = usize;
 pub type StackIndex = usize;
 pub type ChunkIndex = usize;
 pub type ArgumentCount = usize;
 pub type UpvalueIndex = usize;
+pub type GlobalIndex = usize;
 
 #[derive(Debug, PartialEq, Copy, Clone, Serialize, Deserialize)]
 pub enum Instruction {
@@ -30,9 +34,9 @@ pub enum Instruction {
     Return,
     Print,
 
-    DefineGlobal(ConstantIndex),
-    GetGlobal(ConstantIndex),
-    SetGlobal(ConstantIndex),
+    DefineGlobal(GlobalIndex),
+    GetGlobal(GlobalIndex),
+    SetGlobal(GlobalIndex),
     GetLocal(StackIndex),
     SetLocal(StackIndex),
     GetUpvalue(StackIndex),
@@ -43,6 +47,8 @@ pub enum Instruction {
     Jump(InstructionIndex),
     JumpIfFalse(InstructionIndex),
     Call(ArgumentCount),
+    /// Call and return the result, reusing the frame of the function that is returning.
+    TailCall(ArgumentCount),
     CloseUpvalue,
 
     Class(ConstantIndex),
@@ -50,32 +56,32 @@ pub enum Instruction {
     // etc
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct Class {
     pub name: String,
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct Closure {
     pub function: Function,
     pub upvalues: Vec<Upvalue>,
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub enum Upvalue {
     Local(StackIndex),
     Upvalue(UpvalueIndex),
 }
 
 //TODO Merge this into Closure, we'll wait until methods are implemented though
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct Function {
     pub name: String,
     pub chunk_index: ChunkIndex,
     pub arity: usize,
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub enum Constant {
     Number(f64),
     String(String),
@@ -93,15 +99,147 @@ impl From<Function> for Constant {
     fn from(item: Function) -> Self { Constant::Closure(Closure{ function: item, upvalues: vec![]}) }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Instructions are encoded into bytes, see `Instruction::encode`. The code is validated when it's deserialized, so
+/// every instruction of a chunk decodes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "EncodedChunk")]
 pub struct Chunk {
-    instructions: Vec<Instruction>,
+    code: Vec<u8>,
 }
 
-#[derive(Serialize, Deserialize)]
+/// A chunk as it is serialized, before its code is validated.
+#[derive(Deserialize)]
+struct EncodedChunk {
+    code: Vec<u8>,
+}
+
+impl std::convert::TryFrom<EncodedChunk> for Chunk {
+    type Error = String;
+
+    /// Fails when an opcode is invalid, an operand is cut off or a jump doesn't land on an instruction.
+    fn try_from(chunk: EncodedChunk) -> Result<Chunk, String> {
+        let code = chunk.code;
+        let mut starts = vec![false; code.len() + 1];
+        let mut jumps = vec![];
+        let mut index = 0;
+        while index < code.len() {
+            starts[index] = true;
+            let (instruction, next) = Instruction::try_decode(&code, index)
+                .ok_or_else(|| format!("Invalid instruction at {}", index))?;
+            if let Instruction::Jump(to) | Instruction::JumpIfFalse(to) = instruction {
+                jumps.push((index, to));
+            }
+            index = next;
+        }
+        starts[code.len()] = true;
+
+        match jumps.into_iter().find(|&(_, to)| !starts.get(to).copied().unwrap_or(false)) {
+            Some((index, to)) => Err(format!("Invalid jump to {} at {}", to, index)),
+            None => Ok(Chunk { code }),
+        }
+    }
+}
+
+/// The names of the globals of a module, by slot. Globals with the same name share a slot. It is serialized as the
+/// list of names.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(from = "Vec<String>", into = "Vec<String>")]
+pub struct Globals {
+    names: Vec<String>,
+    /// The slot of every name, the compiler looks up every global it refers to.
+    slots: HashMap<String, GlobalIndex>,
+}
+
+impl From<Vec<String>> for Globals {
+    fn from(names: Vec<String>) -> Self {
+        // The first slot of a name wins, like `add` does
+        let mut slots = HashMap::new();
+        for (index, name) in names.iter().enumerate() {
+            slots.entry(name.clone()).or_insert(index);
+        }
+        Globals { names, slots }
+    }
+}
+
+impl From<Globals> for Vec<String> {
+    fn from(globals: Globals) -> Self {
+        globals.names
+    }
+}
+
+impl Globals {
+    /// Returns the slot of the global with this name, adding it if it doesn't exist yet.
+    pub fn add(&mut self, name: &str) -> GlobalIndex {
+        if let Some(index) = self.index(name) {
+            return index;
+        }
+
+        self.names.push(name.to_string());
+        self.slots.insert(name.to_string(), self.names.len() - 1);
+        self.names.len() - 1
+    }
+
+    pub fn index(&self, name: &str) -> Option<GlobalIndex> {
+        self.slots.get(name).copied()
+    }
+
+    pub fn name(&self, index: GlobalIndex) -> &str {
+        &self.names[index]
+    }
+
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+}
+
+/// The operands of the instructions are checked when a module is deserialized, so every constant, chunk and global
+/// they refer to exists.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(try_from = "EncodedModule")]
 pub struct Module {
     chunks: Vec<Chunk>,
     constants: Vec<Constant>,
+    globals: Globals,
+    /// The globals the script declares with `export`, the ones other modules can use once linked with it.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    exports: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    debug_info: Option<DebugInfo>,
+}
+
+/// A module as it is serialized, before the operands of its instructions are checked.
+#[derive(Deserialize)]
+struct EncodedModule {
+    chunks: Vec<Chunk>,
+    constants: Vec<Constant>,
+    globals: Globals,
+    #[serde(default)]
+    exports: Vec<String>,
+    #[serde(default)]
+    debug_info: Option<DebugInfo>,
+}
+
+impl std::convert::TryFrom<EncodedModule> for Module {
+    type Error = String;
+
+    /// Fails when an instruction refers to a constant, chunk or global the module doesn't have, or to a constant of
+    /// the wrong kind.
+    fn try_from(module: EncodedModule) -> Result<Module, String> {
+        let EncodedModule { chunks, constants, globals, exports, debug_info } = module;
+        let module = Module { chunks, constants, globals, exports, debug_info };
+        for (chunk_index, chunk) in module.chunks.iter().enumerate() {
+            for (index, instruction) in chunk.decoded() {
+                module.check_operand(instruction).map_err(|error| format!("{} at {} in chunk {}", error, index, chunk_index))?;
+            }
+        }
+        Ok(module)
+    }
+}
+
+impl Default for Module {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Module {
@@ -109,6 +247,9 @@ impl Module {
         Module {
             chunks: vec![],
             constants: vec![],
+            globals: Globals::default(),
+            exports: vec![],
+            debug_info: None,
         }
     }
 
@@ -133,25 +274,108 @@ impl Module {
         &self.constants[index]
     }
 
+    pub fn constant_mut(&mut self, index: ConstantIndex) -> &mut Constant {
+        &mut self.constants[index]
+    }
+
     pub fn chunks(&self) -> &[Chunk] {
         &self.chunks
     }
+
+    /// The constants, for moving them into another module.
+    pub fn into_constants(self) -> Vec<Constant> {
+        self.constants
+    }
+
+    pub fn globals(&self) -> &Globals {
+        &self.globals
+    }
+
+    pub fn globals_mut(&mut self) -> &mut Globals {
+        &mut self.globals
+    }
+
+    pub fn add_export(&mut self, name: &str) {
+        if !self.exports.iter().any(|export| export == name) {
+            self.exports.push(name.to_string());
+        }
+    }
+
+    pub fn exports(&self) -> &[String] {
+        &self.exports
+    }
+
+    pub fn debug_info(&self) -> Option<&DebugInfo> {
+        self.debug_info.as_ref()
+    }
+
+    pub fn debug_info_mut(&mut self) -> Option<&mut DebugInfo> {
+        self.debug_info.as_mut()
+    }
+
+    pub fn set_debug_info(&mut self, debug_info: DebugInfo) {
+        self.debug_info = Some(debug_info);
+    }
+
+    /// Whether what the operand of the instruction refers to is in the module.
+    fn check_operand(&self, instruction: Instruction) -> Result<(), String> {
+        use Instruction::*;
+
+        let constant = |index: ConstantIndex| self.constants.get(index).ok_or_else(|| format!("Invalid constant {}", index));
+        let unexpected = |index: ConstantIndex, expected: &str| Err(format!("Constant {} is not a {}", index, expected));
+        match instruction {
+            Constant(index) => match constant(index)? {
+                self::Constant::Number(_) | self::Constant::String(_) => Ok(()),
+                _ => unexpected(index, "number or string"),
+            },
+            GetProperty(index) | SetProperty(index) => match constant(index)? {
+                self::Constant::String(_) => Ok(()),
+                _ => unexpected(index, "string"),
+            },
+            Class(index) => match constant(index)? {
+                self::Constant::Class(_) => Ok(()),
+                _ => unexpected(index, "class"),
+            },
+            Closure(index) => match constant(index)? {
+                self::Constant::Closure(closure) if closure.function.chunk_index < self.chunks.len() => Ok(()),
+                self::Constant::Closure(closure) => Err(format!("Invalid chunk {}", closure.function.chunk_index)),
+                _ => unexpected(index, "closure"),
+            },
+            DefineGlobal(index) | GetGlobal(index) | SetGlobal(index) if index >= self.globals.names().len() => {
+                Err(format!("Invalid global {}", index))
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// Remove everything that's only there to map the module back to its source.
+    pub fn strip_debug_info(&mut self) {
+        self.debug_info = None;
+    }
+}
+
+impl Default for Chunk {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Chunk {
     pub fn new() -> Chunk {
         Chunk {
-            instructions: vec![],
+            code: vec![],
         }
     }
 
     pub fn add_instruction(&mut self, instruction: Instruction) -> InstructionIndex {
-        self.instructions.push(instruction);
-        self.instructions.len() - 1
+        let index = self.instruction_index();
+        instruction.encode(&mut self.code);
+        index
     }
 
+    /// The offset the next instruction will be encoded at.
     pub fn instruction_index(&self) -> InstructionIndex {
-        self.instructions.len()
+        self.code.len()
     }
 
     pub fn patch_instruction(&mut self, index: InstructionIndex) {
@@ -160,14 +384,230 @@ impl Chunk {
     }
 
     pub fn patch_instruction_to(&mut self, index: InstructionIndex, to: InstructionIndex) {
-        match self.instructions[index] {
-            Instruction::JumpIfFalse(ref mut placeholder) => *placeholder = to,
-            Instruction::Jump(ref mut placeholder) => *placeholder = to,
+        match self.code[index] {
+            opcode::JUMP | opcode::JUMP_IF_FALSE => write_u24(&mut self.code[index + 1..], to),
             _ => (), // Nothing to patch
         };
     }
 
-    pub fn instructions(&self) -> &[Instruction] {
-        &self.instructions
+    /// Decode the instruction at `index`, also returns the index of the next instruction.
+    pub fn read(&self, index: InstructionIndex) -> (Instruction, InstructionIndex) {
+        Instruction::decode(&self.code, index)
+    }
+
+    pub fn code(&self) -> &[u8] {
+        &self.code
+    }
+
+    /// Decode every instruction, for debugging and testing.
+    pub fn instructions(&self) -> Vec<Instruction> {
+        self.decoded().map(|(_, instruction)| instruction).collect()
+    }
+
+    /// Decode every instruction, together with its index.
+    pub fn decoded(&self) -> impl Iterator<Item=(InstructionIndex, Instruction)> + '_ {
+        let mut index = 0;
+        std::iter::from_fn(move || {
+            if index >= self.code.len() {
+                return None;
+            }
+            let (instruction, next) = self.read(index);
+            let result = (index, instruction);
+            index = next;
+            Some(result)
+        })
+    }
+}
+
+/// Every instruction starts with a one byte opcode. Instructions with an operand have a short form with
+/// a one byte operand, and a long form with a three byte operand for when it doesn't fit. Jumps always
+/// use the long form, so they can be patched once their target is known.
+mod opcode {
+    pub const CONSTANT: u8 = 0;
+    pub const CONSTANT_LONG: u8 = 1;
+    pub const TRUE: u8 = 2;
+    pub const FALSE: u8 = 3;
+    pub const NIL: u8 = 4;
+    pub const NEGATE: u8 = 5;
+    pub const ADD: u8 = 6;
+    pub const SUBTRACT: u8 = 7;
+    pub const MULTIPLY: u8 = 8;
+    pub const DIVIDE: u8 = 9;
+    pub const NOT: u8 = 10;
+    pub const EQUAL: u8 = 11;
+    pub const GREATER: u8 = 12;
+    pub const LESS: u8 = 13;
+    pub const POP: u8 = 14;
+    pub const RETURN: u8 = 15;
+    pub const PRINT: u8 = 16;
+    pub const DEFINE_GLOBAL: u8 = 17;
+    pub const DEFINE_GLOBAL_LONG: u8 = 18;
+    pub const GET_GLOBAL: u8 = 19;
+    pub const GET_GLOBAL_LONG: u8 = 20;
+    pub const SET_GLOBAL: u8 = 21;
+    pub const SET_GLOBAL_LONG: u8 = 22;
+    pub const GET_LOCAL: u8 = 23;
+    pub const GET_LOCAL_LONG: u8 = 24;
+    pub const SET_LOCAL: u8 = 25;
+    pub const SET_LOCAL_LONG: u8 = 26;
+    pub const GET_UPVALUE: u8 = 27;
+    pub const GET_UPVALUE_LONG: u8 = 28;
+    pub const SET_UPVALUE: u8 = 29;
+    pub const SET_UPVALUE_LONG: u8 = 30;
+    pub const SET_PROPERTY: u8 = 31;
+    pub const SET_PROPERTY_LONG: u8 = 32;
+    pub const GET_PROPERTY: u8 = 33;
+    pub const GET_PROPERTY_LONG: u8 = 34;
+    pub const JUMP: u8 = 35;
+    pub const JUMP_IF_FALSE: u8 = 36;
+    pub const CALL: u8 = 37;
+    pub const CALL_LONG: u8 = 38;
+    pub const CLOSE_UPVALUE: u8 = 39;
+    pub const CLASS: u8 = 40;
+    pub const CLASS_LONG: u8 = 41;
+    pub const CLOSURE: u8 = 42;
+    pub const CLOSURE_LONG: u8 = 43;
+    pub const TAIL_CALL: u8 = 44;
+    pub const TAIL_CALL_LONG: u8 = 45;
+}
+
+/// The largest operand an instruction can have.
+pub const OPERAND_MAX: usize = (1 << 24) - 1;
+
+fn write_u24(code: &mut [u8], value: usize) {
+    assert!(value <= OPERAND_MAX, "Operand {} is too large", value);
+    code[0] = (value >> 16) as u8;
+    code[1] = (value >> 8) as u8;
+    code[2] = value as u8;
+}
+
+fn read_u24(code: &[u8]) -> usize {
+    (code[0] as usize) << 16 | (code[1] as usize) << 8 | code[2] as usize
+}
+
+impl Instruction {
+    fn encode(&self, code: &mut Vec<u8>) {
+        use Instruction::*;
+
+        let (short, long, operand) = match *self {
+            Constant(index) => (opcode::CONSTANT, opcode::CONSTANT_LONG, index),
+            DefineGlobal(index) => (opcode::DEFINE_GLOBAL, opcode::DEFINE_GLOBAL_LONG, index),
+            GetGlobal(index) => (opcode::GET_GLOBAL, opcode::GET_GLOBAL_LONG, index),
+            SetGlobal(index) => (opcode::SET_GLOBAL, opcode::SET_GLOBAL_LONG, index),
+            GetLocal(index) => (opcode::GET_LOCAL, opcode::GET_LOCAL_LONG, index),
+            SetLocal(index) => (opcode::SET_LOCAL, opcode::SET_LOCAL_LONG, index),
+            GetUpvalue(index) => (opcode::GET_UPVALUE, opcode::GET_UPVALUE_LONG, index),
+            SetUpvalue(index) => (opcode::SET_UPVALUE, opcode::SET_UPVALUE_LONG, index),
+            SetProperty(index) => (opcode::SET_PROPERTY, opcode::SET_PROPERTY_LONG, index),
+            GetProperty(index) => (opcode::GET_PROPERTY, opcode::GET_PROPERTY_LONG, index),
+            Call(arity) => (opcode::CALL, opcode::CALL_LONG, arity),
+            TailCall(arity) => (opcode::TAIL_CALL, opcode::TAIL_CALL_LONG, arity),
+            Class(index) => (opcode::CLASS, opcode::CLASS_LONG, index),
+            Closure(index) => (opcode::CLOSURE, opcode::CLOSURE_LONG, index),
+            Jump(to) => (opcode::JUMP, opcode::JUMP, to),
+            JumpIfFalse(to) => (opcode::JUMP_IF_FALSE, opcode::JUMP_IF_FALSE, to),
+            _ => {
+                code.push(match *self {
+                    True => opcode::TRUE,
+                    False => opcode::FALSE,
+                    Nil => opcode::NIL,
+                    Negate => opcode::NEGATE,
+                    Add => opcode::ADD,
+                    Subtract => opcode::SUBTRACT,
+                    Multiply => opcode::MULTIPLY,
+                    Divide => opcode::DIVIDE,
+                    Not => opcode::NOT,
+                    Equal => opcode::EQUAL,
+                    Greater => opcode::GREATER,
+                    Less => opcode::LESS,
+                    Pop => opcode::POP,
+                    Return => opcode::RETURN,
+                    Print => opcode::PRINT,
+                    CloseUpvalue => opcode::CLOSE_UPVALUE,
+                    _ => unreachable!(),
+                });
+                return;
+            },
+        };
+
+        if short != long && operand <= u8::MAX as usize {
+            code.push(short);
+            code.push(operand as u8);
+        } else {
+            code.push(long);
+            code.extend_from_slice(&[0; 3]);
+            let start = code.len() - 3;
+            write_u24(&mut code[start..], operand);
+        }
+    }
+
+    fn decode(code: &[u8], index: InstructionIndex) -> (Instruction, InstructionIndex) {
+        match Instruction::try_decode(code, index) {
+            Some(decoded) => decoded,
+            None => panic!("Invalid instruction at {}", index),
+        }
+    }
+
+    /// Like `decode`, None when the opcode is invalid or the operand is cut off.
+    fn try_decode(code: &[u8], index: InstructionIndex) -> Option<(Instruction, InstructionIndex)> {
+        use Instruction::*;
+
+        let short = |instruction: fn(usize) -> Instruction| {
+            code.get(index + 1).map(|&operand| (instruction(operand as usize), index + 2))
+        };
+        let long = |instruction: fn(usize) -> Instruction| {
+            code.get(index + 1..index + 4).map(|operand| (instruction(read_u24(operand)), index + 4))
+        };
+        let simple = |instruction: Instruction| Some((instruction, index + 1));
+
+        match *code.get(index)? {
+            opcode::CONSTANT => short(Constant),
+            opcode::CONSTANT_LONG => long(Constant),
+            opcode::TRUE => simple(True),
+            opcode::FALSE => simple(False),
+            opcode::NIL => simple(Nil),
+            opcode::NEGATE => simple(Negate),
+            opcode::ADD => simple(Add),
+            opcode::SUBTRACT => simple(Subtract),
+            opcode::MULTIPLY => simple(Multiply),
+            opcode::DIVIDE => simple(Divide),
+            opcode::NOT => simple(Not),
+            opcode::EQUAL => simple(Equal),
+            opcode::GREATER => simple(Greater),
+            opcode::LESS => simple(Less),
+            opcode::POP => simple(Pop),
+            opcode::RETURN => simple(Return),
+            opcode::PRINT => simple(Print),
+            opcode::DEFINE_GLOBAL => short(DefineGlobal),
+            opcode::DEFINE_GLOBAL_LONG => long(DefineGlobal),
+            opcode::GET_GLOBAL => short(GetGlobal),
+            opcode::GET_GLOBAL_LONG => long(GetGlobal),
+            opcode::SET_GLOBAL => short(SetGlobal),
+            opcode::SET_GLOBAL_LONG => long(SetGlobal),
+            opcode::GET_LOCAL => short(GetLocal),
+            opcode::GET_LOCAL_LONG => long(GetLocal),
+            opcode::SET_LOCAL => short(SetLocal),
+            opcode::SET_LOCAL_LONG => long(SetLocal),
+            opcode::GET_UPVALUE => short(GetUpvalue),
+            opcode::GET_UPVALUE_LONG => long(GetUpvalue),
+            opcode::SET_UPVALUE => short(SetUpvalue),
+            opcode::SET_UPVALUE_LONG => long(SetUpvalue),
+            opcode::SET_PROPERTY => short(SetProperty),
+            opcode::SET_PROPERTY_LONG => long(SetProperty),
+            opcode::GET_PROPERTY => short(GetProperty),
+            opcode::GET_PROPERTY_LONG => long(GetProperty),
+            opcode::JUMP => long(Jump),
+            opcode::JUMP_IF_FALSE => long(JumpIfFalse),
+            opcode::CALL => short(Call),
+            opcode::CALL_LONG => long(Call),
+            opcode::TAIL_CALL => short(TailCall),
+            opcode::TAIL_CALL_LONG => long(TailCall),
+            opcode::CLOSE_UPVALUE => simple(CloseUpvalue),
+            opcode::CLASS => short(Class),
+            opcode::CLASS_LONG => long(Class),
+            opcode::CLOSURE => short(Closure),
+            opcode::CLOSURE_LONG => long(Closure),
+            _ => None,
+        }
     }
 }
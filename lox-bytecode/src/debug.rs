@@ -0,0 +1,112 @@
+use serde::{Serialize, Deserialize};
+use crate::bytecode::{InstructionIndex, StackIndex};
+
+/// Maps compiled code back to the source it came from, for stack traces, debuggers and profilers.
+/// None of this is needed to run a module, `Module::strip_debug_info` removes it.
+#[derive(Debug, PartialEq, Clone, Default, Serialize, Deserialize)]
+pub struct DebugInfo {
+    /// The name of the file the module was compiled from, if it came from a file.
+    pub source_file: Option<String>,
+    /// Debug info for every chunk, by chunk index.
+    pub chunks: Vec<ChunkDebugInfo>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+/// The start and (inclusive) end of a piece of source.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Span {
+    pub start: Position,
+    pub end: Position,
+}
+
+#[derive(Debug, PartialEq, Clone, Default, Serialize, Deserialize)]
+pub struct ChunkDebugInfo {
+    /// The span of the statement every instruction was compiled from, by the index of the first instruction with
+    /// that span. Sorted by index, the instructions after an entry have its span until the next entry.
+    pub spans: Vec<(InstructionIndex, Span)>,
+    /// Every local that was declared in the chunk, including the parameters.
+    pub locals: Vec<Local>,
+    /// The names of the upvalues of the function, by upvalue index.
+    pub upvalues: Vec<String>,
+    /// The file the chunk was compiled from, the source file of the module when it doesn't have one. Modules that
+    /// import other modules are compiled from more than one file.
+    #[serde(default)]
+    pub source_file: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Local {
+    pub name: String,
+    pub slot: StackIndex,
+    /// The local holds its value from the instruction at `start` until the one at `end` (exclusive).
+    pub start: InstructionIndex,
+    pub end: InstructionIndex,
+}
+
+impl DebugInfo {
+    pub fn chunk(&self, index: usize) -> Option<&ChunkDebugInfo> {
+        self.chunks.get(index)
+    }
+
+    /// The file the chunk at `index` was compiled from.
+    pub fn source_file(&self, index: usize) -> Option<&str> {
+        self.chunk(index).and_then(|chunk| chunk.source_file.as_deref()).or(self.source_file.as_deref())
+    }
+
+    /// Whether `file` names the file the chunk at `index` was compiled from, either of them can be a relative path.
+    pub fn is_source_file(&self, index: usize, file: &str) -> bool {
+        use std::path::Path;
+
+        match self.source_file(index) {
+            Some(source_file) => Path::new(source_file).ends_with(file) || Path::new(file).ends_with(source_file),
+            None => false,
+        }
+    }
+
+    /// The source file of the chunks that `file` names, it can be named by a relative path.
+    pub fn find_source_file(&self, file: &str) -> Option<&str> {
+        (0..self.chunks.len()).find(|&index| self.is_source_file(index, file)).and_then(|index| self.source_file(index))
+    }
+
+    /// The first line at or after `line` of `file` that a statement starts on, where execution can stop.
+    pub fn statement_line_from(&self, file: &str, line: usize) -> Option<usize> {
+        self.chunks.iter().enumerate()
+            .filter(|(index, _)| self.is_source_file(*index, file))
+            .flat_map(|(_, chunk)| chunk.spans.iter())
+            .map(|(_, span)| span.start.line)
+            .filter(|&start| start >= line)
+            .min()
+    }
+}
+
+impl ChunkDebugInfo {
+    /// The span of the instruction at `index`.
+    pub fn span(&self, index: InstructionIndex) -> Option<Span> {
+        match self.spans.binary_search_by_key(&index, |(start, _)| *start) {
+            Ok(entry) => Some(self.spans[entry].1),
+            Err(0) => None,
+            Err(entry) => Some(self.spans[entry - 1].1),
+        }
+    }
+
+    pub fn line(&self, index: InstructionIndex) -> Option<usize> {
+        self.span(index).map(|span| span.start.line)
+    }
+
+    /// The line of the statement that starts with the instruction at `index`, None if it's in the middle of one.
+    pub fn statement_line(&self, index: InstructionIndex) -> Option<usize> {
+        self.spans.binary_search_by_key(&index, |(start, _)| *start)
+            .ok()
+            .map(|entry| self.spans[entry].1.start.line)
+    }
+
+    /// The locals that hold a value while the instruction at `index` runs, innermost last.
+    pub fn locals_at(&self, index: InstructionIndex) -> impl Iterator<Item=&Local> {
+        self.locals.iter().filter(move |local| local.start <= index && index < local.end)
+    }
+}
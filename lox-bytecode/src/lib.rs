@@ -1 +1,4 @@
-pub mod bytecode;
\ No newline at end of file
+pub mod bytecode;
+pub mod register;
+pub mod debug;
+pub mod link;
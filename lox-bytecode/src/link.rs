@@ -0,0 +1,309 @@
+//! Links separately compiled modules into one, so a precompiled library can be distributed apart from the
+//! applications that use it.
+
+use std::collections::HashMap;
+use crate::bytecode::*;
+use crate::debug::{ChunkDebugInfo, DebugInfo};
+
+#[derive(Debug, PartialEq)]
+pub enum LinkError {
+    /// More than one module defines the global.
+    DuplicateGlobal(String),
+}
+
+impl std::fmt::Display for LinkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LinkError::DuplicateGlobal(name) => write!(f, "More than one module defines the global '{}'.", name),
+        }
+    }
+}
+
+/// Link the application with the libraries it uses into a module that runs them all. The script of every library
+/// runs first, in order, and the script of the application last.
+///
+/// Globals are linked by name, a global the application uses and a library exports is the same global in the linked
+/// module. The globals a library defines without exporting them are its own, like the globals of an imported module
+/// they are renamed to `path::name`, after the source file of the library or its index when it has none. The debug
+/// info has a single source file, only the chunks of the application keep their spans.
+pub fn link(application: Module, libraries: Vec<Module>) -> Result<Module, LinkError> {
+    let mut linked = Module::new();
+    // The script that runs the scripts of the modules
+    let script = linked.add_chunk();
+    let mut debug_info = application.debug_info().map(|debug_info| DebugInfo {
+        source_file: debug_info.source_file.clone(),
+        chunks: vec![ChunkDebugInfo::default()],
+    });
+
+    let modules: Vec<Module> = libraries.into_iter().chain(std::iter::once(application)).collect();
+    let application = modules.len() - 1;
+    let mut defined_by = HashMap::new();
+    let mut scripts = vec![];
+    for (index, module) in modules.into_iter().enumerate() {
+        let defined = defined_globals(&module);
+        let private: Vec<&String> = if index == application {
+            vec![]
+        } else {
+            defined.iter().filter(|name| !module.exports().contains(name)).collect()
+        };
+        let path = module.debug_info()
+            .and_then(|debug_info| debug_info.source_file.clone())
+            .unwrap_or_else(|| format!("library {}", index));
+        let linked_name = |name: &String| if private.contains(&name) { format!("{}::{}", path, name) } else { name.clone() };
+
+        for name in defined.iter().map(linked_name) {
+            if defined_by.insert(name.clone(), index).is_some() {
+                return Err(LinkError::DuplicateGlobal(name));
+            }
+        }
+        if index == application {
+            for name in module.exports() {
+                linked.add_export(name);
+            }
+        }
+
+        let chunk_offset = linked.chunks().len();
+        let constant_offset = linked.constants().len();
+        let globals: Vec<GlobalIndex> = module.globals().names().iter().map(|name| linked.globals_mut().add(&linked_name(name))).collect();
+        for (chunk_index, chunk) in module.chunks().iter().enumerate() {
+            let (chunk, offsets) = relocate_chunk(chunk, |instruction| relocate(instruction, constant_offset, &globals));
+            let linked_index = linked.add_chunk();
+            *linked.chunk_mut(linked_index) = chunk;
+
+            if let Some(debug_info) = &mut debug_info {
+                let mut chunk_debug_info = module.debug_info()
+                    .and_then(|debug_info| debug_info.chunk(chunk_index))
+                    .map_or_else(ChunkDebugInfo::default, |chunk_debug_info| relocate_debug_info(chunk_debug_info, &offsets));
+                if index != application {
+                    chunk_debug_info.spans.clear();
+                }
+                debug_info.chunks.push(chunk_debug_info);
+            }
+        }
+
+        for constant in module.into_constants() {
+            linked.add_constant(match constant {
+                Constant::Closure(Closure { function, upvalues }) => {
+                    let function = Function { chunk_index: function.chunk_index + chunk_offset, ..function };
+                    Constant::Closure(Closure { function, upvalues })
+                },
+                constant => constant,
+            });
+        }
+
+        // Chunk 0 of a module is its script
+        scripts.push(Function { name: "script".to_string(), chunk_index: chunk_offset, arity: 0 });
+    }
+
+    for function in scripts {
+        let constant = linked.add_constant(Constant::from(function));
+        let chunk = linked.chunk_mut(script);
+        chunk.add_instruction(Instruction::Closure(constant));
+        chunk.add_instruction(Instruction::Call(0));
+        chunk.add_instruction(Instruction::Pop);
+    }
+    let chunk = linked.chunk_mut(script);
+    chunk.add_instruction(Instruction::Nil);
+    chunk.add_instruction(Instruction::Return);
+
+    if let Some(debug_info) = debug_info {
+        linked.set_debug_info(debug_info);
+    }
+    Ok(linked)
+}
+
+/// Add the chunks, constants and globals of `module` to `into`, with the globals linked by name. What `into` already
+/// has keeps its index, so closures made from it still run. Returns the index of the script of `module`.
+pub fn append(into: &mut Module, module: &Module) -> ChunkIndex {
+    let chunk_offset = into.chunks().len();
+    let constant_offset = into.constants().len();
+    let globals: Vec<GlobalIndex> = module.globals().names().iter().map(|name| into.globals_mut().add(name)).collect();
+    for chunk in module.chunks() {
+        let (chunk, _) = relocate_chunk(chunk, |instruction| relocate(instruction, constant_offset, &globals));
+        let index = into.add_chunk();
+        *into.chunk_mut(index) = chunk;
+    }
+
+    for constant in module.constants() {
+        into.add_constant(match constant.clone() {
+            Constant::Closure(Closure { function, upvalues }) => {
+                let function = Function { chunk_index: function.chunk_index + chunk_offset, ..function };
+                Constant::Closure(Closure { function, upvalues })
+            },
+            constant => constant,
+        });
+    }
+
+    chunk_offset
+}
+
+/// The names of the globals the module defines.
+fn defined_globals(module: &Module) -> Vec<String> {
+    let mut names: Vec<String> = module.chunks().iter()
+        .flat_map(|chunk| chunk.instructions())
+        .filter_map(|instruction| match instruction {
+            Instruction::DefineGlobal(global) => Some(module.globals().name(global).to_string()),
+            _ => None,
+        })
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// The instruction with the constants and globals it refers to moved to where they are in the linked module.
+fn relocate(instruction: Instruction, constant_offset: ConstantIndex, globals: &[GlobalIndex]) -> Instruction {
+    use Instruction::*;
+
+    match instruction {
+        Constant(index) => Constant(index + constant_offset),
+        GetProperty(index) => GetProperty(index + constant_offset),
+        SetProperty(index) => SetProperty(index + constant_offset),
+        Class(index) => Class(index + constant_offset),
+        Closure(index) => Closure(index + constant_offset),
+        DefineGlobal(index) => DefineGlobal(globals[index]),
+        GetGlobal(index) => GetGlobal(globals[index]),
+        SetGlobal(index) => SetGlobal(globals[index]),
+        instruction => instruction,
+    }
+}
+
+/// The chunk with every instruction relocated, and where every instruction ended up. Operands that grow can make
+/// an instruction longer, the jumps are patched to where their targets ended up.
+fn relocate_chunk<F>(chunk: &Chunk, relocate: F) -> (Chunk, HashMap<InstructionIndex, InstructionIndex>)
+    where F: Fn(Instruction) -> Instruction {
+    let mut relocated = Chunk::new();
+    let mut offsets = HashMap::new();
+    let mut jumps = vec![];
+    for (index, instruction) in chunk.decoded() {
+        let relocated_index = relocated.add_instruction(relocate(instruction));
+        offsets.insert(index, relocated_index);
+        if let Instruction::Jump(to) | Instruction::JumpIfFalse(to) = instruction {
+            jumps.push((relocated_index, to));
+        }
+    }
+    // Jumps and locals can end at the end of the chunk
+    offsets.insert(chunk.instruction_index(), relocated.instruction_index());
+
+    for (index, to) in jumps {
+        relocated.patch_instruction_to(index, offsets[&to]);
+    }
+    (relocated, offsets)
+}
+
+fn relocate_debug_info(debug_info: &ChunkDebugInfo, offsets: &HashMap<InstructionIndex, InstructionIndex>) -> ChunkDebugInfo {
+    let mut debug_info = debug_info.clone();
+    for (index, _) in &mut debug_info.spans {
+        *index = offsets[index];
+    }
+    for local in &mut debug_info.locals {
+        local.start = offsets[&local.start];
+        local.end = offsets[&local.end];
+    }
+    debug_info
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debug::{Local, Position, Span};
+
+    /// A module with a script that defines the globals, and exports the ones that are marked.
+    fn defines(globals: &[(&str, bool)]) -> Module {
+        let mut module = Module::new();
+        let chunk = module.add_chunk();
+        for &(name, exported) in globals {
+            let global = module.globals_mut().add(name);
+            module.chunk_mut(chunk).add_instruction(Instruction::Nil);
+            module.chunk_mut(chunk).add_instruction(Instruction::DefineGlobal(global));
+            if exported {
+                module.add_export(name);
+            }
+        }
+        module.chunk_mut(chunk).add_instruction(Instruction::Nil);
+        module.chunk_mut(chunk).add_instruction(Instruction::Return);
+        module
+    }
+
+    #[test]
+    fn test_relocate_chunk() {
+        let mut chunk = Chunk::new();
+        let start = chunk.add_instruction(Instruction::Constant(0));
+        let jump = chunk.add_instruction(Instruction::JumpIfFalse(0));
+        let constant = chunk.add_instruction(Instruction::Constant(1));
+        chunk.add_instruction(Instruction::Jump(start));
+        chunk.patch_instruction(jump);
+
+        // The constants no longer fit in the short form of the instruction
+        let (relocated, offsets) = relocate_chunk(&chunk, |instruction| relocate(instruction, 300, &[]));
+        let end = relocated.instruction_index();
+        assert!(end > chunk.instruction_index());
+        assert_eq!(relocated.instructions(), vec![
+            Instruction::Constant(300),
+            Instruction::JumpIfFalse(end),
+            Instruction::Constant(301),
+            Instruction::Jump(0),
+        ]);
+        assert_eq!(relocated.read(offsets[&constant]).0, Instruction::Constant(301));
+        assert_eq!(offsets[&chunk.instruction_index()], end);
+    }
+
+    #[test]
+    fn test_relocate_debug_info() {
+        let span = |line| Span { start: Position { line, column: 1 }, end: Position { line, column: 10 } };
+        let debug_info = ChunkDebugInfo {
+            spans: vec![(0, span(1)), (2, span(2))],
+            locals: vec![Local { name: "a".to_string(), slot: 1, start: 2, end: 5 }],
+            ..ChunkDebugInfo::default()
+        };
+        let offsets = vec![(0, 0), (2, 4), (5, 9)].into_iter().collect();
+
+        let relocated = relocate_debug_info(&debug_info, &offsets);
+        assert_eq!(relocated.spans, vec![(0, span(1)), (4, span(2))]);
+        assert_eq!(relocated.locals, vec![Local { name: "a".to_string(), slot: 1, start: 4, end: 9 }]);
+    }
+
+    #[test]
+    fn test_link_globals() {
+        let mut application = defines(&[("secret", false)]);
+        let total = application.globals_mut().add("total");
+        application.chunk_mut(0).add_instruction(Instruction::GetGlobal(total));
+        let libraries = vec![defines(&[("total", true), ("secret", false)]), defines(&[("secret", false)])];
+
+        // Only exported globals are shared, the others are private to their library
+        let linked = link(application, libraries).unwrap();
+        assert_eq!(linked.globals().names(), ["total", "library 0::secret", "library 1::secret", "secret"]);
+        assert_eq!(linked.chunks().len(), 4);
+    }
+
+    #[test]
+    fn test_append() {
+        let mut module = defines(&[("total", false)]);
+        module.add_constant(Constant::from(1.0));
+        let mut other = defines(&[("count", false), ("total", false)]);
+        let constant = other.add_constant(Constant::from(Function { name: "f".to_string(), chunk_index: 0, arity: 0 }));
+        other.chunk_mut(0).add_instruction(Instruction::Closure(constant));
+
+        let script = append(&mut module, &other);
+        assert_eq!(script, 1);
+        assert_eq!(module.globals().names(), ["total", "count"]);
+        assert_eq!(module.chunk(0).instructions()[1], Instruction::DefineGlobal(0));
+        assert_eq!(&module.chunk(1).instructions()[..4], [
+            Instruction::Nil, Instruction::DefineGlobal(1), Instruction::Nil, Instruction::DefineGlobal(0),
+        ]);
+        assert_eq!(module.chunk(1).instructions().last(), Some(&Instruction::Closure(1)));
+        match module.constant(1) {
+            Constant::Closure(closure) => assert_eq!(closure.function.chunk_index, 1),
+            constant => panic!("expected a closure, got {:?}", constant),
+        }
+    }
+
+    #[test]
+    fn test_duplicate_global() {
+        let result = link(defines(&[]), vec![defines(&[("total", true)]), defines(&[("total", true)])]);
+        assert_eq!(result.err(), Some(LinkError::DuplicateGlobal("total".to_string())));
+
+        let result = link(defines(&[("total", false)]), vec![defines(&[("total", true)])]);
+        assert_eq!(result.err(), Some(LinkError::DuplicateGlobal("total".to_string())));
+    }
+}
@@ -0,0 +1,177 @@
+//! A register based instruction set, an alternative to the stack based one in `bytecode`.
+//!
+//! Instructions work on the slots of the current call frame, like Lua. Slot 0 holds the closure
+//! being called, the arguments come after it, then the locals in the order they are declared,
+//! and temporaries above those.
+
+use serde::{Serialize, Deserialize};
+use crate::bytecode::{Constant, ArgumentCount, ChunkIndex, ConstantIndex, GlobalIndex, Globals, InstructionIndex, UpvalueIndex};
+
+/// A slot in the current call frame.
+pub type Register = usize;
+
+/// Operands come in the order destination, sources.
+#[derive(Debug, PartialEq, Copy, Clone, Serialize, Deserialize)]
+pub enum Instruction {
+    LoadConstant(Register, ConstantIndex),
+    LoadNil(Register),
+    LoadTrue(Register),
+    LoadFalse(Register),
+    Move(Register, Register),
+
+    Negate(Register, Register),
+    Not(Register, Register),
+    Add(Register, Register, Register),
+    Subtract(Register, Register, Register),
+    Multiply(Register, Register, Register),
+    Divide(Register, Register, Register),
+    Equal(Register, Register, Register),
+    Greater(Register, Register, Register),
+    Less(Register, Register, Register),
+
+    Print(Register),
+
+    DefineGlobal(GlobalIndex, Register),
+    GetGlobal(Register, GlobalIndex),
+    SetGlobal(GlobalIndex, Register),
+    GetUpvalue(Register, UpvalueIndex),
+    SetUpvalue(UpvalueIndex, Register),
+    /// Destination, instance, property name.
+    GetProperty(Register, Register, ConstantIndex),
+    /// Instance, property name, value.
+    SetProperty(Register, ConstantIndex, Register),
+
+    Jump(InstructionIndex),
+    JumpIfFalse(Register, InstructionIndex),
+    JumpIfTrue(Register, InstructionIndex),
+
+    /// The callee is in the register, followed by the arguments. The result replaces the callee.
+    Call(Register, ArgumentCount),
+    Return(Register),
+    /// Closes every open upvalue of this register and the ones above it.
+    CloseUpvalues(Register),
+
+    Class(Register, ConstantIndex),
+    Closure(Register, ConstantIndex),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Chunk {
+    instructions: Vec<Instruction>,
+    /// How many registers a call frame running this chunk needs.
+    registers: usize,
+}
+
+/// A module compiled to register instructions. It has the same constants as a stack based module,
+/// closures in it refer to register chunks.
+#[derive(Serialize, Deserialize)]
+pub struct Module {
+    chunks: Vec<Chunk>,
+    constants: Vec<Constant>,
+    globals: Globals,
+}
+
+impl Module {
+    pub fn new() -> Module {
+        Module {
+            chunks: vec![],
+            constants: vec![],
+            globals: Globals::default(),
+        }
+    }
+
+    pub fn chunk(&self, index: ChunkIndex) -> &Chunk { &self.chunks[index] }
+    pub fn chunk_mut(&mut self, index: ChunkIndex) -> &mut Chunk { &mut self.chunks[index] }
+
+    pub fn add_chunk(&mut self) -> ChunkIndex {
+        self.chunks.push(Chunk::new());
+        self.chunks.len() - 1
+    }
+
+    pub fn add_constant(&mut self, constant: Constant) -> ConstantIndex {
+        self.constants.push(constant);
+        self.constants.len() - 1
+    }
+
+    pub fn constants(&self) -> &[Constant] {
+        &self.constants
+    }
+
+    pub fn constant(&self, index: ConstantIndex) -> &Constant {
+        &self.constants[index]
+    }
+
+    pub fn chunks(&self) -> &[Chunk] {
+        &self.chunks
+    }
+
+    pub fn globals(&self) -> &Globals {
+        &self.globals
+    }
+
+    pub fn globals_mut(&mut self) -> &mut Globals {
+        &mut self.globals
+    }
+}
+
+impl Default for Module {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Chunk {
+    pub fn new() -> Chunk {
+        Chunk {
+            instructions: vec![],
+            registers: 0,
+        }
+    }
+
+    pub fn add_instruction(&mut self, instruction: Instruction) -> InstructionIndex {
+        self.instructions.push(instruction);
+        self.instructions.len() - 1
+    }
+
+    pub fn instruction_index(&self) -> InstructionIndex {
+        self.instructions.len()
+    }
+
+    pub fn patch_instruction(&mut self, index: InstructionIndex) {
+        let current = self.instruction_index();
+        self.patch_instruction_to(index, current)
+    }
+
+    pub fn patch_instruction_to(&mut self, index: InstructionIndex, to: InstructionIndex) {
+        match &mut self.instructions[index] {
+            Instruction::Jump(target) => *target = to,
+            Instruction::JumpIfFalse(_, target) => *target = to,
+            Instruction::JumpIfTrue(_, target) => *target = to,
+            _ => (), // Nothing to patch
+        }
+    }
+
+    #[inline]
+    pub fn instruction(&self, index: InstructionIndex) -> Instruction {
+        self.instructions[index]
+    }
+
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.instructions
+    }
+
+    pub fn registers(&self) -> usize {
+        self.registers
+    }
+
+    /// Make sure a frame running this chunk has at least `count` registers.
+    pub fn reserve_registers(&mut self, count: usize) {
+        self.registers = self.registers.max(count);
+    }
+}
+
+impl Default for Chunk {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,502 @@
+//! What editors need to know about a program without running it: errors, where every name is declared and used,
+//! and what kind of thing every token is.
+
+use crate::ast::*;
+use crate::bettercompiler::CompilerError;
+use crate::common::ParseError;
+use crate::bettercompiler::locals::Locals;
+use crate::position::{Position, Span, WithSpan};
+use crate::resolver;
+use crate::token::Token;
+use std::collections::HashMap;
+
+#[derive(Debug, Default)]
+pub struct Analysis {
+    pub diagnostics: Vec<Diagnostic>,
+    pub symbols: Vec<Symbol>,
+    /// The names that aren't declared anywhere, globals that are defined by the VM like `clock` among them.
+    pub unresolved: Vec<Unresolved>,
+    /// The tokens that are more than punctuation with what they are, in source order.
+    pub tokens: Vec<(Span, TokenKind)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub span: Span,
+    pub message: String,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SymbolKind {
+    Global,
+    Local,
+    Parameter,
+    Function,
+    Class,
+    Method,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    /// The name in the declaration.
+    pub declaration: Span,
+    /// The whole declaration.
+    pub span: Span,
+    pub references: Vec<Span>,
+    /// The number of parameters of functions and methods.
+    pub arity: Option<usize>,
+    /// The function or class the symbol is declared in, by symbol index.
+    pub parent: Option<usize>,
+    /// The local in an enclosing scope with the same name, by symbol index.
+    pub shadows: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Unresolved {
+    pub name: String,
+    pub span: Span,
+    /// It is assigned to instead of read.
+    pub assignment: bool,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TokenKind {
+    Keyword,
+    Variable,
+    Parameter,
+    Function,
+    Class,
+    Method,
+    Property,
+    String,
+    Number,
+    Operator,
+}
+
+impl Analysis {
+    /// The symbol that is declared or referred to at `position`.
+    pub fn symbol_at(&self, position: Position) -> Option<usize> {
+        let contains = |span: &Span| span.start <= position && position <= span.end;
+        self.symbols.iter().position(|symbol| contains(&symbol.declaration) || symbol.references.iter().any(contains))
+    }
+}
+
+/// Analyzes code that is being typed, so what doesn't lex or parse is a diagnostic.
+pub fn analyze(code: &str) -> Analysis {
+    use crate::{tokenizer::tokenize_with_errors, stmt_parser::parse};
+    let (tokens, errors) = tokenize_with_errors(code);
+    let mut it = tokens.as_slice().iter().peekable();
+
+    let lexing = errors.into_iter().map(|error| parse_diagnostic(error, &tokens)).collect();
+    let mut analysis = Analysis { diagnostics: lexing, ..Analysis::default() };
+    match parse(&mut it) {
+        Ok(ast) => {
+            analysis.diagnostics.extend(diagnostics(&resolver::resolve_ast(&ast, false).errors));
+            let (symbols, unresolved) = Resolver::resolve(&ast);
+            analysis.symbols = symbols;
+            analysis.unresolved = unresolved;
+        },
+        Err(error) => analysis.diagnostics.push(parse_diagnostic(error, &tokens)),
+    }
+
+    analysis.tokens = classify_tokens(&tokens, &analysis.symbols);
+    analysis
+}
+
+/// The diagnostic for the error the tokens failed to parse with.
+pub(crate) fn parse_diagnostic(error: ParseError, tokens: &[WithSpan<Token>]) -> Diagnostic {
+    Diagnostic {
+        // The parser runs out of tokens at the end of the code
+        span: error.span.or_else(|| tokens.last().map(|token| token.span)).unwrap_or_default(),
+        message: error.error,
+    }
+}
+
+/// The diagnostics for the errors of resolving the variables.
+pub(crate) fn diagnostics(errors: &[CompilerError]) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+    for error in errors {
+        add_compiler_errors(&mut diagnostics, error, None);
+    }
+    diagnostics
+}
+
+fn add_compiler_errors(diagnostics: &mut Vec<Diagnostic>, error: &CompilerError, span: Option<Span>) {
+    match error {
+        CompilerError::Multiple(errors) => for error in errors {
+            add_compiler_errors(diagnostics, error, span);
+        },
+        CompilerError::WithSpan(error) => add_compiler_errors(diagnostics, &error.value, Some(error.span)),
+        error => diagnostics.push(Diagnostic { span: span.unwrap_or_default(), message: error.to_string() }),
+    }
+}
+
+/// The locals of a function, with the symbol of every local by its id.
+struct Scope {
+    locals: Locals,
+    symbols: Vec<usize>,
+}
+
+/// Finds the declaration and the references of every name, with the same `Locals` as the resolver. What a variable
+/// refers to in the compiled code is up to the resolver, this only keeps track of the names for editors.
+struct Resolver {
+    symbols: Vec<Symbol>,
+    unresolved: Vec<Unresolved>,
+    globals: HashMap<String, usize>,
+    /// The innermost function last, the script is first.
+    scopes: Vec<Scope>,
+    parent: Option<usize>,
+}
+
+impl Resolver {
+    fn resolve(ast: &Ast) -> (Vec<Symbol>, Vec<Unresolved>) {
+        let mut resolver = Resolver { symbols: vec![], unresolved: vec![], globals: HashMap::new(), scopes: vec![], parent: None };
+        resolver.scopes.push(Scope { locals: Locals::new(), symbols: vec![] });
+
+        // Functions can refer to globals that are declared after them
+        for stmt in ast {
+            let stmt = match &stmt.value {
+                Stmt::Export(declaration) => declaration.as_ref(),
+                _ => stmt,
+            };
+            match &stmt.value {
+                Stmt::Var(name, ..) => resolver.declare_global(name, SymbolKind::Global, stmt.span, None),
+                Stmt::Function(name, params, ..) => resolver.declare_global(name, SymbolKind::Function, stmt.span, Some(params.len())),
+                Stmt::Class(name, _, _) => resolver.declare_global(name, SymbolKind::Class, stmt.span, None),
+                // The names `import "path";` brings into scope aren't known without loading the module
+                Stmt::Import(_, Some(names)) => for name in names {
+                    resolver.declare_global(name, SymbolKind::Global, stmt.span, None);
+                },
+                _ => (),
+            }
+        }
+
+        for stmt in ast {
+            resolver.stmt(stmt);
+        }
+        (resolver.symbols, resolver.unresolved)
+    }
+
+    fn is_global_scope(&self) -> bool {
+        self.scopes.len() == 1 && self.scopes[0].locals.scope_depth() == 0
+    }
+
+    fn add_symbol(&mut self, name: &WithSpan<Identifier>, kind: SymbolKind, span: Span, arity: Option<usize>) -> usize {
+        self.symbols.push(Symbol {
+            name: name.value.clone(),
+            kind,
+            declaration: name.span,
+            span,
+            references: vec![],
+            arity,
+            parent: self.parent,
+            shadows: None,
+        });
+        self.symbols.len() - 1
+    }
+
+    fn declare_global(&mut self, name: &WithSpan<Identifier>, kind: SymbolKind, span: Span, arity: Option<usize>) {
+        if !self.globals.contains_key(&name.value) {
+            let symbol = self.add_symbol(name, kind, span, arity);
+            self.globals.insert(name.value.clone(), symbol);
+        }
+    }
+
+    /// Declares a name in the current scope, returns its symbol.
+    fn declare(&mut self, name: &WithSpan<Identifier>, kind: SymbolKind, span: Span, arity: Option<usize>) -> usize {
+        if self.is_global_scope() {
+            let symbol = match self.globals.get(&name.value) {
+                Some(&symbol) => symbol,
+                None => {
+                    let symbol = self.add_symbol(name, kind, span, arity);
+                    self.globals.insert(name.value.clone(), symbol);
+                    symbol
+                },
+            };
+            // Declaring a global again refers to the same global
+            if self.symbols[symbol].declaration != name.span {
+                self.symbols[symbol].references.push(name.span);
+            }
+            return symbol;
+        }
+
+        let shadows = self.local(&name.value);
+        let symbol = self.add_symbol(name, kind, span, arity);
+        self.symbols[symbol].shadows = shadows;
+        let scope = self.scopes.last_mut().expect("no scope");
+        // A name that is already declared in the scope is an error the compiler reports
+        if scope.locals.insert(&name.value).is_some() {
+            scope.symbols.push(symbol);
+        }
+        symbol
+    }
+
+    fn mark_initialized(&mut self) {
+        if !self.is_global_scope() {
+            self.scopes.last_mut().expect("no scope").locals.mark_initialized();
+        }
+    }
+
+    /// The symbol of the innermost local with the name, in this function or one around it.
+    fn local(&self, name: &str) -> Option<usize> {
+        self.scopes.iter().rev().find_map(|scope| scope.locals.get(name).map(|local| scope.symbols[local.id()]))
+    }
+
+    fn reference(&mut self, name: &WithSpan<Identifier>, assignment: bool) {
+        match self.local(&name.value).or_else(|| self.globals.get(&name.value).copied()) {
+            Some(symbol) => self.symbols[symbol].references.push(name.span),
+            None => self.unresolved.push(Unresolved { name: name.value.clone(), span: name.span, assignment }),
+        }
+    }
+
+    fn with_scope(&mut self, f: impl FnOnce(&mut Self)) {
+        self.scopes.last_mut().expect("no scope").locals.begin_scope();
+        f(self);
+        self.scopes.last_mut().expect("no scope").locals.end_scope();
+    }
+
+    fn stmt(&mut self, stmt: &WithSpan<Stmt>) {
+        match &stmt.value {
+            Stmt::Expression(expr) | Stmt::Print(expr) => self.expr(expr),
+            Stmt::Var(name, initializer, _) => {
+                self.declare(name, if self.is_global_scope() { SymbolKind::Global } else { SymbolKind::Local }, stmt.span, None);
+                if let Some(initializer) = initializer {
+                    self.expr(initializer);
+                }
+                self.mark_initialized();
+            },
+            Stmt::If(condition, then_stmt, else_stmt) => {
+                self.expr(condition);
+                self.stmt(then_stmt);
+                if let Some(else_stmt) = else_stmt {
+                    self.stmt(else_stmt);
+                }
+            },
+            Stmt::Block(stmts) => self.with_scope(|resolver| for stmt in stmts {
+                resolver.stmt(stmt);
+            }),
+            Stmt::While(condition, body) => {
+                self.expr(condition);
+                self.stmt(body);
+            },
+            Stmt::Return(expr) => if let Some(expr) = expr {
+                self.expr(expr);
+            },
+            Stmt::Function(name, params, body, _) => {
+                let symbol = self.declare(name, SymbolKind::Function, stmt.span, Some(params.len()));
+                self.mark_initialized();
+                self.function(symbol, params, body);
+            },
+            Stmt::Class(name, superclass, methods) => {
+                let symbol = self.declare(name, SymbolKind::Class, stmt.span, None);
+                self.mark_initialized();
+                if let Some(superclass) = superclass {
+                    self.reference(superclass, false);
+                }
+
+                let parent = self.parent.replace(symbol);
+                for method in methods {
+                    if let Stmt::Function(name, params, body, _) = &method.value {
+                        let method_symbol = self.add_symbol(name, SymbolKind::Method, method.span, Some(params.len()));
+                        self.function(method_symbol, params, body);
+                    }
+                }
+                self.parent = parent;
+            },
+            Stmt::Import(..) => (),
+            Stmt::Export(declaration) => self.stmt(declaration),
+        }
+    }
+
+    fn function(&mut self, symbol: usize, params: &[WithSpan<Identifier>], body: &[WithSpan<Stmt>]) {
+        let parent = self.parent.replace(symbol);
+        self.scopes.push(Scope { locals: Locals::new(), symbols: vec![] });
+        self.with_scope(|resolver| {
+            for param in params {
+                resolver.declare(param, SymbolKind::Parameter, param.span, None);
+                resolver.mark_initialized();
+            }
+            resolver.with_scope(|resolver| for stmt in body {
+                resolver.stmt(stmt);
+            });
+        });
+        self.scopes.pop();
+        self.parent = parent;
+    }
+
+    fn expr(&mut self, expr: &WithSpan<Expr>) {
+        match &expr.value {
+            Expr::Variable(name) => self.reference(name, false),
+            Expr::Assign(name, value) => {
+                self.expr(value);
+                self.reference(name, true);
+            },
+            Expr::Binary(left, _, right) | Expr::Logical(left, _, right) => {
+                self.expr(left);
+                self.expr(right);
+            },
+            Expr::Grouping(expr) | Expr::Unary(_, expr) | Expr::Get(expr, _) => self.expr(expr),
+            Expr::Set(object, _, value) => {
+                self.expr(object);
+                self.expr(value);
+            },
+            Expr::Call(callee, args) => {
+                self.expr(callee);
+                for arg in args {
+                    self.expr(arg);
+                }
+            },
+            Expr::Number(_) | Expr::Boolean(_) | Expr::Nil | Expr::This | Expr::Super(_) | Expr::String(_) => (),
+        }
+    }
+}
+
+fn classify_tokens(tokens: &[WithSpan<Token>], symbols: &[Symbol]) -> Vec<(Span, TokenKind)> {
+    let mut names = HashMap::new();
+    for symbol in symbols {
+        let kind = match symbol.kind {
+            SymbolKind::Global | SymbolKind::Local => TokenKind::Variable,
+            SymbolKind::Parameter => TokenKind::Parameter,
+            SymbolKind::Function => TokenKind::Function,
+            SymbolKind::Class => TokenKind::Class,
+            SymbolKind::Method => TokenKind::Method,
+        };
+        for span in std::iter::once(&symbol.declaration).chain(&symbol.references) {
+            names.insert((span.start.line, span.start.column), kind);
+        }
+    }
+
+    let mut classified = vec![];
+    let mut previous = None;
+    for token in tokens {
+        let kind = match &token.value {
+            // Only a keyword in `import { a } from "path";`
+            Token::Identifier(name) if name == "from" && previous == Some(&Token::RightBrace) => Some(TokenKind::Keyword),
+            Token::Identifier(_) => Some(match names.get(&(token.span.start.line, token.span.start.column)) {
+                Some(kind) => *kind,
+                None if previous == Some(&Token::Dot) => TokenKind::Property,
+                None => TokenKind::Variable,
+            }),
+            Token::String(_) => Some(TokenKind::String),
+            Token::Number(_) => Some(TokenKind::Number),
+            Token::And | Token::Class | Token::Else | Token::Export | Token::False | Token::Fun | Token::For | Token::If |
+            Token::Import | Token::Nil | Token::Or | Token::Print | Token::Return | Token::Super | Token::This | Token::True |
+            Token::Var | Token::While => Some(TokenKind::Keyword),
+            Token::Minus | Token::Plus | Token::Slash | Token::Star | Token::Bang | Token::BangEqual | Token::Equal |
+            Token::EqualEqual | Token::Greater | Token::GreaterEqual | Token::Less | Token::LessEqual => Some(TokenKind::Operator),
+            _ => None,
+        };
+
+        if let Some(kind) = kind {
+            classified.push((token.span, kind));
+        }
+        previous = Some(&token.value);
+    }
+    classified
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(line: usize, column: usize) -> Position {
+        Position { line, column }
+    }
+
+    fn symbol<'a>(analysis: &'a Analysis, name: &str) -> &'a Symbol {
+        analysis.symbols.iter().find(|symbol| symbol.name == name).expect("no such symbol")
+    }
+
+    fn lines(spans: &[Span]) -> Vec<(usize, usize)> {
+        spans.iter().map(|span| (span.start.line, span.start.column)).collect()
+    }
+
+    #[test]
+    fn test_globals_and_functions() {
+        let analysis = analyze("fun add(a, b) { return a + b + total; }\nvar total = 1;\nprint add(total, 2);");
+        assert!(analysis.diagnostics.is_empty());
+
+        let add = symbol(&analysis, "add");
+        assert_eq!(add.kind, SymbolKind::Function);
+        assert_eq!(add.arity, Some(2));
+        assert_eq!(lines(&add.references), vec![(3, 7)]);
+
+        let total = symbol(&analysis, "total");
+        assert_eq!(total.kind, SymbolKind::Global);
+        assert_eq!((total.declaration.start.line, total.declaration.start.column), (2, 5));
+        assert_eq!(lines(&total.references), vec![(1, 32), (3, 11)]);
+
+        let a = symbol(&analysis, "a");
+        assert_eq!(a.kind, SymbolKind::Parameter);
+        assert_eq!(a.parent, analysis.symbol_at(at(1, 5)));
+        assert_eq!(lines(&a.references), vec![(1, 24)]);
+    }
+
+    #[test]
+    fn test_shadowing_and_upvalues() {
+        let code = "{ var a = 1; { var a = 2; print a; } fun f() { a = 3; } print a; }";
+        let analysis = analyze(code);
+        let symbols: Vec<_> = analysis.symbols.iter().filter(|symbol| symbol.name == "a").collect();
+        assert_eq!(symbols.len(), 2);
+        assert_eq!(lines(&symbols[0].references), vec![(1, 48), (1, 63)]);
+        assert_eq!(lines(&symbols[1].references), vec![(1, 33)]);
+        let outer = analysis.symbols.iter().position(|symbol| symbol.name == "a");
+        assert_eq!((symbols[0].shadows, symbols[1].shadows), (None, outer));
+    }
+
+    #[test]
+    fn test_unresolved() {
+        let analysis = analyze("fun f() { b = a; }\nprint clock;");
+        let unresolved: Vec<_> = analysis.unresolved.iter().map(|name| (name.name.as_str(), name.assignment)).collect();
+        assert_eq!(unresolved, vec![("a", false), ("b", true), ("clock", false)]);
+    }
+
+    #[test]
+    fn test_symbol_at() {
+        let analysis = analyze("var a = 1;\nprint a;");
+        assert_eq!(analysis.symbol_at(at(2, 7)), analysis.symbol_at(at(1, 5)));
+        assert!(analysis.symbol_at(at(2, 7)).is_some());
+        assert_eq!(analysis.symbol_at(at(2, 1)), None);
+    }
+
+    #[test]
+    fn test_classes_and_methods() {
+        let analysis = analyze("class A {}\nclass B < A { get(x) { return x.field; } }");
+        let a = symbol(&analysis, "A");
+        assert_eq!(a.kind, SymbolKind::Class);
+        assert_eq!(lines(&a.references), vec![(2, 11)]);
+
+        let get = symbol(&analysis, "get");
+        assert_eq!(get.kind, SymbolKind::Method);
+        assert_eq!(get.arity, Some(1));
+        assert_eq!(get.parent, analysis.symbol_at(at(2, 7)));
+
+        let field = analysis.tokens.iter().find(|(span, _)| span.start == at(2, 33)).map(|(_, kind)| *kind);
+        assert_eq!(field, Some(TokenKind::Property));
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let analysis = analyze("print 1;\nprint ;");
+        assert_eq!(analysis.diagnostics.len(), 1);
+        assert_eq!(analysis.diagnostics[0].span.start, at(2, 7));
+
+        let analysis = analyze("{\n  var a = 1;\n  var a = 2;\n}");
+        assert_eq!(analysis.diagnostics, vec![Diagnostic {
+            span: Span { start: at(3, 3), end: at(3, 12) },
+            message: "Already a variable with this name in this scope.".to_string(),
+        }]);
+    }
+
+    #[test]
+    fn test_token_kinds() {
+        let analysis = analyze("fun f(a) { return a + 1; }");
+        let kinds: Vec<_> = analysis.tokens.iter().map(|(_, kind)| *kind).collect();
+        assert_eq!(kinds, vec![
+            TokenKind::Keyword, TokenKind::Function, TokenKind::Parameter,
+            TokenKind::Keyword, TokenKind::Parameter, TokenKind::Operator, TokenKind::Number,
+        ]);
+    }
+}
@@ -30,34 +30,79 @@ pub enum LogicalOperator {
 
 #[derive(Debug, PartialEq)]
 pub enum Expr {
-    Binary(Box<Expr>, BinaryOperator, Box<Expr>),
-    Grouping(Box<Expr>),
+    Binary(Box<WithSpan<Expr>>, BinaryOperator, Box<WithSpan<Expr>>),
+    Grouping(Box<WithSpan<Expr>>),
     Number(f64),
     Boolean(bool),
     Nil,
     This,
     Super(Identifier),
     String(String),
-    Unary(UnaryOperator, Box<Expr>),
-    Variable(Identifier),
-    Logical(Box<Expr>, LogicalOperator, Box<Expr>),
-    Assign(Identifier, Box<Expr>),
-    Call(Box<Expr>, Vec<Expr>),
-    Get(Box<Expr>, Identifier),
-    Set(Box<Expr>, Identifier, Box<Expr>),
+    Unary(UnaryOperator, Box<WithSpan<Expr>>),
+    Variable(WithSpan<Identifier>),
+    Logical(Box<WithSpan<Expr>>, LogicalOperator, Box<WithSpan<Expr>>),
+    Assign(WithSpan<Identifier>, Box<WithSpan<Expr>>),
+    Call(Box<WithSpan<Expr>>, Vec<WithSpan<Expr>>),
+    Get(Box<WithSpan<Expr>>, Identifier),
+    Set(Box<WithSpan<Expr>>, Identifier, Box<WithSpan<Expr>>),
+}
+
+/// The name of a type in an annotation, a built in type like `Number` or a class. Only the type checker looks at
+/// annotations.
+pub type TypeName = WithSpan<Identifier>;
+
+/// The annotated types of the parameters, one for every parameter, and of what a function returns.
+#[derive(Debug, PartialEq, Default)]
+pub struct Signature {
+    pub params: Vec<Option<TypeName>>,
+    pub returns: Option<TypeName>,
 }
 
 #[derive(Debug, PartialEq)]
 pub enum Stmt {
-    Expression(Box<Expr>),
-    Print(Box<Expr>),
-    Var(WithSpan<Identifier>, Option<Box<Expr>>),
-    If(Box<Expr>, Box<Stmt>, Option<Box<Stmt>>),
-    Block(Vec<Stmt>),
-    While(Box<Expr>, Box<Stmt>),
-    Return(Option<Box<Expr>>),
-    Function(Identifier, Vec<Identifier>, Vec<Stmt>),
-    Class(Identifier, Option<Identifier>, Vec<Stmt>),
-}
-
-pub type Ast = Vec<Stmt>;
\ No newline at end of file
+    Expression(Box<WithSpan<Expr>>),
+    Print(Box<WithSpan<Expr>>),
+    Var(WithSpan<Identifier>, Option<Box<WithSpan<Expr>>>, Option<TypeName>),
+    If(Box<WithSpan<Expr>>, Box<WithSpan<Stmt>>, Option<Box<WithSpan<Stmt>>>),
+    Block(Vec<WithSpan<Stmt>>),
+    While(Box<WithSpan<Expr>>, Box<WithSpan<Stmt>>),
+    Return(Option<Box<WithSpan<Expr>>>),
+    Function(WithSpan<Identifier>, Vec<WithSpan<Identifier>>, Vec<WithSpan<Stmt>>, Signature),
+    Class(WithSpan<Identifier>, Option<WithSpan<Identifier>>, Vec<WithSpan<Stmt>>),
+    /// The path of the module, and the names to import from it, or all of its exports without them.
+    Import(WithSpan<String>, Option<Vec<WithSpan<Identifier>>>),
+    /// A declaration other modules can import.
+    Export(Box<WithSpan<Stmt>>),
+}
+
+/// Every statement and expression has the span from its first to its last token.
+pub type Ast = Vec<WithSpan<Stmt>>;
+
+#[cfg(test)]
+impl WithSpan<Expr> {
+    /// The same expression with every span left out, for comparing parsed expressions in tests.
+    pub fn without_spans(self) -> WithSpan<Expr> {
+        let strip = |expr: Box<WithSpan<Expr>>| Box::new(expr.without_spans());
+        let expr = match self.value {
+            Expr::Binary(left, operator, right) => Expr::Binary(strip(left), operator, strip(right)),
+            Expr::Grouping(expr) => Expr::Grouping(strip(expr)),
+            Expr::Unary(operator, expr) => Expr::Unary(operator, strip(expr)),
+            Expr::Variable(identifier) => Expr::Variable(identifier.value.as_str().into()),
+            Expr::Logical(left, operator, right) => Expr::Logical(strip(left), operator, strip(right)),
+            Expr::Assign(identifier, expr) => Expr::Assign(identifier.value.as_str().into(), strip(expr)),
+            Expr::Call(callee, args) => Expr::Call(strip(callee), args.into_iter().map(WithSpan::without_spans).collect()),
+            Expr::Get(expr, identifier) => Expr::Get(strip(expr), identifier),
+            Expr::Set(expr, identifier, value) => Expr::Set(strip(expr), identifier, strip(value)),
+            expr => expr,
+        };
+        expr.into()
+    }
+}
+
+/// An expression without a span, for building expected expressions in tests.
+#[cfg(test)]
+impl From<Expr> for WithSpan<Expr> {
+    fn from(expr: Expr) -> Self {
+        WithSpan::new(expr, crate::position::Span::default())
+    }
+}
@@ -1,11 +1,19 @@
+use std::collections::{HashMap, HashSet};
 use crate::bytecode::*;
+use crate::ir;
+use crate::modules::LoadedModule;
+use crate::position::{Span, WithSpan};
+use crate::resolver::{self, Binding, Resolution};
+use lox_bytecode::debug::DebugInfo;
 use super::CompilerError;
 use super::locals::*;
 
 #[derive(Copy, Clone)]
 pub enum ContextType {
     Function,
+    #[allow(dead_code)] //TODO Methods
     Initializer,
+    #[allow(dead_code)]
     Method,
     TopLevel,
 }
@@ -14,44 +22,43 @@ struct CompilerContext {
     context_type: ContextType,
     chunk_index: ChunkIndex,
     locals: Locals,
-    upvalues: Vec<Upvalue>,
+    upvalues: Vec<ir::Upvalue>,
+    /// The name of every upvalue, for debugging.
+    upvalue_names: Vec<String>,
+    function: ir::Function,
 }
 
 pub struct Compiler {
     module: Module,
     contexts: Vec<CompilerContext>,
+    /// The IR of every function that has been compiled, by chunk.
+    functions: Vec<(ChunkIndex, ir::Function)>,
+    debug_info: DebugInfo,
+    /// The variables of the module being compiled.
+    resolution: Resolution,
+    /// The global each global name of the module is stored in, when it isn't the name itself.
+    globals: HashMap<String, String>,
+    /// The globals of other modules the module can't refer to.
+    hidden: HashSet<String>,
+    /// The file of the module being compiled, for the debug info of its chunks.
+    source_file: Option<String>,
 }
 
 impl CompilerContext {
-    fn new(context_type: ContextType, chunk_index: ChunkIndex) -> CompilerContext {
+    fn new(context_type: ContextType, chunk_index: ChunkIndex, name: &str, upvalues: Vec<(String, resolver::Upvalue)>) -> CompilerContext {
+        let (upvalue_names, upvalues) = upvalues.into_iter()
+            .map(|(name, upvalue)| match upvalue {
+                resolver::Upvalue::Local(id) => (name, ir::Upvalue::Local(id)),
+                resolver::Upvalue::Upvalue(index) => (name, ir::Upvalue::Upvalue(index)),
+            })
+            .unzip();
         CompilerContext {
             context_type,
             chunk_index,
             locals: Locals::new(),
-            upvalues: vec![],
-        }
-    }
-
-    fn add_upvalue(&mut self, upvalue: Upvalue) -> StackIndex {
-        for i in 0..self.upvalues.len() {
-            let existing_upvalue = &self.upvalues[i];
-            if upvalue == *existing_upvalue {
-                return i;
-            }
-        }
-
-        self.upvalues.push(upvalue);
-
-        self.upvalues.len() - 1
-    }
-
-    fn resolve_local(&self, name: &str) -> Result<Option<StackIndex>, CompilerError> {
-        if let Some(local) = self.locals.get(name) {
-            if !local.initialized() {
-                Err(CompilerError::LocalNotInitialized)
-            } else { Ok(Some(local.slot()))}
-        } else {
-            Ok(None)
+            upvalues,
+            upvalue_names,
+            function: ir::Function::new(name),
         }
     }
 }
@@ -65,21 +72,38 @@ impl Compiler {
         self.contexts.last_mut().expect("no context")
     }
 
-    fn current_chunk_mut(&mut self) -> &mut Chunk {
-        self.module.chunk_mut(self.current_context().chunk_index)
-    }
-
-    fn current_chunk(&self) -> &Chunk {
-        self.module.chunk(self.current_context().chunk_index)
+    fn current_function_mut(&mut self) -> &mut ir::Function {
+        &mut self.current_context_mut().function
     }
 
-    fn begin_context(&mut self, context_type: ContextType) {
+    fn begin_context(&mut self, context_type: ContextType, name: &str, upvalues: Vec<(String, resolver::Upvalue)>) {
         let chunk = self.module.add_chunk();
-        self.contexts.push(CompilerContext::new(context_type, chunk));
+        // Until the first statement, code belongs to the statement that declares the function
+        let span = self.contexts.last().and_then(|context| context.function.span());
+        for (_, upvalue) in &upvalues {
+            if let resolver::Upvalue::Local(local) = upvalue {
+                self.current_function_mut().mark_captured(*local);
+            }
+        }
+        let mut context = CompilerContext::new(context_type, chunk, name, upvalues);
+        context.function.set_span(span);
+        self.contexts.push(context);
     }
 
-    fn end_context(&mut self) -> (ChunkIndex, Vec<Upvalue>) {
-        let context = self.contexts.pop().expect("no context");
+    fn end_context(&mut self) -> (ChunkIndex, Vec<ir::Upvalue>) {
+        let mut context = self.contexts.pop().expect("no context");
+        let slots = ir::optimize(&mut context.function);
+        let mut debug_info = ir::generate(&context.function, &slots, &mut self.module, context.chunk_index);
+        debug_info.upvalues = context.upvalue_names;
+        debug_info.source_file = self.source_file.clone();
+
+        let chunks = &mut self.debug_info.chunks;
+        if chunks.len() <= context.chunk_index {
+            chunks.resize(context.chunk_index + 1, Default::default());
+        }
+        chunks[context.chunk_index] = debug_info;
+
+        self.functions.push((context.chunk_index, context.function));
         (context.chunk_index, context.upvalues)
     }
 
@@ -89,22 +113,41 @@ impl Compiler {
 
     fn end_scope(&mut self) {
         for local in self.current_context_mut().locals.end_scope().iter().rev() {
-            if local.captured() {
-                self.add_instruction(Instruction::CloseUpvalue);
-            } else {
-                self.add_instruction(Instruction::Pop);
-            }
+            self.add_instruction(ir::Instruction::DropLocal(local.id()));
         }
     }
 
-    pub fn new() -> Compiler {
+    pub fn new(resolution: Resolution) -> Compiler {
         Compiler {
             module: Module::new(),
             contexts: vec![],
+            functions: vec![],
+            debug_info: DebugInfo::default(),
+            resolution,
+            globals: HashMap::new(),
+            hidden: HashSet::new(),
+            source_file: None,
         }
     }
 
-    pub fn into_module(self) -> Module { self.module }
+    /// Compile the code of another module from now on.
+    pub fn set_module(&mut self, resolution: Resolution, module: &LoadedModule) {
+        self.resolution = resolution;
+        self.globals = module.globals.clone();
+        self.hidden = module.hidden.clone();
+        self.source_file = Some(module.path.clone());
+    }
+
+    pub fn into_module(mut self) -> Module {
+        self.module.set_debug_info(self.debug_info);
+        self.module
+    }
+
+    /// The IR of every function, in the order of their chunks.
+    pub fn into_functions(mut self) -> Vec<ir::Function> {
+        self.functions.sort_by_key(|(chunk_index, _)| *chunk_index);
+        self.functions.into_iter().map(|(_, function)| function).collect()
+    }
 
     pub fn context_type(&self) -> ContextType {
         self.current_context().context_type
@@ -122,48 +165,69 @@ impl Compiler {
         c.locals.scope_depth() > 0
     }
 
-    pub fn with_context<F>(&mut self, context_type: ContextType, f: F) -> Result<(ChunkIndex, Vec<Upvalue>), CompilerError> where F: FnOnce(&mut Self) -> Result<(), CompilerError> {
-        self.begin_context(context_type);
+    pub fn with_context<F>(&mut self, context_type: ContextType, name: &str, upvalues: Vec<(String, resolver::Upvalue)>, f: F) -> Result<(ChunkIndex, Vec<ir::Upvalue>), CompilerError> where F: FnOnce(&mut Self) -> Result<(), CompilerError> {
+        self.begin_context(context_type, name, upvalues);
 
         //TODO Move to begin_context
-        self.add_local(""); //TODO call local 'this' for method/initializer and maybe toplevel?
-        self.mark_local_initialized();
+        self.add_parameter(""); //TODO call local 'this' for method/initializer and maybe toplevel?
 
-        let result = f(self);
-        let ctx_result = self.end_context();
-        result?;
-        Ok(ctx_result)
+        if let Err(error) = f(self) {
+            // The function may not be finished, there is no code to generate
+            self.contexts.pop();
+            return Err(error);
+        }
+        Ok(self.end_context())
     }
 
-    pub fn with_scoped_context<F>(&mut self, context_type: ContextType, f: F) -> Result<(ChunkIndex, Vec<Upvalue>), CompilerError> where F: FnOnce(&mut Self) -> Result<(), CompilerError> {
-        self.with_context(context_type, |compiler| {
+    pub fn with_scoped_context<F>(&mut self, context_type: ContextType, name: &str, upvalues: Vec<(String, resolver::Upvalue)>, f: F) -> Result<(ChunkIndex, Vec<ir::Upvalue>), CompilerError> where F: FnOnce(&mut Self) -> Result<(), CompilerError> {
+        self.with_context(context_type, name, upvalues, |compiler| {
             compiler.begin_scope();
             f(compiler)
         })
     }
 
-    pub fn add_instruction(&mut self, instruction: Instruction) -> InstructionIndex {
-        self.current_chunk_mut().add_instruction(instruction)
+    /// Code that is compiled from now on comes from `span`, returns the span it came from before.
+    pub fn set_span(&mut self, span: Span) -> Option<Span> {
+        let function = self.current_function_mut();
+        let previous = function.span();
+        function.set_span(Some(span));
+        previous
     }
 
-    pub fn patch_instruction(&mut self, index: InstructionIndex) {
-        self.current_chunk_mut().patch_instruction(index)
+    pub fn restore_span(&mut self, span: Option<Span>) {
+        self.current_function_mut().set_span(span);
     }
 
-    pub fn patch_instruction_to(&mut self, index: InstructionIndex, to: InstructionIndex) {
-        self.current_chunk_mut().patch_instruction_to(index, to)
+    pub fn add_instruction(&mut self, instruction: ir::Instruction) {
+        self.current_function_mut().add_instruction(instruction)
     }
 
-    pub fn instruction_index(&self) -> InstructionIndex {
-        self.current_chunk().instruction_index()
+    pub fn add_block(&mut self) -> ir::BlockId {
+        self.current_function_mut().add_block()
+    }
+
+    pub fn switch_to(&mut self, block: ir::BlockId) {
+        self.current_function_mut().switch_to(block)
+    }
+
+    pub fn terminate(&mut self, terminator: ir::Terminator) {
+        self.current_function_mut().terminate(terminator)
     }
 
     pub fn add_local(&mut self, name: &str) {
-        self.current_context_mut().locals.insert(name);
+        let context = self.current_context_mut();
+        let variable = context.function.add_variable(name, false);
+        let id = context.locals.insert(name).map(Local::id);
+        debug_assert_eq!(id, Some(variable));
     }
 
-    pub fn has_local_in_current_scope(&self, name: &str) -> bool {
-        self.current_context().locals.get_at_current_depth(name).is_some()
+    /// Parameters are already on the stack when the function is called.
+    pub fn add_parameter(&mut self, name: &str) {
+        let context = self.current_context_mut();
+        let variable = context.function.add_variable(name, true);
+        let id = context.locals.insert(name).map(Local::id);
+        debug_assert_eq!(id, Some(variable));
+        context.locals.mark_initialized();
     }
 
     pub fn mark_local_initialized(&mut self) { //TODO refactor
@@ -171,26 +235,37 @@ impl Compiler {
         self.current_context_mut().locals.mark_initialized()
     }
 
-    pub fn resolve_local(&self, name: &str) -> Result<Option<StackIndex>, CompilerError> {
-        self.current_context().resolve_local(name)
+    /// The value on top of the stack becomes the last local that was added.
+    pub fn define_local(&mut self) {
+        self.mark_local_initialized();
+        let variable = self.current_context().function.variables.len() - 1;
+        self.add_instruction(ir::Instruction::DeclareLocal(variable));
+    }
+
+    /// What the variable refers to, the resolver has seen every variable.
+    pub fn binding(&self, name: &WithSpan<String>) -> Result<Binding, CompilerError> {
+        self.resolution.binding(name.span).ok_or_else(|| CompilerError::Unresolved(name.value.clone()))
+    }
+
+    /// The upvalues of the function with the name, for compiling it.
+    pub fn upvalues(&self, name: &WithSpan<String>) -> Vec<(String, resolver::Upvalue)> {
+        self.resolution.upvalues(name.span).to_vec()
     }
 
     pub fn add_constant<C: Into<Constant>>(&mut self, constant: C) -> ConstantIndex {
         self.module.add_constant(constant.into())
     }
 
-    pub fn resolve_upvalue(&mut self, name: &str) -> Result<Option<StackIndex>, CompilerError> {
-        for i in (0..(self.contexts.len()-1)).rev() { // Skip the current context
-            if let Some(local) = self.contexts[i].resolve_local(name)? { //TODO expect() this instead?, locals should *never* be uninitialized when resolving upvalues
-                self.contexts[i].locals.mark_captured(local);
-                let mut upvalue = self.contexts[i+1].add_upvalue(Upvalue::Local(local));
-                for j in (i+2)..self.contexts.len() {
-                    upvalue = self.contexts[j].add_upvalue(Upvalue::Upvalue(upvalue));
-                }
-                return Ok(Some(upvalue));
-            }
-        }
+    pub fn add_global(&mut self, name: &str) -> GlobalIndex {
+        let name = self.globals.get(name).map_or(name, String::as_str);
+        self.module.globals_mut().add(name)
+    }
 
-        Ok(None)
+    /// The global a variable refers to, the globals of other modules have to be imported.
+    pub fn global(&mut self, name: &str) -> Result<GlobalIndex, CompilerError> {
+        if self.hidden.contains(name) {
+            return Err(CompilerError::NotImported(name.to_string()));
+        }
+        Ok(self.add_global(name))
     }
-}
\ No newline at end of file
+}
@@ -4,12 +4,15 @@ pub struct Local {
     name: String,
     depth: usize,
     slot: usize,
+    /// Unique within a function, unlike the slot which is reused once a scope ends.
+    id: usize,
     initialized: bool,
     is_upvalue: bool,
 }
 
 impl Local {
     pub fn slot(&self) -> usize { self.slot }
+    pub fn id(&self) -> usize { self.id }
     pub fn initialized(&self) -> bool { self.initialized }
     pub fn captured(&self) -> bool { self.is_upvalue }
 }
@@ -18,6 +21,7 @@ impl Local {
 pub struct Locals {
     stack: Vec<Local>,
     scope_depth: usize,
+    declared: usize,
 }
 
 impl Locals {
@@ -25,6 +29,7 @@ impl Locals {
         Locals {
             stack: vec![],
             scope_depth: 0,
+            declared: 0,
         }
     }
 
@@ -47,8 +52,8 @@ impl Locals {
         self.stack.iter().rev().find(|l| l.name == identifier)
     }
 
-    pub fn get_at_current_depth(&self, identifier: &str) -> Option<&Local> {
-        self.get_at_depth(identifier, self.scope_depth)
+    pub fn get_by_id(&self, id: usize) -> Option<&Local> {
+        self.stack.iter().find(|l| l.id == id)
     }
 
     pub fn mark_captured(&mut self, slot: usize) {
@@ -68,16 +73,18 @@ impl Locals {
     }
 
     pub fn insert(&mut self, identifier: &str) -> Option<&Local> { //TODO Maybe Result<&Local, ()> instead
-        if let Some(_) = self.get_at_depth(identifier, self.scope_depth) {
+        if self.get_at_depth(identifier, self.scope_depth).is_some() {
             None
         } else {
             self.stack.push(Local{
                 name: identifier.to_string(),
                 depth: self.scope_depth,
                 slot: self.stack.len(),
+                id: self.declared,
                 initialized: false,
                 is_upvalue: false,
             });
+            self.declared += 1;
             self.stack.last()
         }
     }
@@ -1,34 +1,152 @@
 mod compiler;
 mod statements;
-mod locals;
+pub(crate) mod locals;
 
 #[cfg(test)]
 mod tests;
 
 use crate::bytecode::*;
 use crate::ast::*;
+use crate::ir;
 use compiler::{Compiler, ContextType};
 use statements::compile_ast;
 use crate::position::WithSpan;
+use crate::resolver::{self, Resolution};
+use crate::modules::{self, LoadedModule};
 
 #[derive(Debug)]
 pub enum CompilerError {
     LocalAlreadyDefined,
     LocalNotInitialized,
+    ReturnAtTopLevel,
+    ThisOutsideClass,
+    SuperOutsideClass,
+    SuperWithoutSuperclass,
+    /// The variable wasn't resolved, the resolution is for other code.
+    Unresolved(String),
+    /// The global is declared in another module, which the module doesn't import it from.
+    NotImported(String),
+    /// The register compiler can't compile this yet.
+    Unsupported(&'static str),
 
     Multiple(Vec<CompilerError>),
     WithSpan(WithSpan<Box<CompilerError>>),
+    /// An error in the module at the path.
+    InModule(String, Box<CompilerError>),
+}
+
+impl std::fmt::Display for CompilerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CompilerError::LocalAlreadyDefined => write!(f, "Already a variable with this name in this scope."),
+            CompilerError::LocalNotInitialized => write!(f, "Can't read local variable in its own initializer."),
+            CompilerError::ReturnAtTopLevel => write!(f, "Can't return from top-level code."),
+            CompilerError::ThisOutsideClass => write!(f, "Can't use 'this' outside of a class."),
+            CompilerError::SuperOutsideClass => write!(f, "Can't use 'super' outside of a class."),
+            CompilerError::SuperWithoutSuperclass => write!(f, "Can't use 'super' in a class with no superclass."),
+            CompilerError::Unresolved(name) => write!(f, "The variable '{}' wasn't resolved.", name),
+            CompilerError::NotImported(name) => write!(f, "'{}' is declared in another module, import it to use it.", name),
+            CompilerError::Unsupported(what) => write!(f, "Can't compile {} to registers yet.", what),
+            CompilerError::Multiple(errors) => {
+                let errors: Vec<_> = errors.iter().map(|error| error.to_string()).collect();
+                write!(f, "{}", errors.join("\n"))
+            },
+            CompilerError::WithSpan(error) => {
+                let start = error.span.start;
+                write!(f, "[line {}:{}] {}", start.line, start.column, error.value)
+            },
+            CompilerError::InModule(path, error) => match error.as_ref() {
+                CompilerError::Multiple(errors) => {
+                    let errors: Vec<_> = errors.iter().map(|error| format!("{}: {}", path, error)).collect();
+                    write!(f, "{}", errors.join("\n"))
+                },
+                error => write!(f, "{}: {}", path, error),
+            },
+        }
+    }
 }
 
 pub fn compile(ast: &Ast) -> Result<Module, CompilerError> {
-    let mut compiler = Compiler::new();
+    compile_resolved(ast, resolver::resolve_ast(ast, false))
+}
 
-    compiler.with_context(ContextType::TopLevel, |compiler| {
-        compile_ast(compiler, ast)?;
-        compiler.add_instruction(Instruction::Nil);
-        compiler.add_instruction(Instruction::Return);
+/// Like `compile`, with the variables resolved already.
+pub fn compile_resolved(ast: &Ast, resolution: Resolution) -> Result<Module, CompilerError> {
+    let mut compiler = resolved_compiler(resolution)?;
+
+    compile_script(&mut compiler, ast)?;
+    Ok(with_exports(compiler.into_module(), ast))
+}
+
+/// Like `compile`, but returns the IR every function was lowered to instead of the bytecode.
+pub fn compile_to_ir(ast: &Ast) -> Result<Vec<ir::Function>, CompilerError> {
+    let mut compiler = resolved_compiler(resolver::resolve_ast(ast, false))?;
+    compile_script(&mut compiler, ast)?;
+    Ok(compiler.into_functions())
+}
+
+/// A compiler for the code, unless resolving its variables failed.
+fn resolved_compiler(resolution: Resolution) -> Result<Compiler, CompilerError> {
+    Ok(Compiler::new(resolved(resolution)?))
+}
+
+/// The resolution, unless resolving the variables failed.
+pub(crate) fn resolved(mut resolution: Resolution) -> Result<Resolution, CompilerError> {
+    let errors = std::mem::take(&mut resolution.errors);
+    if errors.is_empty() { Ok(resolution) } else { Err(CompilerError::Multiple(errors)) }
+}
+
+/// Compile the modules of a program into one. The last module is the script, the others run before it in order.
+pub(crate) fn compile_program(modules: &[LoadedModule]) -> Result<Module, CompilerError> {
+    let (script, imported) = modules.split_last().expect("a program has a script");
+    let mut compiler = Compiler::new(Resolution::default());
+    compiler.with_context(ContextType::TopLevel, "script", vec![], |compiler| {
+        for module in imported {
+            let function = compile_module(compiler, module)
+                .map_err(|error| CompilerError::InModule(module.path.clone(), Box::new(error)))?;
+            let constant = compiler.add_constant(Constant::Closure(Closure { function, upvalues: vec![] }));
+            compiler.add_instruction(ir::Instruction::Closure(constant, vec![]));
+            compiler.add_instruction(ir::Instruction::Call(0));
+            compiler.add_instruction(ir::Instruction::Pop);
+        }
+
+        // The errors of the script name its path too, like those of the modules it imports
+        let in_script = |error| CompilerError::InModule(script.path.clone(), Box::new(error));
+        compiler.set_module(resolved(resolver::resolve_ast(&script.ast, false)).map_err(in_script)?, script);
+        compile_ast(compiler, &script.ast).map_err(in_script)?;
+        compiler.add_instruction(ir::Instruction::Nil);
+        compiler.terminate(ir::Terminator::Return);
+        Ok(())
+    })?;
+    Ok(with_exports(compiler.into_module(), &script.ast))
+}
+
+/// The module with the exports of its script, for linking it with other modules.
+fn with_exports(mut module: Module, ast: &Ast) -> Module {
+    for name in modules::exports(ast) {
+        module.add_export(&name);
+    }
+    module
+}
+
+/// Compile the code of an imported module into a function that runs it.
+fn compile_module(compiler: &mut Compiler, module: &LoadedModule) -> Result<Function, CompilerError> {
+    compiler.set_module(resolved(resolver::resolve_ast(&module.ast, false))?, module);
+    let (chunk_index, _) = compiler.with_context(ContextType::TopLevel, &module.path, vec![], |compiler| {
+        compile_ast(compiler, &module.ast)?;
+        compiler.add_instruction(ir::Instruction::Nil);
+        compiler.terminate(ir::Terminator::Return);
         Ok(())
     })?;
+    Ok(Function { name: module.path.clone(), chunk_index, arity: 0 })
+}
 
-    Ok(compiler.into_module())
+fn compile_script(compiler: &mut Compiler, ast: &Ast) -> Result<(), CompilerError> {
+    compiler.with_context(ContextType::TopLevel, "script", vec![], |compiler| {
+        compile_ast(compiler, ast)?;
+        compiler.add_instruction(ir::Instruction::Nil);
+        compiler.terminate(ir::Terminator::Return);
+        Ok(())
+    })?;
+    Ok(())
 }
\ No newline at end of file
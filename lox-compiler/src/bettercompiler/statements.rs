@@ -1,11 +1,13 @@
 use super::{CompilerError};
 use crate::ast::*;
 use crate::bytecode::*;
+use crate::ir::{Instruction, Terminator};
 use super::compiler::Compiler;
 use super::compiler::ContextType;
 use crate::position::WithSpan;
+use crate::resolver::Binding;
 
-pub fn compile_ast(compiler: &mut Compiler, ast: &Ast) -> Result<(), CompilerError> {
+pub fn compile_ast(compiler: &mut Compiler, ast: &[WithSpan<Stmt>]) -> Result<(), CompilerError> {
     let errors: Vec<_> = ast.iter()
         .map(|stmt| compile_stmt(compiler, stmt))
         .filter_map(Result::err)
@@ -13,43 +15,56 @@ pub fn compile_ast(compiler: &mut Compiler, ast: &Ast) -> Result<(), CompilerErr
     if errors.is_empty() { Ok(()) } else { Err(CompilerError::Multiple(errors)) }
 }
 
-fn compile_stmt(compiler: &mut Compiler, stmt: &Stmt) -> Result<(), CompilerError> {
-    match stmt {
+fn compile_stmt(compiler: &mut Compiler, stmt: &WithSpan<Stmt>) -> Result<(), CompilerError> {
+    let previous = compiler.set_span(stmt.span);
+    let result = compile_stmt_kind(compiler, stmt);
+    compiler.restore_span(previous);
+
+    // Errors point at the innermost statement they came from
+    result.map_err(|error| match error {
+        CompilerError::WithSpan(_) | CompilerError::Multiple(_) => error,
+        error => CompilerError::WithSpan(WithSpan::new(Box::new(error), stmt.span)),
+    })
+}
+
+fn compile_stmt_kind(compiler: &mut Compiler, stmt: &WithSpan<Stmt>) -> Result<(), CompilerError> {
+    match stmt.value {
         Stmt::Print(ref expr) => compile_print(compiler, expr),
-        Stmt::Var(ref identifier, ref expr) => compile_var_declaration(compiler, identifier.as_ref(), expr.as_ref()),
+        Stmt::Var(ref identifier, ref expr, _) => compile_var_declaration(compiler, &identifier.value, expr.as_ref()),
         Stmt::Block(ref stmts) => compile_block(compiler, stmts),
         Stmt::Expression(ref expr) => compile_expression_statement(compiler, expr),
-        Stmt::If(ref condition, ref then_stmt, ref else_stmt) => compile_if(compiler, condition, then_stmt, else_stmt.as_ref()),
+        Stmt::If(ref condition, ref then_stmt, ref else_stmt) => compile_if(compiler, condition, then_stmt, else_stmt.as_deref()),
         Stmt::While(ref expr, ref stmt) => compile_while(compiler, expr, stmt),
-        Stmt::Function(ref identifier, ref args, ref stmts) => compile_function(compiler, identifier, args, stmts),
+        Stmt::Function(ref identifier, ref args, ref stmts, _) => compile_function(compiler, identifier, args, stmts),
         Stmt::Return(ref expr) => compile_return(compiler, expr.as_ref()),
-        Stmt::Class(ref identifier, ref extends, ref stmts) => compile_class(compiler, identifier, extends.as_deref(), stmts),
+        Stmt::Class(ref identifier, ref extends, ref stmts) => {
+            compile_class(compiler, &identifier.value, extends.as_ref().map(|extends| extends.value.as_str()), stmts)
+        },
+        // The imported modules run before the module
+        Stmt::Import(..) => Ok(()),
+        Stmt::Export(ref declaration) => compile_stmt_kind(compiler, declaration),
     }
 }
 
-fn declare_variable(compiler: &mut Compiler, identifier: &str) -> Result<(), CompilerError> {
+fn declare_variable(compiler: &mut Compiler, identifier: &str) {
+    // The resolver reports a name that is declared twice in a scope
     if compiler.is_scoped() {
-        if compiler.has_local_in_current_scope(identifier) {
-            return Err(CompilerError::LocalAlreadyDefined); //TODO Don't return error, do `add_error` instead.
-        }
-
         compiler.add_local(identifier);
     }
-    Ok(())
 }
 
 fn define_variable(compiler: &mut Compiler, identifier: &str) {
     if compiler.is_scoped() {
-        compiler.mark_local_initialized();
+        compiler.define_local();
     } else {
-        let constant = compiler.add_constant(identifier);
-        compiler.add_instruction(Instruction::DefineGlobal(constant));
+        let global = compiler.add_global(identifier);
+        compiler.add_instruction(Instruction::DefineGlobal(global));
     }
 }
 
-fn compile_class(compiler: &mut Compiler, identifier: &str, _extends: Option<&str>, _stmts: &[Stmt]) -> Result<(), CompilerError> {
+fn compile_class(compiler: &mut Compiler, identifier: &str, _extends: Option<&str>, _stmts: &[WithSpan<Stmt>]) -> Result<(), CompilerError> {
 
-    declare_variable(compiler, identifier)?;
+    declare_variable(compiler, identifier);
     let constant = compiler.add_constant(Constant::Class(Class{ name: identifier.to_string() }));
     compiler.add_instruction(Instruction::Class(constant));
     define_variable(compiler, identifier);
@@ -60,100 +75,138 @@ fn compile_class(compiler: &mut Compiler, identifier: &str, _extends: Option<&st
     Ok(())
 }
 
-fn compile_return<E: AsRef<Expr>>(compiler: &mut Compiler, expr: Option<E>) -> Result<(), CompilerError> {
-    if let Some(expr) = expr {
-        compile_expr(compiler, expr.as_ref())?;
-    } else {
-        compile_nil(compiler)?;
+fn compile_return<E: AsRef<WithSpan<Expr>>>(compiler: &mut Compiler, expr: Option<E>) -> Result<(), CompilerError> {
+    match expr.as_ref().map(|expr| tail_call(expr.as_ref())) {
+        // The frame of the script can't be reused, nothing would be left to return to
+        Some(Some((callee, args))) if !matches!(compiler.context_type(), ContextType::TopLevel) => {
+            compile_expr(compiler, callee)?;
+            for arg in args {
+                compile_expr(compiler, arg)?;
+            }
+            compiler.terminate(Terminator::TailCall(args.len()));
+        },
+        _ => {
+            if let Some(expr) = expr {
+                compile_expr(compiler, expr.as_ref())?;
+            } else {
+                compile_nil(compiler)?;
+            }
+            compiler.terminate(Terminator::Return);
+        },
     }
-    compiler.add_instruction(Instruction::Return);
+
+    // Anything after the return can't be reached, this block is removed unless something jumps to it.
+    let next = compiler.add_block();
+    compiler.switch_to(next);
     Ok(())
 }
 
-fn compile_function(compiler: &mut Compiler, identifier: &str, args: &Vec<Identifier>, block: &Vec<Stmt>) -> Result<(), CompilerError> {
-    declare_variable(compiler, identifier)?;
+/// The callee and arguments when returning `expr` is a call in tail position.
+fn tail_call(expr: &WithSpan<Expr>) -> Option<(&WithSpan<Expr>, &Vec<WithSpan<Expr>>)> {
+    match &expr.value {
+        Expr::Call(callee, args) => Some((callee, args)),
+        Expr::Grouping(expr) => tail_call(expr),
+        _ => None,
+    }
+}
+
+fn compile_function(compiler: &mut Compiler, identifier: &WithSpan<Identifier>, args: &[WithSpan<Identifier>], block: &[WithSpan<Stmt>]) -> Result<(), CompilerError> {
+    declare_variable(compiler, &identifier.value);
     if compiler.is_scoped() {
         compiler.mark_local_initialized();
     }
 
-    let (chunk_index, upvalues) = compiler.with_scoped_context(ContextType::Function, |compiler| {
+    let upvalues = compiler.upvalues(identifier);
+    let (chunk_index, upvalues) = compiler.with_scoped_context(ContextType::Function, &identifier.value, upvalues, |compiler| {
         for arg in args {
-            declare_variable(compiler, arg)?;
-            define_variable(compiler, arg);
+            compiler.add_parameter(&arg.value);
         }
 
         compile_block(compiler, block)?;
 
         compiler.add_instruction(Instruction::Nil);
-        compiler.add_instruction(Instruction::Return);
+        compiler.terminate(Terminator::Return);
         Ok(())
     })?;
 
     let function = Function {
-        name: identifier.into(),
+        name: identifier.value.clone(),
         chunk_index,
         arity: args.len(),
     };
 
+    // The upvalues are filled in once the slots of the locals they capture are known.
     let closure = Closure {
         function,
-        upvalues: upvalues,
+        upvalues: vec![],
     };
 
     let constant = compiler.add_constant(Constant::Closure(closure));
-    compiler.add_instruction(Instruction::Closure(constant));
+    compiler.add_instruction(Instruction::Closure(constant, upvalues));
 
-    define_variable(compiler, identifier);
+    define_variable(compiler, &identifier.value);
 
     Ok(())
 }
 
-fn compile_while(compiler: &mut Compiler, condition: &Expr, body: &Stmt) -> Result<(), CompilerError> {
-    let loop_start = compiler.instruction_index();
+fn compile_while(compiler: &mut Compiler, condition: &WithSpan<Expr>, body: &WithSpan<Stmt>) -> Result<(), CompilerError> {
+    let loop_start = compiler.add_block();
+    let loop_body = compiler.add_block();
+    let loop_end = compiler.add_block();
+
+    compiler.terminate(Terminator::Jump(loop_start));
+    compiler.switch_to(loop_start);
     compile_expr(compiler, condition)?;
-    let end_jump = compiler.add_instruction(Instruction::JumpIfFalse(0));
+    compiler.terminate(Terminator::Branch { if_false: loop_end, otherwise: loop_body });
+
+    compiler.switch_to(loop_body);
     compiler.add_instruction(Instruction::Pop);
     compile_stmt(compiler, body)?;
-    let loop_jump = compiler.add_instruction(Instruction::Jump(0));
-    compiler.patch_instruction_to(loop_jump, loop_start);
-    compiler.patch_instruction(end_jump);
+    compiler.terminate(Terminator::Jump(loop_start));
+
+    compiler.switch_to(loop_end);
     compiler.add_instruction(Instruction::Pop);
     Ok(())
 }
 
-fn compile_if<S: AsRef<Stmt>>(compiler: &mut Compiler, condition: &Expr, then_stmt: &Stmt, else_stmt: Option<S>) -> Result<(), CompilerError> {
+fn compile_if(compiler: &mut Compiler, condition: &WithSpan<Expr>, then_stmt: &WithSpan<Stmt>, else_stmt: Option<&WithSpan<Stmt>>) -> Result<(), CompilerError> {
     compile_expr(compiler, condition)?;
 
-    let then_index = compiler.add_instruction(Instruction::JumpIfFalse(0));
+    let then_block = compiler.add_block();
+    let else_block = else_stmt.as_ref().map(|_| compiler.add_block());
+    let end_block = compiler.add_block();
+    compiler.terminate(Terminator::Branch { if_false: else_block.unwrap_or(end_block), otherwise: then_block });
+
+    compiler.switch_to(then_block);
     compiler.add_instruction(Instruction::Pop);
     compile_stmt(compiler, then_stmt)?;
-    
-    if let Some(else_stmt) = else_stmt {
-        let else_index = compiler.add_instruction(Instruction::Jump(0));
-        compiler.patch_instruction(then_index);
+    compiler.terminate(Terminator::Jump(end_block));
+
+    if let (Some(else_stmt), Some(else_block)) = (else_stmt, else_block) {
+        compiler.switch_to(else_block);
         compiler.add_instruction(Instruction::Pop);
-        compile_stmt(compiler, else_stmt.as_ref())?;
-        compiler.patch_instruction(else_index);
-    } else {
-        compiler.patch_instruction(then_index);
+        compile_stmt(compiler, else_stmt)?;
+        compiler.terminate(Terminator::Jump(end_block));
     }
+
+    compiler.switch_to(end_block);
     Ok(())
 }
 
-fn compile_expression_statement(compiler: &mut Compiler, expr: &Expr) -> Result<(), CompilerError> {
+fn compile_expression_statement(compiler: &mut Compiler, expr: &WithSpan<Expr>) -> Result<(), CompilerError> {
     compile_expr(compiler, expr)?;
     compiler.add_instruction(Instruction::Pop);
     Ok(())
 }
 
-fn compile_block(compiler: &mut Compiler, ast: &Ast) -> Result<(), CompilerError> {
+fn compile_block(compiler: &mut Compiler, ast: &[WithSpan<Stmt>]) -> Result<(), CompilerError> {
     compiler.with_scope(|compiler| {
         compile_ast(compiler, ast)
     })
 }
 
-fn compile_var_declaration<T: AsRef<Expr>, I: AsRef<str>>(compiler: &mut Compiler, identifier: WithSpan<I>, expr: Option<T>) -> Result<(), CompilerError> {
-    declare_variable(compiler, identifier.value.as_ref())?;
+fn compile_var_declaration<T: AsRef<WithSpan<Expr>>>(compiler: &mut Compiler, identifier: &str, expr: Option<T>) -> Result<(), CompilerError> {
+    declare_variable(compiler, identifier);
     
     //expr
     if let Some(expr) = expr {
@@ -162,19 +215,19 @@ fn compile_var_declaration<T: AsRef<Expr>, I: AsRef<str>>(compiler: &mut Compile
         compile_nil(compiler)?;
     }
 
-    define_variable(compiler, identifier.value.as_ref());
+    define_variable(compiler, identifier);
 
     Ok(())
 }
 
-fn compile_print(compiler: &mut Compiler, expr: &Expr) -> Result<(), CompilerError> {
+fn compile_print(compiler: &mut Compiler, expr: &WithSpan<Expr>) -> Result<(), CompilerError> {
     compile_expr(compiler, expr)?;
     compiler.add_instruction(Instruction::Print);
     Ok(())
 }
 
-fn compile_expr(compiler: &mut Compiler, expr: &Expr) -> Result<(), CompilerError> {
-    match *expr {
+fn compile_expr(compiler: &mut Compiler, expr: &WithSpan<Expr>) -> Result<(), CompilerError> {
+    match expr.value {
         Expr::Number(num) => compile_number(compiler, num),
         Expr::String(ref string) => compile_string(compiler, string),
         Expr::Binary(ref left, operator, ref right) => compile_binary(compiler, operator, left, right),
@@ -192,14 +245,14 @@ fn compile_expr(compiler: &mut Compiler, expr: &Expr) -> Result<(), CompilerErro
     }
 }
 
-fn compiler_get(compiler: &mut Compiler, expr: &Expr, identifier: &str) -> Result<(), CompilerError> {
+fn compiler_get(compiler: &mut Compiler, expr: &WithSpan<Expr>, identifier: &str) -> Result<(), CompilerError> {
     compile_expr(compiler, expr)?;
     let constant = compiler.add_constant(identifier);
     compiler.add_instruction(Instruction::GetProperty(constant));
     Ok(())
 }
 
-fn compiler_set(compiler: &mut Compiler, expr: &Expr, identifier: &str, value: &Expr) -> Result<(), CompilerError> {
+fn compiler_set(compiler: &mut Compiler, expr: &WithSpan<Expr>, identifier: &str, value: &WithSpan<Expr>) -> Result<(), CompilerError> {
     compile_expr(compiler, expr)?;
     compile_expr(compiler, value)?;
     let constant = compiler.add_constant(identifier);
@@ -207,7 +260,7 @@ fn compiler_set(compiler: &mut Compiler, expr: &Expr, identifier: &str, value: &
     Ok(())
 }
 
-fn compile_unary(compiler: &mut Compiler, operator: UnaryOperator, expr: &Expr) -> Result<(), CompilerError> {
+fn compile_unary(compiler: &mut Compiler, operator: UnaryOperator, expr: &WithSpan<Expr>) -> Result<(), CompilerError> {
     compile_expr(compiler, expr)?;
     match operator {
         UnaryOperator::Minus => compiler.add_instruction(Instruction::Negate),
@@ -217,7 +270,7 @@ fn compile_unary(compiler: &mut Compiler, operator: UnaryOperator, expr: &Expr)
     Ok(())
 }
 
-fn compile_call(compiler: &mut Compiler, identifier: &Expr, args: &Vec<Expr>) -> Result<(), CompilerError> {
+fn compile_call(compiler: &mut Compiler, identifier: &WithSpan<Expr>, args: &Vec<WithSpan<Expr>>) -> Result<(), CompilerError> {
     compile_expr(compiler, identifier)?;
     for arg in args {
         compile_expr(compiler, arg)?;
@@ -226,7 +279,7 @@ fn compile_call(compiler: &mut Compiler, identifier: &Expr, args: &Vec<Expr>) ->
     Ok(())
 }
 
-fn compile_logical(compiler: &mut Compiler, operator: LogicalOperator, left: &Expr, right: &Expr) -> Result<(), CompilerError> {
+fn compile_logical(compiler: &mut Compiler, operator: LogicalOperator, left: &WithSpan<Expr>, right: &WithSpan<Expr>) -> Result<(), CompilerError> {
     match operator {
         LogicalOperator::And => compile_logical_and(compiler, left, right),
         LogicalOperator::Or => compile_logical_or(compiler, left, right),
@@ -234,23 +287,33 @@ fn compile_logical(compiler: &mut Compiler, operator: LogicalOperator, left: &Ex
 }
 
 //TODO Implement this better, using one less jump, we can easily introduce a JumpIfTrue instruction.
-fn compile_logical_or(compiler: &mut Compiler, left: &Expr, right: &Expr) -> Result<(), CompilerError> {
+fn compile_logical_or(compiler: &mut Compiler, left: &WithSpan<Expr>, right: &WithSpan<Expr>) -> Result<(), CompilerError> {
     compile_expr(compiler, left)?;
-    let else_jump = compiler.add_instruction(Instruction::JumpIfFalse(0));
-    let end_jump = compiler.add_instruction(Instruction::Jump(0));
-    compiler.patch_instruction(else_jump);
+    let else_block = compiler.add_block();
+    let end_block = compiler.add_block();
+    compiler.terminate(Terminator::Branch { if_false: else_block, otherwise: end_block });
+
+    compiler.switch_to(else_block);
     compiler.add_instruction(Instruction::Pop);
     compile_expr(compiler, right)?;
-    compiler.patch_instruction(end_jump);
+    compiler.terminate(Terminator::Jump(end_block));
+
+    compiler.switch_to(end_block);
     Ok(())
 }
 
-fn compile_logical_and(compiler: &mut Compiler, left: &Expr, right: &Expr) -> Result<(), CompilerError> {
+fn compile_logical_and(compiler: &mut Compiler, left: &WithSpan<Expr>, right: &WithSpan<Expr>) -> Result<(), CompilerError> {
     compile_expr(compiler, left)?;
-    let end_jump = compiler.add_instruction(Instruction::JumpIfFalse(0));
+    let right_block = compiler.add_block();
+    let end_block = compiler.add_block();
+    compiler.terminate(Terminator::Branch { if_false: end_block, otherwise: right_block });
+
+    compiler.switch_to(right_block);
     compiler.add_instruction(Instruction::Pop);
     compile_expr(compiler, right)?;
-    compiler.patch_instruction(end_jump);
+    compiler.terminate(Terminator::Jump(end_block));
+
+    compiler.switch_to(end_block);
     Ok(())
 }
 
@@ -264,33 +327,27 @@ fn compile_boolean(compiler: &mut Compiler, boolean: bool) -> Result<(), Compile
     Ok(())
 }
 
-fn compile_assign(compiler: &mut Compiler, identifier: &str, expr: &Expr) -> Result<(), CompilerError> {
+fn compile_assign(compiler: &mut Compiler, identifier: &WithSpan<Identifier>, expr: &WithSpan<Expr>) -> Result<(), CompilerError> {
     compile_expr(compiler, expr)?;
-    if let Some(local) = compiler.resolve_local(identifier)? {
-        // Local
-        compiler.add_instruction(Instruction::SetLocal(local));
-    } else if let Some(upvalue) = compiler.resolve_upvalue(identifier)? {
-        // Upvalue
-        compiler.add_instruction(Instruction::SetUpvalue(upvalue));
-    } else {
-        // Global
-        let constant = compiler.add_constant(identifier);
-        compiler.add_instruction(Instruction::SetGlobal(constant));
+    match compiler.binding(identifier)? {
+        Binding::Local(id) => compiler.add_instruction(Instruction::SetLocal(id)),
+        Binding::Upvalue(upvalue) => compiler.add_instruction(Instruction::SetUpvalue(upvalue)),
+        Binding::Global => {
+            let global = compiler.global(&identifier.value)?;
+            compiler.add_instruction(Instruction::SetGlobal(global));
+        },
     }
     Ok(())
 }
 
-fn compile_variable(compiler: &mut Compiler, identifier: &str) -> Result<(), CompilerError> {
-    if let Some(local) = compiler.resolve_local(identifier)? {
-        // Local
-        compiler.add_instruction(Instruction::GetLocal(local));
-    } else if let Some(upvalue) = compiler.resolve_upvalue(identifier)? {
-        // Upvalue
-        compiler.add_instruction(Instruction::GetUpvalue(upvalue));
-    } else {
-        // Global
-        let constant = compiler.add_constant(identifier);
-        compiler.add_instruction(Instruction::GetGlobal(constant));
+fn compile_variable(compiler: &mut Compiler, identifier: &WithSpan<Identifier>) -> Result<(), CompilerError> {
+    match compiler.binding(identifier)? {
+        Binding::Local(id) => compiler.add_instruction(Instruction::GetLocal(id)),
+        Binding::Upvalue(upvalue) => compiler.add_instruction(Instruction::GetUpvalue(upvalue)),
+        Binding::Global => {
+            let global = compiler.global(&identifier.value)?;
+            compiler.add_instruction(Instruction::GetGlobal(global));
+        },
     }
     Ok(())
 }
@@ -312,7 +369,7 @@ fn compile_string(compiler: &mut Compiler, string: &str) -> Result<(), CompilerE
     Ok(())
 }
 
-fn compile_binary(compiler: &mut Compiler, operator: BinaryOperator, left: &Expr, right: &Expr) -> Result<(), CompilerError> {
+fn compile_binary(compiler: &mut Compiler, operator: BinaryOperator, left: &WithSpan<Expr>, right: &WithSpan<Expr>) -> Result<(), CompilerError> {
     compile_expr(compiler, left)?;
     compile_expr(compiler, right)?;
     match operator {
@@ -2,11 +2,11 @@ use crate::bytecode::*;
 use crate::ast::*;
 use crate::common::ParseError;
 
-fn parse_stmt(data: &str) -> Result<Vec<Stmt>, ParseError> {
+fn parse_stmt(data: &str) -> Result<Ast, ParseError> {
     use crate::tokenizer::tokenize_with_context;
     let tokens = tokenize_with_context(data);
     // println!("Tokens: {:?}", tokens);
-    let mut it = tokens.as_slice().into_iter().peekable();
+    let mut it = tokens.as_slice().iter().peekable();
     crate::stmt_parser::parse(&mut it)
 }
 
@@ -33,6 +33,10 @@ fn assert_constants(module: &Module, constants: Vec<Constant>) {
     assert_eq!(constants, module.constants());
 }
 
+fn assert_globals(module: &Module, globals: Vec<&str>) {
+    assert_eq!(globals, module.globals().names());
+}
+
 #[test]
 fn test_stmt_print_numbers() {
     assert_first_chunk(
@@ -76,26 +80,36 @@ fn test_global_variables() {
     use crate::bytecode::Instruction::*;
     assert_first_chunk(
         "var x=3;", 
-        vec![3.0.into(), "x".into()],
-        vec![Instruction::Constant(0), Instruction::DefineGlobal(1), Instruction::Nil, Instruction::Return]
+        vec![3.0.into()],
+        vec![Instruction::Constant(0), Instruction::DefineGlobal(0), Instruction::Nil, Instruction::Return]
     );
     assert_first_chunk(
         "var x;", 
-        vec!["x".into()],
+        vec![],
         vec![Instruction::Nil, Instruction::DefineGlobal(0), Instruction::Nil, Instruction::Return]
     );
     assert_first_chunk(
         "var x=3; print x;", 
-        vec![3.0.into(), "x".into(), "x".into()],
-        vec![Instruction::Constant(0), Instruction::DefineGlobal(1), Instruction::GetGlobal(2), Instruction::Print, Instruction::Nil, Instruction::Return]
+        vec![3.0.into()],
+        vec![Instruction::Constant(0), Instruction::DefineGlobal(0), Instruction::GetGlobal(0), Instruction::Print, Instruction::Nil, Instruction::Return]
     );
     assert_first_chunk(
         "var x=3;x=2;", 
-        vec![3.0.into(), "x".into(), 2.0.into(), "x".into()],
-        vec![Constant(0), DefineGlobal(1), Constant(2), SetGlobal(3), Pop, Instruction::Nil, Instruction::Return]
+        vec![3.0.into(), 2.0.into()],
+        vec![Constant(0), DefineGlobal(0), Constant(1), SetGlobal(0), Pop, Instruction::Nil, Instruction::Return]
     );
 }
 
+#[test]
+fn test_global_slots() {
+    use crate::bytecode::Instruction::*;
+
+    let module = compile_code("var x; var y; print y; x = y; print z;");
+
+    assert_instructions(module.chunk(0), vec![Nil, DefineGlobal(0), Nil, DefineGlobal(1), GetGlobal(1), Print, GetGlobal(1), SetGlobal(0), Pop, GetGlobal(2), Print, Nil, Return]);
+    assert_globals(&module, vec!["x", "y", "z"]);
+}
+
 #[test]
 fn test_local_variables() {
     use crate::bytecode::Instruction::*;
@@ -111,8 +125,8 @@ fn test_local_variables() {
     );
     assert_first_chunk(
         "var x=2; {var x=3; { var x=4; print x; } print x;} print x;", 
-        vec![2.0.into(), "x".into(), 3.0.into(), 4.0.into(), "x".into()],
-        vec![Constant(0), DefineGlobal(1), Constant(2), Constant(3), GetLocal(2), Print, Pop, GetLocal(1), Print, Pop, GetGlobal(4), Print, Instruction::Nil, Instruction::Return]
+        vec![2.0.into(), 3.0.into(), 4.0.into()],
+        vec![Constant(0), DefineGlobal(0), Constant(1), Constant(2), GetLocal(2), Print, Pop, GetLocal(1), Print, Pop, GetGlobal(0), Print, Instruction::Nil, Instruction::Return]
     );
     assert_first_chunk(
         "{var x;}", 
@@ -161,13 +175,13 @@ fn test_if() {
     assert_first_chunk(
         "if(false) 3;4;", 
         vec![3.0.into(), 4.0.into()],
-        vec![False, JumpIfFalse(5), Pop, Constant(0), Pop, Constant(1), Pop, Nil, Return],
+        vec![False, JumpIfFalse(9), Pop, Constant(0), Pop, Constant(1), Pop, Nil, Return],
     );
 
     assert_first_chunk(
         "if(false) 3; else 4;5;", 
         vec![3.0.into(), 4.0.into(), 5.0.into()],
-        vec![False, JumpIfFalse(6), Pop, Constant(0), Pop, Jump(9), Pop, Constant(1), Pop, Constant(2), Pop, Nil, Return],
+        vec![False, JumpIfFalse(13), Pop, Constant(0), Pop, Jump(17), Pop, Constant(1), Pop, Constant(2), Pop, Nil, Return],
     );
 }
 
@@ -178,13 +192,13 @@ fn test_logical_operators() {
     assert_first_chunk(
         "3 and 4;", 
         vec![3.0.into(), 4.0.into()],
-        vec![Constant(0), JumpIfFalse(4), Pop, Constant(1), Pop, Nil, Return],
+        vec![Constant(0), JumpIfFalse(9), Pop, Constant(1), Pop, Nil, Return],
     );
 
     assert_first_chunk(
         "3 or 4;", 
         vec![3.0.into(), 4.0.into()],
-        vec![Constant(0), JumpIfFalse(3), Jump(5), Pop, Constant(1), Pop, Nil, Return],
+        vec![Constant(0), JumpIfFalse(10), Jump(13), Pop, Constant(1), Pop, Nil, Return],
     );
 }
 
@@ -206,7 +220,7 @@ fn test_while() {
     assert_first_chunk(
         "while(true) print 3;", 
         vec![3.0.into()],
-        vec![True, JumpIfFalse(6), Pop, Constant(0), Print, Jump(0), Pop, Nil, Return],
+        vec![True, JumpIfFalse(13), Pop, Constant(0), Print, Jump(0), Pop, Nil, Return],
     );
 }
 
@@ -217,7 +231,7 @@ fn test_for() {
     assert_first_chunk(
         "for(var i = 0; i < 10; i = i + 1) print i;", 
         vec![0.0.into(), 10.0.into(), 1.0.into()],
-        vec![Constant(0), GetLocal(1), Constant(1), Less, JumpIfFalse(14), Pop, GetLocal(1), Print, GetLocal(1), Constant(2), Add, SetLocal(1), Pop, Jump(1), Pop, Pop, Instruction::Nil, Instruction::Return],
+        vec![Constant(0), GetLocal(1), Constant(1), Less, JumpIfFalse(27), Pop, GetLocal(1), Print, GetLocal(1), Constant(2), Add, SetLocal(1), Pop, Jump(2), Pop, Pop, Instruction::Nil, Instruction::Return],
     );
 }
 
@@ -227,15 +241,14 @@ fn test_simple_function() {
 
     let module = compile_code("fun first() { print 3; } first();");
 
-    assert_instructions(module.chunk(0), vec![Closure(1), DefineGlobal(2), GetGlobal(3), Call(0), Pop, Instruction::Nil, Instruction::Return]);
+    assert_instructions(module.chunk(0), vec![Closure(1), DefineGlobal(0), GetGlobal(0), Call(0), Pop, Instruction::Nil, Instruction::Return]);
     assert_instructions(module.chunk(1), vec![Constant(0), Print, Nil, Return]);
 
     assert_constants(&module, vec![
         3.0.into(),
         make_fun("first", 1, 0),
-        "first".into(), 
-        "first".into()
     ]);
+    assert_globals(&module, vec!["first"]);
 }
 
 #[test]
@@ -244,13 +257,11 @@ fn test_function_with_one_argument() {
 
     let module = compile_code("fun first(a) { print a; } first(3);");
 
-    assert_instructions(module.chunk(0), vec![Closure(0), DefineGlobal(1), GetGlobal(2), Constant(3), Call(1), Pop, Instruction::Nil, Instruction::Return]);
+    assert_instructions(module.chunk(0), vec![Closure(0), DefineGlobal(0), GetGlobal(0), Constant(1), Call(1), Pop, Instruction::Nil, Instruction::Return]);
     assert_instructions(module.chunk(1), vec![GetLocal(1), Print, Nil, Return]);
 
     assert_constants(&module, vec![
         make_fun("first", 1, 1),
-        "first".into(), 
-        "first".into(), 
         3.0.into()
     ]);
 }
@@ -261,15 +272,12 @@ fn test_recursive_function_with_one_argument() {
 
     let module = compile_code("fun first(a) { print first(a+1); } first(3);");
 
-    assert_instructions(module.chunk(0), vec![Closure(2), DefineGlobal(3), GetGlobal(4), Constant(5), Call(1), Pop, Nil, Return]);
-    assert_instructions(module.chunk(1), vec![GetGlobal(0), GetLocal(1), Constant(1), Add, Call(1), Print, Nil, Return]);
+    assert_instructions(module.chunk(0), vec![Closure(1), DefineGlobal(0), GetGlobal(0), Constant(2), Call(1), Pop, Nil, Return]);
+    assert_instructions(module.chunk(1), vec![GetGlobal(0), GetLocal(1), Constant(0), Add, Call(1), Print, Nil, Return]);
 
     assert_constants(&module, vec![
-        "first".into(), 
         1.0.into(),  
         make_fun("first", 1, 1),
-        "first".into(), 
-        "first".into(), 
         3.0.into()
     ]);
 }
@@ -280,19 +288,16 @@ fn test_functions_calling_functions() {
 
     let module = compile_code("fun first() { second(); } fun second() { print 3; } first();");
 
-    assert_instructions(module.chunk(0), vec![Closure(1), DefineGlobal(2), Closure(4), DefineGlobal(5), GetGlobal(6), Call(0), Pop, Instruction::Nil, Instruction::Return]);
+    assert_instructions(module.chunk(0), vec![Closure(0), DefineGlobal(1), Closure(2), DefineGlobal(0), GetGlobal(1), Call(0), Pop, Instruction::Nil, Instruction::Return]);
     assert_instructions(module.chunk(1), vec![GetGlobal(0), Call(0), Pop, Nil, Return]);
-    assert_instructions(module.chunk(2), vec![Constant(3), Print, Nil, Return]);
+    assert_instructions(module.chunk(2), vec![Constant(1), Print, Nil, Return]);
 
     assert_constants(&module, vec![
-        "second".into(), 
         make_fun("first", 1, 0),
-        "first".into(),
         3.0.into(),
         make_fun("second", 2, 0),
-        "second".into(), 
-        "first".into(),
     ]);
+    assert_globals(&module, vec!["second", "first"]);
 }
 
 #[test]
@@ -330,16 +335,25 @@ fn test_function_with_return() {
 
     let module = compile_code("fun first() { return 3; }");
 
-    assert_instructions(module.chunk(0), vec![Closure(1), DefineGlobal(2), Instruction::Nil, Instruction::Return]);
-    assert_instructions(module.chunk(1), vec![Constant(0), Return, Nil, Return]);
+    assert_instructions(module.chunk(0), vec![Closure(1), DefineGlobal(0), Instruction::Nil, Instruction::Return]);
+    assert_instructions(module.chunk(1), vec![Constant(0), Return]);
 
     assert_constants(&module, vec![
         3.0.into(),
         make_fun("first", 1, 0),
-        "first".into()
     ]);
 }
 
+#[test]
+fn test_tail_call() {
+    use crate::bytecode::Instruction::*;
+
+    let module = compile_code("fun f(n) { return f(n); } fun g(n) { return g(n) + 1; }");
+
+    assert_instructions(module.chunk(1), vec![GetGlobal(0), GetLocal(1), TailCall(1)]);
+    assert_instructions(module.chunk(2), vec![GetGlobal(1), GetLocal(1), Call(1), Constant(1), Add, Return]);
+}
+
 #[test]
 fn test_upvalue() {
     use crate::bytecode::Instruction::*;
@@ -412,19 +426,16 @@ fn test_scoped_upvalue() {
 
     let module = compile_code("var global; fun main() { { var a = 3; fun one() { print a; } global = one; } } main();");
 
-    assert_instructions(module.chunk(0), vec![Nil, DefineGlobal(0), Closure(4), DefineGlobal(5), GetGlobal(6), Call(0), Pop, Instruction::Nil, Instruction::Return]);
-    assert_instructions(module.chunk(1), vec![Constant(1), Closure(2), GetLocal(2), SetGlobal(3), Pop, Pop, CloseUpvalue, Nil, Return]);
+    assert_instructions(module.chunk(0), vec![Nil, DefineGlobal(0), Closure(2), DefineGlobal(1), GetGlobal(1), Call(0), Pop, Instruction::Nil, Instruction::Return]);
+    assert_instructions(module.chunk(1), vec![Constant(0), Closure(1), GetLocal(2), SetGlobal(0), Pop, Pop, CloseUpvalue, Nil, Return]);
     assert_instructions(module.chunk(2), vec![GetUpvalue(0), Print, Nil, Return]);
 
     assert_constants(&module, vec![
-        "global".into(),
         3.0.into(),
         make_closure("one",2, 0, vec![Upvalue::Local(1)]),
-        "global".into(),
         make_closure("main",1, 0, vec![]),
-        "main".into(),
-        "main".into(),
     ]);
+    assert_globals(&module, vec!["global", "main"]);
 }
 
 #[test]
@@ -433,12 +444,12 @@ fn test_empty_class_global() {
 
     let module = compile_code("class Foo {}");
 
-    assert_instructions(module.chunk(0), vec![Class(0), DefineGlobal(1), Nil, Return]);
+    assert_instructions(module.chunk(0), vec![Class(0), DefineGlobal(0), Nil, Return]);
 
     assert_constants(&module, vec![
         make_class("Foo"),
-        "Foo".into(),
     ]);
+    assert_globals(&module, vec!["Foo"]);
 }
 
 #[test]
@@ -460,10 +471,9 @@ fn test_set_property() {
 
     let module = compile_code("x.test = 3;");
 
-    assert_instructions(module.chunk(0), vec![GetGlobal(0), Constant(1), SetProperty(2), Pop, Nil, Return]);
+    assert_instructions(module.chunk(0), vec![GetGlobal(0), Constant(0), SetProperty(1), Pop, Nil, Return]);
 
     assert_constants(&module, vec![
-        "x".into(),
         3.0.into(),
         "test".into(),
     ]);
@@ -475,24 +485,175 @@ fn test_get_property() {
 
     let module = compile_code("x.test;");
 
-    assert_instructions(module.chunk(0), vec![GetGlobal(0), GetProperty(1), Pop, Nil, Return]);
+    assert_instructions(module.chunk(0), vec![GetGlobal(0), GetProperty(0), Pop, Nil, Return]);
 
     assert_constants(&module, vec![
-        "x".into(),
         "test".into(),
     ]);
 }
 
 fn make_fun(name: &str, index: usize, arity: usize) -> Constant {
-    crate::bytecode::Function{name: name.into(), chunk_index: index, arity: arity}.into()
+    crate::bytecode::Function{name: name.into(), chunk_index: index, arity}.into()
 }
 
 fn make_closure(name: &str, index: usize, arity: usize, upvalues: Vec<Upvalue>) -> Constant {
-    let function = crate::bytecode::Function{name: name.into(), chunk_index: index, arity: arity};
+    let function = crate::bytecode::Function{name: name.into(), chunk_index: index, arity};
     let closure = crate::bytecode::Closure{function, upvalues};
     Constant::Closure(closure)
 }
 
 fn make_class(name: &str) -> Constant {
     Constant::Class(Class{ name: name.to_string() })
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_encoded_size() {
+    let module = compile_code("print 1+2;");
+    assert_eq!(module.chunk(0).code().len(), 8);
+}
+
+#[test]
+fn test_long_operands() {
+    use crate::bytecode::Instruction::*;
+
+    let data: String = (0..300).map(|i| format!("print {};", i)).collect();
+    let module = compile_code(&data);
+    let instructions = module.chunk(0).instructions();
+
+    assert_eq!(instructions.len(), 602);
+    assert_eq!(instructions[2 * 255], Constant(255));
+    assert_eq!(instructions[2 * 256], Constant(256));
+    assert_eq!(instructions[2 * 299], Constant(299));
+    assert_eq!(module.chunk(0).code().len(), 256 * 3 + 44 * 5 + 2);
+}
+
+#[test]
+fn test_debug_info_lines() {
+    let module = compile_code("print 1;\nvar a = 2;\n\nprint a;");
+    let debug_info = module.debug_info().unwrap().chunk(0).unwrap();
+
+    let lines: Vec<_> = module.chunk(0).decoded()
+        .map(|(index, _)| debug_info.line(index))
+        .collect();
+    assert_eq!(lines, vec![Some(1), Some(1), Some(2), Some(2), Some(4), Some(4), Some(4), Some(4)]);
+}
+
+#[test]
+fn test_debug_info_locals() {
+    let module = compile_code("fun f(a) { var b = a; { var c = b; print c; } return a + b; }");
+    let debug_info = module.debug_info().unwrap().chunk(1).unwrap();
+    let indices: Vec<_> = module.chunk(1).decoded().map(|(index, _)| index).collect();
+
+    let names = |index| debug_info.locals_at(index).map(|local| local.name.as_str()).collect::<Vec<_>>();
+    assert_eq!(names(indices[0]), vec!["a"]);
+    assert_eq!(names(indices[1]), vec!["a", "b"]);
+    assert_eq!(names(indices[2]), vec!["a", "b", "c"]);
+    assert_eq!(names(*indices.last().unwrap()), vec!["a", "b"]);
+}
+
+#[test]
+fn test_debug_info_reused_slots() {
+    let module = compile_code("fun f() { var a = 1; var b = a; return b; }");
+    let debug_info = module.debug_info().unwrap().chunk(1).unwrap();
+    let indices: Vec<_> = module.chunk(1).decoded().map(|(index, _)| index).collect();
+
+    // `b` takes over the slot of `a`, which isn't used anymore.
+    let locals: Vec<_> = debug_info.locals.iter().map(|local| (local.name.as_str(), local.slot)).collect();
+    assert_eq!(locals, vec![("a", 1), ("b", 1)]);
+    let names = |index| debug_info.locals_at(index).map(|local| local.name.as_str()).collect::<Vec<_>>();
+    assert_eq!(names(indices[1]), vec!["a"]);
+    assert_eq!(names(*indices.last().unwrap()), vec!["b"]);
+}
+
+#[test]
+fn test_debug_info_keeps_parameters() {
+    let module = compile_code("fun f(a) { var b = a; return b; }");
+    let debug_info = module.debug_info().unwrap().chunk(1).unwrap();
+    let locals: Vec<_> = debug_info.locals.iter().map(|local| (local.name.as_str(), local.slot)).collect();
+    assert_eq!(locals, vec![("a", 1), ("b", 2)]);
+    let last = module.chunk(1).decoded().last().unwrap().0;
+    assert_eq!(debug_info.locals_at(last).map(|local| local.name.as_str()).collect::<Vec<_>>(), vec!["a", "b"]);
+}
+
+#[test]
+fn test_debug_info_upvalues() {
+    let module = compile_code("fun f() { var a = 1; var b = 2; fun g() { print b; print a; } }");
+    let debug_info = module.debug_info().unwrap();
+    assert_eq!(debug_info.chunk(2).unwrap().upvalues, vec!["b".to_string(), "a".to_string()]);
+}
+
+#[test]
+fn test_strip_debug_info() {
+    let mut module = compile_code("print 1;");
+    let serialized = serde_json::to_string(&module).unwrap();
+    let deserialized: Module = serde_json::from_str(&serialized).unwrap();
+    assert_eq!(deserialized.debug_info(), module.debug_info());
+
+    module.strip_debug_info();
+    assert_eq!(module.debug_info(), None);
+    let serialized = serde_json::to_string(&module).unwrap();
+    assert!(!serialized.contains("debug_info"));
+    let deserialized: Module = serde_json::from_str(&serialized).unwrap();
+    assert_eq!(deserialized.debug_info(), None);
+}
+
+#[test]
+fn test_deserialize_validates_code() {
+    let module = compile_code("var a = 1; while (a) a = nil;");
+    let serialized = serde_json::to_string(&module).unwrap();
+    let deserialized: Module = serde_json::from_str(&serialized).unwrap();
+    assert_eq!(deserialized.chunk(0).code(), module.chunk(0).code());
+    assert_eq!(deserialized.globals().names(), ["a"]);
+    assert_eq!(deserialized.globals().index("a"), Some(0));
+
+    let chunk = |code: &str| serde_json::from_str::<crate::bytecode::Chunk>(code).map_err(|error| error.to_string());
+    assert!(chunk(r#"{"code":[]}"#).is_ok());
+    assert!(chunk(r#"{"code":[255]}"#).unwrap_err().starts_with("Invalid instruction at 0"));
+    // A constant without its operand, and a long constant with only part of it
+    assert!(chunk(r#"{"code":[2,0]}"#).unwrap_err().starts_with("Invalid instruction at 1"));
+    assert!(chunk(r#"{"code":[1,0,0]}"#).unwrap_err().starts_with("Invalid instruction at 0"));
+    // Jumps to the end are fine, into an operand or past the end aren't
+    assert!(chunk(r#"{"code":[35,0,0,4]}"#).is_ok());
+    assert!(chunk(r#"{"code":[0,1,35,0,0,1]}"#).unwrap_err().starts_with("Invalid jump to 1 at 2"));
+    assert!(chunk(r#"{"code":[35,0,0,9]}"#).unwrap_err().starts_with("Invalid jump to 9 at 0"));
+}
+
+#[test]
+fn test_deserialize_validates_operands() {
+    use crate::bytecode::{Constant, Function, Instruction};
+
+    let module = compile_code("class A { f() { return this.x; } } fun g() { print A().f; } g();");
+    let deserialized: Module = serde_json::from_str(&serde_json::to_string(&module).unwrap()).unwrap();
+    assert_eq!(deserialized.constants(), module.constants());
+
+    let error = |instruction: Instruction| {
+        let mut module = Module::new();
+        module.add_constant(Constant::Number(1.0));
+        module.add_constant(Function { name: "f".to_string(), chunk_index: 2, arity: 0 }.into());
+        module.globals_mut().add("a");
+        let chunk = module.add_chunk();
+        module.chunk_mut(chunk).add_instruction(Instruction::Nil);
+        module.chunk_mut(chunk).add_instruction(instruction);
+        serde_json::from_str::<Module>(&serde_json::to_string(&module).unwrap()).err().map(|error| error.to_string())
+    };
+    assert_eq!(error(Instruction::Constant(0)), None);
+    assert_eq!(error(Instruction::GetGlobal(0)), None);
+    assert!(error(Instruction::Constant(2)).unwrap().starts_with("Invalid constant 2 at 1 in chunk 0"));
+    assert!(error(Instruction::Constant(1)).unwrap().starts_with("Constant 1 is not a number or string"));
+    assert!(error(Instruction::GetProperty(0)).unwrap().starts_with("Constant 0 is not a string"));
+    assert!(error(Instruction::Class(300)).unwrap().starts_with("Invalid constant 300"));
+    assert!(error(Instruction::Closure(0)).unwrap().starts_with("Constant 0 is not a closure"));
+    assert!(error(Instruction::Closure(1)).unwrap().starts_with("Invalid chunk 2"));
+    assert!(error(Instruction::SetGlobal(1)).unwrap().starts_with("Invalid global 1"));
+}
+
+#[test]
+fn test_compile_expression() {
+    use crate::bytecode::Instruction::*;
+
+    let module = crate::compile_expression("a + 1").unwrap();
+    assert_instructions(module.chunk(0), vec![GetGlobal(0), Constant(0), Add, Return]);
+    assert_eq!(module.globals().names(), &["a".to_string()]);
+
+    assert!(crate::compile_expression("a + 1; print a;").is_err());
+}
@@ -28,7 +28,7 @@ impl From<&String> for ParseError {
 impl From<String> for ParseError {
     fn from(error: String) -> ParseError {
         ParseError {
-            error: error,
+            error,
             span: None,
         }
     }
@@ -65,15 +65,25 @@ where
     }
 }
 
-pub fn expect<'a, It>(it: &mut Peekable<It>, expected: &Token) -> Result<&'a Token, ParseError>
+pub fn peek_span<'a, It>(it: &mut Peekable<It>) -> Result<Span, String>
+where
+    It: Iterator<Item = &'a WithSpan<Token>>,
+{
+    match it.peek() {
+        Some(&t) => Ok(t.span),
+        None => Err(String::from("No more tokens")),
+    }
+}
+
+pub fn expect<'a, It>(it: &mut Peekable<It>, expected: &Token) -> Result<&'a WithSpan<Token>, ParseError>
 where
     It: Iterator<Item = &'a WithSpan<Token>>,
 {
     let token = next_with_context(it)?;
     if &token.value == expected {
-        Ok(&token.value)
+        Ok(token)
     } else {
-        Err(ParseError { error: format!("Expected {:?} got {:?}", expected, &token.value).into(), span: Some(token.span) })
+        Err(ParseError { error: format!("Expected {:?} got {:?}", expected, &token.value), span: Some(token.span) })
     }
 }
 
@@ -94,29 +104,12 @@ where
     }
 }
 
-macro_rules! expect {
-    ($x:expr, $y:pat) => {{
-        let tc = next_with_context($x)?;
-        match &tc.value {
-            $y => Ok(&t.token),
-            t => Err(ParseError { error: format!("Unexpected {:?}", t).into(), span: Some(tc.span) }),
-        }
-    }};
-    ($x:expr, $y:pat => $z:expr) => {{
-        let tc = next_with_context($x)?;
-        match &tc.value {
-            $y => Ok($z),
-            t => Err(ParseError { error: format!("Unexpected {:?}", t).into(), span: Some(tc.span) }),
-        }
-    }};
-}
-
 macro_rules! expect_with_span {
     ($x:expr, $y:pat => $z:expr) => {{
         let tc = next_with_context($x)?;
         match &tc.value {
             $y => Ok(WithSpan::new($z, tc.span)),
-            _ => Err(ParseError { error: "Unexpected token".into(), span: Some(tc.span) }),
+            t => Err(ParseError { error: format!("Unexpected {:?}", t).into(), span: Some(tc.span) }),
         }
     }};
 }
@@ -0,0 +1,100 @@
+use super::{SyntaxKind, SyntaxToken, Trivia, TriviaKind};
+use crate::position::{Position, Span};
+use crate::token::Token;
+use crate::tokenizer::tokenize_with_trivia;
+
+enum Piece {
+    Trivia(TriviaKind),
+    Token(SyntaxKind),
+}
+
+fn piece(token: &Token) -> Piece {
+    match token {
+        Token::Whitespace => Piece::Trivia(TriviaKind::Whitespace),
+        Token::Comment(_) => Piece::Trivia(TriviaKind::Comment),
+        Token::LeftParen => Piece::Token(SyntaxKind::LeftParen),
+        Token::RightParen => Piece::Token(SyntaxKind::RightParen),
+        Token::LeftBrace => Piece::Token(SyntaxKind::LeftBrace),
+        Token::RightBrace => Piece::Token(SyntaxKind::RightBrace),
+        Token::Comma => Piece::Token(SyntaxKind::Comma),
+        Token::Dot => Piece::Token(SyntaxKind::Dot),
+        Token::Minus => Piece::Token(SyntaxKind::Minus),
+        Token::Plus => Piece::Token(SyntaxKind::Plus),
+        Token::Semicolon => Piece::Token(SyntaxKind::Semicolon),
+        Token::Slash => Piece::Token(SyntaxKind::Slash),
+        Token::Star => Piece::Token(SyntaxKind::Star),
+        Token::Colon => Piece::Token(SyntaxKind::Colon),
+        Token::Bang => Piece::Token(SyntaxKind::Bang),
+        Token::BangEqual => Piece::Token(SyntaxKind::BangEqual),
+        Token::Equal => Piece::Token(SyntaxKind::Equal),
+        Token::EqualEqual => Piece::Token(SyntaxKind::EqualEqual),
+        Token::Greater => Piece::Token(SyntaxKind::Greater),
+        Token::GreaterEqual => Piece::Token(SyntaxKind::GreaterEqual),
+        Token::Less => Piece::Token(SyntaxKind::Less),
+        Token::LessEqual => Piece::Token(SyntaxKind::LessEqual),
+        Token::Identifier(_) => Piece::Token(SyntaxKind::Identifier),
+        Token::String(_) => Piece::Token(SyntaxKind::String),
+        Token::Number(_) => Piece::Token(SyntaxKind::Number),
+        Token::And => Piece::Token(SyntaxKind::And),
+        Token::Class => Piece::Token(SyntaxKind::Class),
+        Token::Else => Piece::Token(SyntaxKind::Else),
+        Token::Export => Piece::Token(SyntaxKind::Export),
+        Token::False => Piece::Token(SyntaxKind::False),
+        Token::Fun => Piece::Token(SyntaxKind::Fun),
+        Token::For => Piece::Token(SyntaxKind::For),
+        Token::If => Piece::Token(SyntaxKind::If),
+        Token::Import => Piece::Token(SyntaxKind::Import),
+        Token::Nil => Piece::Token(SyntaxKind::Nil),
+        Token::Or => Piece::Token(SyntaxKind::Or),
+        Token::Print => Piece::Token(SyntaxKind::Print),
+        Token::Return => Piece::Token(SyntaxKind::Return),
+        Token::Super => Piece::Token(SyntaxKind::Super),
+        Token::This => Piece::Token(SyntaxKind::This),
+        Token::True => Piece::Token(SyntaxKind::True),
+        Token::Var => Piece::Token(SyntaxKind::Var),
+        Token::While => Piece::Token(SyntaxKind::While),
+        Token::Eof => Piece::Token(SyntaxKind::Eof),
+        Token::Error => Piece::Token(SyntaxKind::Error),
+    }
+}
+
+/// Every token with its trivia, ending with an `Eof` token. Built on the tokenizer that keeps trivia, so lexing never
+/// fails, what isn't Lox becomes `Error` tokens.
+pub(super) fn lex(code: &str) -> Vec<SyntaxToken> {
+    let mut tokens: Vec<SyntaxToken> = vec![];
+    let mut leading = vec![];
+    let mut end = Position::default();
+    // Trivia is trailing until the line of the last token ends
+    let mut line_ended = true;
+    for (token, text) in tokenize_with_trivia(code) {
+        end = text.chars().last().map_or(end, |ch| token.span.end.shift(ch));
+        let kind = match piece(&token.value) {
+            Piece::Token(kind) => kind,
+            Piece::Trivia(kind) => {
+                let trivia = |text: &str| Trivia { kind, text: text.to_string() };
+                match tokens.last_mut() {
+                    Some(last) if !line_ended => match text.find('\n') {
+                        Some(line_break) => {
+                            if line_break > 0 {
+                                last.trailing.push(trivia(&text[..line_break]));
+                            }
+                            leading.push(trivia(&text[line_break..]));
+                            line_ended = true;
+                        },
+                        None => last.trailing.push(trivia(text)),
+                    },
+                    _ => leading.push(trivia(text)),
+                }
+                continue;
+            },
+        };
+
+        let span = token.span;
+        tokens.push(SyntaxToken { kind, text: text.to_string(), leading: std::mem::take(&mut leading), trailing: vec![], span });
+        line_ended = false;
+    }
+
+    let end = Span { start: end, end };
+    tokens.push(SyntaxToken { kind: SyntaxKind::Eof, text: String::new(), leading, trailing: vec![], span: end });
+    tokens
+}
@@ -0,0 +1,245 @@
+//! A lossless concrete syntax tree. Every character of the source is in a token, whitespace and comments are trivia
+//! on the token next to them, so printing the tree gives back the source byte for byte, also when it doesn't parse.
+//! Parts that don't parse end up in `Error` nodes. `typed` has a typed view on the nodes.
+
+mod lexer;
+mod parser;
+pub mod typed;
+#[cfg(test)]
+mod tests;
+
+use crate::common::ParseError;
+use crate::position::{Position, Span};
+use std::fmt;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SyntaxKind {
+    // Tokens
+    LeftParen,
+    RightParen,
+    LeftBrace,
+    RightBrace,
+    Comma,
+    Dot,
+    Minus,
+    Plus,
+    Semicolon,
+    Slash,
+    Star,
+    Colon,
+    Bang,
+    BangEqual,
+    Equal,
+    EqualEqual,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+    Identifier,
+    String,
+    Number,
+    And,
+    Class,
+    Else,
+    Export,
+    False,
+    Fun,
+    For,
+    If,
+    Import,
+    Nil,
+    Or,
+    Print,
+    Return,
+    Super,
+    This,
+    True,
+    Var,
+    While,
+    /// The end of the file, it has no text but holds the trivia after the last token.
+    Eof,
+    /// A character that isn't Lox or an unterminated string as a token, the part that didn't parse as a node.
+    Error,
+    // Nodes
+    Program,
+    /// `import "path";` or `import { a, b } from "path";`.
+    ImportDecl,
+    /// `export` and the declaration it exports.
+    ExportDecl,
+    VarDecl,
+    FunDecl,
+    ClassDecl,
+    /// A function in a class, without `fun`.
+    Method,
+    ParamList,
+    Block,
+    ExprStmt,
+    PrintStmt,
+    IfStmt,
+    WhileStmt,
+    ForStmt,
+    ReturnStmt,
+    BinaryExpr,
+    LogicalExpr,
+    UnaryExpr,
+    GroupingExpr,
+    Literal,
+    VariableExpr,
+    AssignExpr,
+    CallExpr,
+    ArgList,
+    GetExpr,
+    SetExpr,
+    ThisExpr,
+    SuperExpr,
+    /// A `:` and the name of a type, after a variable, parameter or the parameters of a function.
+    TypeAnnotation,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TriviaKind {
+    Whitespace,
+    /// A `//` comment, without the line break after it.
+    Comment,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trivia {
+    pub kind: TriviaKind,
+    pub text: String,
+}
+
+/// A token with the trivia around it. Trailing trivia is what follows on the same line, up to the line break, the
+/// rest is leading trivia of the next token.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntaxToken {
+    pub kind: SyntaxKind,
+    pub text: String,
+    pub leading: Vec<Trivia>,
+    pub trailing: Vec<Trivia>,
+    /// Where the text is, without the trivia.
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SyntaxElement {
+    Node(SyntaxNode),
+    Token(SyntaxToken),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntaxNode {
+    pub kind: SyntaxKind,
+    pub children: Vec<SyntaxElement>,
+}
+
+impl SyntaxNode {
+    pub fn child_nodes(&self) -> impl Iterator<Item=&SyntaxNode> + '_ {
+        self.children.iter().filter_map(|child| match child {
+            SyntaxElement::Node(node) => Some(node),
+            SyntaxElement::Token(_) => None,
+        })
+    }
+
+    pub fn child_tokens(&self) -> impl Iterator<Item=&SyntaxToken> + '_ {
+        self.children.iter().filter_map(|child| match child {
+            SyntaxElement::Token(token) => Some(token),
+            SyntaxElement::Node(_) => None,
+        })
+    }
+
+    pub fn child_token(&self, kind: SyntaxKind) -> Option<&SyntaxToken> {
+        self.child_tokens().find(|token| token.kind == kind)
+    }
+
+    /// Every token in the node, in source order.
+    pub fn tokens(&self) -> Vec<&SyntaxToken> {
+        let mut tokens = vec![];
+        self.collect_tokens(&mut tokens);
+        tokens
+    }
+
+    fn collect_tokens<'a>(&'a self, tokens: &mut Vec<&'a SyntaxToken>) {
+        for child in &self.children {
+            match child {
+                SyntaxElement::Node(node) => node.collect_tokens(tokens),
+                SyntaxElement::Token(token) => tokens.push(token),
+            }
+        }
+    }
+
+    /// The node and every node in it, parents before their children.
+    pub fn descendants(&self) -> Vec<&SyntaxNode> {
+        let mut nodes = vec![self];
+        let mut index = 0;
+        while index < nodes.len() {
+            let children: Vec<_> = nodes[index].child_nodes().collect();
+            nodes.splice(index + 1..index + 1, children);
+            index += 1;
+        }
+        nodes
+    }
+
+    /// From the first to the last character of its tokens, without the trivia. None when it has no text.
+    pub fn span(&self) -> Option<Span> {
+        let tokens = self.tokens();
+        let mut text = tokens.iter().filter(|token| !token.text.is_empty());
+        let first = text.next()?;
+        let last = text.next_back().unwrap_or(first);
+        Some(Span { start: first.span.start, end: last.span.end })
+    }
+
+    /// The token with a character at `position`.
+    pub fn token_at(&self, position: Position) -> Option<&SyntaxToken> {
+        self.tokens().into_iter()
+            .find(|token| !token.text.is_empty() && token.span.start <= position && position <= token.span.end)
+    }
+}
+
+impl fmt::Display for Trivia {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+impl fmt::Display for SyntaxToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for trivia in &self.leading {
+            write!(f, "{}", trivia)?;
+        }
+        f.write_str(&self.text)?;
+        for trivia in &self.trailing {
+            write!(f, "{}", trivia)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for SyntaxNode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for child in &self.children {
+            match child {
+                SyntaxElement::Node(node) => write!(f, "{}", node)?,
+                SyntaxElement::Token(token) => write!(f, "{}", token)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The tree of a whole file and what didn't parse in it.
+#[derive(Debug)]
+pub struct Parse {
+    pub root: SyntaxNode,
+    pub errors: Vec<ParseError>,
+}
+
+impl Parse {
+    pub fn program(&self) -> typed::Program<'_> {
+        typed::Program::cast(&self.root).expect("the root is a program")
+    }
+}
+
+pub fn parse(code: &str) -> Parse {
+    parser::parse(lexer::lex(code))
+}
@@ -0,0 +1,490 @@
+//! The grammar of `stmt_parser` and `expr_parser`, but it never stops at an error. A missing token is reported and
+//! left out, tokens that can't start what is expected go into an `Error` node.
+
+use super::{Parse, SyntaxElement, SyntaxKind, SyntaxNode, SyntaxToken};
+use crate::common::ParseError;
+
+#[derive(PartialEq, PartialOrd, Copy, Clone)]
+enum Precedence {
+    None,
+    Assign,
+    Or,
+    And,
+    Equality,
+    Comparison,
+    Term,
+    Factor,
+    Unary,
+    Call,
+}
+
+/// The precedence of a token that continues an expression, None for one that doesn't.
+fn infix_precedence(kind: SyntaxKind) -> Precedence {
+    match kind {
+        SyntaxKind::Equal => Precedence::Assign,
+        SyntaxKind::Or => Precedence::Or,
+        SyntaxKind::And => Precedence::And,
+        SyntaxKind::BangEqual | SyntaxKind::EqualEqual => Precedence::Equality,
+        SyntaxKind::Less | SyntaxKind::LessEqual | SyntaxKind::Greater | SyntaxKind::GreaterEqual => Precedence::Comparison,
+        SyntaxKind::Plus | SyntaxKind::Minus => Precedence::Term,
+        SyntaxKind::Star | SyntaxKind::Slash => Precedence::Factor,
+        SyntaxKind::LeftParen | SyntaxKind::Dot => Precedence::Call,
+        _ => Precedence::None,
+    }
+}
+
+/// Tokens that an expression never swallows when it is missing, the statement around it can go on from them.
+fn is_recovery_point(kind: SyntaxKind) -> bool {
+    matches!(kind,
+        SyntaxKind::Semicolon | SyntaxKind::LeftBrace | SyntaxKind::RightBrace | SyntaxKind::Var | SyntaxKind::Fun |
+        SyntaxKind::Class | SyntaxKind::For | SyntaxKind::If | SyntaxKind::While | SyntaxKind::Print |
+        SyntaxKind::Return | SyntaxKind::Import | SyntaxKind::Export | SyntaxKind::Eof)
+}
+
+fn starts_declaration(kind: SyntaxKind) -> bool {
+    starts_expression(kind) || matches!(kind,
+        SyntaxKind::Var | SyntaxKind::Fun | SyntaxKind::Class | SyntaxKind::For | SyntaxKind::If | SyntaxKind::While |
+        SyntaxKind::Print | SyntaxKind::Return | SyntaxKind::LeftBrace)
+}
+
+fn starts_expression(kind: SyntaxKind) -> bool {
+    matches!(kind,
+        SyntaxKind::Number | SyntaxKind::String | SyntaxKind::Nil | SyntaxKind::True | SyntaxKind::False |
+        SyntaxKind::Identifier | SyntaxKind::This | SyntaxKind::Super | SyntaxKind::Bang | SyntaxKind::Minus |
+        SyntaxKind::LeftParen)
+}
+
+struct Parser {
+    /// The tokens that are left, the next one last.
+    tokens: Vec<SyntaxToken>,
+    /// The nodes that are being built, the innermost one last.
+    nodes: Vec<SyntaxNode>,
+    errors: Vec<ParseError>,
+}
+
+pub(super) fn parse(mut tokens: Vec<SyntaxToken>) -> Parse {
+    tokens.reverse();
+    let mut parser = Parser { tokens, nodes: vec![], errors: vec![] };
+
+    parser.start(SyntaxKind::Program);
+    while !parser.at(SyntaxKind::Eof) {
+        parser.top_level_declaration();
+    }
+    parser.bump();
+    let root = parser.nodes.pop().expect("the program is built");
+
+    Parse { root, errors: parser.errors }
+}
+
+impl Parser {
+    fn current(&self) -> SyntaxKind {
+        self.tokens.last().map_or(SyntaxKind::Eof, |token| token.kind)
+    }
+
+    fn at(&self, kind: SyntaxKind) -> bool {
+        self.current() == kind
+    }
+
+    /// Move the next token into the node that is being built.
+    fn bump(&mut self) {
+        if let Some(token) = self.tokens.pop() {
+            self.nodes.last_mut().expect("a node is being built").children.push(SyntaxElement::Token(token));
+        }
+    }
+
+    fn start(&mut self, kind: SyntaxKind) {
+        self.nodes.push(SyntaxNode { kind, children: vec![] });
+    }
+
+    fn finish(&mut self) {
+        let node = self.nodes.pop().expect("a node is being built");
+        self.nodes.last_mut().expect("the program is never finished early").children.push(SyntaxElement::Node(node));
+    }
+
+    /// Where a node can later be started with `start_at`, to wrap what is built after this.
+    fn checkpoint(&self) -> usize {
+        self.nodes.last().expect("a node is being built").children.len()
+    }
+
+    fn start_at(&mut self, checkpoint: usize, kind: SyntaxKind) {
+        let children = self.nodes.last_mut().expect("a node is being built").children.split_off(checkpoint);
+        self.nodes.push(SyntaxNode { kind, children });
+    }
+
+    fn error(&mut self, error: String) {
+        let span = self.tokens.last().map(|token| token.span);
+        self.errors.push(ParseError { error, span });
+    }
+
+    fn expect(&mut self, kind: SyntaxKind) -> bool {
+        if self.at(kind) {
+            self.bump();
+            true
+        } else {
+            self.error(format!("Expected {:?} got {:?}", kind, self.current()));
+            false
+        }
+    }
+
+    /// Report the next token and put it in an `Error` node.
+    fn skip(&mut self, error: String) {
+        self.error(error);
+        self.start(SyntaxKind::Error);
+        self.bump();
+        self.finish();
+    }
+
+    /// Imports and exports are only allowed at the top level.
+    fn top_level_declaration(&mut self) {
+        match self.current() {
+            SyntaxKind::Import => self.import_declaration(),
+            SyntaxKind::Export => {
+                self.start(SyntaxKind::ExportDecl);
+                self.bump();
+                match self.current() {
+                    SyntaxKind::Var | SyntaxKind::Fun | SyntaxKind::Class => self.declaration(),
+                    kind => self.error(format!("Expected a declaration after Export got {:?}", kind)),
+                }
+                self.finish();
+            },
+            _ => self.declaration_or_skip(),
+        }
+    }
+
+    fn import_declaration(&mut self) {
+        self.start(SyntaxKind::ImportDecl);
+        self.bump();
+        if self.at(SyntaxKind::LeftBrace) {
+            self.bump();
+            self.expect(SyntaxKind::Identifier);
+            while self.at(SyntaxKind::Comma) {
+                self.bump();
+                self.expect(SyntaxKind::Identifier);
+            }
+            self.expect(SyntaxKind::RightBrace);
+            // `from` is only a keyword here
+            if self.tokens.last().is_some_and(|token| token.kind == SyntaxKind::Identifier && token.text == "from") {
+                self.bump();
+            } else {
+                self.error(format!("Expected from got {:?}", self.current()));
+            }
+        }
+        self.expect(SyntaxKind::String);
+        self.expect(SyntaxKind::Semicolon);
+        self.finish();
+    }
+
+    /// A declaration, or the next token as an error when nothing can start there.
+    fn declaration_or_skip(&mut self) {
+        let kind = self.current();
+        if starts_declaration(kind) {
+            self.declaration();
+        } else {
+            self.skip(format!("Unexpected {:?}", kind));
+        }
+    }
+
+    fn declaration(&mut self) {
+        match self.current() {
+            SyntaxKind::Var => self.var_declaration(),
+            SyntaxKind::Fun => {
+                self.start(SyntaxKind::FunDecl);
+                self.bump();
+                self.function();
+                self.finish();
+            },
+            SyntaxKind::Class => self.class_declaration(),
+            _ => self.statement(),
+        }
+    }
+
+    fn var_declaration(&mut self) {
+        self.start(SyntaxKind::VarDecl);
+        self.bump();
+        self.expect(SyntaxKind::Identifier);
+        self.type_annotation();
+        if self.at(SyntaxKind::Equal) {
+            self.bump();
+            self.expression(Precedence::None);
+        }
+        self.expect(SyntaxKind::Semicolon);
+        self.finish();
+    }
+
+    /// The name, parameters and body of a function, in the node of the declaration or method.
+    fn function(&mut self) {
+        self.expect(SyntaxKind::Identifier);
+
+        self.start(SyntaxKind::ParamList);
+        if self.expect(SyntaxKind::LeftParen) {
+            if !self.at(SyntaxKind::RightParen) {
+                self.expect(SyntaxKind::Identifier);
+                self.type_annotation();
+                while self.at(SyntaxKind::Comma) {
+                    self.bump();
+                    self.expect(SyntaxKind::Identifier);
+                    self.type_annotation();
+                }
+            }
+            self.expect(SyntaxKind::RightParen);
+        }
+        self.finish();
+        self.type_annotation();
+
+        if self.at(SyntaxKind::LeftBrace) {
+            self.block();
+        } else {
+            self.error(format!("Expected {:?} got {:?}", SyntaxKind::LeftBrace, self.current()));
+        }
+    }
+
+    fn type_annotation(&mut self) {
+        if self.at(SyntaxKind::Colon) {
+            self.start(SyntaxKind::TypeAnnotation);
+            self.bump();
+            self.expect(SyntaxKind::Identifier);
+            self.finish();
+        }
+    }
+
+    fn class_declaration(&mut self) {
+        self.start(SyntaxKind::ClassDecl);
+        self.bump();
+        self.expect(SyntaxKind::Identifier);
+        if self.at(SyntaxKind::Less) {
+            self.bump();
+            self.expect(SyntaxKind::Identifier);
+        }
+        if self.expect(SyntaxKind::LeftBrace) {
+            while !self.at(SyntaxKind::RightBrace) && !self.at(SyntaxKind::Eof) {
+                if self.at(SyntaxKind::Identifier) {
+                    self.start(SyntaxKind::Method);
+                    self.function();
+                    self.finish();
+                } else {
+                    let error = format!("Unexpected {:?}", self.current());
+                    self.skip(error);
+                }
+            }
+            self.expect(SyntaxKind::RightBrace);
+        }
+        self.finish();
+    }
+
+    fn statement(&mut self) {
+        match self.current() {
+            SyntaxKind::Print => self.keyword_statement(SyntaxKind::PrintStmt),
+            SyntaxKind::Return => {
+                self.start(SyntaxKind::ReturnStmt);
+                self.bump();
+                if !self.at(SyntaxKind::Semicolon) {
+                    self.expression(Precedence::None);
+                }
+                self.expect(SyntaxKind::Semicolon);
+                self.finish();
+            },
+            SyntaxKind::LeftBrace => self.block(),
+            SyntaxKind::If => {
+                self.start(SyntaxKind::IfStmt);
+                self.bump();
+                self.condition();
+                self.body();
+                if self.at(SyntaxKind::Else) {
+                    self.bump();
+                    self.body();
+                }
+                self.finish();
+            },
+            SyntaxKind::While => {
+                self.start(SyntaxKind::WhileStmt);
+                self.bump();
+                self.condition();
+                self.body();
+                self.finish();
+            },
+            SyntaxKind::For => self.for_statement(),
+            _ => {
+                self.start(SyntaxKind::ExprStmt);
+                self.expression(Precedence::None);
+                self.expect(SyntaxKind::Semicolon);
+                self.finish();
+            },
+        }
+    }
+
+    fn keyword_statement(&mut self, kind: SyntaxKind) {
+        self.start(kind);
+        self.bump();
+        self.expression(Precedence::None);
+        self.expect(SyntaxKind::Semicolon);
+        self.finish();
+    }
+
+    /// The statement of an `if`, `else`, `while` or `for`, which can't be a declaration.
+    fn body(&mut self) {
+        if matches!(self.current(), SyntaxKind::Var | SyntaxKind::Fun | SyntaxKind::Class | SyntaxKind::Eof) {
+            self.error(format!("Expected a statement got {:?}", self.current()));
+        } else {
+            self.statement();
+        }
+    }
+
+    fn condition(&mut self) {
+        self.expect(SyntaxKind::LeftParen);
+        self.expression(Precedence::None);
+        self.expect(SyntaxKind::RightParen);
+    }
+
+    fn block(&mut self) {
+        self.start(SyntaxKind::Block);
+        self.bump();
+        while !self.at(SyntaxKind::RightBrace) && !self.at(SyntaxKind::Eof) {
+            self.declaration_or_skip();
+        }
+        self.expect(SyntaxKind::RightBrace);
+        self.finish();
+    }
+
+    fn for_statement(&mut self) {
+        self.start(SyntaxKind::ForStmt);
+        self.bump();
+        self.expect(SyntaxKind::LeftParen);
+        match self.current() {
+            SyntaxKind::Var => self.var_declaration(),
+            SyntaxKind::Semicolon => self.bump(),
+            _ => {
+                self.start(SyntaxKind::ExprStmt);
+                self.expression(Precedence::None);
+                self.expect(SyntaxKind::Semicolon);
+                self.finish();
+            },
+        }
+        if !self.at(SyntaxKind::Semicolon) {
+            self.expression(Precedence::None);
+        }
+        self.expect(SyntaxKind::Semicolon);
+        if !self.at(SyntaxKind::RightParen) {
+            self.expression(Precedence::None);
+        }
+        self.expect(SyntaxKind::RightParen);
+        self.body();
+        self.finish();
+    }
+
+    fn expression(&mut self, precedence: Precedence) {
+        let checkpoint = self.checkpoint();
+        let mut left = match self.prefix() {
+            Some(kind) => kind,
+            None => return,
+        };
+
+        loop {
+            let next = infix_precedence(self.current());
+            if precedence >= next {
+                break;
+            }
+
+            left = match self.current() {
+                SyntaxKind::Equal => {
+                    let kind = match left {
+                        SyntaxKind::VariableExpr => SyntaxKind::AssignExpr,
+                        SyntaxKind::GetExpr => SyntaxKind::SetExpr,
+                        _ => {
+                            self.error(format!("Invalid assignment target {:?}", left));
+                            SyntaxKind::AssignExpr
+                        },
+                    };
+                    self.start_at(checkpoint, kind);
+                    self.bump();
+                    self.expression(Precedence::None);
+                    kind
+                },
+                SyntaxKind::LeftParen => {
+                    self.start_at(checkpoint, SyntaxKind::CallExpr);
+                    self.start(SyntaxKind::ArgList);
+                    self.bump();
+                    if !self.at(SyntaxKind::RightParen) {
+                        self.expression(Precedence::None);
+                        while self.at(SyntaxKind::Comma) {
+                            self.bump();
+                            self.expression(Precedence::None);
+                        }
+                    }
+                    self.expect(SyntaxKind::RightParen);
+                    self.finish();
+                    SyntaxKind::CallExpr
+                },
+                SyntaxKind::Dot => {
+                    self.start_at(checkpoint, SyntaxKind::GetExpr);
+                    self.bump();
+                    self.expect(SyntaxKind::Identifier);
+                    SyntaxKind::GetExpr
+                },
+                SyntaxKind::And | SyntaxKind::Or => {
+                    self.start_at(checkpoint, SyntaxKind::LogicalExpr);
+                    self.bump();
+                    self.expression(next);
+                    SyntaxKind::LogicalExpr
+                },
+                _ => {
+                    self.start_at(checkpoint, SyntaxKind::BinaryExpr);
+                    self.bump();
+                    self.expression(next);
+                    SyntaxKind::BinaryExpr
+                },
+            };
+            self.finish();
+        }
+    }
+
+    /// The expression an expression starts with, returns the kind of its node. None when it's missing.
+    fn prefix(&mut self) -> Option<SyntaxKind> {
+        let kind = match self.current() {
+            SyntaxKind::Number | SyntaxKind::String | SyntaxKind::Nil | SyntaxKind::True | SyntaxKind::False => {
+                self.start(SyntaxKind::Literal);
+                self.bump();
+                SyntaxKind::Literal
+            },
+            SyntaxKind::Identifier => {
+                self.start(SyntaxKind::VariableExpr);
+                self.bump();
+                SyntaxKind::VariableExpr
+            },
+            SyntaxKind::This => {
+                self.start(SyntaxKind::ThisExpr);
+                self.bump();
+                SyntaxKind::ThisExpr
+            },
+            SyntaxKind::Super => {
+                self.start(SyntaxKind::SuperExpr);
+                self.bump();
+                self.expect(SyntaxKind::Dot);
+                self.expect(SyntaxKind::Identifier);
+                SyntaxKind::SuperExpr
+            },
+            SyntaxKind::Bang | SyntaxKind::Minus => {
+                self.start(SyntaxKind::UnaryExpr);
+                self.bump();
+                self.expression(Precedence::Unary);
+                SyntaxKind::UnaryExpr
+            },
+            SyntaxKind::LeftParen => {
+                self.start(SyntaxKind::GroupingExpr);
+                self.bump();
+                self.expression(Precedence::None);
+                self.expect(SyntaxKind::RightParen);
+                SyntaxKind::GroupingExpr
+            },
+            kind if is_recovery_point(kind) => {
+                self.error(format!("Expected an expression got {:?}", kind));
+                return None;
+            },
+            kind => {
+                self.skip(format!("Unexpected {:?}", kind));
+                return None;
+            },
+        };
+        self.finish();
+        Some(kind)
+    }
+}
@@ -0,0 +1,218 @@
+use super::typed::{Expr, Stmt};
+use super::*;
+
+fn kinds(node: &SyntaxNode) -> Vec<SyntaxKind> {
+    node.child_nodes().map(|node| node.kind).collect()
+}
+
+#[test]
+fn test_prints_source_back() {
+    let sources = [
+        "",
+        "   \n\n",
+        "// only a comment",
+        "var a = 1;   // trailing\r\n\t// leading\nprint a;\n\n",
+        "class A < B {\n  init(x, y) { this.x = x; super.init(y); }\n}\n",
+        "for (var i = 0; i < 3; i = i + 1) { if (i == 1) print \"one\"; else print i * -2; }",
+        "fun f(a,b){return a and b or !a;}print f(1,2)(3).c.d = 4;",
+        "print ;\nvar = 3;\nfun (a { }\nclass { 1 }\n} ) @ # print 2;",
+        "var s = \"unterminated\n// not a comment\n",
+        "print 1 +",
+        "for (;;",
+        "var ² = 1;",
+    ];
+
+    for source in sources {
+        let parse = parse(source);
+        assert_eq!(parse.root.to_string(), source);
+        assert_eq!(parse.root.kind, SyntaxKind::Program);
+        assert_eq!(parse.root.tokens().last().map(|token| token.kind), Some(SyntaxKind::Eof));
+    }
+}
+
+#[test]
+fn test_trivia() {
+    let parse = parse("// leading\nvar a; // trailing\n  print a;\n// end\n");
+    let tokens = parse.root.tokens();
+
+    let var = tokens[0];
+    assert_eq!(var.kind, SyntaxKind::Var);
+    assert_eq!(var.leading, vec![
+        Trivia { kind: TriviaKind::Comment, text: "// leading".to_string() },
+        Trivia { kind: TriviaKind::Whitespace, text: "\n".to_string() },
+    ]);
+    assert_eq!(var.trailing, vec![Trivia { kind: TriviaKind::Whitespace, text: " ".to_string() }]);
+
+    let semicolon = tokens[2];
+    assert_eq!(semicolon.trailing, vec![
+        Trivia { kind: TriviaKind::Whitespace, text: " ".to_string() },
+        Trivia { kind: TriviaKind::Comment, text: "// trailing".to_string() },
+    ]);
+    let print = tokens[3];
+    assert_eq!(print.leading, vec![Trivia { kind: TriviaKind::Whitespace, text: "\n  ".to_string() }]);
+    assert_eq!(print.span.start, Position { line: 3, column: 3 });
+
+    let eof = tokens.last().unwrap();
+    assert_eq!(eof.kind, SyntaxKind::Eof);
+    assert_eq!(eof.leading.iter().map(|trivia| trivia.text.as_str()).collect::<String>(), "\n// end\n");
+}
+
+#[test]
+fn test_tree() {
+    let parse = parse("var a = 1 + 2 * 3;\na.b = c(d, e);");
+    assert!(parse.errors.is_empty());
+    assert_eq!(kinds(&parse.root), vec![SyntaxKind::VarDecl, SyntaxKind::ExprStmt]);
+
+    let var = parse.root.child_nodes().next().unwrap();
+    let sum = var.child_nodes().next().unwrap();
+    assert_eq!(sum.kind, SyntaxKind::BinaryExpr);
+    assert_eq!(kinds(sum), vec![SyntaxKind::Literal, SyntaxKind::BinaryExpr]);
+    // The space after the `=` is trivia of the `=`
+    assert_eq!(sum.to_string(), "1 + 2 * 3");
+    assert_eq!(sum.span(), Some(Span { start: Position { line: 1, column: 9 }, end: Position { line: 1, column: 17 } }));
+
+    let set = parse.root.child_nodes().nth(1).unwrap().child_nodes().next().unwrap();
+    assert_eq!(set.kind, SyntaxKind::SetExpr);
+    assert_eq!(kinds(set), vec![SyntaxKind::GetExpr, SyntaxKind::CallExpr]);
+    assert_eq!(parse.root.token_at(Position { line: 2, column: 7 }).map(|token| token.text.as_str()), Some("c"));
+    let variables = parse.root.descendants().into_iter().filter(|node| node.kind == SyntaxKind::VariableExpr).count();
+    assert_eq!(variables, 4);
+}
+
+#[test]
+fn test_error_nodes() {
+    let parse = parse("print ) 1;\nvar = 2;\n@\nprint 3;");
+    assert_eq!(kinds(&parse.root), vec![
+        SyntaxKind::PrintStmt, SyntaxKind::ExprStmt, SyntaxKind::VarDecl, SyntaxKind::Error, SyntaxKind::PrintStmt,
+    ]);
+
+    let errors: Vec<_> = parse.errors.iter().map(|error| (error.error.as_str(), error.span.unwrap().start.line)).collect();
+    assert_eq!(errors, vec![
+        ("Unexpected RightParen", 1),
+        ("Expected Semicolon got Number", 1),
+        ("Expected Identifier got Equal", 2),
+        ("Unexpected Error", 3),
+    ]);
+
+    // What didn't parse is kept in the statement it was in, the rest of the statement starts another one
+    let print = parse.root.child_nodes().next().unwrap();
+    assert_eq!(kinds(print), vec![SyntaxKind::Error]);
+    assert_eq!(parse.program().statements().count(), 4);
+}
+
+#[test]
+fn test_typed_view() {
+    let parse = parse("\
+class Point < Base {
+  sum(a, b) { return this.x + a; }
+}
+for (var i = 0; i < 3; i = i + 1) print i;
+for (;;) i;
+if (ok) x = 1; else point.y = 2;
+");
+    assert!(parse.errors.is_empty());
+    let statements: Vec<_> = parse.program().statements().collect();
+
+    let class = match statements[0] {
+        Stmt::Class(class) => class,
+        stmt => panic!("expected a class, got {:?}", stmt),
+    };
+    assert_eq!(class.name().unwrap().text, "Point");
+    assert_eq!(class.superclass().unwrap().text, "Base");
+    let method = class.methods().next().unwrap();
+    assert_eq!(method.name().unwrap().text, "sum");
+    let params: Vec<_> = method.params().unwrap().params().map(|param| param.text.as_str()).collect();
+    assert_eq!(params, vec!["a", "b"]);
+    match method.body().unwrap().statements().next() {
+        Some(Stmt::Return(ret)) => match ret.value() {
+            Some(Expr::Binary(binary)) => {
+                assert_eq!(binary.operator().unwrap().kind, SyntaxKind::Plus);
+                assert!(matches!(binary.lhs(), Some(Expr::Get(get)) if get.name().unwrap().text == "x"));
+            },
+            expr => panic!("expected a binary expression, got {:?}", expr),
+        },
+        stmt => panic!("expected a return, got {:?}", stmt),
+    }
+
+    let full = match statements[1] {
+        Stmt::For(full) => full,
+        stmt => panic!("expected a for, got {:?}", stmt),
+    };
+    assert!(matches!(full.initializer(), Some(Stmt::Var(var)) if var.name().unwrap().text == "i"));
+    assert_eq!(full.condition().unwrap().syntax().to_string(), "i < 3");
+    assert!(matches!(full.increment(), Some(Expr::Assign(assign)) if assign.name().unwrap().text == "i"));
+    assert!(matches!(full.body(), Some(Stmt::Print(_))));
+
+    let empty = match statements[2] {
+        Stmt::For(empty) => empty,
+        stmt => panic!("expected a for, got {:?}", stmt),
+    };
+    assert!(empty.initializer().is_none() && empty.condition().is_none() && empty.increment().is_none());
+    assert!(matches!(empty.body(), Some(Stmt::Expression(_))));
+
+    let branch = match statements[3] {
+        Stmt::If(branch) => branch,
+        stmt => panic!("expected an if, got {:?}", stmt),
+    };
+    assert!(matches!(branch.condition(), Some(Expr::Variable(variable)) if variable.name().unwrap().text == "ok"));
+    match branch.else_branch() {
+        Some(Stmt::Expression(stmt)) => match stmt.expr() {
+            Some(Expr::Set(set)) => {
+                assert_eq!(set.name().unwrap().text, "y");
+                assert!(matches!(set.object(), Some(Expr::Variable(_))));
+                assert!(matches!(set.value(), Some(Expr::Literal(_))));
+            },
+            expr => panic!("expected a set, got {:?}", expr),
+        },
+        stmt => panic!("expected an expression statement, got {:?}", stmt),
+    }
+}
+
+#[test]
+fn test_imports_and_exports() {
+    let source = "import { a, b } from \"lib.lox\";\nimport \"other.lox\";\nexport fun f() {}\n{ import \"nested.lox\"; }";
+    let parse = parse(source);
+    assert_eq!(parse.root.to_string(), source);
+    let errors: Vec<_> = parse.errors.iter().map(|error| error.error.as_str()).collect();
+    assert_eq!(errors, vec!["Unexpected Import"]);
+
+    let statements: Vec<_> = parse.program().statements().collect();
+    let imports: Vec<_> = statements.iter()
+        .filter_map(|stmt| match stmt {
+            Stmt::Import(import) => Some((import.path().unwrap().text.as_str(), import.names().map(|name| name.text.as_str()).collect::<Vec<_>>())),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(imports, vec![("\"lib.lox\"", vec!["a", "b"]), ("\"other.lox\"", vec![])]);
+    match statements[2] {
+        Stmt::Export(export) => assert!(matches!(export.declaration(), Some(Stmt::Fun(_)))),
+        stmt => panic!("expected an export, got {:?}", stmt),
+    }
+}
+
+#[test]
+fn test_type_annotations() {
+    let source = "var name: String = \"a\";\nfun add(a: Number, b) : Number { return a + b; }\nvar broken: = 1;";
+    let parse = parse(source);
+    assert_eq!(parse.root.to_string(), source);
+    let errors: Vec<_> = parse.errors.iter().map(|error| error.error.as_str()).collect();
+    assert_eq!(errors, vec!["Expected Identifier got Equal"]);
+
+    let statements: Vec<_> = parse.program().statements().collect();
+    let var = match statements[0] {
+        Stmt::Var(var) => var,
+        stmt => panic!("expected a var, got {:?}", stmt),
+    };
+    assert_eq!(var.type_annotation().and_then(|annotation| annotation.name()).unwrap().text, "String");
+    assert!(matches!(var.initializer(), Some(Expr::Literal(_))));
+
+    let function = match statements[1] {
+        Stmt::Fun(function) => function,
+        stmt => panic!("expected a function, got {:?}", stmt),
+    };
+    let params: Vec<_> = function.params().unwrap().annotated_params().into_iter()
+        .map(|(param, annotation)| (param.text.as_str(), annotation.and_then(|annotation| annotation.name()).map(|name| name.text.as_str())))
+        .collect();
+    assert_eq!(params, vec![("a", Some("Number")), ("b", None)]);
+    assert_eq!(function.return_type().and_then(|annotation| annotation.name()).unwrap().text, "Number");
+}
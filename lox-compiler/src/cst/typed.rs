@@ -0,0 +1,488 @@
+//! Typed views on the nodes of a syntax tree. They borrow the node, and every part can be missing because the tree
+//! also holds code that doesn't parse.
+
+use super::{SyntaxElement, SyntaxKind, SyntaxNode, SyntaxToken};
+
+macro_rules! node {
+    ($($name:ident),* $(,)?) => {
+        $(
+            #[derive(Debug, Copy, Clone)]
+            pub struct $name<'a>(&'a SyntaxNode);
+
+            impl<'a> $name<'a> {
+                pub fn cast(node: &'a SyntaxNode) -> Option<Self> {
+                    if node.kind == SyntaxKind::$name { Some($name(node)) } else { None }
+                }
+
+                pub fn syntax(&self) -> &'a SyntaxNode {
+                    self.0
+                }
+            }
+        )*
+    };
+}
+
+node!(
+    Program, ImportDecl, ExportDecl, VarDecl, FunDecl, ClassDecl, Method, ParamList, Block, ExprStmt, PrintStmt, IfStmt, WhileStmt, ForStmt,
+    ReturnStmt, BinaryExpr, LogicalExpr, UnaryExpr, GroupingExpr, Literal, VariableExpr, AssignExpr, CallExpr, ArgList,
+    GetExpr, SetExpr, ThisExpr, SuperExpr, TypeAnnotation,
+);
+
+#[derive(Debug, Copy, Clone)]
+pub enum Stmt<'a> {
+    Import(ImportDecl<'a>),
+    Export(ExportDecl<'a>),
+    Var(VarDecl<'a>),
+    Fun(FunDecl<'a>),
+    Class(ClassDecl<'a>),
+    Expression(ExprStmt<'a>),
+    Print(PrintStmt<'a>),
+    If(IfStmt<'a>),
+    While(WhileStmt<'a>),
+    For(ForStmt<'a>),
+    Return(ReturnStmt<'a>),
+    Block(Block<'a>),
+}
+
+impl<'a> Stmt<'a> {
+    pub fn cast(node: &'a SyntaxNode) -> Option<Self> {
+        match node.kind {
+            SyntaxKind::ImportDecl => Some(Stmt::Import(ImportDecl(node))),
+            SyntaxKind::ExportDecl => Some(Stmt::Export(ExportDecl(node))),
+            SyntaxKind::VarDecl => Some(Stmt::Var(VarDecl(node))),
+            SyntaxKind::FunDecl => Some(Stmt::Fun(FunDecl(node))),
+            SyntaxKind::ClassDecl => Some(Stmt::Class(ClassDecl(node))),
+            SyntaxKind::ExprStmt => Some(Stmt::Expression(ExprStmt(node))),
+            SyntaxKind::PrintStmt => Some(Stmt::Print(PrintStmt(node))),
+            SyntaxKind::IfStmt => Some(Stmt::If(IfStmt(node))),
+            SyntaxKind::WhileStmt => Some(Stmt::While(WhileStmt(node))),
+            SyntaxKind::ForStmt => Some(Stmt::For(ForStmt(node))),
+            SyntaxKind::ReturnStmt => Some(Stmt::Return(ReturnStmt(node))),
+            SyntaxKind::Block => Some(Stmt::Block(Block(node))),
+            _ => None,
+        }
+    }
+
+    pub fn syntax(&self) -> &'a SyntaxNode {
+        match self {
+            Stmt::Import(stmt) => stmt.0,
+            Stmt::Export(stmt) => stmt.0,
+            Stmt::Var(stmt) => stmt.0,
+            Stmt::Fun(stmt) => stmt.0,
+            Stmt::Class(stmt) => stmt.0,
+            Stmt::Expression(stmt) => stmt.0,
+            Stmt::Print(stmt) => stmt.0,
+            Stmt::If(stmt) => stmt.0,
+            Stmt::While(stmt) => stmt.0,
+            Stmt::For(stmt) => stmt.0,
+            Stmt::Return(stmt) => stmt.0,
+            Stmt::Block(stmt) => stmt.0,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum Expr<'a> {
+    Binary(BinaryExpr<'a>),
+    Logical(LogicalExpr<'a>),
+    Unary(UnaryExpr<'a>),
+    Grouping(GroupingExpr<'a>),
+    Literal(Literal<'a>),
+    Variable(VariableExpr<'a>),
+    Assign(AssignExpr<'a>),
+    Call(CallExpr<'a>),
+    Get(GetExpr<'a>),
+    Set(SetExpr<'a>),
+    This(ThisExpr<'a>),
+    Super(SuperExpr<'a>),
+}
+
+impl<'a> Expr<'a> {
+    pub fn cast(node: &'a SyntaxNode) -> Option<Self> {
+        match node.kind {
+            SyntaxKind::BinaryExpr => Some(Expr::Binary(BinaryExpr(node))),
+            SyntaxKind::LogicalExpr => Some(Expr::Logical(LogicalExpr(node))),
+            SyntaxKind::UnaryExpr => Some(Expr::Unary(UnaryExpr(node))),
+            SyntaxKind::GroupingExpr => Some(Expr::Grouping(GroupingExpr(node))),
+            SyntaxKind::Literal => Some(Expr::Literal(Literal(node))),
+            SyntaxKind::VariableExpr => Some(Expr::Variable(VariableExpr(node))),
+            SyntaxKind::AssignExpr => Some(Expr::Assign(AssignExpr(node))),
+            SyntaxKind::CallExpr => Some(Expr::Call(CallExpr(node))),
+            SyntaxKind::GetExpr => Some(Expr::Get(GetExpr(node))),
+            SyntaxKind::SetExpr => Some(Expr::Set(SetExpr(node))),
+            SyntaxKind::ThisExpr => Some(Expr::This(ThisExpr(node))),
+            SyntaxKind::SuperExpr => Some(Expr::Super(SuperExpr(node))),
+            _ => None,
+        }
+    }
+
+    pub fn syntax(&self) -> &'a SyntaxNode {
+        match self {
+            Expr::Binary(expr) => expr.0,
+            Expr::Logical(expr) => expr.0,
+            Expr::Unary(expr) => expr.0,
+            Expr::Grouping(expr) => expr.0,
+            Expr::Literal(expr) => expr.0,
+            Expr::Variable(expr) => expr.0,
+            Expr::Assign(expr) => expr.0,
+            Expr::Call(expr) => expr.0,
+            Expr::Get(expr) => expr.0,
+            Expr::Set(expr) => expr.0,
+            Expr::This(expr) => expr.0,
+            Expr::Super(expr) => expr.0,
+        }
+    }
+}
+
+fn statements(node: &SyntaxNode) -> impl Iterator<Item=Stmt<'_>> {
+    node.child_nodes().filter_map(Stmt::cast)
+}
+
+fn expressions(node: &SyntaxNode) -> impl Iterator<Item=Expr<'_>> {
+    node.child_nodes().filter_map(Expr::cast)
+}
+
+/// The name a declaration, get or call of a method has.
+fn name(node: &SyntaxNode) -> Option<&SyntaxToken> {
+    node.child_token(SyntaxKind::Identifier)
+}
+
+impl<'a> Program<'a> {
+    pub fn statements(&self) -> impl Iterator<Item=Stmt<'a>> {
+        statements(self.0)
+    }
+}
+
+impl<'a> ImportDecl<'a> {
+    /// The names in `{ a, b }`, there are none when it imports everything the module exports.
+    pub fn names(&self) -> impl Iterator<Item=&'a SyntaxToken> {
+        self.0.child_tokens()
+            .skip_while(|token| token.kind != SyntaxKind::LeftBrace)
+            .take_while(|token| token.kind != SyntaxKind::RightBrace)
+            .filter(|token| token.kind == SyntaxKind::Identifier)
+    }
+
+    pub fn path(&self) -> Option<&'a SyntaxToken> {
+        self.0.child_token(SyntaxKind::String)
+    }
+}
+
+impl<'a> ExportDecl<'a> {
+    pub fn declaration(&self) -> Option<Stmt<'a>> {
+        statements(self.0).next()
+    }
+}
+
+impl<'a> VarDecl<'a> {
+    pub fn name(&self) -> Option<&'a SyntaxToken> {
+        name(self.0)
+    }
+
+    pub fn type_annotation(&self) -> Option<TypeAnnotation<'a>> {
+        self.0.child_nodes().find_map(TypeAnnotation::cast)
+    }
+
+    pub fn initializer(&self) -> Option<Expr<'a>> {
+        expressions(self.0).next()
+    }
+}
+
+impl<'a> FunDecl<'a> {
+    pub fn name(&self) -> Option<&'a SyntaxToken> {
+        name(self.0)
+    }
+
+    pub fn params(&self) -> Option<ParamList<'a>> {
+        self.0.child_nodes().find_map(ParamList::cast)
+    }
+
+    /// The annotated type of what it returns.
+    pub fn return_type(&self) -> Option<TypeAnnotation<'a>> {
+        self.0.child_nodes().find_map(TypeAnnotation::cast)
+    }
+
+    pub fn body(&self) -> Option<Block<'a>> {
+        self.0.child_nodes().find_map(Block::cast)
+    }
+}
+
+impl<'a> Method<'a> {
+    pub fn name(&self) -> Option<&'a SyntaxToken> {
+        name(self.0)
+    }
+
+    pub fn params(&self) -> Option<ParamList<'a>> {
+        self.0.child_nodes().find_map(ParamList::cast)
+    }
+
+    /// The annotated type of what it returns.
+    pub fn return_type(&self) -> Option<TypeAnnotation<'a>> {
+        self.0.child_nodes().find_map(TypeAnnotation::cast)
+    }
+
+    pub fn body(&self) -> Option<Block<'a>> {
+        self.0.child_nodes().find_map(Block::cast)
+    }
+}
+
+impl<'a> ParamList<'a> {
+    pub fn params(&self) -> impl Iterator<Item=&'a SyntaxToken> {
+        self.0.child_tokens().filter(|token| token.kind == SyntaxKind::Identifier)
+    }
+
+    /// Every parameter with its annotated type.
+    pub fn annotated_params(&self) -> Vec<(&'a SyntaxToken, Option<TypeAnnotation<'a>>)> {
+        let mut params: Vec<(&'a SyntaxToken, Option<TypeAnnotation<'a>>)> = vec![];
+        for child in &self.0.children {
+            match child {
+                SyntaxElement::Token(token) if token.kind == SyntaxKind::Identifier => params.push((token, None)),
+                SyntaxElement::Node(node) => if let (Some(annotation), Some(param)) = (TypeAnnotation::cast(node), params.last_mut()) {
+                    param.1 = Some(annotation);
+                },
+                _ => (),
+            }
+        }
+        params
+    }
+}
+
+impl<'a> TypeAnnotation<'a> {
+    /// The name of the type after the `:`.
+    pub fn name(&self) -> Option<&'a SyntaxToken> {
+        name(self.0)
+    }
+}
+
+impl<'a> ClassDecl<'a> {
+    pub fn name(&self) -> Option<&'a SyntaxToken> {
+        name(self.0)
+    }
+
+    /// The name after the `<`.
+    pub fn superclass(&self) -> Option<&'a SyntaxToken> {
+        let mut tokens = self.0.child_tokens().skip_while(|token| token.kind != SyntaxKind::Less);
+        tokens.nth(1).filter(|token| token.kind == SyntaxKind::Identifier)
+    }
+
+    pub fn methods(&self) -> impl Iterator<Item=Method<'a>> {
+        self.0.child_nodes().filter_map(Method::cast)
+    }
+}
+
+impl<'a> Block<'a> {
+    pub fn statements(&self) -> impl Iterator<Item=Stmt<'a>> {
+        statements(self.0)
+    }
+}
+
+impl<'a> ExprStmt<'a> {
+    pub fn expr(&self) -> Option<Expr<'a>> {
+        expressions(self.0).next()
+    }
+}
+
+impl<'a> PrintStmt<'a> {
+    pub fn expr(&self) -> Option<Expr<'a>> {
+        expressions(self.0).next()
+    }
+}
+
+impl<'a> ReturnStmt<'a> {
+    pub fn value(&self) -> Option<Expr<'a>> {
+        expressions(self.0).next()
+    }
+}
+
+impl<'a> IfStmt<'a> {
+    pub fn condition(&self) -> Option<Expr<'a>> {
+        expressions(self.0).next()
+    }
+
+    pub fn then_branch(&self) -> Option<Stmt<'a>> {
+        statements(self.0).next()
+    }
+
+    pub fn else_branch(&self) -> Option<Stmt<'a>> {
+        statements(self.0).nth(1)
+    }
+}
+
+impl<'a> WhileStmt<'a> {
+    pub fn condition(&self) -> Option<Expr<'a>> {
+        expressions(self.0).next()
+    }
+
+    pub fn body(&self) -> Option<Stmt<'a>> {
+        statements(self.0).next()
+    }
+}
+
+impl<'a> ForStmt<'a> {
+    /// The `var` declaration or expression statement before the first `;`.
+    pub fn initializer(&self) -> Option<Stmt<'a>> {
+        self.parts().0
+    }
+
+    pub fn condition(&self) -> Option<Expr<'a>> {
+        self.parts().1
+    }
+
+    pub fn increment(&self) -> Option<Expr<'a>> {
+        self.parts().2
+    }
+
+    pub fn body(&self) -> Option<Stmt<'a>> {
+        self.parts().3
+    }
+
+    /// The initializer, condition, increment and body, by where they are between the `(`, the `;`s and the `)`.
+    fn parts(&self) -> (Option<Stmt<'a>>, Option<Expr<'a>>, Option<Expr<'a>>, Option<Stmt<'a>>) {
+        let mut parts = (None, None, None, None);
+        // The `;` of an initializer is in its node
+        let mut semicolons = 0;
+        let mut closed = false;
+        for child in &self.0.children {
+            match child {
+                SyntaxElement::Token(token) if token.kind == SyntaxKind::Semicolon => semicolons += 1,
+                SyntaxElement::Token(token) if token.kind == SyntaxKind::RightParen => closed = true,
+                SyntaxElement::Token(_) => (),
+                SyntaxElement::Node(node) if closed => parts.3 = Stmt::cast(node),
+                SyntaxElement::Node(node) => match Stmt::cast(node) {
+                    Some(stmt) if semicolons == 0 => {
+                        parts.0 = Some(stmt);
+                        semicolons += 1;
+                    },
+                    Some(_) => (),
+                    None if semicolons <= 1 => parts.1 = Expr::cast(node),
+                    None => parts.2 = Expr::cast(node),
+                },
+            }
+        }
+        parts
+    }
+}
+
+impl<'a> BinaryExpr<'a> {
+    pub fn lhs(&self) -> Option<Expr<'a>> {
+        expressions(self.0).next()
+    }
+
+    pub fn operator(&self) -> Option<&'a SyntaxToken> {
+        self.0.child_tokens().next()
+    }
+
+    pub fn rhs(&self) -> Option<Expr<'a>> {
+        expressions(self.0).nth(1)
+    }
+}
+
+impl<'a> LogicalExpr<'a> {
+    pub fn lhs(&self) -> Option<Expr<'a>> {
+        expressions(self.0).next()
+    }
+
+    pub fn operator(&self) -> Option<&'a SyntaxToken> {
+        self.0.child_tokens().next()
+    }
+
+    pub fn rhs(&self) -> Option<Expr<'a>> {
+        expressions(self.0).nth(1)
+    }
+}
+
+impl<'a> UnaryExpr<'a> {
+    pub fn operator(&self) -> Option<&'a SyntaxToken> {
+        self.0.child_tokens().next()
+    }
+
+    pub fn operand(&self) -> Option<Expr<'a>> {
+        expressions(self.0).next()
+    }
+}
+
+impl<'a> GroupingExpr<'a> {
+    pub fn expr(&self) -> Option<Expr<'a>> {
+        expressions(self.0).next()
+    }
+}
+
+impl<'a> Literal<'a> {
+    pub fn token(&self) -> Option<&'a SyntaxToken> {
+        self.0.child_tokens().next()
+    }
+}
+
+impl<'a> VariableExpr<'a> {
+    pub fn name(&self) -> Option<&'a SyntaxToken> {
+        name(self.0)
+    }
+}
+
+impl<'a> AssignExpr<'a> {
+    /// What is assigned to, a variable unless the code has an error.
+    pub fn target(&self) -> Option<Expr<'a>> {
+        expressions(self.0).next()
+    }
+
+    pub fn name(&self) -> Option<&'a SyntaxToken> {
+        match self.target()? {
+            Expr::Variable(variable) => variable.name(),
+            _ => None,
+        }
+    }
+
+    pub fn value(&self) -> Option<Expr<'a>> {
+        expressions(self.0).nth(1)
+    }
+}
+
+impl<'a> CallExpr<'a> {
+    pub fn callee(&self) -> Option<Expr<'a>> {
+        expressions(self.0).next()
+    }
+
+    pub fn args(&self) -> Option<ArgList<'a>> {
+        self.0.child_nodes().find_map(ArgList::cast)
+    }
+}
+
+impl<'a> ArgList<'a> {
+    pub fn args(&self) -> impl Iterator<Item=Expr<'a>> {
+        expressions(self.0)
+    }
+}
+
+impl<'a> GetExpr<'a> {
+    pub fn object(&self) -> Option<Expr<'a>> {
+        expressions(self.0).next()
+    }
+
+    pub fn name(&self) -> Option<&'a SyntaxToken> {
+        name(self.0)
+    }
+}
+
+impl<'a> SetExpr<'a> {
+    /// The property that is assigned to.
+    pub fn target(&self) -> Option<GetExpr<'a>> {
+        self.0.child_nodes().next().and_then(GetExpr::cast)
+    }
+
+    pub fn object(&self) -> Option<Expr<'a>> {
+        self.target()?.object()
+    }
+
+    pub fn name(&self) -> Option<&'a SyntaxToken> {
+        self.target()?.name()
+    }
+
+    pub fn value(&self) -> Option<Expr<'a>> {
+        expressions(self.0).nth(1)
+    }
+}
+
+impl<'a> SuperExpr<'a> {
+    pub fn method(&self) -> Option<&'a SyntaxToken> {
+        name(self.0)
+    }
+}
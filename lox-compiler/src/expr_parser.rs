@@ -2,7 +2,7 @@ use super::ast::*;
 use super::common::*;
 use super::token::*;
 use std::iter::{Iterator, Peekable};
-use crate::position::WithSpan;
+use crate::position::{Span, WithSpan};
 
 #[allow(dead_code)]
 #[derive(PartialEq, PartialOrd, Copy, Clone)]
@@ -20,7 +20,7 @@ enum Precedence {
     Primary,
 }
 
-impl<'a> From<&'a Token> for Precedence {
+impl From<&Token> for Precedence {
     fn from(token: &Token) -> Precedence {
         match *token {
             Token::Equal => Precedence::Assign,
@@ -40,7 +40,7 @@ impl<'a> From<&'a Token> for Precedence {
     }
 }
 
-fn parse_expr<'a, It>(it: &mut Peekable<It>, precedence: Precedence) -> Result<Expr, ParseError>
+fn parse_expr<'a, It>(it: &mut Peekable<It>, precedence: Precedence) -> Result<WithSpan<Expr>, ParseError>
 where
     It: Iterator<Item = &'a WithSpan<Token>>,
 {
@@ -55,7 +55,7 @@ where
     Ok(expr)
 }
 
-fn parse_infix<'a, It>(it: &mut Peekable<It>, left: Expr) -> Result<Expr, ParseError>
+fn parse_infix<'a, It>(it: &mut Peekable<It>, left: WithSpan<Expr>) -> Result<WithSpan<Expr>, ParseError>
 where
     It: Iterator<Item = &'a WithSpan<Token>>,
 {
@@ -78,7 +78,7 @@ where
     }
 }
 
-fn parse_prefix<'a, It>(it: &mut Peekable<It>) -> Result<Expr, ParseError>
+fn parse_prefix<'a, It>(it: &mut Peekable<It>) -> Result<WithSpan<Expr>, ParseError>
 where
     It: Iterator<Item = &'a WithSpan<Token>>,
 {
@@ -99,29 +99,33 @@ where
     }
 }
 
-fn parse_get<'a, It>(it: &mut Peekable<It>, left: Expr) -> Result<Expr, ParseError>
+fn parse_get<'a, It>(it: &mut Peekable<It>, left: WithSpan<Expr>) -> Result<WithSpan<Expr>, ParseError>
 where
     It: Iterator<Item = &'a WithSpan<Token>>,
 {
     expect(it, &Token::Dot)?;
     let tc = next_with_context(it)?;
     match &tc.value {
-        &Token::Identifier(ref i) => Ok(Expr::Get(Box::new(left), i.clone())),
-        _ => Err(ParseError { error: format!("Expected identifier"), span: Some(tc.span) }),
+        Token::Identifier(i) => {
+            let span = Span::union(left.span, tc.span);
+            Ok(WithSpan::new(Expr::Get(Box::new(left), i.clone()), span))
+        }
+        _ => Err(ParseError { error: "Expected identifier".to_string(), span: Some(tc.span) }),
     }
 }
 
-fn parse_call<'a, It>(it: &mut Peekable<It>, left: Expr) -> Result<Expr, ParseError>
+fn parse_call<'a, It>(it: &mut Peekable<It>, left: WithSpan<Expr>) -> Result<WithSpan<Expr>, ParseError>
 where
     It: Iterator<Item = &'a WithSpan<Token>>,
 {
     expect(it, &Token::LeftParen)?;
     let args = parse_arguments(it)?;
-    expect(it, &Token::RightParen)?;
-    Ok(Expr::Call(Box::new(left), args))
+    let end = expect(it, &Token::RightParen)?;
+    let span = Span::union(left.span, end.span);
+    Ok(WithSpan::new(Expr::Call(Box::new(left), args), span))
 }
 
-fn parse_arguments<'a, It>(it: &mut Peekable<It>) -> Result<Vec<Expr>, ParseError>
+fn parse_arguments<'a, It>(it: &mut Peekable<It>) -> Result<Vec<WithSpan<Expr>>, ParseError>
 where
     It: Iterator<Item = &'a WithSpan<Token>>,
 {
@@ -136,56 +140,61 @@ where
     Ok(args)
 }
 
-fn parse_assign<'a, It>(it: &mut Peekable<It>, left: Expr) -> Result<Expr, ParseError>
+fn parse_assign<'a, It>(it: &mut Peekable<It>, left: WithSpan<Expr>) -> Result<WithSpan<Expr>, ParseError>
 where
     It: Iterator<Item = &'a WithSpan<Token>>,
 {
     expect(it, &Token::Equal)?;
     let right = parse_expr(it, Precedence::None)?;
-    match left {
-        Expr::Variable(i) => Ok(Expr::Assign(i, Box::new(right))),
-        Expr::Get(l, i) => Ok(Expr::Set(l, i, Box::new(right))),
+    let span = Span::union(left.span, right.span);
+    match left.value {
+        Expr::Variable(i) => Ok(WithSpan::new(Expr::Assign(i, Box::new(right)), span)),
+        Expr::Get(l, i) => Ok(WithSpan::new(Expr::Set(l, i, Box::new(right)), span)),
         e => Err(format!("invalid l-value: {:?}", e).into()),
     }
 }
 
-fn parse_logical<'a, It>(it: &mut Peekable<It>, left: Expr) -> Result<Expr, ParseError>
+fn parse_logical<'a, It>(it: &mut Peekable<It>, left: WithSpan<Expr>) -> Result<WithSpan<Expr>, ParseError>
 where
     It: Iterator<Item = &'a WithSpan<Token>>,
 {
     let precedence = Precedence::from(peek(it)?);
     let operator = parse_logical_op(it)?;
     let right = parse_expr(it, precedence)?;
-    Ok(Expr::Logical(Box::new(left), operator, Box::new(right)))
+    let span = Span::union(left.span, right.span);
+    Ok(WithSpan::new(Expr::Logical(Box::new(left), operator, Box::new(right)), span))
 }
 
-fn parse_grouping<'a, It>(it: &mut Peekable<It>) -> Result<Expr, ParseError>
+fn parse_grouping<'a, It>(it: &mut Peekable<It>) -> Result<WithSpan<Expr>, ParseError>
 where
     It: Iterator<Item = &'a WithSpan<Token>>,
 {
-    expect(it, &Token::LeftParen)?;
+    let start = expect(it, &Token::LeftParen)?;
     let expr = parse_expr(it, Precedence::None)?;
-    expect(it, &Token::RightParen)?;
-    Ok(Expr::Grouping(Box::new(expr)))
+    let end = expect(it, &Token::RightParen)?;
+    Ok(WithSpan::new(Expr::Grouping(Box::new(expr)), Span::union(start.span, end.span)))
 }
 
-fn parse_binary<'a, It>(it: &mut Peekable<It>, left: Expr) -> Result<Expr, ParseError>
+fn parse_binary<'a, It>(it: &mut Peekable<It>, left: WithSpan<Expr>) -> Result<WithSpan<Expr>, ParseError>
 where
     It: Iterator<Item = &'a WithSpan<Token>>,
 {
     let precedence = Precedence::from(peek(it)?);
     let operator = parse_binary_op(it)?;
     let right = parse_expr(it, precedence)?;
-    Ok(Expr::Binary(Box::new(left), operator, Box::new(right)))
+    let span = Span::union(left.span, right.span);
+    Ok(WithSpan::new(Expr::Binary(Box::new(left), operator, Box::new(right)), span))
 }
 
-fn parse_unary<'a, It>(it: &mut Peekable<It>) -> Result<Expr, ParseError>
+fn parse_unary<'a, It>(it: &mut Peekable<It>) -> Result<WithSpan<Expr>, ParseError>
 where
     It: Iterator<Item = &'a WithSpan<Token>>,
 {
+    let start = peek_span(it)?;
     let operator = parse_unary_op(it)?;
     let right = parse_expr(it, Precedence::Unary)?;
-    Ok(Expr::Unary(operator, Box::new(right)))
+    let span = Span::union(start, right.span);
+    Ok(WithSpan::new(Expr::Unary(operator, Box::new(right)), span))
 }
 
 fn parse_logical_op<'a, It>(it: &mut Peekable<It>) -> Result<LogicalOperator, ParseError>
@@ -193,10 +202,10 @@ where
     It: Iterator<Item = &'a WithSpan<Token>>,
 {
     let tc = next_with_context(it)?;
-    match &tc.value {
-        &Token::And => Ok(LogicalOperator::And),
-        &Token::Or => Ok(LogicalOperator::Or),
-        _ => Err(ParseError { error: format!("expected unary op"), span: Some(tc.span) }),
+    match tc.value {
+        Token::And => Ok(LogicalOperator::And),
+        Token::Or => Ok(LogicalOperator::Or),
+        _ => Err(ParseError { error: "expected unary op".to_string(), span: Some(tc.span) }),
     }
 }
 
@@ -205,10 +214,10 @@ where
     It: Iterator<Item = &'a WithSpan<Token>>,
 {
     let tc = next_with_context(it)?;
-    match &tc.value {
-        &Token::Bang => Ok(UnaryOperator::Bang),
-        &Token::Minus => Ok(UnaryOperator::Minus),
-        _ => Err(ParseError { error: format!("expected unary op"), span: Some(tc.span) }),
+    match tc.value {
+        Token::Bang => Ok(UnaryOperator::Bang),
+        Token::Minus => Ok(UnaryOperator::Minus),
+        _ => Err(ParseError { error: "expected unary op".to_string(), span: Some(tc.span) }),
     }
 }
 
@@ -217,52 +226,53 @@ where
     It: Iterator<Item = &'a WithSpan<Token>>,
 {
     let tc = next_with_context(it)?;
-    match &tc.value {
-        &Token::BangEqual => Ok(BinaryOperator::BangEqual),
-        &Token::EqualEqual => Ok(BinaryOperator::EqualEqual),
-        &Token::Less => Ok(BinaryOperator::Less),
-        &Token::LessEqual => Ok(BinaryOperator::LessEqual),
-        &Token::Greater => Ok(BinaryOperator::Greater),
-        &Token::GreaterEqual => Ok(BinaryOperator::GreaterEqual),
-        &Token::Plus => Ok(BinaryOperator::Plus),
-        &Token::Minus => Ok(BinaryOperator::Minus),
-        &Token::Star => Ok(BinaryOperator::Star),
-        &Token::Slash => Ok(BinaryOperator::Slash),
-        _ => Err(ParseError { error: format!("expected binary op"), span: Some(tc.span) }),
+    match tc.value {
+        Token::BangEqual => Ok(BinaryOperator::BangEqual),
+        Token::EqualEqual => Ok(BinaryOperator::EqualEqual),
+        Token::Less => Ok(BinaryOperator::Less),
+        Token::LessEqual => Ok(BinaryOperator::LessEqual),
+        Token::Greater => Ok(BinaryOperator::Greater),
+        Token::GreaterEqual => Ok(BinaryOperator::GreaterEqual),
+        Token::Plus => Ok(BinaryOperator::Plus),
+        Token::Minus => Ok(BinaryOperator::Minus),
+        Token::Star => Ok(BinaryOperator::Star),
+        Token::Slash => Ok(BinaryOperator::Slash),
+        _ => Err(ParseError { error: "expected binary op".to_string(), span: Some(tc.span) }),
     }
 }
 
-fn parse_primary<'a, It>(it: &mut Peekable<It>) -> Result<Expr, ParseError>
+fn parse_primary<'a, It>(it: &mut Peekable<It>) -> Result<WithSpan<Expr>, ParseError>
 where
     It: Iterator<Item = &'a WithSpan<Token>>,
 {
     let tc = next_with_context(it)?;
-    match &tc.value {
-        &Token::Nil => Ok(Expr::Nil),
-        &Token::This => Ok(Expr::This),
-        &Token::Number(n) => Ok(Expr::Number(n)),
-        &Token::True => Ok(Expr::Boolean(true)),
-        &Token::False => Ok(Expr::Boolean(false)),
-        &Token::String(ref s) => Ok(Expr::String(s.clone())),
-        &Token::Identifier(ref s) => Ok(Expr::Variable(s.clone())),
-        &Token::Super => parse_super(it),
-        _ => Err(ParseError { error: format!("expected primary"), span: Some(tc.span) }),
-    }
+    let expr = match &tc.value {
+        &Token::Nil => Expr::Nil,
+        &Token::This => Expr::This,
+        &Token::Number(n) => Expr::Number(n),
+        &Token::True => Expr::Boolean(true),
+        &Token::False => Expr::Boolean(false),
+        Token::String(s) => Expr::String(s.clone()),
+        Token::Identifier(s) => Expr::Variable(WithSpan::new(s.clone(), tc.span)),
+        &Token::Super => return parse_super(it, tc.span),
+        _ => return Err(ParseError { error: "expected primary".to_string(), span: Some(tc.span) }),
+    };
+    Ok(WithSpan::new(expr, tc.span))
 }
 
-fn parse_super<'a, It>(it: &mut Peekable<It>) -> Result<Expr, ParseError>
+fn parse_super<'a, It>(it: &mut Peekable<It>, start: Span) -> Result<WithSpan<Expr>, ParseError>
 where
     It: Iterator<Item = &'a WithSpan<Token>>,
 {
     expect(it, &Token::Dot)?;
     let tc = next_with_context(it)?;
     match &tc.value {
-        &Token::Identifier(ref i) => Ok(Expr::Super(i.clone())),
-        _ => Err(ParseError { error: format!("expected identifier"), span: Some(tc.span) }),
+        Token::Identifier(i) => Ok(WithSpan::new(Expr::Super(i.clone()), Span::union(start, tc.span))),
+        _ => Err(ParseError { error: "expected identifier".to_string(), span: Some(tc.span) }),
     }
 }
 
-pub fn parse<'a, It>(it: &mut Peekable<It>) -> Result<Expr, ParseError>
+pub fn parse<'a, It>(it: &mut Peekable<It>) -> Result<WithSpan<Expr>, ParseError>
 where
     It: Iterator<Item = &'a WithSpan<Token>>,
 {
@@ -275,8 +285,13 @@ mod tests {
     use super::*;
     fn parse_str(data: &str) -> Result<Expr, String> {
         let tokens = tokenize_with_context(data);
-        let mut it = tokens.as_slice().into_iter().peekable();
-        parse(&mut it).map_err(|e| e.error)
+        let mut it = tokens.as_slice().iter().peekable();
+        parse(&mut it).map(|expr| expr.without_spans().value).map_err(|e| e.error)
+    }
+
+    /// An expression without a span, for the children of an expected expression.
+    fn boxed(expr: Expr) -> Box<WithSpan<Expr>> {
+        Box::new(expr.into())
     }
 
     mod make {
@@ -287,13 +302,13 @@ mod tests {
         pub fn simple_binary(operator: BinaryOperator) -> Expr {
             let left = nr(1.);
             let right = nr(2.);
-            Expr::Binary(Box::new(left), operator, Box::new(right))
+            Expr::Binary(boxed(left), operator, boxed(right))
         }
         pub fn binary(left: Expr, operator: BinaryOperator, right: Expr) -> Expr {
-            Expr::Binary(Box::new(left), operator, Box::new(right))
+            Expr::Binary(boxed(left), operator, boxed(right))
         }
         pub fn minus_nr(value: f64) -> Expr {
-            Expr::Unary(UnaryOperator::Minus, Box::new(nr(value)))
+            Expr::Unary(UnaryOperator::Minus, boxed(nr(value)))
         }
     }
 
@@ -317,31 +332,31 @@ mod tests {
     fn test_unary() {
         assert_eq!(
             parse_str("-nil"),
-            Ok(Expr::Unary(UnaryOperator::Minus, Box::new(Expr::Nil)))
+            Ok(Expr::Unary(UnaryOperator::Minus, boxed(Expr::Nil)))
         );
         assert_eq!(
             parse_str("!nil"),
-            Ok(Expr::Unary(UnaryOperator::Bang, Box::new(Expr::Nil)))
+            Ok(Expr::Unary(UnaryOperator::Bang, boxed(Expr::Nil)))
         );
         assert_eq!(
             parse_str("!!nil"),
             Ok(Expr::Unary(
                 UnaryOperator::Bang,
-                Box::new(Expr::Unary(UnaryOperator::Bang, Box::new(Expr::Nil)))
+                boxed(Expr::Unary(UnaryOperator::Bang, boxed(Expr::Nil)))
             ))
         );
         assert_eq!(
             parse_str("!-nil"),
             Ok(Expr::Unary(
                 UnaryOperator::Bang,
-                Box::new(Expr::Unary(UnaryOperator::Minus, Box::new(Expr::Nil)))
+                boxed(Expr::Unary(UnaryOperator::Minus, boxed(Expr::Nil)))
             ))
         );
         assert_eq!(
             parse_str("-!nil"),
             Ok(Expr::Unary(
                 UnaryOperator::Minus,
-                Box::new(Expr::Unary(UnaryOperator::Bang, Box::new(Expr::Nil)))
+                boxed(Expr::Unary(UnaryOperator::Bang, boxed(Expr::Nil)))
             ))
         );
     }
@@ -420,19 +435,19 @@ mod tests {
     #[test]
     fn test_grouping() {
         use self::make::*;
-        assert_eq!(parse_str("(1)"), Ok(Expr::Grouping(Box::new(make::nr(1.)))));
+        assert_eq!(parse_str("(1)"), Ok(Expr::Grouping(boxed(make::nr(1.)))));
         assert_eq!(
             parse_str("((1))"),
-            Ok(Expr::Grouping(Box::new(Expr::Grouping(Box::new(
+            Ok(Expr::Grouping(boxed(Expr::Grouping(boxed(
                 make::nr(1.)
             )))))
         );
         assert_eq!(
             parse_str("(1+2)*(1+2)"),
             Ok(binary(
-                Expr::Grouping(Box::new(simple_binary(BinaryOperator::Plus))),
+                Expr::Grouping(boxed(simple_binary(BinaryOperator::Plus))),
                 BinaryOperator::Star,
-                Expr::Grouping(Box::new(simple_binary(BinaryOperator::Plus))),
+                Expr::Grouping(boxed(simple_binary(BinaryOperator::Plus))),
             ))
         );
         assert_eq!(parse_str("(1"), Err("No more tokens".into()));
@@ -447,17 +462,17 @@ mod tests {
         assert_eq!(
             parse_str("true or false"),
             Ok(Expr::Logical(
-                Box::new(Expr::Boolean(true)),
+                boxed(Expr::Boolean(true)),
                 LogicalOperator::Or,
-                Box::new(Expr::Boolean(false)),
+                boxed(Expr::Boolean(false)),
             ))
         );
         assert_eq!(
             parse_str("true and false"),
             Ok(Expr::Logical(
-                Box::new(Expr::Boolean(true)),
+                boxed(Expr::Boolean(true)),
                 LogicalOperator::And,
-                Box::new(Expr::Boolean(false)),
+                boxed(Expr::Boolean(false)),
             ))
         );
     }
@@ -467,16 +482,16 @@ mod tests {
         assert_eq!(
             parse_str("1 and 2 or 3 and 4"),
             Ok(Expr::Logical(
-                Box::new(Expr::Logical(
-                    Box::new(Expr::Number(1.)),
+                boxed(Expr::Logical(
+                    boxed(Expr::Number(1.)),
                     LogicalOperator::And,
-                    Box::new(Expr::Number(2.)),
+                    boxed(Expr::Number(2.)),
                 )),
                 LogicalOperator::Or,
-                Box::new(Expr::Logical(
-                    Box::new(Expr::Number(3.)),
+                boxed(Expr::Logical(
+                    boxed(Expr::Number(3.)),
                     LogicalOperator::And,
-                    Box::new(Expr::Number(4.)),
+                    boxed(Expr::Number(4.)),
                 )),
             ))
         );
@@ -486,13 +501,13 @@ mod tests {
     fn test_assignment() {
         assert_eq!(
             parse_str("a=3"),
-            Ok(Expr::Assign("a".into(), Box::new(Expr::Number(3.))))
+            Ok(Expr::Assign("a".into(), boxed(Expr::Number(3.))))
         );
         assert_eq!(
             parse_str("a=b=3"),
             Ok(Expr::Assign(
                 "a".into(),
-                Box::new(Expr::Assign("b".into(), Box::new(Expr::Number(3.))))
+                boxed(Expr::Assign("b".into(), boxed(Expr::Number(3.))))
             ))
         );
         assert_eq!(parse_str("a="), Err("No more tokens".into()));
@@ -501,7 +516,7 @@ mod tests {
             parse_str("a=1+2"),
             Ok(Expr::Assign(
                 "a".into(),
-                Box::new(make::simple_binary(BinaryOperator::Plus))
+                boxed(make::simple_binary(BinaryOperator::Plus))
             ))
         );
     }
@@ -510,21 +525,21 @@ mod tests {
     fn test_call() {
         assert_eq!(
             parse_str("a()"),
-            Ok(Expr::Call(Box::new(Expr::Variable("a".into())), vec![]))
+            Ok(Expr::Call(boxed(Expr::Variable("a".into())), vec![]))
         );
 
         assert_eq!(
             parse_str("a(3)"),
             Ok(Expr::Call(
-                Box::new(Expr::Variable("a".into())),
-                vec![Expr::Number(3.)]
+                boxed(Expr::Variable("a".into())),
+                vec![Expr::Number(3.).into()]
             ))
         );
         assert_eq!(
             parse_str("a(3,4)"),
             Ok(Expr::Call(
-                Box::new(Expr::Variable("a".into())),
-                vec![Expr::Number(3.), Expr::Number(4.),]
+                boxed(Expr::Variable("a".into())),
+                vec![Expr::Number(3.).into(), Expr::Number(4.).into(),]
             ))
         );
 
@@ -532,9 +547,9 @@ mod tests {
             parse_str("-a(3)"),
             Ok(Expr::Unary(
                 UnaryOperator::Minus,
-                Box::new(Expr::Call(
-                    Box::new(Expr::Variable("a".into())),
-                    vec![Expr::Number(3.)]
+                boxed(Expr::Call(
+                    boxed(Expr::Variable("a".into())),
+                    vec![Expr::Number(3.).into()]
                 ))
             ))
         );
@@ -542,14 +557,14 @@ mod tests {
         assert_eq!(
             parse_str("a(3)+a(3)"),
             Ok(Expr::Binary(
-                Box::new(Expr::Call(
-                    Box::new(Expr::Variable("a".into())),
-                    vec![Expr::Number(3.)]
+                boxed(Expr::Call(
+                    boxed(Expr::Variable("a".into())),
+                    vec![Expr::Number(3.).into()]
                 )),
                 BinaryOperator::Plus,
-                Box::new(Expr::Call(
-                    Box::new(Expr::Variable("a".into())),
-                    vec![Expr::Number(3.)]
+                boxed(Expr::Call(
+                    boxed(Expr::Variable("a".into())),
+                    vec![Expr::Number(3.).into()]
                 ))
             ))
         );
@@ -564,13 +579,13 @@ mod tests {
     fn test_get() {
         assert_eq!(
             parse_str("a.b"),
-            Ok(Expr::Get(Box::new(Expr::Variable("a".into())), "b".into(),))
+            Ok(Expr::Get(boxed(Expr::Variable("a".into())), "b".into(),))
         );
 
         assert_eq!(
             parse_str("a.b.c"),
             Ok(Expr::Get(
-                Box::new(Expr::Get(Box::new(Expr::Variable("a".into())), "b".into(),)),
+                boxed(Expr::Get(boxed(Expr::Variable("a".into())), "b".into(),)),
                 "c".into(),
             ))
         );
@@ -578,9 +593,9 @@ mod tests {
         assert_eq!(
             parse_str("a.b(3).c"),
             Ok(Expr::Get(
-                Box::new(Expr::Call(
-                    Box::new(Expr::Get(Box::new(Expr::Variable("a".into())), "b".into())),
-                    vec![Expr::Number(3.0)]
+                boxed(Expr::Call(
+                    boxed(Expr::Get(boxed(Expr::Variable("a".into())), "b".into())),
+                    vec![Expr::Number(3.0).into()]
                 )),
                 "c".into()
             ))
@@ -592,10 +607,38 @@ mod tests {
         assert_eq!(
             parse_str("a.b=3"),
             Ok(Expr::Set(
-                Box::new(Expr::Variable("a".into())),
+                boxed(Expr::Variable("a".into())),
                 "b".into(),
-                Box::new(Expr::Number(3.))
+                boxed(Expr::Number(3.))
             ))
         );
     }
+
+    #[test]
+    fn test_spans() {
+        let columns = |expr: &WithSpan<Expr>| {
+            assert_eq!((expr.span.start.line, expr.span.end.line), (1, 1));
+            (expr.span.start.column, expr.span.end.column)
+        };
+        let tokens = tokenize_with_context("-(a + 1) * f(x, \"s\").y");
+        let mut it = tokens.as_slice().iter().peekable();
+        let expr = parse(&mut it).unwrap();
+        assert_eq!(columns(&expr), (1, 22));
+        let (left, right) = match &expr.value {
+            Expr::Binary(left, BinaryOperator::Star, right) => (left, right),
+            expr => panic!("expected a multiplication, got {:?}", expr),
+        };
+        assert_eq!(columns(left), (1, 8));
+        assert_eq!(columns(right), (12, 22));
+        match &right.value {
+            Expr::Get(call, _) => match &call.value {
+                Expr::Call(_, args) => {
+                    assert_eq!(columns(call), (12, 20));
+                    assert_eq!(columns(&args[1]), (17, 19));
+                },
+                expr => panic!("expected a call, got {:?}", expr),
+            },
+            expr => panic!("expected a property, got {:?}", expr),
+        }
+    }
 }
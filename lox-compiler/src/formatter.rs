@@ -0,0 +1,695 @@
+//! Prints a program back in the canonical style: four spaces of indentation, opening braces on the line of the
+//! statement, spaces around binary operators, and the outermost call or operator that doesn't fit wrapped. Comments
+//! are kept, each one goes before the statement after it or stays behind the statement or token on its line.
+
+use crate::ast::*;
+use crate::common::ParseError;
+use crate::position::{Position, WithSpan};
+use crate::stmt_parser::parse;
+use crate::token::Token;
+use crate::tokenizer::tokenize_with_comments;
+
+const INDENT: &str = "    ";
+const MAX_WIDTH: usize = 80;
+
+pub fn format(code: &str) -> Result<String, ParseError> {
+    let (tokens, errors) = tokenize_with_comments(code);
+    if let Some(error) = errors.into_iter().next() {
+        return Err(error);
+    }
+    let (comments, tokens): (Vec<_>, Vec<_>) = tokens.into_iter()
+        .partition(|token| matches!(token.value, Token::Comment(_)));
+    let ast = parse(&mut tokens.iter().peekable())?;
+
+    let comments = comments.into_iter()
+        .filter_map(|token| match token.value {
+            Token::Comment(comment) => Some(WithSpan::new(comment, token.span)),
+            _ => None,
+        })
+        .collect();
+    let mut formatter = Formatter { tokens: &tokens, comments, next_comment: 0, indent: 0, output: String::new() };
+    formatter.statements(&ast, None, false);
+    Ok(formatter.output)
+}
+
+struct Formatter<'a> {
+    /// Every token but the comments, for the parts of the source the AST doesn't have.
+    tokens: &'a [WithSpan<Token>],
+    comments: Vec<WithSpan<String>>,
+    /// The first comment that isn't written yet.
+    next_comment: usize,
+    indent: usize,
+    output: String,
+}
+
+impl<'a> Formatter<'a> {
+    /// Write each statement on its own lines, with the comments between them. One blank line between statements
+    /// stays, more are joined. `end` is the closing brace, None at the end of the file.
+    fn statements(&mut self, statements: &[WithSpan<Stmt>], end: Option<Position>, methods: bool) {
+        let mut last_line = None;
+        for (index, statement) in statements.iter().enumerate() {
+            last_line = self.comments_before(statement.span.start, last_line);
+            if last_line.is_some_and(|last| statement.span.start.line > last + 1) {
+                self.output.push('\n');
+            }
+
+            self.write_indent();
+            if methods {
+                self.method(statement);
+            } else {
+                self.statement(statement);
+            }
+
+            let next = statements.get(index + 1).map(|next| next.span.start).or(end);
+            self.trailing_comments(statement.span.end, next);
+            last_line = Some(statement.span.end.line);
+        }
+
+        let end = end.unwrap_or(Position { line: usize::MAX, column: usize::MAX });
+        self.comments_before(end, last_line);
+    }
+
+    /// Write the comments before `position` on their own lines, returns the line the last one was on.
+    fn comments_before(&mut self, position: Position, mut last_line: Option<usize>) -> Option<usize> {
+        while let Some(comment) = self.comments.get(self.next_comment) {
+            if comment.span.start >= position {
+                break;
+            }
+            let line = comment.span.start.line;
+            let text = format!("//{}", comment.value.trim_end());
+            self.next_comment += 1;
+
+            if last_line.is_some_and(|last| line > last + 1) {
+                self.output.push('\n');
+            }
+            self.write_indent();
+            self.output.push_str(&text);
+            self.output.push('\n');
+            last_line = Some(line);
+        }
+        last_line
+    }
+
+    /// End the line of a statement that ended at `end`. A comment after it on the same line stays there, so do the
+    /// comments from inside the statement that had no statement to go before.
+    fn trailing_comments(&mut self, end: Position, next: Option<Position>) {
+        let mut trailing = vec![];
+        while let Some(comment) = self.comments.get(self.next_comment) {
+            let inside = comment.span.start < end;
+            let after = comment.span.start.line == end.line && next.is_none_or(|next| comment.span.start < next);
+            if !inside && !after {
+                break;
+            }
+            trailing.push(format!("//{}", comment.value.trim_end()));
+            self.next_comment += 1;
+        }
+
+        let mut trailing = trailing.into_iter();
+        if let Some(comment) = trailing.next() {
+            self.output.push(' ');
+            self.output.push_str(&comment);
+        }
+        self.output.push('\n');
+        for comment in trailing {
+            self.write_indent();
+            self.output.push_str(&comment);
+            self.output.push('\n');
+        }
+    }
+
+    /// Write the comments before `position` behind the current line and end it. Returns false when there are none.
+    fn comments_behind(&mut self, position: Position) -> bool {
+        let mut any = false;
+        while let Some(comment) = self.comments.get(self.next_comment).filter(|comment| comment.span.start < position) {
+            let text = format!("//{}", comment.value.trim_end());
+            self.next_comment += 1;
+            if any {
+                self.write_indent();
+            } else {
+                self.output.push(' ');
+            }
+            self.output.push_str(&text);
+            self.output.push('\n');
+            any = true;
+        }
+        any
+    }
+
+    fn has_comment_before(&self, position: Position) -> bool {
+        self.comments.get(self.next_comment).is_some_and(|comment| comment.span.start < position)
+    }
+
+    fn write_indent(&mut self) {
+        for _ in 0..self.indent {
+            self.output.push_str(INDENT);
+        }
+    }
+
+    /// The column the next character is written at, counting from 0.
+    fn column(&self) -> usize {
+        let line_start = self.output.rfind('\n').map_or(0, |index| index + 1);
+        self.output[line_start..].chars().count()
+    }
+
+    /// Write an expression at the end of the output, with `suffix` characters after it on the same line.
+    fn write_expr(&mut self, expr: &WithSpan<Expr>, suffix: usize) {
+        let text = format_expr(expr, self.column(), self.indent, suffix);
+        self.output.push_str(&text);
+    }
+
+    fn statement(&mut self, statement: &WithSpan<Stmt>) {
+        if self.token_at(statement.span.start) == Some(&Token::For) && self.for_loop(statement) {
+            return;
+        }
+
+        match &statement.value {
+            Stmt::Expression(expr) => {
+                self.write_expr(expr, 1);
+                self.output.push(';');
+            },
+            Stmt::Print(expr) => {
+                self.output.push_str("print ");
+                self.write_expr(expr, 1);
+                self.output.push(';');
+            },
+            Stmt::Var(name, initializer, type_name) => {
+                self.var(name, initializer.as_deref(), type_name.as_ref(), 1);
+                self.output.push(';');
+            },
+            Stmt::Return(None) => self.output.push_str("return;"),
+            Stmt::Return(Some(expr)) => {
+                self.output.push_str("return ");
+                self.write_expr(expr, 1);
+                self.output.push(';');
+            },
+            Stmt::Block(statements) => self.block(statements, statement.span.end, false),
+            Stmt::If(condition, then_branch, else_branch) => {
+                self.output.push_str("if (");
+                self.write_expr(condition, 3);
+                self.output.push(')');
+                let own_line = self.body(then_branch);
+                if let Some(else_branch) = else_branch {
+                    // A comment between the branch and the `else` stays behind the branch
+                    let else_start = self.token_after(then_branch.span.end).map_or(else_branch.span.start, |token| token.span.start);
+                    if self.comments_behind(else_start) {
+                        self.write_indent();
+                    } else if own_line {
+                        self.output.push('\n');
+                        self.write_indent();
+                    } else {
+                        self.output.push(' ');
+                    }
+                    self.output.push_str("else");
+                    self.body(else_branch);
+                }
+            },
+            Stmt::While(condition, body) => {
+                self.output.push_str("while (");
+                self.write_expr(condition, 3);
+                self.output.push(')');
+                self.body(body);
+            },
+            Stmt::Function(..) => {
+                self.output.push_str("fun ");
+                self.method(statement);
+            },
+            Stmt::Class(name, superclass, methods) => {
+                self.output.push_str("class ");
+                self.output.push_str(&name.value);
+                if let Some(superclass) = superclass {
+                    self.output.push_str(" < ");
+                    self.output.push_str(&superclass.value);
+                }
+                self.output.push(' ');
+                self.block(methods, statement.span.end, true);
+            },
+            Stmt::Import(path, names) => {
+                self.output.push_str("import ");
+                if let Some(names) = names {
+                    let names: Vec<_> = names.iter().map(|name| name.value.as_str()).collect();
+                    self.output.push_str(&format!("{{ {} }} from ", names.join(", ")));
+                }
+                self.output.push_str(&format!("\"{}\";", path.value));
+            },
+            Stmt::Export(declaration) => {
+                self.output.push_str("export ");
+                self.statement(declaration);
+            },
+        }
+    }
+
+    /// The body of an `if`, `else`, `while` or `for`, after a space. A comment before a body that isn't a block stays
+    /// on the line before it and the body goes on a line of its own, returns true when it did. The comments before a
+    /// block go in the block.
+    fn body(&mut self, body: &WithSpan<Stmt>) -> bool {
+        if self.token_at(body.span.start) == Some(&Token::LeftBrace) || !self.has_comment_before(body.span.start) {
+            self.output.push(' ');
+            self.statement(body);
+            return false;
+        }
+
+        self.indent += 1;
+        self.comments_behind(body.span.start);
+        self.write_indent();
+        self.statement(body);
+        self.indent -= 1;
+        true
+    }
+
+    /// A function without the `fun`, as it is written in a class.
+    fn method(&mut self, statement: &WithSpan<Stmt>) {
+        match &statement.value {
+            Stmt::Function(name, params, body, signature) => {
+                let params: Vec<_> = params.iter().zip(&signature.params)
+                    .map(|(param, type_name)| annotated(&param.value, type_name.as_ref()))
+                    .collect();
+                let function = format!("{}({})", name.value, params.join(", "));
+                self.output.push_str(&annotated(&function, signature.returns.as_ref()));
+                self.output.push(' ');
+                self.block(body, statement.span.end, false);
+            },
+            _ => self.statement(statement),
+        }
+    }
+
+    fn var(&mut self, name: &WithSpan<Identifier>, initializer: Option<&WithSpan<Expr>>, type_name: Option<&TypeName>, suffix: usize) {
+        self.output.push_str("var ");
+        self.output.push_str(&annotated(&name.value, type_name));
+        if let Some(initializer) = initializer {
+            self.output.push_str(" = ");
+            self.write_expr(initializer, suffix);
+        }
+    }
+
+    fn block(&mut self, statements: &[WithSpan<Stmt>], end: Position, methods: bool) {
+        if statements.is_empty() && !self.has_comment_before(end) {
+            self.output.push_str("{}");
+            return;
+        }
+
+        self.output.push_str("{\n");
+        self.indent += 1;
+        self.statements(statements, Some(end), methods);
+        self.indent -= 1;
+        self.write_indent();
+        self.output.push('}');
+    }
+
+    /// The parser turns a `for` into a `while` in a block, take it apart again. Returns false when the statement
+    /// doesn't have the shape of a `for`.
+    fn for_loop(&mut self, statement: &WithSpan<Stmt>) -> bool {
+        // Only with an initializer the loop is in a block
+        let (initializer, while_loop) = match &statement.value {
+            Stmt::Block(statements) if statements.len() == 2 => (Some(&statements[0]), &statements[1]),
+            _ => (None, statement),
+        };
+        let (condition, body) = match &while_loop.value {
+            Stmt::While(condition, body) => (condition, body.as_ref()),
+            _ => return false,
+        };
+        // The block with the increment starts at the `for` too, a block that was written starts after the `)`
+        let (body, increment) = match &body.value {
+            Stmt::Block(statements) if body.span.start == statement.span.start && statements.len() == 2 => {
+                match &statements[1].value {
+                    Stmt::Expression(increment) => (&statements[0], Some(increment)),
+                    _ => return false,
+                }
+            },
+            _ => (body, None),
+        };
+
+        // Without a condition the parser makes it `true`, look if one was written
+        let semicolon = match initializer {
+            Some(initializer) => Some(initializer.span.end),
+            None => self.token_after(statement.span.start).and_then(|paren| self.token_after(paren.span.start)).map(|semicolon| semicolon.span.start),
+        };
+        let has_condition = semicolon.and_then(|semicolon| self.token_after(semicolon)).is_some_and(|token| token.value != Token::Semicolon);
+
+        self.output.push_str("for (");
+        match initializer.map(|initializer| &initializer.value) {
+            Some(Stmt::Var(name, initializer, type_name)) => self.var(name, initializer.as_deref(), type_name.as_ref(), 1),
+            Some(Stmt::Expression(expr)) => self.write_expr(expr, 1),
+            _ => (),
+        }
+        self.output.push(';');
+        if has_condition {
+            self.output.push(' ');
+            self.write_expr(condition, 1);
+        }
+        self.output.push(';');
+        if let Some(increment) = increment {
+            self.output.push(' ');
+            self.write_expr(increment, 1);
+        }
+        self.output.push(')');
+        self.body(body);
+        true
+    }
+
+    fn token_at(&self, position: Position) -> Option<&Token> {
+        let index = self.tokens.partition_point(|token| token.span.start < position);
+        self.tokens.get(index).filter(|token| token.span.start == position).map(|token| &token.value)
+    }
+
+    fn token_after(&self, position: Position) -> Option<&WithSpan<Token>> {
+        let index = self.tokens.partition_point(|token| token.span.start <= position);
+        self.tokens.get(index)
+    }
+}
+
+/// The name followed by `: Type` when it is annotated.
+fn annotated(name: &str, type_name: Option<&TypeName>) -> String {
+    match type_name {
+        Some(type_name) => format!("{}: {}", name, type_name.value),
+        None => name.to_string(),
+    }
+}
+
+/// The expression starting at `column`, with the outermost call or operator that doesn't fit before `suffix` more
+/// characters wrapped. Lines after the first are indented for `indent`.
+fn format_expr(expr: &WithSpan<Expr>, column: usize, indent: usize, suffix: usize) -> String {
+    let flat = flat_expr(expr);
+    if column + flat.chars().count() + suffix <= MAX_WIDTH {
+        return flat;
+    }
+
+    // Continue after the last line of what is written so far
+    let end_column = |text: &str, column: usize| match text.rfind('\n') {
+        Some(index) => text[index + 1..].chars().count(),
+        None => column + text.chars().count(),
+    };
+
+    match &expr.value {
+        Expr::Call(callee, args) if !args.is_empty() => {
+            let callee = format_expr(callee, column, indent, 1);
+            let inner = INDENT.len() * (indent + 1);
+            let args: Vec<_> = args.iter()
+                .map(|arg| format!("{}{}", INDENT.repeat(indent + 1), format_expr(arg, inner, indent + 1, 1)))
+                .collect();
+            format!("{}(\n{}\n{})", callee, args.join(",\n"), INDENT.repeat(indent))
+        },
+        Expr::Call(callee, _) => format!("{}()", format_expr(callee, column, indent, suffix + 2)),
+        Expr::Binary(left, operator, right) => break_operator(left, binary_operator(*operator), right, column, indent, suffix),
+        Expr::Logical(left, operator, right) => break_operator(left, logical_operator(*operator), right, column, indent, suffix),
+        Expr::Grouping(expr) => format!("({})", format_expr(expr, column + 1, indent, suffix + 1)),
+        Expr::Unary(operator, expr) => format!("{}{}", unary_operator(*operator), format_expr(expr, column + 1, indent, suffix)),
+        Expr::Assign(name, value) => {
+            let column = column + name.value.chars().count() + 3;
+            format!("{} = {}", name.value, format_expr(value, column, indent, suffix))
+        },
+        Expr::Get(object, name) => format!("{}.{}", format_expr(object, column, indent, name.chars().count() + 1), name),
+        Expr::Set(object, name, value) => {
+            let object = format_expr(object, column, indent, 0);
+            let column = end_column(&object, column) + name.chars().count() + 4;
+            format!("{}.{} = {}", object, name, format_expr(value, column, indent, suffix))
+        },
+        _ => flat,
+    }
+}
+
+/// An operator that doesn't fit, with the right operand on the next line. The left operand is wrapped on its own
+/// when it doesn't fit either.
+fn break_operator(left: &WithSpan<Expr>, operator: &str, right: &WithSpan<Expr>, column: usize, indent: usize, suffix: usize) -> String {
+    let left = format_expr(left, column, indent, operator.len() + 1);
+    let right = format_expr(right, INDENT.len() * (indent + 1), indent + 1, suffix);
+    format!("{} {}\n{}{}", left, operator, INDENT.repeat(indent + 1), right)
+}
+
+/// The expression on a single line.
+fn flat_expr(expr: &WithSpan<Expr>) -> String {
+    match &expr.value {
+        Expr::Binary(left, operator, right) => format!("{} {} {}", flat_expr(left), binary_operator(*operator), flat_expr(right)),
+        Expr::Logical(left, operator, right) => format!("{} {} {}", flat_expr(left), logical_operator(*operator), flat_expr(right)),
+        Expr::Grouping(expr) => format!("({})", flat_expr(expr)),
+        Expr::Number(number) => number.to_string(),
+        Expr::Boolean(boolean) => boolean.to_string(),
+        Expr::Nil => "nil".to_string(),
+        Expr::This => "this".to_string(),
+        Expr::Super(name) => format!("super.{}", name),
+        Expr::String(string) => format!("\"{}\"", string),
+        Expr::Unary(operator, expr) => format!("{}{}", unary_operator(*operator), flat_expr(expr)),
+        Expr::Variable(name) => name.value.clone(),
+        Expr::Assign(name, value) => format!("{} = {}", name.value, flat_expr(value)),
+        Expr::Call(callee, args) => {
+            let args: Vec<_> = args.iter().map(flat_expr).collect();
+            format!("{}({})", flat_expr(callee), args.join(", "))
+        },
+        Expr::Get(object, name) => format!("{}.{}", flat_expr(object), name),
+        Expr::Set(object, name, value) => format!("{}.{} = {}", flat_expr(object), name, flat_expr(value)),
+    }
+}
+
+fn binary_operator(operator: BinaryOperator) -> &'static str {
+    match operator {
+        BinaryOperator::Slash => "/",
+        BinaryOperator::Star => "*",
+        BinaryOperator::Plus => "+",
+        BinaryOperator::Minus => "-",
+        BinaryOperator::Greater => ">",
+        BinaryOperator::GreaterEqual => ">=",
+        BinaryOperator::Less => "<",
+        BinaryOperator::LessEqual => "<=",
+        BinaryOperator::BangEqual => "!=",
+        BinaryOperator::EqualEqual => "==",
+    }
+}
+
+fn logical_operator(operator: LogicalOperator) -> &'static str {
+    match operator {
+        LogicalOperator::And => "and",
+        LogicalOperator::Or => "or",
+    }
+}
+
+fn unary_operator(operator: UnaryOperator) -> &'static str {
+    match operator {
+        UnaryOperator::Bang => "!",
+        UnaryOperator::Minus => "-",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenizer::tokenize_with_context;
+
+    fn assert_formats(code: &str, expected: &str) {
+        let formatted = format(code).unwrap();
+        assert_eq!(formatted, expected);
+        assert_eq!(format(&formatted).unwrap(), formatted, "formatting again changed it");
+    }
+
+    /// The tokens, without where they are.
+    fn tokens(code: &str) -> Vec<Token> {
+        tokenize_with_comments(code).0.into_iter().map(|token| token.value).collect()
+    }
+
+    #[test]
+    fn test_spacing_and_indentation() {
+        assert_formats(
+            "var a=1+2*3;fun f(x,y){if(x<y){return -x;}else return!y;}print f( a ,2 );",
+            "\
+var a = 1 + 2 * 3;
+fun f(x, y) {
+    if (x < y) {
+        return -x;
+    } else return !y;
+}
+print f(a, 2);
+",
+        );
+        assert_formats("class A<B{init(){this.x=super.y;}  empty(){}}", "\
+class A < B {
+    init() {
+        this.x = super.y;
+    }
+    empty() {}
+}
+");
+        assert_formats("var a:Number=1;fun f(x :Number,y)  :  String{}", "var a: Number = 1;\nfun f(x: Number, y): String {}\n");
+        assert_formats("print (1+2)*-3;{}while(true and !false){}", "print (1 + 2) * -3;\n{}\nwhile (true and !false) {}\n");
+    }
+
+    #[test]
+    fn test_for_loops() {
+        assert_formats("for(var i=0;i<3;i=i+1)print i;", "for (var i = 0; i < 3; i = i + 1) print i;\n");
+        assert_formats("for(;;){}", "for (;;) {}\n");
+        assert_formats("for(i=0;;){ print i; }", "for (i = 0;;) {\n    print i;\n}\n");
+        assert_formats("for(;i;)print i;", "for (; i;) print i;\n");
+        assert_formats("for(;true;i=i+1){print i;}", "for (; true; i = i + 1) {\n    print i;\n}\n");
+    }
+
+    #[test]
+    fn test_imports() {
+        assert_formats(
+            "import\"lib.lox\";import{a,b}from\"lib.lox\";export fun f(){}",
+            "import \"lib.lox\";\nimport { a, b } from \"lib.lox\";\nexport fun f() {}\n",
+        );
+    }
+
+    #[test]
+    fn test_comments() {
+        assert_formats(
+            "\
+// leading
+var a = 1; // trailing
+
+
+// after blank lines
+fun f() { // opening
+  // inside
+  return a;
+  // before the brace
+}
+{
+  // only a comment
+}
+print a + // in an expression
+  1;
+// at the end",
+            "\
+// leading
+var a = 1; // trailing
+
+// after blank lines
+fun f() {
+    // opening
+    // inside
+    return a;
+    // before the brace
+}
+{
+    // only a comment
+}
+print a + 1; // in an expression
+// at the end
+",
+        );
+        assert_formats("a; b; // after b\nc;", "a;\nb; // after b\nc;\n");
+    }
+
+    #[test]
+    fn test_comments_after_tokens() {
+        assert_formats(
+            "if (a == 1) // why\n  print a; else { print \"x\"; }",
+            "\
+if (a == 1) // why
+    print a;
+else {
+    print \"x\";
+}
+",
+        );
+        assert_formats(
+            "if (a) print a; // then\nelse // otherwise\nprint b;",
+            "\
+if (a) print a; // then
+else // otherwise
+    print b;
+",
+        );
+        assert_formats(
+            "while (a) // loop\n// body\na = a - 1;\nfor (;;) // forever\n{ print a; }",
+            "\
+while (a) // loop
+    // body
+    a = a - 1;
+for (;;) {
+    // forever
+    print a;
+}
+",
+        );
+    }
+
+    #[test]
+    fn test_wraps_long_calls() {
+        assert_formats(
+            "print someFunction(firstArgument, secondArgument, thirdArgument, fourthArgument);",
+            "\
+print someFunction(
+    firstArgument,
+    secondArgument,
+    thirdArgument,
+    fourthArgument
+);
+",
+        );
+        assert_formats(
+            "fun f() { return outer(inner(firstArgument, secondArgument, thirdArgument), lastArgument); }",
+            "\
+fun f() {
+    return outer(
+        inner(firstArgument, secondArgument, thirdArgument),
+        lastArgument
+    );
+}
+",
+        );
+    }
+
+    #[test]
+    fn test_wraps_outermost_operator() {
+        assert_formats(
+            "print someVeryLongVariableName + anotherLongVariableName + yetAnotherName + longFunctionName(1, 2);",
+            "\
+print someVeryLongVariableName + anotherLongVariableName + yetAnotherName +
+    longFunctionName(1, 2);
+",
+        );
+        assert_formats(
+            "var total = firstValueInTheSum + secondValueInTheSum + thirdValueInTheSum + fourthValueInTheSum + fifth;",
+            "\
+var total = firstValueInTheSum + secondValueInTheSum + thirdValueInTheSum +
+    fourthValueInTheSum +
+    fifth;
+",
+        );
+    }
+
+    #[test]
+    fn test_fits_in_max_width() {
+        let code = "\
+print someVeryLongVariableName + anotherLongVariableName + yetAnotherName + longFunctionName(1, 2);
+fun f() { if (first and second or someFunction(argumentNumberOne, argumentNumberTwo) == anotherFunction(three)) { return outer(inner(firstArgument, secondArgument, thirdArgument) * multiplier, lastArgument); } }
+var x = object.method(someArgument + anotherArgument - yetAnotherArgument, (grouped * expression) / divisor, last);
+";
+        let formatted = format(code).unwrap();
+        for line in formatted.lines() {
+            assert!(line.chars().count() <= MAX_WIDTH, "{:?} is longer than {} columns", line, MAX_WIDTH);
+        }
+        assert_eq!(tokens(&formatted), tokens(code));
+        assert_eq!(format(&formatted).unwrap(), formatted);
+    }
+
+    #[test]
+    fn test_keeps_every_token() {
+        let code = "\
+import \"lib.lox\"; import {Base} from \"base.lox\";
+export class Node < Base { init(value) { this.value = value; this.next = nil; } }
+var head: Node = nil; // the list
+for (var i = 0; i < 20; i = i + 1) { var node = Node(i); node.next = head; head = node; }
+fun sum(node: Node): Number { if (node == nil) return 0; else return node.value + sum(node.next); }
+print sum(head) >= 190 or \"strings\" != \"are kept\";
+while (head != nil) head = head.next;
+";
+        let formatted = format(code).unwrap();
+        assert_eq!(tokens(&formatted), tokens(code));
+        assert_eq!(format(&formatted).unwrap(), formatted);
+        let values = |code: &str| -> Vec<Token> { tokenize_with_context(code).into_iter().map(|token| token.value).collect() };
+        assert_eq!(values(&formatted), values(code));
+    }
+
+    #[test]
+    fn test_parse_error() {
+        assert!(format("print ;").is_err());
+    }
+
+    #[test]
+    fn test_lex_error() {
+        let error = format("print a @ 2;").unwrap_err();
+        assert_eq!(error.error, "Unexpected character '@'.");
+        assert_eq!(error.span.unwrap().start, Position { line: 1, column: 9 });
+        assert_eq!(format("var s = \"abc;").unwrap_err().error, "Unterminated string.");
+    }
+}
@@ -0,0 +1,187 @@
+use super::*;
+use crate::bytecode::{self, Chunk, ChunkIndex, Constant, InstructionIndex, Module};
+use lox_bytecode::debug::{self, ChunkDebugInfo};
+
+/// Lay out the blocks of an optimized function after each other as bytecode, into the chunk at `chunk_index`.
+/// Jumps to the block that comes next are left out. Returns where every instruction and local came from.
+pub fn generate(function: &Function, slots: &Slots, module: &mut Module, chunk_index: ChunkIndex) -> ChunkDebugInfo {
+    let mut chunk = Chunk::new();
+    let mut starts = vec![0; function.blocks.len()];
+    let mut jumps = vec![];
+    let mut debug_info = DebugInfoBuilder::new(function);
+
+    for (id, block) in function.blocks.iter().enumerate() {
+        starts[id] = chunk.instruction_index();
+        for (instruction, span) in block.instructions.iter().zip(&block.spans) {
+            debug_info.add_span(chunk.instruction_index(), *span);
+            debug_info.before(&chunk, instruction, slots);
+            generate_instruction(&mut chunk, instruction, function, slots, module);
+            debug_info.after(&chunk, instruction);
+        }
+
+        debug_info.add_span(chunk.instruction_index(), block.spans.last().copied().flatten());
+        let next = id + 1;
+        match block.terminator.expect("unterminated block") {
+            Terminator::Jump(to) => if to != next {
+                jumps.push((chunk.add_instruction(bytecode::Instruction::Jump(0)), to));
+            },
+            Terminator::Branch { if_false, otherwise } => {
+                jumps.push((chunk.add_instruction(bytecode::Instruction::JumpIfFalse(0)), if_false));
+                if otherwise != next {
+                    jumps.push((chunk.add_instruction(bytecode::Instruction::Jump(0)), otherwise));
+                }
+            },
+            Terminator::Return => { chunk.add_instruction(bytecode::Instruction::Return); },
+            Terminator::TailCall(arity) => { chunk.add_instruction(bytecode::Instruction::TailCall(arity)); },
+        }
+    }
+
+    for (index, to) in jumps {
+        chunk.patch_instruction_to(index, starts[to]);
+    }
+
+    let debug_info = debug_info.finish(&chunk, slots);
+    *module.chunk_mut(chunk_index) = chunk;
+    debug_info
+}
+
+/// Keeps track of the span of every instruction and of when locals start and stop holding a value.
+struct DebugInfoBuilder<'a> {
+    function: &'a Function,
+    spans: Vec<(InstructionIndex, debug::Span)>,
+    /// Where every local started holding its value, if it still does, by variable.
+    open: Vec<Option<InstructionIndex>>,
+    locals: Vec<(Variable, InstructionIndex, InstructionIndex)>,
+}
+
+impl<'a> DebugInfoBuilder<'a> {
+    fn new(function: &'a Function) -> Self {
+        let open = function.variables.iter()
+            .map(|variable| if variable.parameter { Some(0) } else { None })
+            .collect();
+
+        DebugInfoBuilder { function, spans: vec![], open, locals: vec![] }
+    }
+
+    fn add_span(&mut self, index: InstructionIndex, span: Option<Span>) {
+        if let Some(span) = span.map(debug::Span::from) {
+            match self.spans.last_mut() {
+                Some((_, last)) if *last == span => (),
+                // Nothing was emitted for the previous span
+                Some((last_index, last)) if *last_index == index => *last = span,
+                _ => self.spans.push((index, span)),
+            }
+        }
+    }
+
+    fn close(&mut self, variable: Variable, end: InstructionIndex) {
+        if let Some(start) = self.open[variable].take() {
+            self.locals.push((variable, start, end));
+        }
+    }
+
+    fn before(&mut self, chunk: &Chunk, instruction: &Instruction, slots: &Slots) {
+        match *instruction {
+            // A local taking over the slot of another one ends that one
+            Instruction::DeclareLocal(variable) if !slots.pushed(variable) => {
+                let slot = slots.slot(variable);
+                for other in 0..self.open.len() {
+                    if other != variable && slots.slot(other) == slot {
+                        self.close(other, chunk.instruction_index());
+                    }
+                }
+            },
+            Instruction::DropLocal(variable) => self.close(variable, chunk.instruction_index()),
+            _ => (),
+        }
+    }
+
+    fn after(&mut self, chunk: &Chunk, instruction: &Instruction) {
+        if let Instruction::DeclareLocal(variable) = *instruction {
+            self.open[variable] = Some(chunk.instruction_index());
+        }
+    }
+
+    fn finish(mut self, chunk: &Chunk, slots: &Slots) -> ChunkDebugInfo {
+        for variable in 0..self.open.len() {
+            self.close(variable, chunk.instruction_index());
+        }
+
+        // Outer locals first
+        self.locals.sort_by_key(|&(variable, start, _)| (start, slots.slot(variable)));
+
+        let function = self.function;
+        let locals = self.locals.iter()
+            // The function itself in slot 0 doesn't have a name
+            .filter(|(variable, _, _)| !function.variables[*variable].name.is_empty())
+            .map(|&(variable, start, end)| debug::Local {
+                name: function.variables[variable].name.clone(),
+                slot: slots.slot(variable),
+                start,
+                end,
+            })
+            .collect();
+
+        ChunkDebugInfo { spans: self.spans, locals, upvalues: vec![], source_file: None }
+    }
+}
+
+fn generate_instruction(chunk: &mut Chunk, instruction: &Instruction, function: &Function, slots: &Slots, module: &mut Module) {
+    use bytecode::Instruction as Bytecode;
+
+    let instruction = match *instruction {
+        Instruction::Constant(constant) => Bytecode::Constant(constant),
+        Instruction::True => Bytecode::True,
+        Instruction::False => Bytecode::False,
+        Instruction::Nil => Bytecode::Nil,
+        Instruction::Negate => Bytecode::Negate,
+        Instruction::Add => Bytecode::Add,
+        Instruction::Subtract => Bytecode::Subtract,
+        Instruction::Multiply => Bytecode::Multiply,
+        Instruction::Divide => Bytecode::Divide,
+        Instruction::Not => Bytecode::Not,
+        Instruction::Equal => Bytecode::Equal,
+        Instruction::Greater => Bytecode::Greater,
+        Instruction::Less => Bytecode::Less,
+        Instruction::Pop => Bytecode::Pop,
+        Instruction::Print => Bytecode::Print,
+        Instruction::DefineGlobal(global) => Bytecode::DefineGlobal(global),
+        Instruction::GetGlobal(global) => Bytecode::GetGlobal(global),
+        Instruction::SetGlobal(global) => Bytecode::SetGlobal(global),
+        Instruction::DeclareLocal(variable) => {
+            // The value is on top of the stack, which is where a new slot would be.
+            if slots.pushed(variable) {
+                return;
+            }
+            chunk.add_instruction(Bytecode::SetLocal(slots.slot(variable)));
+            Bytecode::Pop
+        },
+        Instruction::GetLocal(variable) => Bytecode::GetLocal(slots.slot(variable)),
+        Instruction::SetLocal(variable) => Bytecode::SetLocal(slots.slot(variable)),
+        Instruction::DropLocal(variable) => {
+            if !slots.pushed(variable) {
+                return;
+            }
+            if function.variables[variable].captured { Bytecode::CloseUpvalue } else { Bytecode::Pop }
+        },
+        Instruction::GetUpvalue(upvalue) => Bytecode::GetUpvalue(upvalue),
+        Instruction::SetUpvalue(upvalue) => Bytecode::SetUpvalue(upvalue),
+        Instruction::GetProperty(constant) => Bytecode::GetProperty(constant),
+        Instruction::SetProperty(constant) => Bytecode::SetProperty(constant),
+        Instruction::Call(arity) => Bytecode::Call(arity),
+        Instruction::Class(constant) => Bytecode::Class(constant),
+        Instruction::Closure(constant, ref upvalues) => {
+            if let Constant::Closure(closure) = module.constant_mut(constant) {
+                closure.upvalues = upvalues.iter()
+                    .map(|upvalue| match *upvalue {
+                        Upvalue::Local(variable) => bytecode::Upvalue::Local(slots.slot(variable)),
+                        Upvalue::Upvalue(upvalue) => bytecode::Upvalue::Upvalue(upvalue),
+                    })
+                    .collect();
+            }
+            Bytecode::Closure(constant)
+        },
+    };
+
+    chunk.add_instruction(instruction);
+}
@@ -0,0 +1,212 @@
+//! An intermediate representation between the AST and stack based bytecode.
+//!
+//! Every function is a control-flow graph of basic blocks. A block is a list of stack instructions
+//! that ends in a single terminator, the only way control moves from one block to another.
+//! Locals are referred to by variable instead of by stack slot, slots are only given out when
+//! generating bytecode, after liveness analysis.
+
+mod passes;
+mod codegen;
+
+#[cfg(test)]
+mod tests;
+
+use crate::bytecode::{ArgumentCount, ConstantIndex, GlobalIndex, UpvalueIndex};
+use crate::position::Span;
+use std::fmt;
+
+pub use passes::{predecessors, optimize, Slots};
+pub use codegen::generate;
+
+pub type BlockId = usize;
+/// A local variable of a function, numbered in the order they are declared.
+pub type Variable = usize;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Instruction {
+    Constant(ConstantIndex),
+    True,
+    False,
+    Nil,
+    Negate,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Not,
+    Equal,
+    Greater,
+    Less,
+    Pop,
+    Print,
+    DefineGlobal(GlobalIndex),
+    GetGlobal(GlobalIndex),
+    SetGlobal(GlobalIndex),
+    /// The value on top of the stack becomes the variable.
+    DeclareLocal(Variable),
+    GetLocal(Variable),
+    SetLocal(Variable),
+    /// The variable goes out of scope.
+    DropLocal(Variable),
+    GetUpvalue(UpvalueIndex),
+    SetUpvalue(UpvalueIndex),
+    GetProperty(ConstantIndex),
+    SetProperty(ConstantIndex),
+    Call(ArgumentCount),
+    Class(ConstantIndex),
+    /// Create a closure from a closure constant, the upvalues of the constant are filled in once slots are known.
+    Closure(ConstantIndex, Vec<Upvalue>),
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Upvalue {
+    Local(Variable),
+    Upvalue(UpvalueIndex),
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Terminator {
+    Jump(BlockId),
+    /// Continue at `if_false` when the value on top of the stack is falsey, at `otherwise` if it isn't.
+    /// The value stays on the stack either way.
+    Branch { if_false: BlockId, otherwise: BlockId },
+    Return,
+    /// Call the function below the arguments on the stack and return its result, in place of the current call.
+    TailCall(ArgumentCount),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Block {
+    pub instructions: Vec<Instruction>,
+    /// Only None while the block is being built.
+    pub terminator: Option<Terminator>,
+    /// The span of the statement every instruction was compiled from, followed by that of the terminator.
+    pub spans: Vec<Option<Span>>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct VariableInfo {
+    pub name: String,
+    /// Closures refer to it, so it has to keep a slot of its own until it goes out of scope.
+    pub captured: bool,
+    /// Already on the stack when the function is called, parameters and the closure itself.
+    pub parameter: bool,
+}
+
+/// A function being built, or ready to be turned into bytecode. Instructions are added to the current block.
+#[derive(Debug, PartialEq)]
+pub struct Function {
+    pub name: String,
+    pub blocks: Vec<Block>,
+    pub variables: Vec<VariableInfo>,
+    current: BlockId,
+    /// Blocks in the order they were started, which is the order of the source.
+    order: Vec<BlockId>,
+    /// The span of the statement that is being compiled.
+    span: Option<Span>,
+}
+
+impl Function {
+    pub fn new(name: &str) -> Self {
+        Function {
+            name: name.to_string(),
+            blocks: vec![Block { instructions: vec![], terminator: None, spans: vec![] }],
+            variables: vec![],
+            current: 0,
+            order: vec![0],
+            span: None,
+        }
+    }
+
+    pub fn add_variable(&mut self, name: &str, parameter: bool) -> Variable {
+        self.variables.push(VariableInfo { name: name.to_string(), captured: false, parameter });
+        self.variables.len() - 1
+    }
+
+    pub fn mark_captured(&mut self, variable: Variable) {
+        self.variables[variable].captured = true;
+    }
+
+    pub fn span(&self) -> Option<Span> {
+        self.span
+    }
+
+    /// Instructions that are added from now on were compiled from `span`.
+    pub fn set_span(&mut self, span: Option<Span>) {
+        self.span = span;
+    }
+
+    pub fn add_instruction(&mut self, instruction: Instruction) {
+        let block = &mut self.blocks[self.current];
+        debug_assert!(block.terminator.is_none(), "block {} is already terminated", self.current);
+        block.instructions.push(instruction);
+        block.spans.push(self.span);
+    }
+
+    pub fn add_block(&mut self) -> BlockId {
+        self.blocks.push(Block { instructions: vec![], terminator: None, spans: vec![] });
+        self.blocks.len() - 1
+    }
+
+    /// Continue adding instructions to `block`.
+    pub fn switch_to(&mut self, block: BlockId) {
+        if !self.order.contains(&block) {
+            self.order.push(block);
+        }
+        self.current = block;
+    }
+
+    /// End the current block, `switch_to` another block before adding more instructions.
+    pub fn terminate(&mut self, terminator: Terminator) {
+        let block = &mut self.blocks[self.current];
+        debug_assert!(block.terminator.is_none(), "block {} is already terminated", self.current);
+        block.terminator = Some(terminator);
+        block.spans.push(self.span);
+    }
+}
+
+impl fmt::Display for Terminator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Terminator::Jump(to) => write!(f, "jump block{}", to),
+            Terminator::Branch { if_false, otherwise } => write!(f, "branch if false block{} else block{}", if_false, otherwise),
+            Terminator::Return => write!(f, "return"),
+            Terminator::TailCall(arity) => write!(f, "tail call {}", arity),
+        }
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "fun {}", self.name)?;
+        for (variable, info) in self.variables.iter().enumerate() {
+            let name = if info.name.is_empty() { "<closure>" } else { &info.name };
+            let kind = match (info.parameter, info.captured) {
+                (true, true) => " (parameter, captured)",
+                (true, false) => " (parameter)",
+                (false, true) => " (captured)",
+                (false, false) => "",
+            };
+            writeln!(f, "  var v{} {}{}", variable, name, kind)?;
+        }
+
+        let predecessors = predecessors(self);
+        for (id, block) in self.blocks.iter().enumerate() {
+            let from: Vec<String> = predecessors[id].iter().map(|block| format!("block{}", block)).collect();
+            if from.is_empty() {
+                writeln!(f, "block{}:", id)?;
+            } else {
+                writeln!(f, "block{}: ; from {}", id, from.join(", "))?;
+            }
+
+            for instruction in &block.instructions {
+                writeln!(f, "    {:?}", instruction)?;
+            }
+            match block.terminator {
+                Some(terminator) => writeln!(f, "    {}", terminator)?,
+                None => writeln!(f, "    <unterminated>")?,
+            }
+        }
+        Ok(())
+    }
+}
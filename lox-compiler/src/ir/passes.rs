@@ -0,0 +1,212 @@
+use super::*;
+use crate::bytecode::StackIndex;
+use std::collections::HashSet;
+
+pub fn successors(block: &Block) -> Vec<BlockId> {
+    match block.terminator {
+        Some(Terminator::Jump(to)) => vec![to],
+        Some(Terminator::Branch { if_false, otherwise }) => vec![if_false, otherwise],
+        Some(Terminator::Return) | Some(Terminator::TailCall(_)) | None => vec![],
+    }
+}
+
+pub fn predecessors(function: &Function) -> Vec<Vec<BlockId>> {
+    let mut predecessors = vec![vec![]; function.blocks.len()];
+    for (id, block) in function.blocks.iter().enumerate() {
+        for successor in successors(block) {
+            if !predecessors[successor].contains(&id) {
+                predecessors[successor].push(id);
+            }
+        }
+    }
+    predecessors
+}
+
+/// The slot on the stack of every variable.
+#[derive(Debug, PartialEq)]
+pub struct Slots {
+    slots: Vec<StackIndex>,
+    /// Whether the variable pushed a slot of its own, instead of reusing the slot of a variable that is no longer used.
+    pushed: Vec<bool>,
+}
+
+impl Slots {
+    pub fn slot(&self, variable: Variable) -> StackIndex { self.slots[variable] }
+    pub fn pushed(&self, variable: Variable) -> bool { self.pushed[variable] }
+}
+
+/// Puts the blocks in the order of the source, gives every variable a slot and then removes the blocks that can't be reached.
+/// Anything after a return is unreachable, so that's removed as well.
+pub fn optimize(function: &mut Function) -> Slots {
+    let mut order = function.order.clone();
+    order.extend((0..function.blocks.len()).filter(|block| !function.order.contains(block)));
+    renumber(function, &order);
+
+    // Slots are handed out in the order of the source, before anything is removed. Scopes nest in that order,
+    // even when the end of a scope is unreachable.
+    let slots = allocate_slots(function);
+
+    let reachable = reachable(function);
+    let order: Vec<_> = (0..function.blocks.len()).filter(|&block| reachable[block]).collect();
+    renumber(function, &order);
+
+    slots
+}
+
+fn reachable(function: &Function) -> Vec<bool> {
+    let mut reachable = vec![false; function.blocks.len()];
+    let mut worklist = vec![0];
+    while let Some(block) = worklist.pop() {
+        if !reachable[block] {
+            reachable[block] = true;
+            worklist.extend(successors(&function.blocks[block]));
+        }
+    }
+    reachable
+}
+
+/// Keep only the blocks in `order`, in that order.
+fn renumber(function: &mut Function, order: &[BlockId]) {
+    let mut ids = vec![None; function.blocks.len()];
+    for (id, &block) in order.iter().enumerate() {
+        ids[block] = Some(id);
+    }
+    let id = |block: BlockId| ids[block].expect("jump to a removed block");
+
+    let mut blocks: Vec<_> = function.blocks.drain(..).map(Some).collect();
+    function.blocks = order.iter()
+        .map(|&block| {
+            let mut block = blocks[block].take().expect("block is kept twice");
+            block.terminator = block.terminator.map(|terminator| match terminator {
+                Terminator::Jump(to) => Terminator::Jump(id(to)),
+                Terminator::Branch { if_false, otherwise } => Terminator::Branch { if_false: id(if_false), otherwise: id(otherwise) },
+                Terminator::Return => Terminator::Return,
+                Terminator::TailCall(arity) => Terminator::TailCall(arity),
+            });
+            block
+        })
+        .collect();
+    function.order = (0..function.blocks.len()).collect();
+    function.current = ids[function.current].unwrap_or(0);
+}
+
+/// The variables an instruction refers to. Setting a variable counts as well, another variable can't take over its slot
+/// while it's still being set.
+fn references(instruction: &Instruction) -> Vec<Variable> {
+    match instruction {
+        Instruction::GetLocal(variable) | Instruction::SetLocal(variable) => vec![*variable],
+        Instruction::Closure(_, upvalues) => upvalues.iter()
+            .filter_map(|upvalue| match upvalue {
+                Upvalue::Local(variable) => Some(*variable),
+                Upvalue::Upvalue(_) => None,
+            })
+            .collect(),
+        _ => vec![],
+    }
+}
+
+/// Update `live` from the end of the instructions to the start of them.
+fn live_before(instructions: &[Instruction], live: &mut HashSet<Variable>) {
+    for instruction in instructions.iter().rev() {
+        if let Instruction::DeclareLocal(variable) = instruction {
+            live.remove(variable);
+        }
+        live.extend(references(instruction));
+    }
+}
+
+/// The variables that are still referenced after the end of every block.
+fn live_out(function: &Function) -> Vec<HashSet<Variable>> {
+    let mut live_in = vec![HashSet::new(); function.blocks.len()];
+    let mut live_out = vec![HashSet::new(); function.blocks.len()];
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (id, block) in function.blocks.iter().enumerate().rev() {
+            let out: HashSet<Variable> = successors(block).iter()
+                .flat_map(|&successor| live_in[successor].iter().copied())
+                .collect();
+
+            let mut live = out.clone();
+            live_before(&block.instructions, &mut live);
+            if live != live_in[id] {
+                live_in[id] = live;
+                changed = true;
+            }
+            live_out[id] = out;
+        }
+    }
+
+    live_out
+}
+
+fn live_after(function: &Function, live_out: &[HashSet<Variable>], block: BlockId, index: usize, variable: Variable) -> bool {
+    for instruction in &function.blocks[block].instructions[index + 1..] {
+        if references(instruction).contains(&variable) {
+            return true;
+        }
+        if *instruction == Instruction::DeclareLocal(variable) {
+            return false;
+        }
+    }
+    live_out[block].contains(&variable)
+}
+
+/// Walks the blocks in order, keeping track of which variables are on the stack. A variable that isn't captured
+/// reuses the slot of variables that are still in scope but never referenced again. Slot 0 holds the function
+/// that is running and the slots after it the parameters, those are never reused so the debug info still has the
+/// parameters for the whole function.
+fn allocate_slots(function: &Function) -> Slots {
+    let live_out = live_out(function);
+    let variables = &function.variables;
+    let mut slots = vec![0; variables.len()];
+    let mut pushed = vec![false; variables.len()];
+
+    // The variables in every slot that are in scope.
+    let mut stack: Vec<Vec<Variable>> = vec![];
+    for (variable, _) in variables.iter().enumerate().filter(|(_, info)| info.parameter) {
+        slots[variable] = stack.len();
+        pushed[variable] = true;
+        stack.push(vec![variable]);
+    }
+    let parameters = stack.len();
+
+    for (id, block) in function.blocks.iter().enumerate() {
+        for (index, instruction) in block.instructions.iter().enumerate() {
+            match *instruction {
+                Instruction::DeclareLocal(variable) => {
+                    let reusable = if variables[variable].captured {
+                        None
+                    } else {
+                        stack.iter().enumerate().skip(parameters)
+                            .find(|(_, occupants)| occupants.iter().all(|&occupant| {
+                                !variables[occupant].captured && !live_after(function, &live_out, id, index, occupant)
+                            }))
+                            .map(|(slot, _)| slot)
+                    };
+
+                    if let Some(slot) = reusable {
+                        slots[variable] = slot;
+                        stack[slot].push(variable);
+                    } else {
+                        slots[variable] = stack.len();
+                        pushed[variable] = true;
+                        stack.push(vec![variable]);
+                    }
+                },
+                Instruction::DropLocal(variable) => {
+                    if pushed[variable] {
+                        debug_assert_eq!(stack.last(), Some(&vec![variable]));
+                        stack.pop();
+                    } else {
+                        stack[slots[variable]].retain(|&occupant| occupant != variable);
+                    }
+                },
+                _ => (),
+            }
+        }
+    }
+
+    Slots { slots, pushed }
+}
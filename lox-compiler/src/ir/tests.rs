@@ -0,0 +1,132 @@
+use super::*;
+use crate::bytecode::{self, Module};
+
+fn compile_functions(data: &str) -> Vec<Function> {
+    use crate::tokenizer::tokenize_with_context;
+    let tokens = tokenize_with_context(data);
+    let mut it = tokens.as_slice().iter().peekable();
+    let ast = crate::stmt_parser::parse(&mut it).unwrap();
+    crate::bettercompiler::compile_to_ir(&ast).unwrap()
+}
+
+fn compile_module(data: &str) -> Module {
+    crate::compile(data).unwrap()
+}
+
+#[test]
+fn test_pretty_print() {
+    let functions = compile_functions("var a = true and false; print a;");
+    assert_eq!(functions.len(), 1);
+    assert_eq!(functions[0].to_string(), "\
+fun script
+  var v0 <closure> (parameter)
+block0:
+    True
+    branch if false block2 else block1
+block1: ; from block0
+    Pop
+    False
+    jump block2
+block2: ; from block0, block1
+    DefineGlobal(0)
+    GetGlobal(0)
+    Print
+    Nil
+    return
+");
+}
+
+#[test]
+fn test_code_after_return_is_removed() {
+    let functions = compile_functions("fun f(a) { return a; print a; a = 2; }");
+    let f = &functions[1];
+    assert_eq!(f.blocks.len(), 1);
+    assert_eq!(f.blocks[0].instructions, vec![Instruction::GetLocal(1)]);
+    assert_eq!(f.blocks[0].terminator, Some(Terminator::Return));
+
+    let module = compile_module("fun f(a) { return a; print a; a = 2; }");
+    assert_eq!(module.chunk(1).instructions(), vec![bytecode::Instruction::GetLocal(1), bytecode::Instruction::Return]);
+}
+
+#[test]
+fn test_unreachable_blocks_are_removed() {
+    let code = "
+        fun f(a) {
+            if (a) { return 1; while (a) print a; } else { return 2; }
+            print 3;
+        }
+    ";
+    let f = &compile_functions(code)[1];
+
+    // Everything after the if returns before reaching it.
+    assert_eq!(f.blocks.len(), 3);
+    assert!(f.blocks.iter().all(|block| !block.instructions.contains(&Instruction::Print)));
+    let predecessors = predecessors(f);
+    assert!(predecessors.iter().skip(1).all(|from| !from.is_empty()));
+}
+
+#[test]
+fn test_locals_reuse_slots_of_dead_locals() {
+    use crate::bytecode::Instruction::*;
+
+    let module = compile_module("{ var a = 1; print a; var b = 2; print b; }");
+    assert_eq!(module.chunk(0).instructions(), vec![
+        Constant(0), GetLocal(1), Print,
+        Constant(1), SetLocal(1), Pop, GetLocal(1), Print,
+        Pop, Nil, Return,
+    ]);
+}
+
+#[test]
+fn test_locals_never_reuse_slots_of_parameters() {
+    use crate::bytecode::Instruction::*;
+
+    let module = compile_module("fun add(a, b) { var s = a + b; return s; }");
+    assert_eq!(module.chunk(1).instructions(), vec![
+        GetLocal(1), GetLocal(2), Add, GetLocal(3), Return,
+    ]);
+}
+
+#[test]
+fn test_live_locals_keep_their_slot() {
+    use crate::bytecode::Instruction::*;
+
+    let module = compile_module("{ var a = 1; var b = 2; print b; print a; }");
+    assert_eq!(module.chunk(0).instructions(), vec![
+        Constant(0), Constant(1), GetLocal(2), Print, GetLocal(1), Print,
+        Pop, Pop, Nil, Return,
+    ]);
+}
+
+#[test]
+fn test_locals_referenced_in_a_loop_stay_live() {
+    use crate::bytecode::Instruction::*;
+
+    // `a` is read again in the next iteration, so `b` needs a slot of its own.
+    let module = compile_module("{ var a = 1; while (a) { var b = 2; print b; a = nil; } }");
+    let instructions = module.chunk(0).instructions();
+    assert!(instructions.contains(&GetLocal(2)));
+
+    let module = compile_module("{ var a = 1; print a; while (true) { var b = 2; print b; } }");
+    let instructions = module.chunk(0).instructions();
+    assert!(instructions.contains(&SetLocal(1)));
+    assert!(!instructions.contains(&GetLocal(2)));
+}
+
+#[test]
+fn test_captured_locals_keep_their_slot() {
+    use crate::bytecode::Instruction::*;
+
+    let code = "{ var a = 1; print a; var b = 2; fun f() { print b; } }";
+    let module = compile_module(code);
+    assert_eq!(module.chunk(0).instructions(), vec![
+        Constant(0), GetLocal(1), Print,
+        Constant(1),
+        Closure(2), SetLocal(1), Pop,
+        CloseUpvalue, Pop, Nil, Return,
+    ]);
+    match module.constant(2) {
+        bytecode::Constant::Closure(closure) => assert_eq!(closure.upvalues, vec![bytecode::Upvalue::Local(2)]),
+        constant => panic!("expected a closure, got {:?}", constant),
+    }
+}
@@ -6,28 +6,119 @@ mod expr_parser;
 mod stmt_parser;
 mod token;
 mod bettercompiler;
+mod ir;
+mod registercompiler;
 mod position;
+mod formatter;
+pub mod cst;
+pub mod analysis;
+pub mod lint;
+pub mod resolver;
+pub mod types;
+pub mod modules;
 
-use lox_bytecode::bytecode;
+use lox_bytecode::{bytecode, register};
 
 //TODO Better errors
 
-pub use crate::{bettercompiler::CompilerError, common::ParseError};
+pub use crate::{bettercompiler::CompilerError, common::ParseError, position::{Position, Span}};
+pub use crate::modules::{FileLoader, MemoryLoader, ModuleError, ModuleLoader};
 
 #[derive(Debug)]
 pub enum Error {
     CompileError(CompilerError),
     ParseError(ParseError),
+    ModuleError(ModuleError),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::CompileError(error) => write!(f, "{}", error),
+            Error::ParseError(ParseError { error, span: Some(span) }) => {
+                write!(f, "[line {}:{}] {}", span.start.line, span.start.column, error)
+            },
+            Error::ParseError(ParseError { error, span: None }) => write!(f, "{}", error),
+            Error::ModuleError(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+/// Parse code that doesn't import modules.
+fn parse_script(code: &str) -> Result<ast::Ast, Error> {
+    let ast = modules::parse(code).map_err(Error::ParseError)?;
+    modules::reject_imports(&ast).map_err(Error::ModuleError)?;
+    Ok(ast)
 }
 
 use bytecode::Module;
 pub fn compile(code: &str) -> Result<Module, Error> {
-    use crate::{tokenizer::tokenize_with_context, stmt_parser::parse, bettercompiler::compile};
-    let tokens = tokenize_with_context(code);
-    let mut it = tokens.as_slice().into_iter().peekable();
-
-    let ast = parse(&mut it).map_err(|e| Error::ParseError(e))?;
-    let module = compile(&ast).map_err(|e| Error::CompileError(e))?;
+    use crate::bettercompiler::compile;
+    let ast = parse_script(code)?;
+    let module = compile(&ast).map_err(Error::CompileError)?;
  
     Ok(module)
-}
\ No newline at end of file
+}
+
+/// Like `compile`, and record the name of the file the code came from in the debug info. The modules it imports
+/// are loaded from files, relative to it.
+pub fn compile_file(code: &str, source_file: &str) -> Result<Module, Error> {
+    let mut module = compile_with_loader(code, source_file, &FileLoader)?;
+    if let Some(debug_info) = module.debug_info_mut() {
+        debug_info.source_file = Some(source_file.to_string());
+    }
+    Ok(module)
+}
+
+/// Like `compile`, for the code of the module at `path`. The modules it imports are loaded by the loader.
+pub fn compile_with_loader(code: &str, path: &str, loader: &dyn ModuleLoader) -> Result<Module, Error> {
+    let modules = modules::load(code, path, loader)?;
+    bettercompiler::compile_program(&modules).map_err(Error::CompileError)
+}
+
+/// Compile a single expression into a script that returns its value. The names it refers to are all globals, for
+/// evaluating the expression in a paused frame of a debugged program.
+pub fn compile_expression(code: &str) -> Result<Module, Error> {
+    use crate::{tokenizer::tokenize_with_errors, expr_parser::parse, bettercompiler::compile_resolved, resolver::resolve_ast};
+    use crate::{ast::Stmt, position::{Span, WithSpan}};
+    // The expression can come from hovering over code that is being typed
+    let (tokens, errors) = tokenize_with_errors(code);
+    if let Some(error) = errors.into_iter().next() {
+        return Err(Error::ParseError(error));
+    }
+    let mut it = tokens.as_slice().iter().peekable();
+
+    let expr = parse(&mut it).map_err(Error::ParseError)?;
+    if let Some(token) = it.next() {
+        let error = format!("Unexpected {:?} after expression", token.value);
+        return Err(Error::ParseError(ParseError { error, span: Some(token.span) }));
+    }
+
+    let ast = vec![WithSpan::new(Stmt::Return(Some(Box::new(expr))), Span::default())];
+    let module = compile_resolved(&ast, resolve_ast(&ast, true)).map_err(Error::CompileError)?;
+
+    Ok(module)
+}
+
+/// Format the code in the canonical style, with its comments. Fails when it doesn't parse.
+pub fn format(code: &str) -> Result<String, Error> {
+    formatter::format(code).map_err(Error::ParseError)
+}
+
+/// Compile to the register based instruction set instead of the stack based one.
+pub fn compile_registers(code: &str) -> Result<register::Module, Error> {
+    use crate::registercompiler::compile;
+    let ast = parse_script(code)?;
+    let module = compile(&ast).map_err(Error::CompileError)?;
+
+    Ok(module)
+}
+
+/// The IR of every function in the program, as it is lowered to stack based bytecode. For debugging the compiler.
+pub fn dump_ir(code: &str) -> Result<String, Error> {
+    use crate::bettercompiler::compile_to_ir;
+    let ast = parse_script(code)?;
+    let functions = compile_to_ir(&ast).map_err(Error::CompileError)?;
+
+    Ok(functions.iter().map(|function| function.to_string()).collect::<Vec<_>>().join("\n"))
+}
@@ -0,0 +1,502 @@
+//! Warnings about code that compiles but is probably wrong. Every rule has an id that stays the same, for turning it
+//! off in a config file or for a line with a `// lox-ignore` comment.
+
+use crate::analysis::{self, Analysis, Diagnostic, SymbolKind};
+use crate::ast::*;
+use crate::bettercompiler::CompilerError;
+use crate::position::{Span, WithSpan};
+use crate::token::Token;
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Rule {
+    UnusedVariable,
+    UnusedParameter,
+    ShadowedVariable,
+    UnreachableCode,
+    UndeclaredAssignment,
+    SelfReference,
+    NilComparison,
+    InconsistentReturn,
+    InvalidIgnore,
+}
+
+impl Rule {
+    pub const ALL: &'static [Rule] = &[
+        Rule::UnusedVariable,
+        Rule::UnusedParameter,
+        Rule::ShadowedVariable,
+        Rule::UnreachableCode,
+        Rule::UndeclaredAssignment,
+        Rule::SelfReference,
+        Rule::NilComparison,
+        Rule::InconsistentReturn,
+        Rule::InvalidIgnore,
+    ];
+
+    pub fn id(self) -> &'static str {
+        match self {
+            Rule::UnusedVariable => "unused-variable",
+            Rule::UnusedParameter => "unused-parameter",
+            Rule::ShadowedVariable => "shadowed-variable",
+            Rule::UnreachableCode => "unreachable-code",
+            Rule::UndeclaredAssignment => "undeclared-assignment",
+            Rule::SelfReference => "self-reference",
+            Rule::NilComparison => "nil-comparison",
+            Rule::InconsistentReturn => "inconsistent-return",
+            Rule::InvalidIgnore => "invalid-ignore",
+        }
+    }
+
+    pub fn from_id(id: &str) -> Option<Rule> {
+        Rule::ALL.iter().copied().find(|rule| rule.id() == id)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lint {
+    pub rule: Rule,
+    pub span: Span,
+    pub message: String,
+}
+
+/// Which rules run, all of them by default.
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    disabled: HashSet<Rule>,
+}
+
+impl Config {
+    /// Read a config with a `rule-id = on` or `rule-id = off` per line. Empty lines and lines starting with `#` are
+    /// skipped.
+    pub fn parse(config: &str) -> Result<Config, String> {
+        let mut parsed = Config::default();
+        for (index, line) in config.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (id, setting) = line.split_once('=').ok_or_else(|| format!("line {}: expected `rule = on` or `rule = off`", index + 1))?;
+            let rule = Rule::from_id(id.trim()).ok_or_else(|| format!("line {}: unknown rule {}", index + 1, id.trim()))?;
+            match setting.trim() {
+                "on" => parsed.enable(rule),
+                "off" => parsed.disable(rule),
+                setting => return Err(format!("line {}: expected on or off, got {}", index + 1, setting)),
+            }
+        }
+        Ok(parsed)
+    }
+
+    pub fn enable(&mut self, rule: Rule) {
+        self.disabled.remove(&rule);
+    }
+
+    pub fn disable(&mut self, rule: Rule) {
+        self.disabled.insert(rule);
+    }
+
+    pub fn is_enabled(&self, rule: Rule) -> bool {
+        !self.disabled.contains(&rule)
+    }
+}
+
+/// The warnings in order of where they are, or the errors when the code doesn't compile.
+pub fn lint(code: &str, config: &Config) -> Result<Vec<Lint>, Vec<Diagnostic>> {
+    use crate::{tokenizer::{tokenize_with_comments, tokenize_with_errors}, stmt_parser::parse};
+
+    let analysis = analysis::analyze(code);
+    // A local that refers to itself doesn't resolve, the self-reference rule reports it
+    let self_reference = CompilerError::LocalNotInitialized.to_string();
+    let errors: Vec<_> = analysis.diagnostics.iter()
+        .filter(|diagnostic| diagnostic.message != self_reference)
+        .cloned()
+        .collect();
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    let (tokens, _) = tokenize_with_errors(code);
+    let ast = parse(&mut tokens.iter().peekable()).map_err(|error| vec![analysis::parse_diagnostic(error, &tokens)])?;
+
+    let mut linter = Linter { lints: vec![] };
+    linter.symbols(&analysis);
+    linter.statements(&ast, None);
+
+    let ignored = linter.ignored_lines(&tokenize_with_comments(code).0);
+    let mut lints: Vec<_> = linter.lints.into_iter()
+        .filter(|lint| config.is_enabled(lint.rule))
+        .filter(|lint| match ignored.get(&lint.span.start.line) {
+            Some(None) => false,
+            Some(Some(rules)) => !rules.contains(&lint.rule),
+            None => true,
+        })
+        .collect();
+    lints.sort_by_key(|lint| lint.span.start);
+    Ok(lints)
+}
+
+struct Linter {
+    lints: Vec<Lint>,
+}
+
+impl Linter {
+    fn warn(&mut self, rule: Rule, span: Span, message: String) {
+        self.lints.push(Lint { rule, span, message });
+    }
+
+    /// The lines with a `// lox-ignore` comment at their end or alone on the line before, with the rules it names
+    /// after a `:` or a space, separated by commas or spaces. None ignores every rule. A comment that names no rules
+    /// that way or names a rule that doesn't exist is a warning, so it doesn't look like it ignores something.
+    fn ignored_lines(&mut self, tokens: &[WithSpan<Token>]) -> HashMap<usize, Option<Vec<Rule>>> {
+        let mut ignored = HashMap::new();
+        let mut previous_line = None;
+        for token in tokens {
+            let after = previous_line.replace(token.span.end.line);
+            let comment = match &token.value {
+                Token::Comment(comment) => comment.trim(),
+                _ => continue,
+            };
+            let rules = match comment.strip_prefix("lox-ignore") {
+                Some("") => None,
+                Some(rules) if rules.starts_with(|c: char| c == ':' || c.is_whitespace()) => {
+                    let ids: Vec<_> = rules[1..].split(|c: char| c == ',' || c.is_whitespace()).filter(|id| !id.is_empty()).collect();
+                    if ids.is_empty() {
+                        self.warn(Rule::InvalidIgnore, token.span, "`lox-ignore:` names no rules".to_string());
+                        continue;
+                    }
+                    let mut rules = vec![];
+                    for id in ids {
+                        match Rule::from_id(id) {
+                            Some(rule) => rules.push(rule),
+                            None => self.warn(Rule::InvalidIgnore, token.span, format!("`{}` in `lox-ignore` is not a rule", id)),
+                        }
+                    }
+                    Some(rules)
+                },
+                Some(_) => {
+                    self.warn(Rule::InvalidIgnore, token.span, format!("expected rules after `lox-ignore`, got `{}`", comment));
+                    continue;
+                },
+                None => continue,
+            };
+
+            let line = token.span.start.line;
+            if after == Some(line) {
+                ignored.insert(line, rules);
+            } else {
+                ignored.insert(line + 1, rules);
+            }
+        }
+        ignored
+    }
+
+    /// The rules that need to know where names are declared and used.
+    fn symbols(&mut self, analysis: &Analysis) {
+        for symbol in &analysis.symbols {
+            // A name starting with `_` is unused on purpose
+            let unused = symbol.references.is_empty() && !symbol.name.starts_with('_');
+            match symbol.kind {
+                SymbolKind::Local if unused => {
+                    self.warn(Rule::UnusedVariable, symbol.declaration, format!("`{}` is never used", symbol.name));
+                },
+                SymbolKind::Parameter if unused => {
+                    self.warn(Rule::UnusedParameter, symbol.declaration, format!("parameter `{}` is never used", symbol.name));
+                },
+                _ => (),
+            }
+
+            if let Some(shadowed) = symbol.shadows {
+                let line = analysis.symbols[shadowed].declaration.start.line;
+                self.warn(Rule::ShadowedVariable, symbol.declaration, format!("`{}` shadows the one declared on line {}", symbol.name, line));
+            }
+        }
+
+        for unresolved in analysis.unresolved.iter().filter(|unresolved| unresolved.assignment) {
+            self.warn(Rule::UndeclaredAssignment, unresolved.span, format!("`{}` is assigned but never declared", unresolved.name));
+        }
+    }
+
+    /// `owner` is the span of the block the statements are in.
+    fn statements(&mut self, statements: &[WithSpan<Stmt>], owner: Option<Span>) {
+        let mut returned = false;
+        for statement in statements {
+            // The increment of a `for` is put after its body by the parser, with the span of the loop
+            let desugared = owner.is_some_and(|owner| owner.start == statement.span.start);
+            if returned && !desugared {
+                self.warn(Rule::UnreachableCode, statement.span, "unreachable code after return".to_string());
+                returned = false;
+            } else if always_returns(statement) {
+                returned = true;
+            }
+            self.statement(statement);
+        }
+    }
+
+    fn statement(&mut self, statement: &WithSpan<Stmt>) {
+        let span = statement.span;
+        match &statement.value {
+            Stmt::Expression(expr) | Stmt::Print(expr) => self.expr(expr),
+            Stmt::Var(name, Some(initializer), _) => {
+                if let Some(reference) = find_variable(initializer, &name.value) {
+                    self.warn(Rule::SelfReference, reference, format!("`{}` refers to itself in its initializer", name.value));
+                }
+                self.expr(initializer);
+            },
+            Stmt::Var(_, None, _) | Stmt::Return(None) => (),
+            Stmt::Return(Some(expr)) => self.expr(expr),
+            Stmt::If(condition, then_branch, else_branch) => {
+                self.expr(condition);
+                self.statement(then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.statement(else_branch);
+                }
+            },
+            Stmt::Block(statements) => self.statements(statements, Some(span)),
+            Stmt::While(condition, body) => {
+                self.expr(condition);
+                self.statement(body);
+            },
+            Stmt::Function(name, _, body, _) => self.function(name, body, false),
+            Stmt::Class(_, _, methods) => for method in methods {
+                if let Stmt::Function(name, _, body, _) = &method.value {
+                    self.function(name, body, true);
+                }
+            },
+            Stmt::Import(..) => (),
+            Stmt::Export(declaration) => self.statement(declaration),
+        }
+    }
+
+    fn function(&mut self, name: &WithSpan<Identifier>, body: &[WithSpan<Stmt>], method: bool) {
+        self.statements(body, None);
+
+        // An initializer always returns the instance
+        if method && name.value == "init" {
+            return;
+        }
+        let mut returns = (0, 0);
+        count_returns(body, &mut returns);
+        let (with_value, without_value) = returns;
+        if with_value > 0 && (without_value > 0 || !body.iter().any(always_returns)) {
+            self.warn(Rule::InconsistentReturn, name.span, format!("`{}` returns a value on some paths but not on others", name.value));
+        }
+    }
+
+    fn expr(&mut self, expr: &WithSpan<Expr>) {
+        match &expr.value {
+            Expr::Binary(left, BinaryOperator::EqualEqual, right) | Expr::Binary(left, BinaryOperator::BangEqual, right) => {
+                let compared = match (&left.value, &right.value) {
+                    (Expr::Nil, _) => Some(right.as_ref()),
+                    (_, Expr::Nil) => Some(left.as_ref()),
+                    _ => None,
+                };
+                if compared.is_some_and(never_nil) {
+                    self.warn(Rule::NilComparison, expr.span, "comparing an expression that is never nil with nil".to_string());
+                }
+                self.expr(left);
+                self.expr(right);
+            },
+            Expr::Binary(left, _, right) | Expr::Logical(left, _, right) => {
+                self.expr(left);
+                self.expr(right);
+            },
+            Expr::Grouping(expr) | Expr::Unary(_, expr) | Expr::Get(expr, _) | Expr::Assign(_, expr) => self.expr(expr),
+            Expr::Set(object, _, value) => {
+                self.expr(object);
+                self.expr(value);
+            },
+            Expr::Call(callee, args) => {
+                self.expr(callee);
+                for arg in args {
+                    self.expr(arg);
+                }
+            },
+            Expr::Number(_) | Expr::Boolean(_) | Expr::Nil | Expr::This | Expr::Super(_) | Expr::String(_) | Expr::Variable(_) => (),
+        }
+    }
+}
+
+/// Nothing after the statement runs.
+pub(crate) fn always_returns(statement: &WithSpan<Stmt>) -> bool {
+    match &statement.value {
+        Stmt::Return(_) => true,
+        Stmt::Block(statements) => statements.iter().any(always_returns),
+        Stmt::If(_, then_branch, Some(else_branch)) => always_returns(then_branch) && always_returns(else_branch),
+        _ => false,
+    }
+}
+
+/// Count the returns with and without a value, but not those of functions declared in the statements.
+fn count_returns(statements: &[WithSpan<Stmt>], returns: &mut (usize, usize)) {
+    for statement in statements {
+        match &statement.value {
+            Stmt::Return(Some(_)) => returns.0 += 1,
+            Stmt::Return(None) => returns.1 += 1,
+            Stmt::Block(statements) => count_returns(statements, returns),
+            Stmt::If(_, then_branch, else_branch) => {
+                count_returns(std::slice::from_ref(then_branch.as_ref()), returns);
+                if let Some(else_branch) = else_branch {
+                    count_returns(std::slice::from_ref(else_branch.as_ref()), returns);
+                }
+            },
+            Stmt::While(_, body) => count_returns(std::slice::from_ref(body.as_ref()), returns),
+            _ => (),
+        }
+    }
+}
+
+/// Where the expression reads the variable, if it does.
+fn find_variable(expr: &WithSpan<Expr>, name: &str) -> Option<Span> {
+    match &expr.value {
+        Expr::Variable(variable) if variable.value == name => Some(variable.span),
+        Expr::Binary(left, _, right) | Expr::Logical(left, _, right) | Expr::Set(left, _, right) => {
+            find_variable(left, name).or_else(|| find_variable(right, name))
+        },
+        Expr::Grouping(expr) | Expr::Unary(_, expr) | Expr::Get(expr, _) | Expr::Assign(_, expr) => find_variable(expr, name),
+        Expr::Call(callee, args) => find_variable(callee, name).or_else(|| args.iter().find_map(|arg| find_variable(arg, name))),
+        _ => None,
+    }
+}
+
+/// Literals, `this` and the result of an operator are never nil, variables, calls and `and` or `or` can be.
+fn never_nil(expr: &WithSpan<Expr>) -> bool {
+    match &expr.value {
+        Expr::Number(_) | Expr::String(_) | Expr::Boolean(_) | Expr::This | Expr::Binary(..) | Expr::Unary(..) => true,
+        Expr::Grouping(expr) => never_nil(expr),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The rule and line of every warning.
+    fn lints(code: &str) -> Vec<(&'static str, usize)> {
+        lint(code, &Config::default()).unwrap().iter().map(|lint| (lint.rule.id(), lint.span.start.line)).collect()
+    }
+
+    #[test]
+    fn test_unused() {
+        assert_eq!(lints("fun f(a, b, _c) {\n  var d = 1;\n  var e = 2;\n  return a + e;\n}\nf(1, 2, 3);"), vec![
+            ("unused-parameter", 1),
+            ("unused-variable", 2),
+        ]);
+        // Globals can be used from anywhere
+        assert_eq!(lints("var a = 1;"), vec![]);
+    }
+
+    #[test]
+    fn test_shadowed() {
+        assert_eq!(lints("fun f(a) {\n  { var a = 1; print a; }\n  fun g() { var a = 2; print a; }\n  g();\n}\nf(1);"), vec![
+            ("unused-parameter", 1),
+            ("shadowed-variable", 2),
+            ("shadowed-variable", 3),
+        ]);
+        assert_eq!(lints("var a = 1;\nfun f() { var a = 2; print a; }\nf();"), vec![]);
+    }
+
+    #[test]
+    fn test_unreachable() {
+        assert_eq!(lints("fun f() {\n  return 1;\n  print 2;\n  print 3;\n}\nf();"), vec![("unreachable-code", 3)]);
+        assert_eq!(lints("fun f(a) {\n  if (a) return 1; else { return 2; }\n  print 3;\n}\nf(1);"), vec![("unreachable-code", 3)]);
+        assert_eq!(lints("fun f() {\n  for (var i = 0; i < 1; i = i + 1) { return i; }\n  return 0;\n}\nf();"), vec![]);
+    }
+
+    #[test]
+    fn test_undeclared_and_self_reference() {
+        assert_eq!(lints("fun f() { count = 1; }\nf();\nprint clock();"), vec![("undeclared-assignment", 1)]);
+        assert_eq!(lints("var a = 1;\nvar a = a + 1;"), vec![("self-reference", 2)]);
+        // For a local it is an error too, reported as the same warning
+        assert_eq!(lints("{\n  var q = q;\n  print q;\n}"), vec![("self-reference", 2)]);
+        assert_eq!(lints("var q = 1;\n{\n  var q = q + 1;\n  print q;\n}"), vec![("self-reference", 3)]);
+    }
+
+    #[test]
+    fn test_nil_comparison() {
+        assert_eq!(lints("var a = nil;\nprint a == nil;\nprint (a + 1) != nil;\nprint nil == \"s\";\nprint nil == nil;"), vec![
+            ("nil-comparison", 3),
+            ("nil-comparison", 4),
+        ]);
+    }
+
+    #[test]
+    fn test_inconsistent_return() {
+        assert_eq!(lints("fun f(a) {\n  if (a) return 1;\n}\nf(1);"), vec![("inconsistent-return", 1)]);
+        assert_eq!(lints("fun f(a) {\n  if (a) return 1;\n  return;\n}\nf(1);"), vec![("inconsistent-return", 1)]);
+        assert_eq!(lints("fun f(a) {\n  if (a) return 1;\n  return 2;\n}\nf(1);"), vec![]);
+        assert_eq!(lints("fun f(a) {\n  if (a) return;\n  print a;\n}\nf(1);"), vec![]);
+        assert_eq!(lints("class A {\n  init(a) {\n    if (a) return;\n    this.a = a;\n  }\n}"), vec![]);
+    }
+
+    #[test]
+    fn test_ignore_comments() {
+        let code = "\
+fun f(a) {
+  var b = 1; // lox-ignore
+  // lox-ignore: unused-variable
+  var c = 1;
+  // lox-ignore: shadowed-variable
+  var d = 1;
+}
+f(1);";
+        assert_eq!(lints(code), vec![("unused-parameter", 1), ("unused-variable", 6)]);
+
+        // A comment at the end of a line doesn't reach the next one
+        assert_eq!(lints("fun f() {\n  var a = 1; // lox-ignore\n  var b = 1;\n}\nf();"), vec![("unused-variable", 3)]);
+    }
+
+    #[test]
+    fn test_ignore_comments_with_spaces() {
+        let code = "\
+fun f(a) {
+  // lox-ignore unused-variable
+  var b = 1;
+  var c = 1; // lox-ignore unused-variable, unused-parameter
+  // lox-ignore:unused-parameter shadowed-variable
+  var d = 1;
+}
+f(1);";
+        assert_eq!(lints(code), vec![("unused-parameter", 1), ("unused-variable", 6)]);
+    }
+
+    #[test]
+    fn test_invalid_ignore_comments() {
+        let messages = |code: &str| -> Vec<(&'static str, usize, String)> {
+            lint(code, &Config::default()).unwrap().into_iter().map(|lint| (lint.rule.id(), lint.span.start.line, lint.message)).collect()
+        };
+        // An unknown rule is reported, the rules next to it still apply
+        assert_eq!(messages("fun f() {\n  // lox-ignore: unused-varible, unused-variable\n  var a = 1;\n}\nf();"), vec![
+            ("invalid-ignore", 2, "`unused-varible` in `lox-ignore` is not a rule".to_string()),
+        ]);
+        assert_eq!(messages("fun f() {\n  // lox-ignored\n  var a = 1;\n}\nf();"), vec![
+            ("invalid-ignore", 2, "expected rules after `lox-ignore`, got `lox-ignored`".to_string()),
+            ("unused-variable", 3, "`a` is never used".to_string()),
+        ]);
+        assert_eq!(messages("fun f() {\n  var a = 1; // lox-ignore:\n}\nf();"), vec![
+            ("unused-variable", 2, "`a` is never used".to_string()),
+            ("invalid-ignore", 2, "`lox-ignore:` names no rules".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_config() {
+        let config = Config::parse("# quieter\nunused-parameter = off\n\nunused-variable = on").unwrap();
+        let lints = lint("fun f(a) { var b; }\nf(1);", &config).unwrap();
+        let rules: Vec<_> = lints.iter().map(|lint| lint.rule).collect();
+        assert_eq!(rules, vec![Rule::UnusedVariable]);
+
+        assert!(Config::parse("no-such-rule = off").is_err());
+        assert!(Config::parse("unused-variable = maybe").is_err());
+        assert!(Config::parse("unused-variable").is_err());
+    }
+
+    #[test]
+    fn test_errors() {
+        let diagnostics = lint("print ;", &Config::default()).unwrap_err();
+        let diagnostics: Vec<_> = diagnostics.iter().map(|diagnostic| (diagnostic.span.start.column, diagnostic.message.as_str())).collect();
+        assert_eq!(diagnostics, vec![(7, "unexpected token: Semicolon")]);
+        assert_eq!(lint("print a @ 2;", &Config::default()).unwrap_err()[0].message, "Unexpected character '@'.");
+        assert!(lint("{ var a = 1; var a = 2; }", &Config::default()).is_err());
+    }
+}
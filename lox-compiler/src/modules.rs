@@ -0,0 +1,339 @@
+//! Programs in more than one file. A module imports another with `import "path";`, which brings every name the
+//! other module exports into scope, or with `import { a, b } from "path";` for only some of them. Paths are relative
+//! to the importing module.
+//!
+//! Every module is compiled once, no matter how often it is imported, and runs before the modules that import it.
+//! The globals a module declares are its own, other modules only see the ones it declares with `export`.
+
+use std::collections::{HashMap, HashSet};
+use crate::ast::{Ast, Identifier, Stmt};
+use crate::common::ParseError;
+use crate::position::{Span, WithSpan};
+use crate::Error;
+
+/// Where the code of a module comes from. Embedders implement it to serve modules from memory, or anywhere else.
+pub trait ModuleLoader {
+    /// The path of the module that `import` refers to in the module at `from`. Every import of a module has to
+    /// resolve to the same path, it is how a module is only loaded once.
+    fn resolve(&self, from: &str, import: &str) -> String {
+        resolve_relative(from, import)
+    }
+
+    /// The code of the module at the path.
+    fn load(&self, path: &str) -> Result<String, String>;
+}
+
+/// Loads modules from files.
+#[derive(Default)]
+pub struct FileLoader;
+
+impl ModuleLoader for FileLoader {
+    fn load(&self, path: &str) -> Result<String, String> {
+        std::fs::read_to_string(path).map_err(|error| error.to_string())
+    }
+}
+
+/// Serves modules from memory, by path.
+#[derive(Default)]
+pub struct MemoryLoader {
+    modules: HashMap<String, String>,
+}
+
+impl MemoryLoader {
+    pub fn new() -> MemoryLoader {
+        MemoryLoader::default()
+    }
+
+    pub fn insert(&mut self, path: &str, code: &str) {
+        self.modules.insert(path.to_string(), code.to_string());
+    }
+}
+
+impl ModuleLoader for MemoryLoader {
+    fn load(&self, path: &str) -> Result<String, String> {
+        self.modules.get(path).cloned().ok_or_else(|| "No such module".to_string())
+    }
+}
+
+/// The path of `import` relative to the directory of the module at `from`, without `.` and `..` components.
+pub fn resolve_relative(from: &str, import: &str) -> String {
+    let path = match from.rfind('/') {
+        Some(index) if !import.starts_with('/') => format!("{}/{}", &from[..index], import),
+        _ => import.to_string(),
+    };
+
+    let mut components: Vec<&str> = vec![];
+    for component in path.split('/') {
+        match component {
+            "." => {},
+            "" if !components.is_empty() => {},
+            ".." => match components.last() {
+                Some(&"") => {},
+                Some(last) if *last != ".." => { components.pop(); },
+                _ => components.push(".."),
+            },
+            component => components.push(component),
+        }
+    }
+    components.join("/")
+}
+
+#[derive(Debug)]
+pub enum ModuleError {
+    /// The code imports a module, but isn't compiled with a loader.
+    NoLoader(Span),
+    /// The module at `path` couldn't be loaded, it is imported by the module `from`.
+    Load { from: String, path: String, error: String, span: Span },
+    /// The modules import each other, the first and last module are the same one. The module `from` closes the cycle.
+    Cycle { from: String, modules: Vec<String>, span: Span },
+    /// The module `from` imports a name the module at `path` doesn't export.
+    NotExported { from: String, path: String, name: String, span: Span },
+    /// An imported module doesn't parse.
+    Parse { path: String, error: ParseError },
+}
+
+impl std::fmt::Display for ModuleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ModuleError::NoLoader(span) => {
+                write!(f, "[line {}:{}] Can't import modules without a module loader.", span.start.line, span.start.column)
+            },
+            ModuleError::Load { from, path, error, span } => {
+                write!(f, "{}:{}:{}: Can't load module '{}': {}", from, span.start.line, span.start.column, path, error)
+            },
+            ModuleError::Cycle { from, modules, span } => {
+                write!(f, "{}:{}:{}: Cyclic import: {}", from, span.start.line, span.start.column, modules.join(" -> "))
+            },
+            ModuleError::NotExported { from, path, name, span } => {
+                write!(f, "{}:{}:{}: Module '{}' doesn't export '{}'.", from, span.start.line, span.start.column, path, name)
+            },
+            ModuleError::Parse { path, error } => match error.span {
+                Some(span) => write!(f, "{}:{}:{}: {}", path, span.start.line, span.start.column, error.error),
+                None => write!(f, "{}: {}", path, error.error),
+            },
+        }
+    }
+}
+
+/// A module of a program, ready to be compiled.
+pub(crate) struct LoadedModule {
+    pub path: String,
+    pub ast: Ast,
+    /// The global each global name of the module refers to, the names that aren't in it are globals of the VM.
+    pub globals: HashMap<String, String>,
+    /// The names other modules declare that the module doesn't import, it can't refer to them.
+    pub hidden: HashSet<String>,
+    declared: Vec<String>,
+    exports: Vec<String>,
+}
+
+/// The module with the code and every module it imports, in the order they have to run in. The module with the
+/// code is the last one, and it keeps the names of its globals.
+pub(crate) fn load(code: &str, path: &str, loader: &dyn ModuleLoader) -> Result<Vec<LoadedModule>, Error> {
+    let ast = parse(code).map_err(Error::ParseError)?;
+    let mut modules = Modules { loader, modules: vec![], indices: HashMap::new(), importing: vec![path.to_string()] };
+    modules.add(path, ast, false)?;
+
+    let declared: HashSet<&String> = modules.modules.iter().flat_map(|module| &module.declared).collect();
+    let hidden: Vec<HashSet<String>> = modules.modules.iter().map(|module| {
+        declared.iter()
+            .filter(|name| !module.declared.contains(name) && !module.globals.contains_key(name.as_str()))
+            .map(|name| name.to_string())
+            .collect()
+    }).collect();
+    for (module, hidden) in modules.modules.iter_mut().zip(hidden) {
+        module.hidden = hidden;
+    }
+    Ok(modules.modules)
+}
+
+/// Fails when the code imports modules, for compiling code without a loader.
+pub(crate) fn reject_imports(ast: &Ast) -> Result<(), ModuleError> {
+    match ast.iter().find(|stmt| matches!(stmt.value, Stmt::Import(..))) {
+        Some(stmt) => Err(ModuleError::NoLoader(stmt.span)),
+        None => Ok(()),
+    }
+}
+
+/// The global a name declared in an imported module is stored in.
+fn global_name(path: &str, name: &str) -> String {
+    format!("{}::{}", path, name)
+}
+
+/// Parse the code of a module, failing on the first character that doesn't lex.
+pub(crate) fn parse(code: &str) -> Result<Ast, ParseError> {
+    use crate::{tokenizer::tokenize_with_errors, stmt_parser};
+    let (tokens, errors) = tokenize_with_errors(code);
+    if let Some(error) = errors.into_iter().next() {
+        return Err(error);
+    }
+    let mut it = tokens.as_slice().iter().peekable();
+    stmt_parser::parse(&mut it)
+}
+
+/// The name a top level statement declares.
+fn declared_name(stmt: &Stmt) -> Option<&str> {
+    match stmt {
+        Stmt::Var(name, ..) | Stmt::Function(name, ..) | Stmt::Class(name, ..) => Some(&name.value),
+        Stmt::Export(declaration) => declared_name(&declaration.value),
+        _ => None,
+    }
+}
+
+/// The names the module declares with `export`.
+pub(crate) fn exports(ast: &Ast) -> Vec<String> {
+    ast.iter()
+        .filter(|stmt| matches!(stmt.value, Stmt::Export(_)))
+        .filter_map(|stmt| declared_name(&stmt.value))
+        .map(String::from)
+        .collect()
+}
+
+struct Modules<'a> {
+    loader: &'a dyn ModuleLoader,
+    modules: Vec<LoadedModule>,
+    indices: HashMap<String, usize>,
+    /// The path of every module that is being loaded, with the module that imports it before it.
+    importing: Vec<String>,
+}
+
+impl<'a> Modules<'a> {
+    /// Load the modules the module imports first, and returns its index.
+    fn add(&mut self, path: &str, ast: Ast, imported: bool) -> Result<usize, Error> {
+        let mut globals = HashMap::new();
+        for stmt in &ast {
+            if let Stmt::Import(import, names) = &stmt.value {
+                let index = self.import(path, import)?;
+                let module = &self.modules[index];
+                let imports: Vec<&String> = match names {
+                    Some(names) => names.iter().map(|name| self.exported(path, index, name)).collect::<Result<_, _>>()?,
+                    None => module.exports.iter().collect(),
+                };
+                for name in imports {
+                    globals.insert(name.clone(), global_name(&module.path, name));
+                }
+            }
+        }
+
+        // A declaration shadows an import of the same name
+        let declared: Vec<String> = ast.iter().filter_map(|stmt| declared_name(&stmt.value)).map(String::from).collect();
+        for name in &declared {
+            if imported {
+                globals.insert(name.clone(), global_name(path, name));
+            } else {
+                globals.remove(name);
+            }
+        }
+
+        let exports = exports(&ast);
+
+        self.modules.push(LoadedModule { path: path.to_string(), ast, globals, hidden: HashSet::new(), declared, exports });
+        self.indices.insert(path.to_string(), self.modules.len() - 1);
+        Ok(self.modules.len() - 1)
+    }
+
+    /// The name, when the module at the index exports it.
+    fn exported<'b>(&self, from: &str, index: usize, name: &'b WithSpan<Identifier>) -> Result<&'b String, Error> {
+        let module = &self.modules[index];
+        if module.exports.contains(&name.value) {
+            Ok(&name.value)
+        } else {
+            let path = module.path.clone();
+            Err(Error::ModuleError(ModuleError::NotExported { from: from.to_string(), path, name: name.value.clone(), span: name.span }))
+        }
+    }
+
+    fn import(&mut self, from: &str, import: &WithSpan<String>) -> Result<usize, Error> {
+        let path = self.loader.resolve(from, &import.value);
+        if let Some(start) = self.importing.iter().position(|importing| *importing == path) {
+            let mut cycle = self.importing[start..].to_vec();
+            cycle.push(path);
+            return Err(Error::ModuleError(ModuleError::Cycle { from: from.to_string(), modules: cycle, span: import.span }));
+        }
+        if let Some(&index) = self.indices.get(&path) {
+            return Ok(index);
+        }
+
+        let code = self.loader.load(&path).map_err(|error| {
+            Error::ModuleError(ModuleError::Load { from: from.to_string(), path: path.clone(), error, span: import.span })
+        })?;
+        let ast = parse(&code).map_err(|error| Error::ModuleError(ModuleError::Parse { path: path.clone(), error }))?;
+
+        self.importing.push(path.clone());
+        let index = self.add(&path, ast, true);
+        self.importing.pop();
+        index
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load_modules(modules: &[(&str, &str)]) -> Result<Vec<LoadedModule>, Error> {
+        let mut loader = MemoryLoader::new();
+        for (path, code) in &modules[1..] {
+            loader.insert(path, code);
+        }
+        load(modules[0].1, modules[0].0, &loader)
+    }
+
+    #[test]
+    fn test_resolve_relative() {
+        assert_eq!(resolve_relative("main.lox", "lib.lox"), "lib.lox");
+        assert_eq!(resolve_relative("src/main.lox", "./lib/a.lox"), "src/lib/a.lox");
+        assert_eq!(resolve_relative("src/lib/a.lox", "../b.lox"), "src/b.lox");
+        assert_eq!(resolve_relative("main.lox", "../b.lox"), "../b.lox");
+        assert_eq!(resolve_relative("/home/main.lox", "../../b.lox"), "/b.lox");
+        assert_eq!(resolve_relative("src/main.lox", "/lib.lox"), "/lib.lox");
+    }
+
+    #[test]
+    fn test_load_order() {
+        let modules = load_modules(&[
+            ("main.lox", "import \"a.lox\"; import { c } from \"lib/c.lox\"; var d = c;"),
+            ("a.lox", "import { c } from \"lib/c.lox\"; export fun a() {} var b;"),
+            ("lib/c.lox", "export var c = 1;"),
+        ]).unwrap();
+
+        let paths: Vec<&str> = modules.iter().map(|module| module.path.as_str()).collect();
+        assert_eq!(paths, vec!["lib/c.lox", "a.lox", "main.lox"]);
+
+        let globals = |index: usize, name: &str| modules[index].globals.get(name).cloned();
+        assert_eq!(globals(1, "c"), Some("lib/c.lox::c".to_string()));
+        assert_eq!(globals(1, "b"), Some("a.lox::b".to_string()));
+        assert_eq!(globals(2, "a"), Some("a.lox::a".to_string()));
+        assert_eq!(globals(2, "b"), None);
+        assert_eq!(globals(2, "d"), None);
+
+        let hidden = |index: usize| {
+            let mut hidden: Vec<_> = modules[index].hidden.iter().cloned().collect();
+            hidden.sort();
+            hidden
+        };
+        assert_eq!(hidden(0), vec!["a", "b", "d"]);
+        assert_eq!(hidden(1), vec!["d"]);
+        assert_eq!(hidden(2), vec!["b"]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |modules: &[(&str, &str)]| match load_modules(modules) {
+            Err(Error::ModuleError(error)) => error.to_string(),
+            result => panic!("expected a module error, got {:?}", result.map(|_| ())),
+        };
+
+        assert_eq!(
+            error(&[("main.lox", "import \"a.lox\";"), ("a.lox", "import \"b.lox\";"), ("b.lox", "import \"a.lox\";")]),
+            "b.lox:1:8: Cyclic import: a.lox -> b.lox -> a.lox"
+        );
+        assert_eq!(error(&[("main.lox", "import \"main.lox\";")]), "main.lox:1:8: Cyclic import: main.lox -> main.lox");
+        assert_eq!(
+            error(&[("main.lox", "import { a } from \"a.lox\";"), ("a.lox", "var a;")]),
+            "main.lox:1:10: Module 'a.lox' doesn't export 'a'."
+        );
+        assert_eq!(error(&[("main.lox", "\nimport \"a.lox\";")]), "main.lox:2:8: Can't load module 'a.lox': No such module");
+        assert_eq!(error(&[("main.lox", "import \"a.lox\";"), ("a.lox", "var;")]), "a.lox:1:4: Unexpected Semicolon");
+        assert_eq!(error(&[("main.lox", "import \"a.lox\";"), ("a.lox", "var a @;")]), "a.lox:1:7: Unexpected character '@'.");
+    }
+}
@@ -1,4 +1,4 @@
-#[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Eq, Ord)]
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Eq, Ord, Hash)]
 pub struct Position {
     pub line: usize,
     pub column: usize,
@@ -31,12 +31,12 @@ impl Position {
 
     pub fn unshift(mut self) -> Self {
         self.column -= 1;
-        if self.column <= 0 { panic!("Cannot unshift onto previous line"); }
+        if self.column == 0 { panic!("Cannot unshift onto previous line"); }
         self
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Default)]
 pub struct Span {
     pub start: Position,
     pub end: Position,
@@ -70,4 +70,23 @@ impl<T> WithSpan<T> {
             value: &self.value,
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+impl From<&str> for WithSpan<String> {
+    fn from(identifier: &str) -> Self {
+        WithSpan::new(identifier.to_string(), Span::default())
+    }
+}
+
+impl From<Position> for lox_bytecode::debug::Position {
+    fn from(position: Position) -> Self {
+        lox_bytecode::debug::Position { line: position.line, column: position.column }
+    }
+}
+
+impl From<Span> for lox_bytecode::debug::Span {
+    fn from(span: Span) -> Self {
+        lox_bytecode::debug::Span { start: span.start.into(), end: span.end.into() }
+    }
+}
@@ -0,0 +1,184 @@
+use crate::register::*;
+use crate::bytecode::{Constant, Upvalue, ChunkIndex, ConstantIndex, GlobalIndex, InstructionIndex};
+use crate::bettercompiler::CompilerError;
+use crate::bettercompiler::locals::*;
+use crate::position::WithSpan;
+use crate::resolver::{self, Binding, Resolution};
+
+struct CompilerContext {
+    chunk_index: ChunkIndex,
+    locals: Locals,
+    /// The first register that isn't used by a local or a temporary.
+    next_register: Register,
+}
+
+/// Compiles code the resolver has resolved the variables of, like the stack based compiler.
+pub struct Compiler {
+    module: Module,
+    contexts: Vec<CompilerContext>,
+    resolution: Resolution,
+}
+
+impl CompilerContext {
+    fn new(chunk_index: ChunkIndex) -> CompilerContext {
+        CompilerContext {
+            chunk_index,
+            locals: Locals::new(),
+            next_register: 0,
+        }
+    }
+}
+
+impl Compiler {
+    fn current_context(&self) -> &CompilerContext {
+        self.contexts.last().expect("no context")
+    }
+
+    fn current_context_mut(&mut self) -> &mut CompilerContext {
+        self.contexts.last_mut().expect("no context")
+    }
+
+    fn current_chunk_mut(&mut self) -> &mut Chunk {
+        self.module.chunk_mut(self.current_context().chunk_index)
+    }
+
+    fn current_chunk(&self) -> &Chunk {
+        self.module.chunk(self.current_context().chunk_index)
+    }
+
+    fn begin_scope(&mut self) {
+        self.current_context_mut().locals.begin_scope();
+    }
+
+    fn end_scope(&mut self) {
+        let locals = self.current_context_mut().locals.end_scope();
+        if let Some(first) = locals.first() {
+            let first = first.slot();
+            if locals.iter().any(|local| local.captured()) {
+                self.add_instruction(Instruction::CloseUpvalues(first));
+            }
+            self.free_registers(first);
+        }
+    }
+
+    pub fn new(resolution: Resolution) -> Compiler {
+        Compiler {
+            module: Module::new(),
+            contexts: vec![],
+            resolution,
+        }
+    }
+
+    pub fn into_module(self) -> Module { self.module }
+
+    pub fn with_scope<F>(&mut self, f: F) -> Result<(), CompilerError> where F: FnOnce(&mut Self) -> Result<(), CompilerError> {
+        self.begin_scope();
+        let result = f(self);
+        self.end_scope();
+        result
+    }
+
+    pub fn is_scoped(&self) -> bool {
+        self.current_context().locals.scope_depth() > 0
+    }
+
+    /// Compile a function into a new chunk, the closure being called goes into register 0.
+    pub fn with_context<F>(&mut self, f: F) -> Result<ChunkIndex, CompilerError> where F: FnOnce(&mut Self) -> Result<(), CompilerError> {
+        let chunk = self.module.add_chunk();
+        self.contexts.push(CompilerContext::new(chunk));
+
+        self.add_local("");
+        self.mark_local_initialized();
+
+        let result = f(self);
+        let context = self.contexts.pop().expect("no context");
+        result?;
+        Ok(context.chunk_index)
+    }
+
+    pub fn with_scoped_context<F>(&mut self, f: F) -> Result<ChunkIndex, CompilerError> where F: FnOnce(&mut Self) -> Result<(), CompilerError> {
+        self.with_context(|compiler| {
+            compiler.begin_scope();
+            f(compiler)
+        })
+    }
+
+    pub fn add_instruction(&mut self, instruction: Instruction) -> InstructionIndex {
+        self.current_chunk_mut().add_instruction(instruction)
+    }
+
+    pub fn patch_instruction(&mut self, index: InstructionIndex) {
+        self.current_chunk_mut().patch_instruction(index)
+    }
+
+    pub fn patch_instruction_to(&mut self, index: InstructionIndex, to: InstructionIndex) {
+        self.current_chunk_mut().patch_instruction_to(index, to)
+    }
+
+    pub fn instruction_index(&self) -> InstructionIndex {
+        self.current_chunk().instruction_index()
+    }
+
+    pub fn next_register(&self) -> Register {
+        self.current_context().next_register
+    }
+
+    /// Take the next free register for a temporary.
+    pub fn allocate_register(&mut self) -> Register {
+        let register = self.next_register();
+        self.current_context_mut().next_register += 1;
+        self.current_chunk_mut().reserve_registers(register + 1);
+        register
+    }
+
+    /// Release every register from `register` up, they can be reused by the next temporary.
+    pub fn free_registers(&mut self, register: Register) {
+        self.current_context_mut().next_register = register;
+    }
+
+    /// Locals live in the register matching their slot. They are only added in between statements,
+    /// when no temporaries are in use.
+    pub fn add_local(&mut self, name: &str) -> Register {
+        let slot = self.current_context_mut().locals.insert(name).map(|local| local.slot()).expect("local already defined");
+        self.free_registers(slot);
+        self.allocate_register()
+    }
+
+    pub fn mark_local_initialized(&mut self) {
+        self.current_context_mut().locals.mark_initialized()
+    }
+
+    /// What the variable refers to, the resolver has seen every variable.
+    pub fn binding(&self, name: &WithSpan<String>) -> Result<Binding, CompilerError> {
+        self.resolution.binding(name.span).ok_or_else(|| CompilerError::Unresolved(name.value.clone()))
+    }
+
+    /// The register of a local of the current function, by the id the resolver gave it.
+    pub fn local_register(&self, name: &WithSpan<String>, id: usize) -> Result<Register, CompilerError> {
+        self.current_context().locals.get_by_id(id).map(Local::slot).ok_or_else(|| CompilerError::Unresolved(name.value.clone()))
+    }
+
+    /// Where the closure of the function with the name gets its upvalues from, in the current function.
+    pub fn upvalues(&mut self, name: &WithSpan<String>) -> Result<Vec<Upvalue>, CompilerError> {
+        let mut upvalues = vec![];
+        for (_, upvalue) in self.resolution.upvalues(name.span).to_vec() {
+            upvalues.push(match upvalue {
+                resolver::Upvalue::Local(id) => {
+                    let register = self.local_register(name, id)?;
+                    self.current_context_mut().locals.mark_captured(register);
+                    Upvalue::Local(register)
+                },
+                resolver::Upvalue::Upvalue(index) => Upvalue::Upvalue(index),
+            });
+        }
+        Ok(upvalues)
+    }
+
+    pub fn add_constant<C: Into<Constant>>(&mut self, constant: C) -> ConstantIndex {
+        self.module.add_constant(constant.into())
+    }
+
+    pub fn add_global(&mut self, name: &str) -> GlobalIndex {
+        self.module.globals_mut().add(name)
+    }
+}
@@ -0,0 +1,26 @@
+mod compiler;
+mod statements;
+
+#[cfg(test)]
+mod tests;
+
+use crate::register::*;
+use crate::ast::*;
+use crate::bettercompiler::{resolved, CompilerError};
+use crate::resolver::resolve_ast;
+use compiler::Compiler;
+use statements::compile_ast;
+
+pub fn compile(ast: &Ast) -> Result<Module, CompilerError> {
+    let mut compiler = Compiler::new(resolved(resolve_ast(ast, false))?);
+
+    compiler.with_context(|compiler| {
+        compile_ast(compiler, ast)?;
+        let result = compiler.allocate_register();
+        compiler.add_instruction(Instruction::LoadNil(result));
+        compiler.add_instruction(Instruction::Return(result));
+        Ok(())
+    })?;
+
+    Ok(compiler.into_module())
+}
@@ -0,0 +1,415 @@
+use crate::bettercompiler::CompilerError;
+use crate::ast::*;
+use crate::register::*;
+use crate::bytecode::{Constant, Class, Closure, Function};
+use super::compiler::Compiler;
+use crate::position::WithSpan;
+use crate::resolver::Binding;
+
+pub fn compile_ast(compiler: &mut Compiler, ast: &[WithSpan<Stmt>]) -> Result<(), CompilerError> {
+    let errors: Vec<_> = ast.iter()
+        .map(|stmt| compile_stmt(compiler, stmt))
+        .filter_map(Result::err)
+        .collect();
+    if errors.is_empty() { Ok(()) } else { Err(CompilerError::Multiple(errors)) }
+}
+
+fn compile_stmt(compiler: &mut Compiler, stmt: &WithSpan<Stmt>) -> Result<(), CompilerError> {
+    match stmt.value {
+        Stmt::Print(ref expr) => compile_print(compiler, expr),
+        Stmt::Var(ref identifier, ref expr, _) => compile_var_declaration(compiler, identifier.as_ref(), expr.as_ref()),
+        Stmt::Block(ref stmts) => compile_block(compiler, stmts),
+        Stmt::Expression(ref expr) => compile_expression_statement(compiler, expr),
+        Stmt::If(ref condition, ref then_stmt, ref else_stmt) => compile_if(compiler, condition, then_stmt, else_stmt.as_deref()),
+        Stmt::While(ref expr, ref stmt) => compile_while(compiler, expr, stmt),
+        Stmt::Function(ref identifier, ref args, ref stmts, _) => compile_function(compiler, identifier, args, stmts),
+        Stmt::Return(ref expr) => compile_return(compiler, expr.as_ref()),
+        Stmt::Class(ref identifier, ref extends, ref stmts) => {
+            compile_class(compiler, &identifier.value, extends.as_ref().map(|extends| extends.value.as_str()), stmts)
+        },
+        // Only code without imports is compiled to registers
+        Stmt::Import(..) => Err(CompilerError::Unsupported("imports")),
+        Stmt::Export(ref declaration) => compile_stmt(compiler, declaration),
+    }
+}
+
+/// Returns the register of the local, or a temporary register when the variable is a global.
+fn declare_variable(compiler: &mut Compiler, identifier: &str) -> Register {
+    // The resolver reports a name that is declared twice in a scope
+    if compiler.is_scoped() {
+        compiler.add_local(identifier)
+    } else {
+        compiler.allocate_register()
+    }
+}
+
+fn define_variable(compiler: &mut Compiler, identifier: &str, register: Register) {
+    if compiler.is_scoped() {
+        compiler.mark_local_initialized();
+    } else {
+        let global = compiler.add_global(identifier);
+        compiler.add_instruction(Instruction::DefineGlobal(global, register));
+        compiler.free_registers(register);
+    }
+}
+
+fn compile_class(compiler: &mut Compiler, identifier: &str, extends: Option<&str>, stmts: &[WithSpan<Stmt>]) -> Result<(), CompilerError> {
+    // Like the stack based compiler, only classes without methods are compiled
+    if extends.is_some() {
+        return Err(CompilerError::Unsupported("superclasses"));
+    }
+    if !stmts.is_empty() {
+        return Err(CompilerError::Unsupported("methods"));
+    }
+
+    let register = declare_variable(compiler, identifier);
+    let constant = compiler.add_constant(Constant::Class(Class{ name: identifier.to_string() }));
+    compiler.add_instruction(Instruction::Class(register, constant));
+    define_variable(compiler, identifier, register);
+
+    Ok(())
+}
+
+fn compile_return<E: AsRef<WithSpan<Expr>>>(compiler: &mut Compiler, expr: Option<E>) -> Result<(), CompilerError> {
+    let top = compiler.next_register();
+    let register = if let Some(expr) = expr {
+        compile_to_any_register(compiler, expr.as_ref())?
+    } else {
+        let register = compiler.allocate_register();
+        compiler.add_instruction(Instruction::LoadNil(register));
+        register
+    };
+    compiler.add_instruction(Instruction::Return(register));
+    compiler.free_registers(top);
+    Ok(())
+}
+
+fn compile_function(compiler: &mut Compiler, identifier: &WithSpan<Identifier>, args: &[WithSpan<Identifier>], block: &[WithSpan<Stmt>]) -> Result<(), CompilerError> {
+    let register = declare_variable(compiler, &identifier.value);
+    if compiler.is_scoped() {
+        compiler.mark_local_initialized();
+    }
+
+    let upvalues = compiler.upvalues(identifier)?;
+    let chunk_index = compiler.with_scoped_context(|compiler| {
+        for arg in args {
+            let register = declare_variable(compiler, &arg.value);
+            define_variable(compiler, &arg.value, register);
+        }
+
+        compile_block(compiler, block)?;
+
+        let result = compiler.allocate_register();
+        compiler.add_instruction(Instruction::LoadNil(result));
+        compiler.add_instruction(Instruction::Return(result));
+        Ok(())
+    })?;
+
+    let function = Function {
+        name: identifier.value.clone(),
+        chunk_index,
+        arity: args.len(),
+    };
+
+    let constant = compiler.add_constant(Constant::Closure(Closure { function, upvalues }));
+    compiler.add_instruction(Instruction::Closure(register, constant));
+
+    if !compiler.is_scoped() {
+        define_variable(compiler, &identifier.value, register);
+    }
+
+    Ok(())
+}
+
+fn compile_while(compiler: &mut Compiler, condition: &WithSpan<Expr>, body: &WithSpan<Stmt>) -> Result<(), CompilerError> {
+    let loop_start = compiler.instruction_index();
+    let top = compiler.next_register();
+    let condition = compile_to_any_register(compiler, condition)?;
+    let end_jump = compiler.add_instruction(Instruction::JumpIfFalse(condition, 0));
+    compiler.free_registers(top);
+    compile_stmt(compiler, body)?;
+    let loop_jump = compiler.add_instruction(Instruction::Jump(0));
+    compiler.patch_instruction_to(loop_jump, loop_start);
+    compiler.patch_instruction(end_jump);
+    Ok(())
+}
+
+fn compile_if(compiler: &mut Compiler, condition: &WithSpan<Expr>, then_stmt: &WithSpan<Stmt>, else_stmt: Option<&WithSpan<Stmt>>) -> Result<(), CompilerError> {
+    let top = compiler.next_register();
+    let condition = compile_to_any_register(compiler, condition)?;
+    let then_index = compiler.add_instruction(Instruction::JumpIfFalse(condition, 0));
+    compiler.free_registers(top);
+    compile_stmt(compiler, then_stmt)?;
+
+    if let Some(else_stmt) = else_stmt {
+        let else_index = compiler.add_instruction(Instruction::Jump(0));
+        compiler.patch_instruction(then_index);
+        compile_stmt(compiler, else_stmt)?;
+        compiler.patch_instruction(else_index);
+    } else {
+        compiler.patch_instruction(then_index);
+    }
+    Ok(())
+}
+
+fn compile_expression_statement(compiler: &mut Compiler, expr: &WithSpan<Expr>) -> Result<(), CompilerError> {
+    let top = compiler.next_register();
+    // The result is thrown away, so assignments don't need to copy it anywhere
+    let result = match expr.value {
+        Expr::Assign(ref identifier, ref expr) => compile_assign(compiler, identifier, expr, None),
+        Expr::Set(ref expr, ref identifier, ref value) => compile_set(compiler, expr, identifier, value, None),
+        _ => {
+            let register = compiler.allocate_register();
+            compile_expr(compiler, expr, register)
+        },
+    };
+    compiler.free_registers(top);
+    result
+}
+
+fn compile_block(compiler: &mut Compiler, ast: &[WithSpan<Stmt>]) -> Result<(), CompilerError> {
+    compiler.with_scope(|compiler| {
+        compile_ast(compiler, ast)
+    })
+}
+
+fn compile_var_declaration<T: AsRef<WithSpan<Expr>>, I: AsRef<str>>(compiler: &mut Compiler, identifier: WithSpan<I>, expr: Option<T>) -> Result<(), CompilerError> {
+    let register = declare_variable(compiler, identifier.value.as_ref());
+
+    if let Some(expr) = expr {
+        compile_expr(compiler, expr.as_ref(), register)?;
+    } else {
+        compiler.add_instruction(Instruction::LoadNil(register));
+    }
+
+    define_variable(compiler, identifier.value.as_ref(), register);
+
+    Ok(())
+}
+
+fn compile_print(compiler: &mut Compiler, expr: &WithSpan<Expr>) -> Result<(), CompilerError> {
+    let top = compiler.next_register();
+    let register = compile_to_any_register(compiler, expr)?;
+    compiler.add_instruction(Instruction::Print(register));
+    compiler.free_registers(top);
+    Ok(())
+}
+
+/// Compile an expression into a register of its own, unless it is a local that can be used where it is.
+fn compile_to_any_register(compiler: &mut Compiler, expr: &WithSpan<Expr>) -> Result<Register, CompilerError> {
+    match expr.value {
+        Expr::Grouping(ref expr) => compile_to_any_register(compiler, expr),
+        Expr::Variable(ref identifier) => {
+            if let Binding::Local(id) = compiler.binding(identifier)? {
+                compiler.local_register(identifier, id)
+            } else {
+                compile_to_new_register(compiler, expr)
+            }
+        },
+        _ => compile_to_new_register(compiler, expr),
+    }
+}
+
+fn compile_to_new_register(compiler: &mut Compiler, expr: &WithSpan<Expr>) -> Result<Register, CompilerError> {
+    let register = compiler.allocate_register();
+    compile_expr(compiler, expr, register)?;
+    Ok(register)
+}
+
+/// Compile the first operand of an instruction. A local can only be used in place when evaluating
+/// the operands after it can't change it.
+fn compile_operand(compiler: &mut Compiler, expr: &WithSpan<Expr>, rest: &[&WithSpan<Expr>]) -> Result<Register, CompilerError> {
+    if rest.iter().any(|expr| has_side_effects(expr)) {
+        compile_to_new_register(compiler, expr)
+    } else {
+        compile_to_any_register(compiler, expr)
+    }
+}
+
+fn has_side_effects(expr: &WithSpan<Expr>) -> bool {
+    match expr.value {
+        Expr::Assign(..) | Expr::Call(..) | Expr::Set(..) => true,
+        Expr::Binary(ref left, _, ref right) | Expr::Logical(ref left, _, ref right) => has_side_effects(left) || has_side_effects(right),
+        Expr::Grouping(ref expr) | Expr::Unary(_, ref expr) | Expr::Get(ref expr, _) => has_side_effects(expr),
+        _ => false,
+    }
+}
+
+/// Whether compiling `expr` into a register only writes it after everything else has been read,
+/// so the register can be a local that `expr` itself uses.
+fn writes_destination_last(expr: &WithSpan<Expr>) -> bool {
+    match expr.value {
+        Expr::Grouping(ref expr) => writes_destination_last(expr),
+        Expr::Number(_) | Expr::String(_) | Expr::Boolean(_) | Expr::Nil | Expr::Variable(_) => true,
+        Expr::Binary(..) | Expr::Unary(..) | Expr::Get(..) => true,
+        _ => false,
+    }
+}
+
+/// Compile an expression with its result in `dst`.
+fn compile_expr(compiler: &mut Compiler, expr: &WithSpan<Expr>, dst: Register) -> Result<(), CompilerError> {
+    let top = compiler.next_register();
+    let result = match expr.value {
+        Expr::Number(num) => compile_number(compiler, num, dst),
+        Expr::String(ref string) => compile_string(compiler, string, dst),
+        Expr::Binary(ref left, operator, ref right) => compile_binary(compiler, operator, left, right, dst),
+        Expr::Variable(ref identifier) => compile_variable(compiler, identifier, dst),
+        Expr::Nil => { compiler.add_instruction(Instruction::LoadNil(dst)); Ok(()) },
+        Expr::Boolean(boolean) => compile_boolean(compiler, boolean, dst),
+        Expr::Assign(ref identifier, ref expr) => compile_assign(compiler, identifier, expr, Some(dst)),
+        Expr::Logical(ref left, operator, ref right) => compile_logical(compiler, operator, left, right, dst),
+        Expr::Call(ref identifier, ref args) => compile_call(compiler, identifier, args, dst),
+        Expr::Grouping(ref expr) => compile_expr(compiler, expr, dst),
+        Expr::Unary(operator, ref expr) => compile_unary(compiler, operator, expr, dst),
+        Expr::Set(ref expr, ref identifier, ref value) => compile_set(compiler, expr, identifier, value, Some(dst)),
+        Expr::Get(ref expr, ref identifier) => compile_get(compiler, expr, identifier, dst),
+        // Only methods can refer to them
+        Expr::This => Err(CompilerError::Unsupported("this")),
+        Expr::Super(_) => Err(CompilerError::Unsupported("super")),
+    };
+    compiler.free_registers(top);
+    result
+}
+
+fn compile_get(compiler: &mut Compiler, expr: &WithSpan<Expr>, identifier: &str, dst: Register) -> Result<(), CompilerError> {
+    let instance = compile_to_any_register(compiler, expr)?;
+    let constant = compiler.add_constant(identifier);
+    compiler.add_instruction(Instruction::GetProperty(dst, instance, constant));
+    Ok(())
+}
+
+fn compile_set(compiler: &mut Compiler, expr: &WithSpan<Expr>, identifier: &str, value: &WithSpan<Expr>, dst: Option<Register>) -> Result<(), CompilerError> {
+    let instance = compile_operand(compiler, expr, &[value])?;
+    let value = compile_to_any_register(compiler, value)?;
+    let constant = compiler.add_constant(identifier);
+    compiler.add_instruction(Instruction::SetProperty(instance, constant, value));
+    move_result(compiler, dst, value);
+    Ok(())
+}
+
+fn compile_unary(compiler: &mut Compiler, operator: UnaryOperator, expr: &WithSpan<Expr>, dst: Register) -> Result<(), CompilerError> {
+    let register = compile_to_any_register(compiler, expr)?;
+    match operator {
+        UnaryOperator::Minus => compiler.add_instruction(Instruction::Negate(dst, register)),
+        UnaryOperator::Bang => compiler.add_instruction(Instruction::Not(dst, register)),
+    };
+
+    Ok(())
+}
+
+fn compile_call(compiler: &mut Compiler, identifier: &WithSpan<Expr>, args: &[WithSpan<Expr>], dst: Register) -> Result<(), CompilerError> {
+    // The callee and arguments have to be in consecutive registers, if the destination is the
+    // last register in use the call can happen right there.
+    let base = if dst + 1 == compiler.next_register() { dst } else { compiler.allocate_register() };
+    compile_expr(compiler, identifier, base)?;
+    for arg in args {
+        compile_to_new_register(compiler, arg)?;
+    }
+    compiler.add_instruction(Instruction::Call(base, args.len()));
+    move_result(compiler, Some(dst), base);
+    Ok(())
+}
+
+fn compile_logical(compiler: &mut Compiler, operator: LogicalOperator, left: &WithSpan<Expr>, right: &WithSpan<Expr>, dst: Register) -> Result<(), CompilerError> {
+    compile_expr(compiler, left, dst)?;
+    let end_jump = match operator {
+        LogicalOperator::And => compiler.add_instruction(Instruction::JumpIfFalse(dst, 0)),
+        LogicalOperator::Or => compiler.add_instruction(Instruction::JumpIfTrue(dst, 0)),
+    };
+    compile_expr(compiler, right, dst)?;
+    compiler.patch_instruction(end_jump);
+    Ok(())
+}
+
+fn compile_boolean(compiler: &mut Compiler, boolean: bool, dst: Register) -> Result<(), CompilerError> {
+    if boolean {
+        compiler.add_instruction(Instruction::LoadTrue(dst));
+    } else {
+        compiler.add_instruction(Instruction::LoadFalse(dst));
+    }
+
+    Ok(())
+}
+
+/// Copy a result into `dst`, if it isn't there already.
+fn move_result(compiler: &mut Compiler, dst: Option<Register>, register: Register) {
+    if let Some(dst) = dst {
+        if dst != register {
+            compiler.add_instruction(Instruction::Move(dst, register));
+        }
+    }
+}
+
+fn compile_assign(compiler: &mut Compiler, identifier: &WithSpan<Identifier>, expr: &WithSpan<Expr>, dst: Option<Register>) -> Result<(), CompilerError> {
+    match compiler.binding(identifier)? {
+        Binding::Local(id) => {
+            let local = compiler.local_register(identifier, id)?;
+            if writes_destination_last(expr) {
+                compile_expr(compiler, expr, local)?;
+            } else {
+                let register = compile_to_new_register(compiler, expr)?;
+                compiler.add_instruction(Instruction::Move(local, register));
+            }
+            move_result(compiler, dst, local);
+        },
+        Binding::Upvalue(upvalue) => {
+            let register = compile_to_any_register(compiler, expr)?;
+            compiler.add_instruction(Instruction::SetUpvalue(upvalue, register));
+            move_result(compiler, dst, register);
+        },
+        Binding::Global => {
+            let register = compile_to_any_register(compiler, expr)?;
+            let global = compiler.add_global(&identifier.value);
+            compiler.add_instruction(Instruction::SetGlobal(global, register));
+            move_result(compiler, dst, register);
+        },
+    }
+    Ok(())
+}
+
+fn compile_variable(compiler: &mut Compiler, identifier: &WithSpan<Identifier>, dst: Register) -> Result<(), CompilerError> {
+    match compiler.binding(identifier)? {
+        Binding::Local(id) => {
+            let local = compiler.local_register(identifier, id)?;
+            move_result(compiler, Some(dst), local);
+        },
+        Binding::Upvalue(upvalue) => {
+            compiler.add_instruction(Instruction::GetUpvalue(dst, upvalue));
+        },
+        Binding::Global => {
+            let global = compiler.add_global(&identifier.value);
+            compiler.add_instruction(Instruction::GetGlobal(dst, global));
+        },
+    }
+    Ok(())
+}
+
+fn compile_number(compiler: &mut Compiler, num: f64, dst: Register) -> Result<(), CompilerError> {
+    let constant = compiler.add_constant(num);
+    compiler.add_instruction(Instruction::LoadConstant(dst, constant));
+    Ok(())
+}
+
+fn compile_string(compiler: &mut Compiler, string: &str, dst: Register) -> Result<(), CompilerError> {
+    let constant = compiler.add_constant(string);
+    compiler.add_instruction(Instruction::LoadConstant(dst, constant));
+    Ok(())
+}
+
+fn compile_binary(compiler: &mut Compiler, operator: BinaryOperator, left: &WithSpan<Expr>, right: &WithSpan<Expr>, dst: Register) -> Result<(), CompilerError> {
+    let a = compile_operand(compiler, left, &[right])?;
+    let b = compile_to_any_register(compiler, right)?;
+    match operator {
+        BinaryOperator::Plus => compiler.add_instruction(Instruction::Add(dst, a, b)),
+        BinaryOperator::Minus => compiler.add_instruction(Instruction::Subtract(dst, a, b)),
+        BinaryOperator::Less => compiler.add_instruction(Instruction::Less(dst, a, b)),
+        BinaryOperator::LessEqual => { compiler.add_instruction(Instruction::Greater(dst, a, b)); compiler.add_instruction(Instruction::Not(dst, dst)) },
+        BinaryOperator::Star => compiler.add_instruction(Instruction::Multiply(dst, a, b)),
+        BinaryOperator::EqualEqual => compiler.add_instruction(Instruction::Equal(dst, a, b)),
+        BinaryOperator::BangEqual => { compiler.add_instruction(Instruction::Equal(dst, a, b)); compiler.add_instruction(Instruction::Not(dst, dst)) },
+        BinaryOperator::Greater => compiler.add_instruction(Instruction::Greater(dst, a, b)),
+        BinaryOperator::GreaterEqual => { compiler.add_instruction(Instruction::Less(dst, a, b)); compiler.add_instruction(Instruction::Not(dst, dst)) },
+        BinaryOperator::Slash => compiler.add_instruction(Instruction::Divide(dst, a, b)),
+    };
+    Ok(())
+}
@@ -0,0 +1,182 @@
+use crate::register::*;
+use crate::bytecode::{Constant, Upvalue};
+
+fn compile_code(data: &str) -> Module {
+    use crate::tokenizer::tokenize_with_context;
+    let tokens = tokenize_with_context(data);
+    let mut it = tokens.as_slice().iter().peekable();
+    let ast = crate::stmt_parser::parse(&mut it).unwrap();
+    super::compile(&ast).unwrap()
+}
+
+fn assert_instructions(chunk: &Chunk, instructions: Vec<Instruction>) {
+    assert_eq!(instructions, chunk.instructions());
+}
+
+#[test]
+fn test_print() {
+    let module = compile_code("print 3;");
+    assert_instructions(module.chunk(0), vec![
+        Instruction::LoadConstant(1, 0),
+        Instruction::Print(1),
+        Instruction::LoadNil(1),
+        Instruction::Return(1),
+    ]);
+    assert_eq!(vec![Constant::from(3.0)], module.constants());
+    assert_eq!(2, module.chunk(0).registers());
+}
+
+#[test]
+fn test_global_arithmetic() {
+    let module = compile_code("var x = 1 + 2 * 3;");
+    assert_instructions(module.chunk(0), vec![
+        Instruction::LoadConstant(2, 0),
+        Instruction::LoadConstant(4, 1),
+        Instruction::LoadConstant(5, 2),
+        Instruction::Multiply(3, 4, 5),
+        Instruction::Add(1, 2, 3),
+        Instruction::DefineGlobal(0, 1),
+        Instruction::LoadNil(1),
+        Instruction::Return(1),
+    ]);
+    assert_eq!(vec!["x"], module.globals().names());
+}
+
+#[test]
+fn test_locals_are_used_in_place() {
+    let module = compile_code("{ var a = 1; var b = a; a = a + b; print a; }");
+    assert_instructions(module.chunk(0), vec![
+        Instruction::LoadConstant(1, 0),
+        Instruction::Move(2, 1),
+        Instruction::Add(1, 1, 2),
+        Instruction::Print(1),
+        Instruction::LoadNil(1),
+        Instruction::Return(1),
+    ]);
+}
+
+#[test]
+fn test_operand_copied_before_side_effects() {
+    let module = compile_code("{ var a = 1; print a + (a = 2); }");
+    assert_instructions(module.chunk(0), vec![
+        Instruction::LoadConstant(1, 0),
+        Instruction::Move(3, 1),
+        Instruction::LoadConstant(1, 1),
+        Instruction::Move(4, 1),
+        Instruction::Add(2, 3, 4),
+        Instruction::Print(2),
+        Instruction::LoadNil(1),
+        Instruction::Return(1),
+    ]);
+}
+
+#[test]
+fn test_logical_into_local_uses_temporary() {
+    let module = compile_code("{ var a = 1; var b = 2; a = b and a; }");
+    assert_instructions(module.chunk(0), vec![
+        Instruction::LoadConstant(1, 0),
+        Instruction::LoadConstant(2, 1),
+        Instruction::Move(3, 2),
+        Instruction::JumpIfFalse(3, 5),
+        Instruction::Move(3, 1),
+        Instruction::Move(1, 3),
+        Instruction::LoadNil(1),
+        Instruction::Return(1),
+    ]);
+}
+
+#[test]
+fn test_while() {
+    let module = compile_code("{ var i = 0; while (i < 10) i = i + 1; }");
+    assert_instructions(module.chunk(0), vec![
+        Instruction::LoadConstant(1, 0),
+        Instruction::LoadConstant(3, 1),
+        Instruction::Less(2, 1, 3),
+        Instruction::JumpIfFalse(2, 7),
+        Instruction::LoadConstant(2, 2),
+        Instruction::Add(1, 1, 2),
+        Instruction::Jump(1),
+        Instruction::LoadNil(1),
+        Instruction::Return(1),
+    ]);
+}
+
+#[test]
+fn test_call_arguments_are_consecutive() {
+    let module = compile_code("fun add(a, b) { return a + b; } print add(1, 2);");
+    assert_instructions(module.chunk(0), vec![
+        Instruction::Closure(1, 0),
+        Instruction::DefineGlobal(0, 1),
+        Instruction::GetGlobal(1, 0),
+        Instruction::LoadConstant(2, 1),
+        Instruction::LoadConstant(3, 2),
+        Instruction::Call(1, 2),
+        Instruction::Print(1),
+        Instruction::LoadNil(1),
+        Instruction::Return(1),
+    ]);
+    assert_instructions(module.chunk(1), vec![
+        Instruction::Add(3, 1, 2),
+        Instruction::Return(3),
+        Instruction::LoadNil(3),
+        Instruction::Return(3),
+    ]);
+}
+
+#[test]
+fn test_closed_upvalues() {
+    let module = compile_code("{ var a = 1; fun f() { return a; } }");
+    assert_instructions(module.chunk(0), vec![
+        Instruction::LoadConstant(1, 0),
+        Instruction::Closure(2, 1),
+        Instruction::CloseUpvalues(1),
+        Instruction::LoadNil(1),
+        Instruction::Return(1),
+    ]);
+    assert_instructions(module.chunk(1), vec![
+        Instruction::GetUpvalue(1, 0),
+        Instruction::Return(1),
+        Instruction::LoadNil(1),
+        Instruction::Return(1),
+    ]);
+    if let Constant::Closure(closure) = &module.constants()[1] {
+        assert_eq!(vec![Upvalue::Local(1)], closure.upvalues);
+    } else {
+        panic!("expected a closure");
+    }
+}
+
+#[test]
+fn test_properties() {
+    let module = compile_code("class A {} var a = A(); a.x = 1; print a.x;");
+    assert_instructions(module.chunk(0), vec![
+        Instruction::Class(1, 0),
+        Instruction::DefineGlobal(0, 1),
+        Instruction::GetGlobal(1, 0),
+        Instruction::Call(1, 0),
+        Instruction::DefineGlobal(1, 1),
+        Instruction::GetGlobal(1, 1),
+        Instruction::LoadConstant(2, 1),
+        Instruction::SetProperty(1, 2, 2),
+        Instruction::GetGlobal(2, 1),
+        Instruction::GetProperty(1, 2, 3),
+        Instruction::Print(1),
+        Instruction::LoadNil(1),
+        Instruction::Return(1),
+    ]);
+}
+
+#[test]
+fn test_unsupported() {
+    use crate::tokenizer::tokenize_with_context;
+    use crate::bettercompiler::CompilerError;
+
+    for (code, what) in [("class A { f() {} }", "methods"), ("class A {} class B < A {}", "superclasses")].iter() {
+        let tokens = tokenize_with_context(code);
+        let ast = crate::stmt_parser::parse(&mut tokens.as_slice().iter().peekable()).unwrap();
+        match super::compile(&ast) {
+            Err(CompilerError::Multiple(errors)) => assert!(matches!(errors[..], [CompilerError::Unsupported(unsupported)] if unsupported == *what)),
+            _ => panic!("expected {} to be unsupported", what),
+        }
+    }
+}
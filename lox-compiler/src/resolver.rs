@@ -0,0 +1,363 @@
+//! Finds what every variable refers to before any code is generated: a local of the function it is used in, an
+//! upvalue or a global. Code generation works from the `Resolution`, its errors are the diagnostics of `analysis`
+//! that the linter and the language server report.
+
+use crate::ast::*;
+use crate::bettercompiler::{CompilerError, locals::Locals};
+use crate::common::ParseError;
+use crate::position::{Span, WithSpan};
+use std::collections::HashMap;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Binding {
+    /// A local of the function the variable is used in, by id. Ids are unique within the function, in order of
+    /// declaration, the stack slots are only given out once the code is optimized.
+    Local(usize),
+    /// An upvalue of the function the variable is used in, by index.
+    Upvalue(usize),
+    Global,
+}
+
+/// Where a closure gets an upvalue from when it is created, from the function around it.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Upvalue {
+    /// The local with this id.
+    Local(usize),
+    /// Its upvalue with this index.
+    Upvalue(usize),
+}
+
+#[derive(Debug, Default)]
+pub struct Resolution {
+    /// What every variable that is read or assigned refers to, by the span of its name.
+    pub bindings: HashMap<Span, Binding>,
+    /// The upvalues of every function and method with their names, by the span of its name.
+    pub upvalues: HashMap<Span, Vec<(String, Upvalue)>>,
+    /// Every error is `CompilerError::WithSpan`, with the span of the statement it is in.
+    pub errors: Vec<CompilerError>,
+}
+
+impl Resolution {
+    pub fn binding(&self, name: Span) -> Option<Binding> {
+        self.bindings.get(&name).copied()
+    }
+
+    pub fn upvalues(&self, function: Span) -> &[(String, Upvalue)] {
+        self.upvalues.get(&function).map_or(&[], Vec::as_slice)
+    }
+}
+
+/// Resolve the variables in a script. Fails only when it doesn't parse, other errors are in the resolution.
+pub fn resolve(code: &str) -> Result<Resolution, ParseError> {
+    use crate::{tokenizer::tokenize_with_context, stmt_parser::parse};
+    let tokens = tokenize_with_context(code);
+    let mut it = tokens.as_slice().iter().peekable();
+
+    let ast = parse(&mut it)?;
+    Ok(resolve_ast(&ast, false))
+}
+
+/// `top_level_return` allows returning from the script, for an expression that is evaluated as a script returning
+/// its value.
+pub(crate) fn resolve_ast(ast: &Ast, top_level_return: bool) -> Resolution {
+    let mut resolver = Resolver {
+        resolution: Resolution::default(),
+        functions: vec![],
+        classes: vec![],
+        top_level_return,
+        span: Span::default(),
+    };
+    resolver.begin_function();
+    for stmt in ast {
+        resolver.stmt(stmt);
+    }
+    resolver.resolution
+}
+
+/// The locals of a function the same way the compiler keeps them, with the slot of the function itself first.
+struct Function {
+    locals: Locals,
+    upvalues: Vec<(String, Upvalue)>,
+}
+
+impl Function {
+    fn add_upvalue(&mut self, name: &str, upvalue: Upvalue) -> usize {
+        if let Some(index) = self.upvalues.iter().position(|(_, existing)| *existing == upvalue) {
+            return index;
+        }
+        self.upvalues.push((name.to_string(), upvalue));
+        self.upvalues.len() - 1
+    }
+}
+
+struct Resolver {
+    resolution: Resolution,
+    /// The innermost function last, the script is first.
+    functions: Vec<Function>,
+    /// Whether every class around the code has a superclass, the innermost last.
+    classes: Vec<bool>,
+    top_level_return: bool,
+    /// The statement that is being resolved, where errors are reported.
+    span: Span,
+}
+
+impl Resolver {
+    fn error(&mut self, error: CompilerError) {
+        self.resolution.errors.push(CompilerError::WithSpan(WithSpan::new(Box::new(error), self.span)));
+    }
+
+    fn function(&self) -> &Function {
+        self.functions.last().expect("no function")
+    }
+
+    fn function_mut(&mut self) -> &mut Function {
+        self.functions.last_mut().expect("no function")
+    }
+
+    fn begin_function(&mut self) {
+        let mut locals = Locals::new();
+        locals.insert("");
+        locals.mark_initialized();
+        self.functions.push(Function { locals, upvalues: vec![] });
+    }
+
+    fn is_global_scope(&self) -> bool {
+        self.functions.len() == 1 && self.function().locals.scope_depth() == 0
+    }
+
+    fn with_scope(&mut self, f: impl FnOnce(&mut Self)) {
+        self.function_mut().locals.begin_scope();
+        f(self);
+        self.function_mut().locals.end_scope();
+    }
+
+    fn declare(&mut self, name: &str) {
+        if self.is_global_scope() {
+            return;
+        }
+        if self.function_mut().locals.insert(name).is_none() {
+            self.error(CompilerError::LocalAlreadyDefined);
+        }
+    }
+
+    fn mark_initialized(&mut self) {
+        if !self.is_global_scope() {
+            self.function_mut().locals.mark_initialized();
+        }
+    }
+
+    fn stmt(&mut self, stmt: &WithSpan<Stmt>) {
+        let span = std::mem::replace(&mut self.span, stmt.span);
+        match &stmt.value {
+            Stmt::Expression(expr) | Stmt::Print(expr) => self.expr(expr),
+            Stmt::Var(name, initializer, _) => {
+                self.declare(&name.value);
+                if let Some(initializer) = initializer {
+                    self.expr(initializer);
+                }
+                self.mark_initialized();
+            },
+            Stmt::If(condition, then_stmt, else_stmt) => {
+                self.expr(condition);
+                self.stmt(then_stmt);
+                if let Some(else_stmt) = else_stmt {
+                    self.stmt(else_stmt);
+                }
+            },
+            Stmt::Block(stmts) => self.block(stmts),
+            Stmt::While(condition, body) => {
+                self.expr(condition);
+                self.stmt(body);
+            },
+            Stmt::Return(expr) => {
+                if self.functions.len() == 1 && !self.top_level_return {
+                    self.error(CompilerError::ReturnAtTopLevel);
+                }
+                if let Some(expr) = expr {
+                    self.expr(expr);
+                }
+            },
+            Stmt::Function(name, params, body, _) => {
+                self.declare(&name.value);
+                // A function can call itself
+                self.mark_initialized();
+                self.function_body(name, params, body);
+            },
+            Stmt::Class(name, superclass, methods) => {
+                self.declare(&name.value);
+                self.mark_initialized();
+                if let Some(superclass) = superclass {
+                    self.variable(superclass);
+                }
+
+                self.classes.push(superclass.is_some());
+                for method in methods {
+                    if let Stmt::Function(name, params, body, _) = &method.value {
+                        let span = std::mem::replace(&mut self.span, method.span);
+                        self.function_body(name, params, body);
+                        self.span = span;
+                    }
+                }
+                self.classes.pop();
+            },
+            Stmt::Import(..) => (),
+            Stmt::Export(declaration) => self.stmt(declaration),
+        }
+        self.span = span;
+    }
+
+    fn block(&mut self, stmts: &[WithSpan<Stmt>]) {
+        self.with_scope(|resolver| for stmt in stmts {
+            resolver.stmt(stmt);
+        });
+    }
+
+    fn function_body(&mut self, name: &WithSpan<Identifier>, params: &[WithSpan<Identifier>], body: &[WithSpan<Stmt>]) {
+        self.begin_function();
+        self.with_scope(|resolver| {
+            for param in params {
+                resolver.declare(&param.value);
+                resolver.mark_initialized();
+            }
+            resolver.block(body);
+        });
+        let function = self.functions.pop().expect("no function");
+        self.resolution.upvalues.insert(name.span, function.upvalues);
+    }
+
+    fn expr(&mut self, expr: &WithSpan<Expr>) {
+        match &expr.value {
+            Expr::Variable(name) => self.variable(name),
+            Expr::Assign(name, value) => {
+                self.expr(value);
+                self.variable(name);
+            },
+            Expr::Binary(left, _, right) | Expr::Logical(left, _, right) => {
+                self.expr(left);
+                self.expr(right);
+            },
+            Expr::Grouping(expr) | Expr::Unary(_, expr) | Expr::Get(expr, _) => self.expr(expr),
+            Expr::Set(object, _, value) => {
+                self.expr(object);
+                self.expr(value);
+            },
+            Expr::Call(callee, args) => {
+                self.expr(callee);
+                for arg in args {
+                    self.expr(arg);
+                }
+            },
+            Expr::This => if self.classes.is_empty() {
+                self.error(CompilerError::ThisOutsideClass);
+            },
+            Expr::Super(_) => match self.classes.last() {
+                None => self.error(CompilerError::SuperOutsideClass),
+                Some(false) => self.error(CompilerError::SuperWithoutSuperclass),
+                Some(true) => (),
+            },
+            Expr::Number(_) | Expr::Boolean(_) | Expr::Nil | Expr::String(_) => (),
+        }
+    }
+
+    fn variable(&mut self, name: &WithSpan<Identifier>) {
+        let function = self.functions.len() - 1;
+        let binding = match self.local(function, &name.value) {
+            Some(id) => Binding::Local(id),
+            None => self.upvalue(function, &name.value).map_or(Binding::Global, Binding::Upvalue),
+        };
+        self.resolution.bindings.insert(name.span, binding);
+    }
+
+    /// The id of the local with the name in the function.
+    fn local(&mut self, function: usize, name: &str) -> Option<usize> {
+        let local = self.functions[function].locals.get(name)?;
+        let (id, initialized) = (local.id(), local.initialized());
+        if !initialized {
+            self.error(CompilerError::LocalNotInitialized);
+        }
+        Some(id)
+    }
+
+    /// The index of the upvalue of the function for a local of a function around it, it is added to every function in
+    /// between.
+    fn upvalue(&mut self, function: usize, name: &str) -> Option<usize> {
+        let enclosing = function.checked_sub(1)?;
+        let upvalue = match self.local(enclosing, name) {
+            Some(id) => Upvalue::Local(id),
+            None => Upvalue::Upvalue(self.upvalue(enclosing, name)?),
+        };
+        Some(self.functions[function].add_upvalue(name, upvalue))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::position::Position;
+
+    /// The binding of the variable used at the line and column.
+    fn binding(resolution: &Resolution, line: usize, column: usize) -> Option<Binding> {
+        resolution.bindings.iter()
+            .find(|(span, _)| span.start == Position { line, column })
+            .map(|(_, binding)| *binding)
+    }
+
+    fn errors(code: &str) -> Vec<String> {
+        resolve(code).unwrap().errors.iter().map(|error| error.to_string()).collect()
+    }
+
+    #[test]
+    fn test_bindings() {
+        let resolution = resolve("var a = 1;\n{ var b = a; { var c = b; } var d = b; print d; }").unwrap();
+        assert!(resolution.errors.is_empty());
+        assert_eq!(binding(&resolution, 2, 11), Some(Binding::Global));
+        assert_eq!(binding(&resolution, 2, 24), Some(Binding::Local(1)));
+        // `d` is declared where `c` was, but doesn't get its id
+        assert_eq!(binding(&resolution, 2, 46), Some(Binding::Local(3)));
+    }
+
+    #[test]
+    fn test_upvalues() {
+        let code = "\
+fun outer(a) {
+  var b = 2;
+  fun middle() {
+    fun inner() { return a + b + a; }
+    return b;
+  }
+  return middle;
+}";
+        let resolution = resolve(code).unwrap();
+        assert!(resolution.errors.is_empty());
+        assert_eq!(binding(&resolution, 4, 26), Some(Binding::Upvalue(0)));
+        assert_eq!(binding(&resolution, 4, 30), Some(Binding::Upvalue(1)));
+        assert_eq!(binding(&resolution, 4, 34), Some(Binding::Upvalue(0)));
+        assert_eq!(binding(&resolution, 5, 12), Some(Binding::Upvalue(1)));
+        assert_eq!(binding(&resolution, 7, 10), Some(Binding::Local(3)));
+
+        let upvalues = |line, column| {
+            let (_, upvalues) = resolution.upvalues.iter().find(|(span, _)| span.start == Position { line, column }).unwrap();
+            upvalues.iter().map(|(name, upvalue)| (name.as_str(), *upvalue)).collect::<Vec<_>>()
+        };
+        assert_eq!(upvalues(3, 7), vec![("a", Upvalue::Local(1)), ("b", Upvalue::Local(2))]);
+        assert_eq!(upvalues(4, 9), vec![("a", Upvalue::Upvalue(0)), ("b", Upvalue::Upvalue(1))]);
+        assert_eq!(upvalues(1, 5), vec![]);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(errors("return 1;"), vec!["[line 1:1] Can't return from top-level code."]);
+        assert_eq!(errors("fun f() { return 1; }\nprint this;"), vec!["[line 2:1] Can't use 'this' outside of a class."]);
+        assert_eq!(errors("class A { f() { return this; } }\nfun g() { return this; }"), vec![
+            "[line 2:11] Can't use 'this' outside of a class.",
+        ]);
+        assert_eq!(errors("class A { f() { return super.f; } }\nprint super.f;"), vec![
+            "[line 1:17] Can't use 'super' in a class with no superclass.",
+            "[line 2:1] Can't use 'super' outside of a class.",
+        ]);
+        assert_eq!(errors("var a = a;\n{ var b = b; }\nfun f(c, c) {}"), vec![
+            "[line 2:3] Can't read local variable in its own initializer.",
+            "[line 3:1] Already a variable with this name in this scope.",
+        ]);
+    }
+}
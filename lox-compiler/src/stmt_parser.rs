@@ -2,15 +2,15 @@ use super::ast::*;
 use super::common::*;
 use super::token::*;
 use std::iter::{Iterator, Peekable};
-use crate::position::WithSpan;
+use crate::position::{Span, WithSpan};
 
-fn parse_program<'a, It>(it: &mut Peekable<It>) -> Result<Vec<Stmt>, ParseError>
+fn parse_program<'a, It>(it: &mut Peekable<It>) -> Result<Vec<WithSpan<Stmt>>, ParseError>
 where
     It: Iterator<Item = &'a WithSpan<Token>>,
 {
     let mut statements = Vec::new();
-    while let Some(_) = it.peek() {
-        statements.push(parse_declaration(it)?);
+    while it.peek().is_some() {
+        statements.push(parse_top_level_declaration(it)?);
     }
     match it.next() {
         Some(t) => Err(ParseError { error: "Expected None".into(), span: Some(t.span) }),
@@ -18,130 +18,203 @@ where
     }
 }
 
-fn parse_declaration<'a, It>(it: &mut Peekable<It>) -> Result<Stmt, ParseError>
+/// Imports and exports are only allowed at the top level of a module.
+fn parse_top_level_declaration<'a, It>(it: &mut Peekable<It>) -> Result<WithSpan<Stmt>, ParseError>
 where
     It: Iterator<Item = &'a WithSpan<Token>>,
 {
     match peek(it)? {
-        &Token::Var => parse_var_declaration(it),
-        &Token::Fun => parse_function_declaration(it),
-        &Token::Class => parse_class_declaration(it),
+        Token::Import => parse_import(it),
+        Token::Export => parse_export(it),
+        _ => parse_declaration(it),
+    }
+}
+
+fn parse_import<'a, It>(it: &mut Peekable<It>) -> Result<WithSpan<Stmt>, ParseError>
+where
+    It: Iterator<Item = &'a WithSpan<Token>>,
+{
+    let start = expect(it, &Token::Import)?.span;
+    let names = if optionally(it, &Token::LeftBrace)? {
+        let mut names = vec![expect_with_span!(it, Token::Identifier(i) => i.clone())?];
+        while optionally(it, &Token::Comma)? {
+            names.push(expect_with_span!(it, Token::Identifier(i) => i.clone())?);
+        }
+        expect(it, &Token::RightBrace)?;
+        expect(it, &Token::Identifier("from".into()))?;
+        Some(names)
+    } else {
+        None
+    };
+    let path = expect_with_span!(it, Token::String(path) => path.clone())?;
+    let end = expect(it, &Token::Semicolon)?.span;
+    Ok(WithSpan::new(Stmt::Import(path, names), Span::union(start, end)))
+}
+
+fn parse_export<'a, It>(it: &mut Peekable<It>) -> Result<WithSpan<Stmt>, ParseError>
+where
+    It: Iterator<Item = &'a WithSpan<Token>>,
+{
+    let start = expect(it, &Token::Export)?.span;
+    let declaration = match peek(it)? {
+        Token::Var => parse_var_declaration(it)?,
+        Token::Fun => parse_function_declaration(it)?,
+        Token::Class => parse_class_declaration(it)?,
+        token => {
+            let error = format!("Expected a declaration after Export got {:?}", token);
+            return Err(ParseError { error, span: Some(peek_span(it)?) });
+        },
+    };
+    let span = Span::union(start, declaration.span);
+    Ok(WithSpan::new(Stmt::Export(Box::new(declaration)), span))
+}
+
+fn parse_declaration<'a, It>(it: &mut Peekable<It>) -> Result<WithSpan<Stmt>, ParseError>
+where
+    It: Iterator<Item = &'a WithSpan<Token>>,
+{
+    match *peek(it)? {
+        Token::Var => parse_var_declaration(it),
+        Token::Fun => parse_function_declaration(it),
+        Token::Class => parse_class_declaration(it),
         _ => parse_statement(it),
     }
 }
 
-fn parse_statement<'a, It>(it: &mut Peekable<It>) -> Result<Stmt, ParseError>
+fn parse_statement<'a, It>(it: &mut Peekable<It>) -> Result<WithSpan<Stmt>, ParseError>
 where
     It: Iterator<Item = &'a WithSpan<Token>>,
 {
-    match peek(it)? {
-        &Token::Print => parse_print_statement(it),
-        &Token::If => parse_if_statement(it),
-        &Token::LeftBrace => parse_block_statement(it),
-        &Token::While => parse_while_statement(it),
-        &Token::Return => parse_return_statement(it),
-        &Token::For => parse_for_statement(it),
+    match *peek(it)? {
+        Token::Print => parse_print_statement(it),
+        Token::If => parse_if_statement(it),
+        Token::LeftBrace => parse_block_statement(it),
+        Token::While => parse_while_statement(it),
+        Token::Return => parse_return_statement(it),
+        Token::For => parse_for_statement(it),
         _ => parse_expr_statement(it),
     }
 }
 
-fn parse_class_declaration<'a, It>(it: &mut Peekable<It>) -> Result<Stmt, ParseError>
+fn parse_class_declaration<'a, It>(it: &mut Peekable<It>) -> Result<WithSpan<Stmt>, ParseError>
 where
     It: Iterator<Item = &'a WithSpan<Token>>,
 {
-    expect(it, &Token::Class)?;
-    let name = expect!(it, Token::Identifier(i) => i)?;
+    let start = expect(it, &Token::Class)?.span;
+    let name = expect_with_span!(it, Token::Identifier(i) => i.clone())?;
     let superclass = if optionally(it, &Token::Less)? {
-        let name = expect!(it, Token::Identifier(i) => i)?;
-        Some(name.clone())
+        Some(expect_with_span!(it, Token::Identifier(i) => i.clone())?)
     } else {
         None
     };
     expect(it, &Token::LeftBrace)?;
-    let mut functions: Vec<Stmt> = vec![];
+    let mut functions: Vec<WithSpan<Stmt>> = vec![];
     while peek(it)? != &Token::RightBrace {
         functions.push(parse_function(it)?);
     }
-    expect(it, &Token::RightBrace)?;
+    let end = expect(it, &Token::RightBrace)?.span;
 
-    Ok(Stmt::Class(name.clone(), superclass, functions))
+    Ok(WithSpan::new(Stmt::Class(name, superclass, functions), Span::union(start, end)))
 }
 
-fn parse_function_declaration<'a, It>(it: &mut Peekable<It>) -> Result<Stmt, ParseError>
+fn parse_function_declaration<'a, It>(it: &mut Peekable<It>) -> Result<WithSpan<Stmt>, ParseError>
 where
     It: Iterator<Item = &'a WithSpan<Token>>,
 {
-    expect(it, &Token::Fun)?;
-    parse_function(it)
+    let start = expect(it, &Token::Fun)?.span;
+    let function = parse_function(it)?;
+    Ok(WithSpan::new(function.value, Span::union(start, function.span)))
 }
 
-fn parse_function<'a, It>(it: &mut Peekable<It>) -> Result<Stmt, ParseError>
+fn parse_function<'a, It>(it: &mut Peekable<It>) -> Result<WithSpan<Stmt>, ParseError>
 where
     It: Iterator<Item = &'a WithSpan<Token>>,
 {
-    let name = expect!(it, Token::Identifier(i) => i)?;
+    let name = expect_with_span!(it, Token::Identifier(i) => i.clone())?;
     expect(it, &Token::LeftParen)?;
-    let params = if peek(it)? != &Token::RightParen {
+    let (params, param_types) = if peek(it)? != &Token::RightParen {
         parse_params(it)?
     } else {
-        Vec::new()
+        (Vec::new(), Vec::new())
     };
     expect(it, &Token::RightParen)?;
+    let returns = parse_type_annotation(it)?;
     expect(it, &Token::LeftBrace)?;
-    let mut body: Vec<Stmt> = Vec::new();
+    let mut body: Vec<WithSpan<Stmt>> = Vec::new();
     while peek(it)? != &Token::RightBrace {
         body.push(parse_declaration(it)?);
     }
-    expect(it, &Token::RightBrace)?;
-    Ok(Stmt::Function(name.clone(), params, body))
+    let end = expect(it, &Token::RightBrace)?.span;
+    let span = Span::union(name.span, end);
+    let signature = Signature { params: param_types, returns };
+    Ok(WithSpan::new(Stmt::Function(name, params, body, signature), span))
 }
 
-fn parse_params<'a, It>(it: &mut Peekable<It>) -> Result<Vec<Identifier>, ParseError>
+/// The parameters and their annotated types.
+type Params = (Vec<WithSpan<Identifier>>, Vec<Option<TypeName>>);
+
+fn parse_params<'a, It>(it: &mut Peekable<It>) -> Result<Params, ParseError>
 where
     It: Iterator<Item = &'a WithSpan<Token>>,
 {
-    let mut params: Vec<Identifier> = Vec::new();
-    params.push(expect!(it, Token::Identifier(i) => i.clone())?);
+    let mut params: Vec<WithSpan<Identifier>> = Vec::new();
+    let mut types = Vec::new();
+    params.push(expect_with_span!(it, Token::Identifier(i) => i.clone())?);
+    types.push(parse_type_annotation(it)?);
     while peek(it)? == &Token::Comma {
         expect(it, &Token::Comma)?;
-        params.push(expect!(it, Token::Identifier(i) => i.clone())?);
+        params.push(expect_with_span!(it, Token::Identifier(i) => i.clone())?);
+        types.push(parse_type_annotation(it)?);
+    }
+    Ok((params, types))
+}
+
+/// An optional `: Type`.
+fn parse_type_annotation<'a, It>(it: &mut Peekable<It>) -> Result<Option<TypeName>, ParseError>
+where
+    It: Iterator<Item = &'a WithSpan<Token>>,
+{
+    if optionally(it, &Token::Colon)? {
+        Ok(Some(expect_with_span!(it, Token::Identifier(i) => i.clone())?))
+    } else {
+        Ok(None)
     }
-    Ok(params)
 }
 
-fn parse_var_declaration<'a, It>(it: &mut Peekable<It>) -> Result<Stmt, ParseError>
+fn parse_var_declaration<'a, It>(it: &mut Peekable<It>) -> Result<WithSpan<Stmt>, ParseError>
 where
     It: Iterator<Item = &'a WithSpan<Token>>,
 {
-    expect(it, &Token::Var)?;
+    let start = expect(it, &Token::Var)?.span;
     let name = expect_with_span!(it, Token::Identifier(i) => i.clone())?;
-    let mut initializer: Option<Expr> = None;
+    let type_name = parse_type_annotation(it)?;
+    let mut initializer: Option<WithSpan<Expr>> = None;
 
     if optionally(it, &Token::Equal)? {
         initializer = Some(parse_expr(it)?);
     }
 
-    expect(it, &Token::Semicolon)?;
+    let end = expect(it, &Token::Semicolon)?.span;
 
-    Ok(Stmt::Var(name, initializer.map(Box::new)))
+    Ok(WithSpan::new(Stmt::Var(name, initializer.map(Box::new), type_name), Span::union(start, end)))
 }
 
-fn parse_expr<'a, It>(it: &mut Peekable<It>) -> Result<Expr, ParseError>
+fn parse_expr<'a, It>(it: &mut Peekable<It>) -> Result<WithSpan<Expr>, ParseError>
 where
     It: Iterator<Item = &'a WithSpan<Token>>,
 {
-    super::expr_parser::parse(it).map_err(|e| e.into())
+    super::expr_parser::parse(it)
 }
 
-fn parse_for_statement<'a, It>(it: &mut Peekable<It>) -> Result<Stmt, ParseError>
+fn parse_for_statement<'a, It>(it: &mut Peekable<It>) -> Result<WithSpan<Stmt>, ParseError>
 where
     It: Iterator<Item = &'a WithSpan<Token>>,
 {
-    expect(it, &Token::For)?;
+    let start = expect(it, &Token::For)?.span;
     expect(it, &Token::LeftParen)?;
-    let initializer = match peek(it)? {
-        &Token::Var => Some(parse_var_declaration(it)?),
-        &Token::Semicolon => {
+    let initializer = match *peek(it)? {
+        Token::Var => Some(parse_var_declaration(it)?),
+        Token::Semicolon => {
             expect(it, &Token::Semicolon)?;
             None
         }
@@ -150,7 +223,8 @@ where
     let condition = if peek(it)? != &Token::Semicolon {
         parse_expr(it)?
     } else {
-        Expr::Boolean(true)
+        // A loop without a condition loops while `true`, the span of the missing condition is that of the semicolon
+        WithSpan::new(Expr::Boolean(true), peek_span(it)?)
     };
     expect(it, &Token::Semicolon)?;
     let increment = if peek(it)? != &Token::RightParen {
@@ -158,103 +232,108 @@ where
     } else {
         None
     };
-    expect(it, &Token::RightParen)?;
+    // The statements the loop is made of get the span of the whole loop, the increment that of the parentheses
+    let header = Span::union(start, expect(it, &Token::RightParen)?.span);
     let body = parse_statement(it)?;
+    let span = Span::union(start, body.span);
     // Add increment if it exists
     let body = match increment {
-        Some(expr) => Stmt::Block(vec![body, Stmt::Expression(Box::new(expr))]),
+        Some(expr) => WithSpan::new(Stmt::Block(vec![body, WithSpan::new(Stmt::Expression(Box::new(expr)), header)]), span),
         None => body,
     };
-    let body = Stmt::While(Box::new(condition), Box::new(body));
+    let body = WithSpan::new(Stmt::While(Box::new(condition), Box::new(body)), span);
     let body = match initializer {
-        Some(stmt) => Stmt::Block(vec![stmt, body]),
+        Some(stmt) => WithSpan::new(Stmt::Block(vec![stmt, body]), span),
         None => body,
     };
 
     Ok(body)
 }
 
-fn parse_return_statement<'a, It>(it: &mut Peekable<It>) -> Result<Stmt, ParseError>
+fn parse_return_statement<'a, It>(it: &mut Peekable<It>) -> Result<WithSpan<Stmt>, ParseError>
 where
     It: Iterator<Item = &'a WithSpan<Token>>,
 {
-    expect(it, &Token::Return)?;
-    let mut expr: Option<Expr> = None;
+    let start = expect(it, &Token::Return)?.span;
+    let mut expr: Option<WithSpan<Expr>> = None;
     if peek(it)? != &Token::Semicolon {
         expr = Some(parse_expr(it)?);
     }
-    expect(it, &Token::Semicolon)?;
-    Ok(Stmt::Return(expr.map(Box::new)))
+    let end = expect(it, &Token::Semicolon)?.span;
+    Ok(WithSpan::new(Stmt::Return(expr.map(Box::new)), Span::union(start, end)))
 }
 
-fn parse_expr_statement<'a, It>(it: &mut Peekable<It>) -> Result<Stmt, ParseError>
+fn parse_expr_statement<'a, It>(it: &mut Peekable<It>) -> Result<WithSpan<Stmt>, ParseError>
 where
     It: Iterator<Item = &'a WithSpan<Token>>,
 {
+    let start = peek_span(it)?;
     let expr = parse_expr(it)?;
-    expect(it, &Token::Semicolon)?;
+    let end = expect(it, &Token::Semicolon)?.span;
 
-    Ok(Stmt::Expression(Box::new(expr)))
+    Ok(WithSpan::new(Stmt::Expression(Box::new(expr)), Span::union(start, end)))
 }
 
-fn parse_block_statement<'a, It>(it: &mut Peekable<It>) -> Result<Stmt, ParseError>
+fn parse_block_statement<'a, It>(it: &mut Peekable<It>) -> Result<WithSpan<Stmt>, ParseError>
 where
     It: Iterator<Item = &'a WithSpan<Token>>,
 {
-    expect(it, &Token::LeftBrace)?;
-    let mut statements: Vec<Stmt> = Vec::new();
+    let start = expect(it, &Token::LeftBrace)?.span;
+    let mut statements: Vec<WithSpan<Stmt>> = Vec::new();
     while peek(it)? != &Token::RightBrace {
         statements.push(parse_declaration(it)?);
     }
-    expect(it, &Token::RightBrace)?;
-    Ok(Stmt::Block(statements))
+    let end = expect(it, &Token::RightBrace)?.span;
+    Ok(WithSpan::new(Stmt::Block(statements), Span::union(start, end)))
 }
 
-fn parse_while_statement<'a, It>(it: &mut Peekable<It>) -> Result<Stmt, ParseError>
+fn parse_while_statement<'a, It>(it: &mut Peekable<It>) -> Result<WithSpan<Stmt>, ParseError>
 where
     It: Iterator<Item = &'a WithSpan<Token>>,
 {
-    expect(it, &Token::While)?;
+    let start = expect(it, &Token::While)?.span;
     expect(it, &Token::LeftParen)?;
     let condition = parse_expr(it)?;
     expect(it, &Token::RightParen)?;
     let statement = parse_statement(it)?;
-    Ok(Stmt::While(Box::new(condition), Box::new(statement)))
+    let span = Span::union(start, statement.span);
+    Ok(WithSpan::new(Stmt::While(Box::new(condition), Box::new(statement)), span))
 }
 
-fn parse_if_statement<'a, It>(it: &mut Peekable<It>) -> Result<Stmt, ParseError>
+fn parse_if_statement<'a, It>(it: &mut Peekable<It>) -> Result<WithSpan<Stmt>, ParseError>
 where
     It: Iterator<Item = &'a WithSpan<Token>>,
 {
-    expect(it, &Token::If)?;
+    let start = expect(it, &Token::If)?.span;
     expect(it, &Token::LeftParen)?;
     let condition = parse_expr(it)?;
     expect(it, &Token::RightParen)?;
     let if_stmt = parse_statement(it)?;
-    let mut else_stmt: Option<Stmt> = None;
+    let mut else_stmt: Option<WithSpan<Stmt>> = None;
 
     if optionally(it, &Token::Else)? {
         else_stmt = Some(parse_statement(it)?);
     }
 
-    Ok(Stmt::If(
+    let end = else_stmt.as_ref().unwrap_or(&if_stmt).span;
+    Ok(WithSpan::new(Stmt::If(
         Box::new(condition),
         Box::new(if_stmt),
         else_stmt.map(Box::new),
-    ))
+    ), Span::union(start, end)))
 }
 
-fn parse_print_statement<'a, It>(it: &mut Peekable<It>) -> Result<Stmt, ParseError>
+fn parse_print_statement<'a, It>(it: &mut Peekable<It>) -> Result<WithSpan<Stmt>, ParseError>
 where
     It: Iterator<Item = &'a WithSpan<Token>>,
 {
-    expect(it, &Token::Print)?;
+    let start = expect(it, &Token::Print)?.span;
     let expr = parse_expr(it)?;
-    expect(it, &Token::Semicolon)?;
-    Ok(Stmt::Print(Box::new(expr)))
+    let end = expect(it, &Token::Semicolon)?.span;
+    Ok(WithSpan::new(Stmt::Print(Box::new(expr)), Span::union(start, end)))
 }
 
-pub fn parse<'a, It>(it: &mut Peekable<It>) -> Result<Vec<Stmt>, ParseError>
+pub fn parse<'a, It>(it: &mut Peekable<It>) -> Result<Ast, ParseError>
 where
     It: Iterator<Item = &'a WithSpan<Token>>,
 {
@@ -267,21 +346,89 @@ mod tests {
     use super::*;
     fn parse_str(data: &str) -> Result<Vec<Stmt>, String> {
         let tokens = tokenize_with_context(data);
-        let mut it = tokens.as_slice().into_iter().peekable();
-        parse(&mut it).map_err(|e| e.error) //TODO
+        let mut it = tokens.as_slice().iter().peekable();
+        let ast = parse(&mut it).map_err(|e| e.error)?; //TODO
+        Ok(ast.into_iter().map(|stmt| without_spans(stmt).value).collect())
+    }
+
+    /// Statement and identifier spans are tested separately, the other tests compare statements without them.
+    /// Only the name of a variable declaration keeps its span.
+    fn without_spans(stmt: WithSpan<Stmt>) -> WithSpan<Stmt> {
+        let strip = |stmts: Vec<WithSpan<Stmt>>| stmts.into_iter().map(without_spans).collect();
+        let expr = |expr: Box<WithSpan<Expr>>| Box::new(expr.without_spans());
+        let name = |name: WithSpan<Identifier>| WithSpan::from(name.value.as_str());
+        let stmt = match stmt.value {
+            Stmt::Expression(e) => Stmt::Expression(expr(e)),
+            Stmt::Print(e) => Stmt::Print(expr(e)),
+            Stmt::Var(identifier, initializer, type_name) => Stmt::Var(identifier, initializer.map(expr), type_name.map(name)),
+            Stmt::If(condition, then_stmt, else_stmt) => Stmt::If(
+                expr(condition),
+                Box::new(without_spans(*then_stmt)),
+                else_stmt.map(|stmt| Box::new(without_spans(*stmt))),
+            ),
+            Stmt::Block(stmts) => Stmt::Block(strip(stmts)),
+            Stmt::While(condition, body) => Stmt::While(expr(condition), Box::new(without_spans(*body))),
+            Stmt::Return(e) => Stmt::Return(e.map(expr)),
+            Stmt::Function(identifier, params, body, signature) => {
+                let params = params.into_iter().map(name).collect();
+                let signature = Signature {
+                    params: signature.params.into_iter().map(|type_name| type_name.map(name)).collect(),
+                    returns: signature.returns.map(name),
+                };
+                Stmt::Function(name(identifier), params, strip(body), signature)
+            },
+            Stmt::Class(identifier, superclass, methods) => Stmt::Class(name(identifier), superclass.map(name), strip(methods)),
+            Stmt::Import(path, names) => Stmt::Import(name(path), names.map(|names| names.into_iter().map(name).collect())),
+            Stmt::Export(declaration) => Stmt::Export(Box::new(without_spans(*declaration))),
+        };
+        s(stmt)
+    }
+
+    fn s(stmt: Stmt) -> WithSpan<Stmt> {
+        WithSpan::new(stmt, Span::default())
+    }
+
+    fn e(expr: Expr) -> Box<WithSpan<Expr>> {
+        Box::new(expr.into())
+    }
+
+    fn span(start: (usize, usize), end: (usize, usize)) -> Span {
+        use crate::position::Position;
+        Span {
+            start: Position { line: start.0, column: start.1 },
+            end: Position { line: end.0, column: end.1 },
+        }
+    }
+
+    #[test]
+    fn test_stmt_spans() {
+        let tokens = tokenize_with_context("print 1;\nif (x) {\n  x = 2;\n}\nfor (;;) f();");
+        let mut it = tokens.as_slice().iter().peekable();
+        let ast = parse(&mut it).unwrap();
+
+        assert_eq!(ast[0].span, span((1, 1), (1, 8)));
+        assert_eq!(ast[1].span, span((2, 1), (4, 1)));
+        match &ast[1].value {
+            Stmt::If(_, then_stmt, None) => match &then_stmt.value {
+                Stmt::Block(stmts) => assert_eq!(stmts[0].span, span((3, 3), (3, 8))),
+                stmt => panic!("expected a block, got {:?}", stmt),
+            },
+            stmt => panic!("expected an if, got {:?}", stmt),
+        }
+        assert_eq!(ast[2].span, span((5, 1), (5, 13)));
     }
 
     #[test]
     fn test_expr_stmt() {
         assert_eq!(
             parse_str("nil;"),
-            Ok(vec![Stmt::Expression(Box::new(Expr::Nil)),])
+            Ok(vec![Stmt::Expression(e(Expr::Nil)),])
         );
         assert_eq!(
             parse_str("nil;nil;"),
             Ok(vec![
-                Stmt::Expression(Box::new(Expr::Nil)),
-                Stmt::Expression(Box::new(Expr::Nil)),
+                Stmt::Expression(e(Expr::Nil)),
+                Stmt::Expression(e(Expr::Nil)),
             ])
         );
     }
@@ -290,7 +437,7 @@ mod tests {
     fn test_print_stmt() {
         assert_eq!(
             parse_str("print nil;"),
-            Ok(vec![Stmt::Print(Box::new(Expr::Nil)),])
+            Ok(vec![Stmt::Print(e(Expr::Nil)),])
         );
     }
 
@@ -305,13 +452,14 @@ mod tests {
     fn test_var_decl() {
         assert_eq!(
             parse_str("var beverage;"),
-            Ok(vec![Stmt::Var(make_span_string("beverage", 5), None),])
+            Ok(vec![Stmt::Var(make_span_string("beverage", 5), None, None),])
         );
         assert_eq!(
             parse_str("var beverage = nil;"),
             Ok(vec![Stmt::Var(
                 make_span_string("beverage", 5),
-                Some(Box::new(Expr::Nil))
+                Some(e(Expr::Nil)),
+                None
             ),])
         );
 
@@ -319,7 +467,8 @@ mod tests {
             parse_str("var beverage = x = nil;"),
             Ok(vec![Stmt::Var(
                 make_span_string("beverage", 5),
-                Some(Box::new(Expr::Assign("x".into(), Box::new(Expr::Nil))))
+                Some(e(Expr::Assign("x".into(), e(Expr::Nil)))),
+                None
             ),])
         );
 
@@ -334,17 +483,17 @@ mod tests {
         assert_eq!(
             parse_str("if(nil) print nil;"),
             Ok(vec![Stmt::If(
-                Box::new(Expr::Nil),
-                Box::new(Stmt::Print(Box::new(Expr::Nil))),
+                e(Expr::Nil),
+                Box::new(s(Stmt::Print(e(Expr::Nil)))),
                 None,
             ),])
         );
         assert_eq!(
             parse_str("if(nil) print nil; else print false;"),
             Ok(vec![Stmt::If(
-                Box::new(Expr::Nil),
-                Box::new(Stmt::Print(Box::new(Expr::Nil))),
-                Some(Box::new(Stmt::Print(Box::new(Expr::Boolean(false))))),
+                e(Expr::Nil),
+                Box::new(s(Stmt::Print(e(Expr::Nil)))),
+                Some(Box::new(s(Stmt::Print(e(Expr::Boolean(false)))))),
             ),])
         );
     }
@@ -354,15 +503,15 @@ mod tests {
         assert_eq!(parse_str("{}"), Ok(vec![Stmt::Block(vec![])]));
         assert_eq!(
             parse_str("{nil;}"),
-            Ok(vec![Stmt::Block(vec![Stmt::Expression(Box::new(
+            Ok(vec![Stmt::Block(vec![s(Stmt::Expression(e(
                 Expr::Nil
-            )),])])
+            ))),])])
         );
         assert_eq!(
             parse_str("{nil;nil;}"),
             Ok(vec![Stmt::Block(vec![
-                Stmt::Expression(Box::new(Expr::Nil)),
-                Stmt::Expression(Box::new(Expr::Nil)),
+                s(Stmt::Expression(e(Expr::Nil))),
+                s(Stmt::Expression(e(Expr::Nil))),
             ])])
         );
     }
@@ -372,8 +521,8 @@ mod tests {
         assert_eq!(
             parse_str("while(nil)false;"),
             Ok(vec![Stmt::While(
-                Box::new(Expr::Nil),
-                Box::new(Stmt::Expression(Box::new(Expr::Boolean(false)))),
+                e(Expr::Nil),
+                Box::new(s(Stmt::Expression(e(Expr::Boolean(false))))),
             )])
         );
     }
@@ -383,7 +532,7 @@ mod tests {
         assert_eq!(parse_str("return;"), Ok(vec![Stmt::Return(None),]));
         assert_eq!(
             parse_str("return nil;"),
-            Ok(vec![Stmt::Return(Some(Box::new(Expr::Nil)))])
+            Ok(vec![Stmt::Return(Some(e(Expr::Nil)))])
         );
     }
 
@@ -391,14 +540,15 @@ mod tests {
     fn test_function_stmt() {
         assert_eq!(
             parse_str("fun test(){}"),
-            Ok(vec![Stmt::Function("test".into(), vec![], vec![]),])
+            Ok(vec![Stmt::Function("test".into(), vec![], vec![], Signature::default()),])
         );
         assert_eq!(
             parse_str("fun test(a){}"),
             Ok(vec![Stmt::Function(
                 "test".into(),
                 vec!["a".into()],
-                vec![]
+                vec![],
+                Signature { params: vec![None], returns: None }
             ),])
         );
         assert_eq!(
@@ -406,11 +556,49 @@ mod tests {
             Ok(vec![Stmt::Function(
                 "test".into(),
                 vec![],
-                vec![Stmt::Expression(Box::new(Expr::Nil,)),]
+                vec![s(Stmt::Expression(e(Expr::Nil,))),],
+                Signature::default()
             ),])
         );
     }
 
+    #[test]
+    fn test_type_annotations() {
+        assert_eq!(
+            parse_str("var name: String = nil;"),
+            Ok(vec![Stmt::Var(make_span_string("name", 5), Some(e(Expr::Nil)), Some("String".into()))])
+        );
+        assert_eq!(
+            parse_str("fun add(a: Number, b): Number {}"),
+            Ok(vec![Stmt::Function(
+                "add".into(),
+                vec!["a".into(), "b".into()],
+                vec![],
+                Signature { params: vec![Some("Number".into()), None], returns: Some("Number".into()) }
+            )])
+        );
+        assert_eq!(parse_str("var a: = 1;"), Err("Unexpected Equal".into()));
+    }
+
+    #[test]
+    fn test_imports() {
+        assert_eq!(
+            parse_str("import \"lib.lox\";"),
+            Ok(vec![Stmt::Import("lib.lox".into(), None)])
+        );
+        assert_eq!(
+            parse_str("import { a, b } from \"lib.lox\";"),
+            Ok(vec![Stmt::Import("lib.lox".into(), Some(vec!["a".into(), "b".into()]))])
+        );
+        assert_eq!(
+            parse_str("export var a = 1;"),
+            Ok(vec![Stmt::Export(Box::new(s(Stmt::Var(make_span_string("a", 12), Some(e(Expr::Number(1.0))), None))))])
+        );
+        assert_eq!(parse_str("import { a } \"lib.lox\";"), Err("Expected Identifier(\"from\") got String(\"lib.lox\")".into()));
+        assert_eq!(parse_str("export print 1;"), Err("Expected a declaration after Export got Print".into()));
+        assert_eq!(parse_str("{ import \"lib.lox\"; }"), Err("unexpected token: Import".into()));
+    }
+
     #[test]
     fn test_class_stmt() {
         assert_eq!(
@@ -422,7 +610,7 @@ mod tests {
             Ok(vec![Stmt::Class(
                 "test".into(),
                 None,
-                vec![Stmt::Function("a".into(), vec![], vec![])]
+                vec![s(Stmt::Function("a".into(), vec![], vec![], Signature::default()))]
             )])
         );
     }
@@ -450,36 +638,36 @@ mod tests {
     #[test]
     fn test_for() {
         fn block(what: Vec<Stmt>) -> Stmt {
-            Stmt::Block(what)
+            Stmt::Block(what.into_iter().map(s).collect())
         }
         fn var_i_zero() -> Stmt {
-            Stmt::Var(make_span_string("i", 9), Some(Box::new(Expr::Number(0.))))
+            Stmt::Var(make_span_string("i", 9), Some(e(Expr::Number(0.))), None)
         }
         fn nil() -> Expr {
             Expr::Nil
         }
-        fn while_stmt(e: Expr, s: Stmt) -> Stmt {
-            Stmt::While(Box::new(e), Box::new(s))
+        fn while_stmt(e: Expr, body: Stmt) -> Stmt {
+            Stmt::While(Box::new(e.into()), Box::new(s(body)))
         }
 
         assert_eq!(
             parse_str("for(;;){}"),
-            Ok(vec![while_stmt(Expr::Boolean(true), Stmt::Block(vec![])),])
+            Ok(vec![while_stmt(Expr::Boolean(true), block(vec![])),])
         );
         assert_eq!(
             parse_str("for(var i=0;;){}"),
             Ok(vec![block(vec![
                 var_i_zero(),
-                while_stmt(Expr::Boolean(true), Stmt::Block(vec![])),
+                while_stmt(Expr::Boolean(true), block(vec![])),
             ])])
         );
         assert_eq!(
             parse_str("for(nil;nil;nil){}"),
             Ok(vec![block(vec![
-                Stmt::Expression(Box::new(nil())),
+                Stmt::Expression(e(nil())),
                 while_stmt(
                     Expr::Nil,
-                    Stmt::Block(vec![Stmt::Block(vec![]), Stmt::Expression(Box::new(nil())),])
+                    block(vec![block(vec![]), Stmt::Expression(e(nil())),])
                 ),
             ])])
         );
@@ -13,6 +13,7 @@ pub enum Token {
     Semicolon,
     Slash,
     Star,
+    Colon,
     // One or two character tokens.
     Bang,
     BangEqual,
@@ -30,10 +31,12 @@ pub enum Token {
     And,
     Class,
     Else,
+    Export,
     False,
     Fun,
     For,
     If,
+    Import,
     Nil,
     Or,
     Print,
@@ -43,5 +46,11 @@ pub enum Token {
     True,
     Var,
     While,
+    // Trivia, only when comments are kept.
+    Comment(String),
+    // Only when trivia is kept.
+    Whitespace,
+    /// A character that isn't Lox or an unterminated string.
+    Error,
     Eof,
 }
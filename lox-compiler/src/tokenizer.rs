@@ -1,5 +1,7 @@
 use super::token::Token;
+use crate::common::ParseError;
 use std::iter::Peekable;
+use std::ops::Range;
 use std::str;
 use std::str::Chars;
 use crate::position::*;
@@ -8,13 +10,19 @@ use crate::position::*;
 
 struct Scanner<'a> {
     current_position: Position,
+    /// Where the last character that was consumed is.
+    last_position: Position,
+    /// The byte offset of the next character.
+    index: usize,
     it: Peekable<Chars<'a>>,
 }
 
 impl<'a> Scanner<'a> {
-    fn new(buf: &str) -> Scanner {
+    fn new(buf: &str) -> Scanner<'_> {
         Scanner {
             current_position: Position::default(),
+            last_position: Position::default(),
+            index: 0,
             it: buf.chars().peekable(),
         }
     }
@@ -22,7 +30,9 @@ impl<'a> Scanner<'a> {
     fn next(&mut self) -> Option<char> {
         let next = self.it.next();
         if let Some(c) = next {
+            self.last_position = self.current_position;
             self.current_position = self.current_position.shift(c);
+            self.index += c.len_utf8();
         }
         next
     }
@@ -54,9 +64,8 @@ impl<'a> Scanner<'a> {
         F: Fn(char) -> bool,
     {
         let mut it = self.it.clone();
-        match it.next() {
-            None => return false,
-            _ => (),
+        if it.next().is_none() {
+            return false;
         }
 
         if let Some(&ch) = it.peek() {
@@ -90,12 +99,18 @@ impl<'a> Scanner<'a> {
 
 struct Lexer<'a> {
     it: Scanner<'a>,
+    /// Emit comments as tokens instead of skipping them.
+    comments: bool,
+    /// Emit whitespace and what isn't Lox as tokens instead of skipping or panic-ing, so no character is lost.
+    trivia: bool,
 }
 
 impl<'a> Lexer<'a> {
-    fn new(buf: &str) -> Lexer {
+    fn new(buf: &str) -> Lexer<'_> {
         Lexer {
             it: Scanner::new(buf),
+            comments: false,
+            trivia: false,
         }
     }
 
@@ -105,11 +120,19 @@ impl<'a> Lexer<'a> {
             '!' => Some(self.either('=', Token::BangEqual, Token::Bang)),
             '<' => Some(self.either('=', Token::LessEqual, Token::Less)),
             '>' => Some(self.either('=', Token::GreaterEqual, Token::Greater)),
+            ' ' | '\n' | '\t' | '\r' if self.trivia => {
+                self.it.consume_while(|ch| matches!(ch, ' ' | '\n' | '\t' | '\r'));
+                Some(Token::Whitespace)
+            }
             ' ' => None,
             '/' => {
                 if self.it.consume_if(|ch| ch == '/') {
-                    self.it.consume_while(|ch| ch != '\n');
-                    None
+                    let comment: String = self.it.consume_while(|ch| ch != '\n').into_iter().collect();
+                    if self.comments {
+                        Some(Token::Comment(comment))
+                    } else {
+                        None
+                    }
                 } else {
                     Some(Token::Slash)
                 }
@@ -121,12 +144,13 @@ impl<'a> Lexer<'a> {
                 let string: String = self.it.consume_while(|ch| ch != '"').into_iter().collect();
                 match self.it.next() {
                     // Skip last "
+                    None if self.trivia => return Some(Token::Error),
                     None => panic!("Unterminated string"),
                     _ => (),
                 }
                 Some(Token::String(string))
             }
-            x if x.is_numeric() => self.number(x),
+            x if x.is_ascii_digit() => self.number(x),
             x if x.is_ascii_alphabetic() || x == '_' => self.identifier(x),
             '.' => Some(Token::Dot),
             '(' => Some(Token::LeftParen),
@@ -138,6 +162,8 @@ impl<'a> Lexer<'a> {
             '+' => Some(Token::Plus),
             ';' => Some(Token::Semicolon),
             '*' => Some(Token::Star),
+            ':' => Some(Token::Colon),
+            _ if self.trivia => Some(Token::Error),
             _ => panic!("invalid char"),
         }
     }
@@ -157,10 +183,12 @@ impl<'a> Lexer<'a> {
         keywords.insert("and", Token::And);
         keywords.insert("class", Token::Class);
         keywords.insert("else", Token::Else);
+        keywords.insert("export", Token::Export);
         keywords.insert("false", Token::False);
         keywords.insert("for", Token::For);
         keywords.insert("fun", Token::Fun);
         keywords.insert("if", Token::If);
+        keywords.insert("import", Token::Import);
         keywords.insert("nil", Token::Nil);
         keywords.insert("or", Token::Or);
         keywords.insert("print", Token::Print);
@@ -171,10 +199,7 @@ impl<'a> Lexer<'a> {
         keywords.insert("var", Token::Var);
         keywords.insert("while", Token::While);
 
-        match keywords.get(identifier) {
-            None => None,
-            Some(token) => Some(token.clone()),
-        }
+        keywords.get(identifier).cloned()
     }
 
     fn identifier(&mut self, x: char) -> Option<Token> {
@@ -197,14 +222,14 @@ impl<'a> Lexer<'a> {
         number.push(x);
         let num: String = self
             .it
-            .consume_while(|a| a.is_numeric())
+            .consume_while(|a| a.is_ascii_digit())
             .into_iter()
             .collect();
         number.push_str(num.as_str());
-        if self.it.peek() == Some(&'.') && self.it.consume_if_next(|ch| ch.is_numeric()) {
+        if self.it.peek() == Some(&'.') && self.it.consume_if_next(|ch| ch.is_ascii_digit()) {
             let num2: String = self
                 .it
-                .consume_while(|a| a.is_numeric())
+                .consume_while(|a| a.is_ascii_digit())
                 .into_iter()
                 .collect();
             number.push('.');
@@ -213,16 +238,19 @@ impl<'a> Lexer<'a> {
         Some(Token::Number(number.parse::<f64>().unwrap()))
     }
 
-    fn tokenize_with_context(&mut self) -> Vec<WithSpan<Token>> {
-        let mut tokens: Vec<WithSpan<Token>> = Vec::new();
+    /// Every token with the byte range of its text.
+    fn tokenize_with_context(&mut self) -> Vec<(WithSpan<Token>, Range<usize>)> {
+        let mut tokens = Vec::new();
         loop {
             let initial_position = self.it.current_position;
+            let initial_index = self.it.index;
             let ch = match self.it.next() {
                 None => break,
                 Some(c) => c,
             };
             if let Some(token) = self.match_token(ch) {
-                tokens.push(WithSpan::new(token, Span { start: initial_position, end: self.it.current_position.unshift() }));
+                let span = Span { start: initial_position, end: self.it.last_position };
+                tokens.push((WithSpan::new(token, span), initial_index..self.it.index));
             }
         }
         tokens
@@ -231,7 +259,41 @@ impl<'a> Lexer<'a> {
 
 pub fn tokenize_with_context(buf: &str) -> Vec<WithSpan<Token>> {
     let mut t = Lexer::new(buf);
-    t.tokenize_with_context()
+    t.tokenize_with_context().into_iter().map(|(token, _)| token).collect()
+}
+
+/// Every character of `buf` in a token with its text: comments, runs of whitespace as `Token::Whitespace` and
+/// `Token::Error` for a character that isn't Lox or an unterminated string. Never panics.
+pub fn tokenize_with_trivia(buf: &str) -> Vec<(WithSpan<Token>, &str)> {
+    let mut t = Lexer::new(buf);
+    t.comments = true;
+    t.trivia = true;
+    t.tokenize_with_context().into_iter().map(|(token, range)| (token, &buf[range])).collect()
+}
+
+/// Like `tokenize_with_context`, but what isn't Lox is left out with an error for it instead of panic-ing. For code
+/// that is still being typed.
+pub fn tokenize_with_errors(buf: &str) -> (Vec<WithSpan<Token>>, Vec<ParseError>) {
+    let (tokens, errors) = tokenize_with_comments(buf);
+    let tokens = tokens.into_iter().filter(|token| !matches!(token.value, Token::Comment(_))).collect();
+    (tokens, errors)
+}
+
+/// Like `tokenize_with_errors`, with a `Token::Comment` for every comment. The text after the `//` is kept.
+pub fn tokenize_with_comments(buf: &str) -> (Vec<WithSpan<Token>>, Vec<ParseError>) {
+    let mut tokens = vec![];
+    let mut errors = vec![];
+    for (token, text) in tokenize_with_trivia(buf) {
+        match token.value {
+            Token::Whitespace => (),
+            Token::Error => {
+                let error = if text.starts_with('"') { "Unterminated string.".to_string() } else { format!("Unexpected character '{}'.", text) };
+                errors.push(ParseError { error, span: Some(token.span) });
+            },
+            _ => tokens.push(token),
+        }
+    }
+    (tokens, errors)
 }
 
 #[cfg(test)]
@@ -284,4 +346,57 @@ mod tests {
         assert_eq!(tokenize("or"), vec![Token::Or]);
     }
 
+    #[test]
+    fn test_comments() {
+        use super::tokenize_with_comments;
+        let (tokens, errors) = tokenize_with_comments("= // one\n//two");
+        assert!(errors.is_empty());
+        let values: Vec<_> = tokens.iter().map(|tc| tc.value.clone()).collect();
+        assert_eq!(values, vec![Token::Equal, Token::Comment(" one".to_string()), Token::Comment("two".to_string())]);
+        assert_eq!((tokens[1].span.start.column, tokens[1].span.end.column), (3, 8));
+        assert_eq!(tokens[2].span.start.line, 2);
+    }
+
+    #[test]
+    fn test_trivia() {
+        use super::tokenize_with_trivia;
+        let tokens = tokenize_with_trivia("a  = \"b\n@ 1.50 \"c");
+        let values: Vec<_> = tokens.iter().map(|(tc, text)| (tc.value.clone(), *text)).collect();
+        assert_eq!(values, vec![
+            (Token::Identifier("a".to_string()), "a"),
+            (Token::Whitespace, "  "),
+            (Token::Equal, "="),
+            (Token::Whitespace, " "),
+            (Token::String("b\n@ 1.50 ".to_string()), "\"b\n@ 1.50 \""),
+            (Token::Identifier("c".to_string()), "c"),
+        ]);
+        assert_eq!(tokenize_with_trivia("@1.50\"x").iter().map(|(tc, text)| (tc.value.clone(), *text)).collect::<Vec<_>>(), vec![
+            (Token::Error, "@"),
+            (Token::Number(1.5), "1.50"),
+            (Token::Error, "\"x"),
+        ]);
+        // Only ASCII digits make a number
+        assert_eq!(tokenize_with_trivia("var ² = 1٣;").iter().map(|(tc, text)| (tc.value.clone(), *text)).collect::<Vec<_>>(), vec![
+            (Token::Var, "var"),
+            (Token::Whitespace, " "),
+            (Token::Error, "²"),
+            (Token::Whitespace, " "),
+            (Token::Equal, "="),
+            (Token::Whitespace, " "),
+            (Token::Number(1.0), "1"),
+            (Token::Error, "٣"),
+            (Token::Semicolon, ";"),
+        ]);
+    }
+
+    #[test]
+    fn test_errors() {
+        use super::tokenize_with_errors;
+        let (tokens, errors) = tokenize_with_errors("print @ 1;\nvar a = \"b;");
+        let values: Vec<_> = tokens.iter().map(|tc| tc.value.clone()).collect();
+        assert_eq!(values, vec![Token::Print, Token::Number(1.0), Token::Semicolon, Token::Var, Token::Identifier("a".to_string()), Token::Equal]);
+        let errors: Vec<_> = errors.iter().map(|error| (error.error.as_str(), error.span.unwrap().start.line)).collect();
+        assert_eq!(errors, vec![("Unexpected character '@'.", 1), ("Unterminated string.", 2)]);
+    }
+
 }
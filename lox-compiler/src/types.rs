@@ -0,0 +1,724 @@
+//! A type checker for the optional annotations, `var name: String = ...` and `fun add(a: Number): Number`. What
+//! isn't annotated is inferred from how it is initialized, and becomes `Any` once it is assigned something else, so
+//! code without annotations only gets errors for what fails at runtime on every path, like `"a" - 1`. A function can
+//! run after a variable it captures is assigned, so in functions a captured variable that is assigned anywhere is
+//! `Any`.
+
+use crate::analysis::{self, Diagnostic};
+use crate::ast::*;
+use crate::lint::always_returns;
+use crate::position::{Span, WithSpan};
+use crate::resolver;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::rc::Rc;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Type {
+    /// Not annotated and not known, anything goes.
+    Any,
+    Nil,
+    Boolean,
+    Number,
+    String,
+    Function(Rc<FunctionType>),
+    /// The class itself, calling it makes an instance.
+    Class(String),
+    Instance(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionType {
+    pub params: Vec<Type>,
+    pub returns: Type,
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Type::Any => write!(f, "Any"),
+            Type::Nil => write!(f, "Nil"),
+            Type::Boolean => write!(f, "Boolean"),
+            Type::Number => write!(f, "Number"),
+            Type::String => write!(f, "String"),
+            Type::Function(function) => {
+                let params: Vec<_> = function.params.iter().map(Type::to_string).collect();
+                write!(f, "fun({}): {}", params.join(", "), function.returns)
+            },
+            Type::Class(name) => write!(f, "class {}", name),
+            Type::Instance(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TypeError {
+    pub span: Span,
+    pub message: String,
+}
+
+/// The type errors in order of where they are, or the errors when the code doesn't compile.
+pub fn check(code: &str) -> Result<Vec<TypeError>, Vec<Diagnostic>> {
+    use crate::{tokenizer::tokenize_with_errors, stmt_parser::parse};
+
+    let (tokens, errors) = tokenize_with_errors(code);
+    if !errors.is_empty() {
+        return Err(errors.into_iter().map(|error| analysis::parse_diagnostic(error, &tokens)).collect());
+    }
+    let ast = parse(&mut tokens.iter().peekable()).map_err(|error| vec![analysis::parse_diagnostic(error, &tokens)])?;
+    let diagnostics = analysis::diagnostics(&resolver::resolve_ast(&ast, false).errors);
+    if !diagnostics.is_empty() {
+        return Err(diagnostics);
+    }
+
+    let mut assigned = HashSet::new();
+    collect_assigned(&ast, &mut assigned);
+    let mut checker = Checker {
+        errors: vec![],
+        assigned,
+        scopes: vec![HashMap::new()],
+        classes: HashMap::new(),
+        functions: vec![],
+        class: vec![],
+    };
+    checker.declare_classes(&ast);
+    for stmt in &ast {
+        checker.stmt(stmt);
+    }
+    Ok(checker.errors)
+}
+
+struct Variable {
+    ty: Type,
+    /// An annotated variable keeps its type, one that isn't becomes `Any` when it is assigned another type.
+    annotated: bool,
+}
+
+struct Class {
+    superclass: Option<String>,
+    fields: HashMap<String, Type>,
+    methods: HashMap<String, Rc<FunctionType>>,
+}
+
+/// A function that is being checked.
+struct Function {
+    name: String,
+    /// The annotated type of what it returns.
+    returns: Option<Type>,
+    /// The types of the values it returns.
+    returned: Vec<Type>,
+    /// The first scope of the function, the variables in the scopes before it are captured.
+    scope: usize,
+}
+
+struct Checker {
+    errors: Vec<TypeError>,
+    /// The names of the variables that are assigned somewhere.
+    assigned: HashSet<String>,
+    /// The globals first, the innermost scope last.
+    scopes: Vec<HashMap<String, Variable>>,
+    classes: HashMap<String, Class>,
+    /// The innermost function last.
+    functions: Vec<Function>,
+    /// The innermost class last, for `this` and `super`.
+    class: Vec<String>,
+}
+
+impl Checker {
+    fn error(&mut self, span: Span, message: String) {
+        self.errors.push(TypeError { span, message });
+    }
+
+    /// Every class can be used in annotations, also before it is declared. Their methods get the annotated types.
+    fn declare_classes(&mut self, stmts: &[WithSpan<Stmt>]) {
+        let mut classes = vec![];
+        collect_classes(stmts, &mut classes);
+        for (name, _, _) in &classes {
+            let class = Class { superclass: None, fields: HashMap::new(), methods: HashMap::new() };
+            self.classes.insert(name.clone(), class);
+        }
+        // A class that inherits from itself fails at runtime, leave the superclass out to not go around in circles
+        for (name, superclass, _) in &classes {
+            if let Some(superclass) = superclass.as_ref().filter(|superclass| !self.is_subclass(superclass, name)) {
+                self.classes.get_mut(name).expect("class is declared").superclass = Some(superclass.clone());
+            }
+        }
+
+        for (name, _, methods) in classes {
+            for method in methods {
+                if let Stmt::Function(method_name, params, _, signature) = &method.value {
+                    let function = self.signature(params, signature);
+                    self.classes.get_mut(&name).expect("class is declared").methods.insert(method_name.value.clone(), Rc::new(function));
+                }
+            }
+        }
+    }
+
+    fn signature(&mut self, params: &[WithSpan<Identifier>], signature: &Signature) -> FunctionType {
+        let params = params.iter().zip(&signature.params).map(|(_, type_name)| self.annotated(type_name.as_ref())).collect();
+        let returns = self.annotated(signature.returns.as_ref());
+        FunctionType { params, returns }
+    }
+
+    /// The type that is annotated, `Any` without an annotation.
+    fn annotated(&mut self, type_name: Option<&TypeName>) -> Type {
+        let type_name = match type_name {
+            Some(type_name) => type_name,
+            None => return Type::Any,
+        };
+        match type_name.value.as_str() {
+            "Any" => Type::Any,
+            "Nil" => Type::Nil,
+            "Boolean" => Type::Boolean,
+            "Number" => Type::Number,
+            "String" => Type::String,
+            name if self.classes.contains_key(name) => Type::Instance(name.to_string()),
+            name => {
+                self.error(type_name.span, format!("Unknown type `{}`", name));
+                Type::Any
+            },
+        }
+    }
+
+    fn is_subclass(&self, class: &str, superclass: &str) -> bool {
+        let mut class = Some(class);
+        while let Some(name) = class {
+            if name == superclass {
+                return true;
+            }
+            class = self.classes.get(name).and_then(|class| class.superclass.as_deref());
+        }
+        false
+    }
+
+    fn assignable(&self, from: &Type, to: &Type) -> bool {
+        match (from, to) {
+            (Type::Any, _) | (_, Type::Any) => true,
+            (Type::Instance(from), Type::Instance(to)) => self.is_subclass(from, to),
+            (from, to) => from == to,
+        }
+    }
+
+    /// The name of the class or superclass the field or method is in.
+    fn owner(&self, class: &str, has: impl Fn(&Class) -> bool) -> Option<String> {
+        let mut name = Some(class.to_string());
+        while let Some(class) = name {
+            let found = self.classes.get(&class)?;
+            if has(found) {
+                return Some(class);
+            }
+            name = found.superclass.clone();
+        }
+        None
+    }
+
+    fn method(&self, class: &str, name: &str) -> Option<Rc<FunctionType>> {
+        self.owner(class, |class| class.methods.contains_key(name)).map(|owner| self.classes[&owner].methods[name].clone())
+    }
+
+    fn declare(&mut self, name: &str, ty: Type, annotated: bool) {
+        self.scopes.last_mut().expect("no scope").insert(name.to_string(), Variable { ty, annotated });
+    }
+
+    fn variable(&mut self, name: &str) -> Option<&mut Variable> {
+        self.scopes.iter_mut().rev().find_map(|scope| scope.get_mut(name))
+    }
+
+    /// The type of the variable where it is read.
+    fn variable_type(&self, name: &str) -> Type {
+        let captured = self.functions.last().map_or(0, |function| function.scope);
+        let found = self.scopes.iter().enumerate().rev().find_map(|(index, scope)| scope.get(name).map(|variable| (index, variable)));
+        match found {
+            // The function can be called after the variable is assigned another type
+            Some((index, variable)) if index < captured && !variable.annotated && self.assigned.contains(name) => Type::Any,
+            Some((_, variable)) => variable.ty.clone(),
+            None => Type::Any,
+        }
+    }
+
+    fn with_scope(&mut self, f: impl FnOnce(&mut Self)) {
+        self.scopes.push(HashMap::new());
+        f(self);
+        self.scopes.pop();
+    }
+
+    fn stmt(&mut self, stmt: &WithSpan<Stmt>) {
+        match &stmt.value {
+            Stmt::Expression(expr) | Stmt::Print(expr) => {
+                self.expr(expr);
+            },
+            Stmt::Var(name, initializer, type_name) => {
+                let initialized = initializer.as_ref().map(|initializer| self.expr(initializer));
+                match type_name {
+                    Some(type_name) => {
+                        let ty = self.annotated(Some(type_name));
+                        // Without an initializer it is nil until it is assigned, which isn't an error
+                        if let Some(initialized) = initialized.filter(|initialized| !self.assignable(initialized, &ty)) {
+                            let span = initializer.as_ref().map_or(stmt.span, |initializer| initializer.span);
+                            self.error(span, format!("Can't initialize `{}` of type {} with {}", name.value, ty, initialized));
+                        }
+                        self.declare(&name.value, ty, true);
+                    },
+                    None => self.declare(&name.value, initialized.unwrap_or(Type::Nil), false),
+                }
+            },
+            Stmt::If(condition, then_stmt, else_stmt) => {
+                self.expr(condition);
+                self.stmt(then_stmt);
+                if let Some(else_stmt) = else_stmt {
+                    self.stmt(else_stmt);
+                }
+            },
+            Stmt::Block(stmts) => self.with_scope(|checker| for stmt in stmts {
+                checker.stmt(stmt);
+            }),
+            Stmt::While(condition, body) => {
+                self.expr(condition);
+                self.stmt(body);
+            },
+            Stmt::Return(expr) => {
+                let returned = expr.as_ref().map_or(Type::Nil, |expr| self.expr(expr));
+                if let Some(function) = self.functions.last() {
+                    if let Some(returns) = function.returns.clone().filter(|returns| !self.assignable(&returned, returns)) {
+                        let message = format!("`{}` returns {}, can't return {}", function.name, returns, returned);
+                        self.error(expr.as_ref().map_or(stmt.span, |expr| expr.span), message);
+                    }
+                }
+                if let Some(function) = self.functions.last_mut() {
+                    function.returned.push(returned);
+                }
+            },
+            Stmt::Function(name, params, body, signature) => {
+                let function = self.signature(params, signature);
+                // It can call itself, before what it returns is inferred
+                self.declare(&name.value, Type::Function(Rc::new(function.clone())), false);
+                let returns = self.function(name, params, body, &function, signature.returns.is_some());
+                if let Some(variable) = self.variable(&name.value) {
+                    variable.ty = Type::Function(Rc::new(FunctionType { returns, ..function }));
+                }
+            },
+            Stmt::Class(name, superclass, methods) => {
+                if let Some(superclass) = superclass {
+                    let ty = self.variable(&superclass.value).map_or(Type::Any, |variable| variable.ty.clone());
+                    if !matches!(ty, Type::Class(_) | Type::Any) {
+                        self.error(superclass.span, format!("A superclass must be a class, got {}", ty));
+                    }
+                }
+                self.declare(&name.value, Type::Class(name.value.clone()), false);
+
+                self.class.push(name.value.clone());
+                for method in methods {
+                    if let Stmt::Function(method_name, params, body, signature) = &method.value {
+                        let function = self.method(&name.value, &method_name.value).expect("methods are declared");
+                        let returns = self.function(method_name, params, body, &function, signature.returns.is_some());
+                        let function = Rc::new(FunctionType { returns, params: function.params.clone() });
+                        self.classes.get_mut(&name.value).expect("class is declared").methods.insert(method_name.value.clone(), function);
+                    }
+                }
+                self.class.pop();
+            },
+            // What other modules export isn't known, the names they refer to are of any type
+            Stmt::Import(..) => (),
+            Stmt::Export(declaration) => self.stmt(declaration),
+        }
+    }
+
+    /// Check the body of a function, returns what it returns: the annotated type, or else the type that is inferred.
+    fn function(&mut self, name: &WithSpan<Identifier>, params: &[WithSpan<Identifier>], body: &[WithSpan<Stmt>], function: &FunctionType, annotated: bool) -> Type {
+        self.functions.push(Function {
+            name: name.value.clone(),
+            returns: if annotated { Some(function.returns.clone()) } else { None },
+            returned: vec![],
+            scope: self.scopes.len(),
+        });
+        self.with_scope(|checker| {
+            for (param, ty) in params.iter().zip(&function.params) {
+                checker.declare(&param.value, ty.clone(), *ty != Type::Any);
+            }
+            checker.with_scope(|checker| for stmt in body {
+                checker.stmt(stmt);
+            });
+        });
+        let mut returned = self.functions.pop().expect("no function").returned;
+
+        // Falling off the end returns nil
+        if !body.iter().any(always_returns) {
+            if annotated && !self.assignable(&Type::Nil, &function.returns) {
+                self.error(name.span, format!("`{}` returns {}, but can end without returning", name.value, function.returns));
+            }
+            returned.push(Type::Nil);
+        }
+        if annotated {
+            return function.returns.clone();
+        }
+        match returned.split_first() {
+            Some((first, rest)) if rest.iter().all(|ty| ty == first) => first.clone(),
+            _ => Type::Any,
+        }
+    }
+
+    fn expr(&mut self, expr: &WithSpan<Expr>) -> Type {
+        match &expr.value {
+            Expr::Number(_) => Type::Number,
+            Expr::String(_) => Type::String,
+            Expr::Boolean(_) => Type::Boolean,
+            Expr::Nil => Type::Nil,
+            Expr::This => self.class.last().map_or(Type::Any, |class| Type::Instance(class.clone())),
+            Expr::Super(name) => {
+                let superclass = self.class.last().and_then(|class| self.classes[class].superclass.clone());
+                superclass.and_then(|superclass| self.method(&superclass, name)).map_or(Type::Any, Type::Function)
+            },
+            Expr::Grouping(expr) => self.expr(expr),
+            Expr::Unary(UnaryOperator::Bang, expr) => {
+                self.expr(expr);
+                Type::Boolean
+            },
+            Expr::Unary(UnaryOperator::Minus, operand) => {
+                let ty = self.expr(operand);
+                if !matches!(ty, Type::Number | Type::Any) {
+                    self.error(operand.span, format!("The operand of '-' must be a number, got {}", ty));
+                }
+                Type::Number
+            },
+            Expr::Binary(left, operator, right) => self.binary(*operator, left, right),
+            Expr::Logical(left, _, right) => {
+                let (left, right) = (self.expr(left), self.expr(right));
+                if left == right { left } else { Type::Any }
+            },
+            Expr::Variable(name) => self.variable_type(&name.value),
+            Expr::Assign(name, value) => {
+                let ty = self.expr(value);
+                self.assign(&name.value, &ty, value.span);
+                ty
+            },
+            Expr::Call(callee, args) => self.call(callee, args, expr.span),
+            Expr::Get(object, name) => {
+                let ty = self.expr(object);
+                self.get(ty, name, object.span)
+            },
+            Expr::Set(object, name, value) => {
+                let (ty, value) = (self.expr(object), self.expr(value));
+                self.set(ty, name, &value, object.span);
+                value
+            },
+        }
+    }
+
+    /// Errors point at the operand that has the wrong type.
+    fn binary(&mut self, operator: BinaryOperator, left_expr: &WithSpan<Expr>, right_expr: &WithSpan<Expr>) -> Type {
+        use BinaryOperator::*;
+        let (left, right) = (self.expr(left_expr), self.expr(right_expr));
+        let number = |ty: &Type| matches!(ty, Type::Number | Type::Any);
+        let symbol = match operator {
+            EqualEqual | BangEqual => return Type::Boolean,
+            Plus => return match (&left, &right) {
+                (Type::Number, ty) | (ty, Type::Number) if number(ty) => Type::Number,
+                (Type::String, Type::String | Type::Any) | (Type::Any, Type::String) => Type::String,
+                (Type::Any, Type::Any) => Type::Any,
+                _ => {
+                    // The left operand is wrong when nothing can be added to it, otherwise the right one doesn't go with it
+                    let offending = if matches!(left, Type::Number | Type::String | Type::Any) { right_expr } else { left_expr };
+                    self.error(offending.span, format!("The operands of '+' must be two numbers or two strings, got {} and {}", left, right));
+                    Type::Any
+                },
+            },
+            Minus => "-",
+            Star => "*",
+            Slash => "/",
+            Greater => ">",
+            GreaterEqual => ">=",
+            Less => "<",
+            LessEqual => "<=",
+        };
+
+        if !number(&left) || !number(&right) {
+            let offending = if number(&left) { right_expr } else { left_expr };
+            self.error(offending.span, format!("The operands of '{}' must be numbers, got {} and {}", symbol, left, right));
+        }
+        match operator {
+            Minus | Star | Slash => Type::Number,
+            _ => Type::Boolean,
+        }
+    }
+
+    fn assign(&mut self, name: &str, value: &Type, span: Span) {
+        let ty = match self.variable(name) {
+            Some(variable) if variable.annotated => variable.ty.clone(),
+            Some(variable) => {
+                if variable.ty != *value {
+                    variable.ty = Type::Any;
+                }
+                return;
+            },
+            None => return,
+        };
+        if !self.assignable(value, &ty) {
+            self.error(span, format!("Can't assign {} to `{}` of type {}", value, name, ty));
+        }
+    }
+
+    /// Errors point at the callee that can't be called, the whole call for the number of arguments, or the argument
+    /// of the wrong type.
+    fn call(&mut self, callee: &WithSpan<Expr>, args: &[WithSpan<Expr>], span: Span) -> Type {
+        let ty = self.expr(callee);
+        let types: Vec<_> = args.iter().map(|arg| self.expr(arg)).collect();
+        let (function, returns) = match &ty {
+            Type::Any => return Type::Any,
+            Type::Function(function) => (Some(function.clone()), function.returns.clone()),
+            Type::Class(class) => (self.method(class, "init"), Type::Instance(class.clone())),
+            ty => {
+                self.error(callee.span, format!("Can only call functions and classes, got {}", ty));
+                return Type::Any;
+            },
+        };
+
+        let params = function.map_or(vec![], |function| function.params.clone());
+        if params.len() != args.len() {
+            self.error(span, format!("Expected {} arguments but got {}", params.len(), args.len()));
+            return returns;
+        }
+        for (index, ((arg, ty), param)) in args.iter().zip(&types).zip(&params).enumerate() {
+            if !self.assignable(ty, param) {
+                self.error(arg.span, format!("Argument {} must be {}, got {}", index + 1, param, ty));
+            }
+        }
+        returns
+    }
+
+    /// `span` is the one of the object.
+    fn get(&mut self, object: Type, name: &str, span: Span) -> Type {
+        match &object {
+            Type::Any => Type::Any,
+            Type::Instance(class) => {
+                if let Some(owner) = self.owner(class, |class| class.fields.contains_key(name)) {
+                    return self.classes[&owner].fields[name].clone();
+                }
+                // A field that is only set later can have any type
+                self.method(class, name).map_or(Type::Any, Type::Function)
+            },
+            object => {
+                self.error(span, format!("Only instances have properties, got {}", object));
+                Type::Any
+            },
+        }
+    }
+
+    /// A field that isn't annotated has the type of what it is set to first, `Any` once it is set to another type.
+    fn set(&mut self, object: Type, name: &str, value: &Type, span: Span) {
+        let class = match &object {
+            Type::Any => return,
+            Type::Instance(class) => class,
+            object => {
+                self.error(span, format!("Only instances have fields, got {}", object));
+                return;
+            },
+        };
+
+        let owner = self.owner(class, |class| class.fields.contains_key(name)).unwrap_or_else(|| class.clone());
+        let class = self.classes.get_mut(&owner).expect("class is declared");
+        let field = class.fields.entry(name.to_string()).or_insert_with(|| value.clone());
+        if field != value {
+            *field = Type::Any;
+        }
+    }
+}
+
+/// The name of a class with its superclass and methods.
+type ClassDeclaration<'a> = (String, Option<String>, &'a [WithSpan<Stmt>]);
+
+/// Every class in the statements, also those in functions and blocks.
+fn collect_classes<'a>(stmts: &'a [WithSpan<Stmt>], classes: &mut Vec<ClassDeclaration<'a>>) {
+    for stmt in stmts {
+        match &stmt.value {
+            Stmt::Class(name, superclass, methods) => {
+                classes.push((name.value.clone(), superclass.as_ref().map(|superclass| superclass.value.clone()), methods));
+                for method in methods {
+                    if let Stmt::Function(_, _, body, _) = &method.value {
+                        collect_classes(body, classes);
+                    }
+                }
+            },
+            Stmt::Function(_, _, body, _) | Stmt::Block(body) => collect_classes(body, classes),
+            Stmt::If(_, then_stmt, else_stmt) => {
+                collect_classes(std::slice::from_ref(then_stmt.as_ref()), classes);
+                if let Some(else_stmt) = else_stmt {
+                    collect_classes(std::slice::from_ref(else_stmt.as_ref()), classes);
+                }
+            },
+            Stmt::While(_, body) | Stmt::Export(body) => collect_classes(std::slice::from_ref(body.as_ref()), classes),
+            _ => (),
+        }
+    }
+}
+
+/// The name of every variable that is assigned in the statements.
+fn collect_assigned(stmts: &[WithSpan<Stmt>], assigned: &mut HashSet<String>) {
+    for stmt in stmts {
+        match &stmt.value {
+            Stmt::Expression(expr) | Stmt::Print(expr) | Stmt::Var(_, Some(expr), _) | Stmt::Return(Some(expr)) => {
+                collect_assigned_in(expr, assigned);
+            },
+            Stmt::If(condition, then_stmt, else_stmt) => {
+                collect_assigned_in(condition, assigned);
+                collect_assigned(std::slice::from_ref(then_stmt.as_ref()), assigned);
+                if let Some(else_stmt) = else_stmt {
+                    collect_assigned(std::slice::from_ref(else_stmt.as_ref()), assigned);
+                }
+            },
+            Stmt::While(condition, body) => {
+                collect_assigned_in(condition, assigned);
+                collect_assigned(std::slice::from_ref(body.as_ref()), assigned);
+            },
+            Stmt::Function(_, _, body, _) | Stmt::Block(body) | Stmt::Class(_, _, body) => collect_assigned(body, assigned),
+            Stmt::Export(declaration) => collect_assigned(std::slice::from_ref(declaration.as_ref()), assigned),
+            _ => (),
+        }
+    }
+}
+
+fn collect_assigned_in(expr: &WithSpan<Expr>, assigned: &mut HashSet<String>) {
+    match &expr.value {
+        Expr::Assign(name, value) => {
+            assigned.insert(name.value.clone());
+            collect_assigned_in(value, assigned);
+        },
+        Expr::Binary(left, _, right) | Expr::Logical(left, _, right) | Expr::Set(left, _, right) => {
+            collect_assigned_in(left, assigned);
+            collect_assigned_in(right, assigned);
+        },
+        Expr::Grouping(expr) | Expr::Unary(_, expr) | Expr::Get(expr, _) => collect_assigned_in(expr, assigned),
+        Expr::Call(callee, args) => {
+            collect_assigned_in(callee, assigned);
+            for arg in args {
+                collect_assigned_in(arg, assigned);
+            }
+        },
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The line, column and message of every error.
+    fn errors(code: &str) -> Vec<(usize, usize, String)> {
+        check(code).unwrap().into_iter().map(|error| (error.span.start.line, error.span.start.column, error.message)).collect()
+    }
+
+    fn messages(code: &str) -> Vec<String> {
+        errors(code).into_iter().map(|(_, _, message)| message).collect()
+    }
+
+    #[test]
+    fn test_operators() {
+        assert_eq!(errors("print \"a\" - 1;\nprint -\"b\";\nprint 1 + \"c\";\nprint \"d\" + \"e\" < 1;"), vec![
+            (1, 7, "The operands of '-' must be numbers, got String and Number".to_string()),
+            (2, 8, "The operand of '-' must be a number, got String".to_string()),
+            (3, 11, "The operands of '+' must be two numbers or two strings, got Number and String".to_string()),
+            (4, 7, "The operands of '<' must be numbers, got String and Number".to_string()),
+        ]);
+        // Without annotations the parameters can be anything
+        assert_eq!(errors("fun f(a, b) { return a - b + a * 2; }\nprint f(1, 2) == nil;\nprint !\"a\";"), vec![]);
+    }
+
+    #[test]
+    fn test_annotated_variables() {
+        assert_eq!(messages("var a: Number = \"one\";\nvar b: String;\nb = 2;\nvar c: Any = 1;\nc = \"c\";"), vec![
+            "Can't initialize `a` of type Number with String",
+            "Can't assign Number to `b` of type String",
+        ]);
+        assert_eq!(messages("var a: Numbr = 1;"), vec!["Unknown type `Numbr`"]);
+    }
+
+    #[test]
+    fn test_inference_through_locals() {
+        assert_eq!(messages("{ var a = \"a\"; var b = a; print b * 2; }"), vec![
+            "The operands of '*' must be numbers, got String and Number",
+        ]);
+        // Assigning another type makes it `Any`
+        assert_eq!(messages("var a = \"a\";\na = 1;\nprint a * 2;\nvar b;\nb = 1;\nprint b - 1;"), vec![] as Vec<String>);
+        // A function can run after what it captures is assigned another type
+        assert_eq!(messages("var a = \"a\";\nfun f() { return a - 1; }\na = 1;\nprint f();"), vec![] as Vec<String>);
+        assert_eq!(messages("var a = \"a\";\nfun f() { return a - 1; }"), vec![
+            "The operands of '-' must be numbers, got String and Number",
+        ]);
+    }
+
+    #[test]
+    fn test_functions() {
+        let code = "\
+fun add(a: Number, b: Number): Number { return a + b; }
+print add(1, \"2\");
+print add(1);
+print add(1, 2) + \"3\";
+fun name() { return \"name\"; }
+print name() - 1;
+fun wrong(): String { return 1; }
+fun maybe(a): Number { if (a) return 1; }
+print 1();";
+        assert_eq!(errors(code), vec![
+            (2, 14, "Argument 2 must be Number, got String".to_string()),
+            (3, 7, "Expected 2 arguments but got 1".to_string()),
+            (4, 19, "The operands of '+' must be two numbers or two strings, got Number and String".to_string()),
+            (6, 7, "The operands of '-' must be numbers, got String and Number".to_string()),
+            (7, 30, "`wrong` returns String, can't return Number".to_string()),
+            (8, 5, "`maybe` returns Number, but can end without returning".to_string()),
+            (9, 7, "Can only call functions and classes, got Number".to_string()),
+        ]);
+        assert_eq!(errors("fun add(a: Number, b: Number): Number { return a + b; }\nprint add(\"a\", 2);"), vec![
+            (2, 11, "Argument 1 must be Number, got String".to_string()),
+        ]);
+        // Recursion sees what the function returns as `Any`, different returns infer `Any`
+        assert_eq!(messages("fun f(n) { if (n < 1) return 0; return f(n - 1) + 1; }\nfun g(a) { if (a) return 1; return \"s\"; }\nprint g(true) - 1;"), vec![] as Vec<String>);
+    }
+
+    #[test]
+    fn test_classes_and_fields() {
+        let code = "\
+class Point {
+  init(x: Number, y: Number) { this.x = x; this.y = y; }
+  sum(): Number { return this.x + this.y; }
+}
+class Named < Point { name(): String { return \"named\"; } }
+var p: Point = Named(1, 2);
+print p.x + \"s\";
+print Point(1);
+var n: Named = Point(1, 2);
+print p.sum() - 1;
+print Named(1, 2).name() * 2;
+print 1.field;
+var s = \"s\";
+s.field = 1;";
+        assert_eq!(errors(code), vec![
+            (7, 13, "The operands of '+' must be two numbers or two strings, got Number and String".to_string()),
+            (8, 7, "Expected 2 arguments but got 1".to_string()),
+            (9, 16, "Can't initialize `n` of type Named with Point".to_string()),
+            (11, 7, "The operands of '*' must be numbers, got String and Number".to_string()),
+            (12, 7, "Only instances have properties, got Number".to_string()),
+            (14, 1, "Only instances have fields, got String".to_string()),
+        ]);
+        assert_eq!(errors("class P { init() { this.name = \"p\"; } }\nvar p = P();\nprint p.name - 1;"), vec![
+            (3, 7, "The operands of '-' must be numbers, got String and Number".to_string()),
+        ]);
+        // A field set to different types can be anything
+        assert_eq!(messages("class A {}\nvar a = A();\na.f = 1;\na.f = \"f\";\nprint a.f - 1;"), vec![] as Vec<String>);
+        // Classes that inherit from each other fail at runtime, but don't hang the checker
+        assert_eq!(messages("class A < B {}\nclass B < A {}\nprint A().f;"), vec![] as Vec<String>);
+    }
+
+    #[test]
+    fn test_compile_errors() {
+        assert!(check("print ;").is_err());
+        assert!(check("return 1;").is_err());
+    }
+
+    #[test]
+    fn test_lex_errors() {
+        let diagnostics = check("var s = \"abc;").unwrap_err();
+        assert_eq!(diagnostics.iter().map(|diagnostic| diagnostic.message.as_str()).collect::<Vec<_>>(), vec!["Unterminated string."]);
+        let diagnostics = check("print 1 @ 2;").unwrap_err();
+        assert_eq!(diagnostics[0].message, "Unexpected character '@'.");
+        assert_eq!(diagnostics[0].span.start.column, 9);
+    }
+}
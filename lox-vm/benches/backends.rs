@@ -0,0 +1,84 @@
+//! Runs the same workloads on the stack VM and the register VM, to compare their throughput.
+//!
+//! Run with `cargo bench -p lox-vm --bench backends`.
+
+use std::time::{Duration, Instant};
+
+const RUNS: u32 = 5;
+
+const WORKLOADS: &[(&str, &str)] = &[
+    ("local arithmetic", "
+        {
+            var x = 0;
+            for (var i = 0; i < 300000; i = i + 1) {
+                x = x * 0.5 + i / 3 - 1;
+            }
+        }
+    "),
+    ("global counter", "
+        var i = 0;
+        var sum = 0;
+        while (i < 300000) {
+            sum = sum + i;
+            i = i + 1;
+        }
+    "),
+    ("fibonacci", "
+        fun fib(n) {
+            if (n < 2) return n;
+            return fib(n - 1) + fib(n - 2);
+        }
+        fib(22);
+    "),
+    ("closures", "
+        fun counter() {
+            var count = 0;
+            fun increment() { count = count + 1; return count; }
+            return increment;
+        }
+        var c = counter();
+        for (var i = 0; i < 100000; i = i + 1) c();
+    "),
+    ("properties", "
+        class Point {}
+        var p = Point();
+        p.x = 0;
+        p.y = 0;
+        for (var i = 0; i < 100000; i = i + 1) {
+            p.x = p.x + 1;
+            p.y = p.x + p.y;
+        }
+    "),
+    ("strings", "
+        {
+            var s = \"\";
+            for (var i = 0; i < 100000; i = i + 1) {
+                s = \"a\" + \"b\";
+            }
+        }
+    "),
+];
+
+fn time<F: Fn()>(f: F) -> Duration {
+    let mut total = Duration::default();
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        f();
+        total += start.elapsed();
+    }
+    total / RUNS
+}
+
+fn main() {
+    println!("{:<20} {:>12} {:>12} {:>8}", "workload", "stack", "registers", "speedup");
+    for (name, source) in WORKLOADS {
+        let stack_module = lox_compiler::compile(source).unwrap();
+        let register_module = lox_compiler::compile_registers(source).unwrap();
+
+        let stack = time(|| lox_vm::bettervm::execute(&stack_module).unwrap());
+        let registers = time(|| lox_vm::registervm::execute(&register_module).unwrap());
+
+        let speedup = stack.as_secs_f64() / registers.as_secs_f64();
+        println!("{:<20} {:>12} {:>12} {:>7.2}x", name, format!("{:.2?}", stack), format!("{:.2?}", registers), speedup);
+    }
+}
@@ -0,0 +1,78 @@
+//! Compares the generational and incremental collectors with the plain mark and sweep collector.
+//! For every collector the average run time and the longest pause are shown.
+//!
+//! Run with `cargo bench -p lox-vm --bench gc`.
+
+use lox_vm::bettergc::gc::{self, Collector};
+use std::time::{Duration, Instant};
+
+const RUNS: u32 = 5;
+
+const WORKLOADS: &[(&str, &str)] = &[
+    ("short lived strings", "
+        for (var i = 0; i < 200000; i = i + 1) {
+            var s = \"hello\" + \", \" + \"world\";
+        }
+    "),
+    ("short lived strings, large old heap", "
+        class Node {}
+        var head = nil;
+        for (var i = 0; i < 20000; i = i + 1) {
+            var node = Node();
+            node.next = head;
+            head = node;
+        }
+        for (var i = 0; i < 200000; i = i + 1) {
+            var s = \"hello\" + \", \" + \"world\";
+        }
+    "),
+    ("closures", "
+        fun make(n) {
+            fun get() { return n; }
+            return get;
+        }
+        for (var i = 0; i < 100000; i = i + 1) {
+            make(i)();
+        }
+    "),
+    ("instances", "
+        class Point {}
+        for (var i = 0; i < 100000; i = i + 1) {
+            var p = Point();
+            p.x = i;
+            p.y = \"y\" + \"!\";
+        }
+    "),
+];
+
+fn run(module: &lox_bytecode::bytecode::Module, collector: Collector) -> (Duration, Duration) {
+    gc::set_collector(collector);
+    gc::reset_stats();
+    let mut total = Duration::default();
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        lox_vm::bettervm::execute(module).unwrap();
+        total += start.elapsed();
+    }
+    (total / RUNS, gc::stats().pauses.max)
+}
+
+fn main() {
+    let collectors = [Collector::MarkSweep, Collector::Generational, Collector::Incremental];
+
+    print!("{:<40}", "workload");
+    for collector in &collectors {
+        print!(" {:>28}", format!("{:?} (max pause)", collector));
+    }
+    println!();
+
+    for (name, source) in WORKLOADS {
+        let module = lox_compiler::compile(source).unwrap();
+        print!("{:<40}", name);
+        for collector in &collectors {
+            let (time, max_pause) = run(&module, *collector);
+            print!(" {:>28}", format!("{:.2?} ({:.2?})", time, max_pause));
+        }
+        println!();
+    }
+}
@@ -0,0 +1,48 @@
+//! Workloads dominated by global variable access, to compare globals by slot with globals in a map by name.
+//!
+//! Run with `cargo bench -p lox-vm --bench globals`, and again with `--features hashmap-globals`.
+
+use std::time::{Duration, Instant};
+
+const RUNS: u32 = 5;
+
+const WORKLOADS: &[(&str, &str)] = &[
+    ("counter", "
+        var i = 0;
+        var sum = 0;
+        while (i < 300000) {
+            sum = sum + i;
+            i = i + 1;
+        }
+    "),
+    ("many globals", "
+        var a = 1; var b = 2; var c = 3; var d = 4; var e = 5;
+        var f = 6; var g = 7; var h = 8; var j = 9; var k = 10;
+        for (var i = 0; i < 100000; i = i + 1) {
+            a = b + c; d = e + f; g = h + j; k = a + d + g;
+        }
+    "),
+    ("global function calls", "
+        fun add(a, b) { return a + b; }
+        var total = 0;
+        for (var i = 0; i < 100000; i = i + 1) {
+            total = add(total, i);
+        }
+    "),
+];
+
+fn main() {
+    let globals = if cfg!(feature = "hashmap-globals") { "by name" } else { "by slot" };
+    println!("{:<30} {:>12} (globals {})", "workload", "time", globals);
+
+    for (name, source) in WORKLOADS {
+        let module = lox_compiler::compile(source).unwrap();
+        let mut total = Duration::default();
+        for _ in 0..RUNS {
+            let start = Instant::now();
+            lox_vm::bettervm::execute(&module).unwrap();
+            total += start.elapsed();
+        }
+        println!("{:<30} {:>12}", name, format!("{:.2?}", total / RUNS));
+    }
+}
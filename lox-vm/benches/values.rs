@@ -0,0 +1,49 @@
+//! Workloads dominated by pushing and popping values, to compare value representations.
+//!
+//! Run with `cargo bench -p lox-vm --bench values`, and again with `--features nan-boxing`.
+
+use std::time::{Duration, Instant};
+
+const RUNS: u32 = 5;
+
+const WORKLOADS: &[(&str, &str)] = &[
+    ("arithmetic", "
+        {
+            var x = 0;
+            for (var i = 0; i < 300000; i = i + 1) {
+                x = x * 0.5 + i / 3 - 1;
+            }
+        }
+    "),
+    ("fibonacci", "
+        fun fib(n) {
+            if (n < 2) return n;
+            return fib(n - 1) + fib(n - 2);
+        }
+        fib(22);
+    "),
+    ("strings", "
+        {
+            var s = \"\";
+            for (var i = 0; i < 100000; i = i + 1) {
+                s = \"a\" + \"b\";
+            }
+        }
+    "),
+];
+
+fn main() {
+    let representation = if cfg!(feature = "nan-boxing") { "nan-boxed" } else { "tagged" };
+    println!("{:<20} {:>12} ({} values, {} bytes each)", "workload", "time", representation, std::mem::size_of::<lox_vm::bettervm::Value>());
+
+    for (name, source) in WORKLOADS {
+        let module = lox_compiler::compile(source).unwrap();
+        let mut total = Duration::default();
+        for _ in 0..RUNS {
+            let start = Instant::now();
+            lox_vm::bettervm::execute(&module).unwrap();
+            total += start.elapsed();
+        }
+        println!("{:<20} {:>12}", name, format!("{:.2?}", total / RUNS));
+    }
+}
@@ -1,26 +1,148 @@
 use super::*;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
 
-struct GcStats {
-    bytes_allocated: usize,
+/// By default minor collections happen whenever the nursery grows past this size.
+/// Smaller nurseries mean shorter but more frequent minor collections.
+pub const NURSERY_SIZE: usize = 256 * 1024;
+
+/// While marking incrementally minor collections can't happen, if the nursery grows past this many
+/// times its normal size marking is finished immediately.
+const MAX_NURSERY_GROWTH: usize = 4;
+
+/// The default amount of objects scanned in a single incremental marking step.
+pub const SLICE_SIZE: usize = 64;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Collector {
+    /// Always collect the entire heap.
+    MarkSweep,
+    /// Collect the nursery often, and the entire heap only when the old generation has grown enough.
+    Generational,
+    /// Like `Generational`, but the entire heap is marked in small steps, interleaved with the program.
+    Incremental,
+}
+
+/// Pauses are the times the program was stopped for the collector, this includes minor collections,
+/// full collections and every incremental marking step.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct PauseStats {
+    pub count: usize,
+    pub total: Duration,
+    pub max: Duration,
+}
+
+impl PauseStats {
+    fn record(&mut self, pause: Duration) {
+        self.count += 1;
+        self.total += pause;
+        self.max = self.max.max(pause);
+    }
+
+    pub fn average(&self) -> Duration {
+        if self.count == 0 { Duration::default() } else { self.total / self.count as u32 }
+    }
+}
+
+/// Counters describing what the collector has done so far.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct GcStats {
+    pub minor_collections: usize,
+    pub major_collections: usize,
+    /// Total amount of bytes ever allocated.
+    pub bytes_allocated: usize,
+    /// Total amount of bytes ever freed.
+    pub bytes_freed: usize,
+    /// Amount of bytes currently allocated, including garbage that hasn't been collected yet.
+    pub heap_bytes: usize,
+    pub peak_heap_bytes: usize,
+    pub pauses: PauseStats,
+}
+
+impl fmt::Display for GcStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "collections: {} minor, {} major", self.minor_collections, self.major_collections)?;
+        writeln!(f, "allocated: {} bytes, freed: {} bytes", self.bytes_allocated, self.bytes_freed)?;
+        writeln!(f, "heap: {} bytes, peak: {} bytes", self.heap_bytes, self.peak_heap_bytes)?;
+        write!(f, "pauses: {} totaling {:.2?}, longest {:.2?}", self.pauses.count, self.pauses.total, self.pauses.max)
+    }
+}
+
+struct GcState {
+    collector: Collector,
+    stress: bool,
+    slice_size: usize,
+    nursery_size: usize,
     threshold: usize,
+    old_threshold: usize,
+    stats: GcStats,
 }
 
-thread_local!(static STATS: RefCell<GcStats> = RefCell::new(GcStats {
-    bytes_allocated: 0,
+impl GcState {
+    fn freed(&mut self, bytes: usize) {
+        self.stats.heap_bytes -= bytes;
+        self.stats.bytes_freed += bytes;
+    }
+
+    fn collect(&mut self) {
+        self.freed(collect());
+        self.stats.major_collections += 1;
+    }
+
+    fn collect_minor(&mut self) {
+        self.freed(collect_minor());
+        self.stats.minor_collections += 1;
+    }
+
+    fn start_marking(&mut self) {
+        HEAP.with(|heap| heap.borrow_mut().start_marking());
+        MARKING.with(|marking| marking.set(true));
+    }
+
+    fn finish_marking(&mut self) {
+        self.freed(HEAP.with(|heap| heap.borrow_mut().finish_marking()));
+        self.stats.major_collections += 1;
+        MARKING.with(|marking| marking.set(false));
+        self.old_threshold = self.nursery_size.max((old_bytes() as f32 * 1.4) as usize);
+    }
+}
+
+thread_local!(static STATE: RefCell<GcState> = RefCell::new(GcState {
+    collector: Collector::Incremental,
+    stress: false,
+    slice_size: SLICE_SIZE,
+    nursery_size: NURSERY_SIZE,
     threshold: 100,
+    old_threshold: NURSERY_SIZE,
+    stats: GcStats::default(),
 }));
 
 thread_local!(static HEAP: RefCell<Heap> = RefCell::new(Heap::new()));
 
+thread_local!(static MARKING: Cell<bool> = const { Cell::new(false) });
+
 pub fn manage<T: 'static + Trace>(data: T) -> Root<T> {
     collect_if_needed();
+    run_finalizers();
     add_bytes::<T>();
     HEAP.with(|heap| heap.borrow_mut().manage(data))
 }
 
+/// Like `manage`, but `finalizer` is called after the object has been collected.
+/// The finalizer runs once the collection is over, so it is free to allocate. Other objects the
+/// finalized object points to may already be gone and must not be used.
+pub fn manage_with_finalizer<T: 'static + Trace, F: FnOnce(&T) + 'static>(data: T, finalizer: F) -> Root<T> {
+    collect_if_needed();
+    run_finalizers();
+    add_bytes::<T>();
+    HEAP.with(|heap| heap.borrow_mut().manage_with_finalizer(data, finalizer))
+}
+
 pub fn unique<T: 'static + Trace>(data: T) -> UniqueRoot<T> {
     collect_if_needed();
+    run_finalizers();
     add_bytes::<T>();
     HEAP.with(|heap| heap.borrow_mut().unique(data))
 }
@@ -29,34 +151,194 @@ pub fn root<T: 'static + Trace + ?Sized>(obj: Gc<T>) -> Root<T> {
     HEAP.with(|heap| heap.borrow_mut().root(obj))
 }
 
-//TODO Currently unused
-// pub fn force_collect() {
-//     STATS.with(|stats| {
-//         let mut stats = stats.borrow_mut();
-//         stats.bytes_allocated -= collect();
-//     })
-// }
+/// Must be called after a `Gc` has been stored inside `obj` through interior mutability.
+pub fn write_barrier<T: 'static + Trace>(obj: Gc<T>) {
+    if obj.needs_remembering() || (obj.is_black() && MARKING.with(Cell::get)) {
+        HEAP.with(|heap| heap.borrow_mut().write_barrier(obj))
+    }
+}
+
+/// Do a bit of incremental marking, if there is any to do.
+/// This should only be called when every live object is reachable from a root.
+pub fn step() {
+    if !MARKING.with(Cell::get) { return; }
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let start = Instant::now();
+
+        let done = HEAP.with(|heap| heap.borrow_mut().mark_step(state.slice_size));
+        if done {
+            state.finish_marking();
+        }
+
+        state.stats.pauses.record(start.elapsed());
+    });
+    run_finalizers();
+}
+
+pub fn set_collector(collector: Collector) {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if MARKING.with(Cell::get) {
+            state.finish_marking();
+        }
+        state.collector = collector;
+    });
+    run_finalizers();
+}
+
+/// In stress mode the entire heap is collected on every allocation, and unreachable objects are poisoned
+/// instead of freed, so any use of an object that was collected too early panics. This is very slow and
+/// never frees any memory, it is only meant for finding rooting bugs.
+pub fn set_stress(stress: bool) {
+    STATE.with(|state| state.borrow_mut().stress = stress);
+    HEAP.with(|heap| heap.borrow_mut().set_poison(stress));
+}
+
+/// Set the amount of objects scanned in a single incremental marking step.
+pub fn set_slice_size(slice_size: usize) {
+    STATE.with(|state| state.borrow_mut().slice_size = slice_size.max(1))
+}
+
+/// Set the size the nursery can grow to before a minor collection happens.
+pub fn set_nursery_size(nursery_size: usize) {
+    STATE.with(|state| state.borrow_mut().nursery_size = nursery_size)
+}
+
+pub fn stats() -> GcStats {
+    STATE.with(|state| state.borrow().stats)
+}
+
+/// Reset all counters, except for the current heap size.
+pub fn reset_stats() {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let heap_bytes = state.stats.heap_bytes;
+        state.stats = GcStats { heap_bytes, peak_heap_bytes: heap_bytes, ..GcStats::default() };
+    })
+}
+
+/// Count the objects currently on the heap by their type name, this includes garbage that hasn't been collected yet.
+/// Use `force_collect` first to only count live objects.
+pub fn objects_by_type() -> HashMap<&'static str, usize> {
+    HEAP.with(|heap| heap.borrow().objects_by_type())
+}
+
+/// Collect the entire heap right now, this finishes incremental marking if it is in progress.
+/// Returns the amount of bytes freed.
+/// This should only be called when every live object is reachable from a root.
+pub fn force_collect() -> usize {
+    let freed = STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let start = Instant::now();
+        let freed = state.stats.bytes_freed;
+
+        if MARKING.with(Cell::get) {
+            state.finish_marking();
+        } else {
+            state.collect();
+        }
+
+        state.stats.pauses.record(start.elapsed());
+        state.stats.bytes_freed - freed
+    });
+    run_finalizers();
+    freed
+}
+
+/// Finalizers can't run while the state or the heap is borrowed, so they are deferred until here.
+fn run_finalizers() {
+    HEAP.with(|heap| {
+        if heap.borrow().has_finalizable() {
+            Heap::run_finalizers(heap);
+        }
+    })
+}
 
 fn collect() -> usize {
     HEAP.with(|heap| heap.borrow_mut().collect())
 }
 
+fn collect_minor() -> usize {
+    HEAP.with(|heap| heap.borrow_mut().collect_minor())
+}
+
+fn nursery_bytes() -> usize {
+    HEAP.with(|heap| heap.borrow().nursery_bytes())
+}
+
+fn old_bytes() -> usize {
+    HEAP.with(|heap| heap.borrow().old_bytes())
+}
+
 fn collect_if_needed() {
-    STATS.with(|stats| {
-        let mut stats = stats.borrow_mut();
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let start = Instant::now();
+
+        if state.stress {
+            if MARKING.with(Cell::get) {
+                state.finish_marking();
+            } else {
+                state.collect();
+            }
+            state.stats.pauses.record(start.elapsed());
+            return;
+        }
+
+        match state.collector {
+            Collector::MarkSweep => {
+                if state.stats.heap_bytes > state.threshold {
+                    state.collect();
 
-        if stats.bytes_allocated > stats.threshold {
-            stats.bytes_allocated -= collect();
+                    state.threshold = (state.stats.heap_bytes as f32 * 1.4) as usize;
+                } else {
+                    return;
+                }
+            },
+            Collector::Generational => {
+                if nursery_bytes() > state.nursery_size {
+                    state.collect_minor();
 
-            stats.threshold = (stats.bytes_allocated as f32 * 1.4) as usize;
+                    // Only minor collections grow the old generation
+                    if old_bytes() > state.old_threshold {
+                        state.collect();
+
+                        state.old_threshold = state.nursery_size.max((old_bytes() as f32 * 1.4) as usize);
+                    }
+                } else {
+                    return;
+                }
+            },
+            Collector::Incremental => {
+                if MARKING.with(Cell::get) {
+                    if nursery_bytes() > state.nursery_size * MAX_NURSERY_GROWTH {
+                        state.finish_marking();
+                    } else {
+                        return;
+                    }
+                } else if nursery_bytes() > state.nursery_size {
+                    state.collect_minor();
+
+                    if old_bytes() > state.old_threshold {
+                        state.start_marking();
+                    }
+                } else {
+                    return;
+                }
+            },
         }
-        
+
+        state.stats.pauses.record(start.elapsed());
     })
 }
 
 fn add_bytes<T>() {
-    STATS.with(|stats| {
-        let mut stats = stats.borrow_mut();
+    STATE.with(|state| {
+        let stats = &mut state.borrow_mut().stats;
         stats.bytes_allocated += std::mem::size_of::<T>();
+        stats.heap_bytes += std::mem::size_of::<T>();
+        stats.peak_heap_bytes = stats.peak_heap_bytes.max(stats.heap_bytes);
     })
-}
\ No newline at end of file
+}
@@ -2,12 +2,19 @@ pub mod gc;
 
 use std::ptr::NonNull;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::cell::Cell;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
 use std::ops::{Deref, DerefMut};
 use std::fmt;
+use std::collections::HashMap;
 
 pub trait Trace {
     fn trace(&self);
+
+    /// The name of the type, used to report statistics about the heap.
+    fn type_name(&self) -> &'static str {
+        std::any::type_name::<Self>()
+    }
 }
 
 impl fmt::Debug for dyn Trace {
@@ -16,10 +23,33 @@ impl fmt::Debug for dyn Trace {
     }
 }
 
+/// White objects have not been reached yet, gray objects have been reached but their children
+/// still need to be scanned, black objects and all of their children have been reached.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Color {
+    White,
+    Gray,
+    Black,
+}
+
 #[derive(Debug)]
 struct Header {
     roots: AtomicUsize,
-    marked: Cell<bool>,
+    color: Cell<Color>,
+    old: Cell<bool>,
+    remembered: Cell<bool>,
+    poisoned: Cell<bool>,
+    /// Shared with every `Weak` pointing to this object, it is cleared when the object is collected.
+    alive: RefCell<Option<Rc<Cell<bool>>>>,
+    finalizer: RefCell<Option<Finalizer>>,
+}
+
+struct Finalizer(Box<dyn FnOnce()>);
+
+impl fmt::Debug for Finalizer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<Finalizer>")
+    }
 }
 
 #[derive(Debug)]
@@ -28,9 +58,55 @@ struct Allocation<T: 'static + Trace + ?Sized> {
     data: T,
 }
 
+/// A generational heap.
+///
+/// New objects are allocated in the nursery. A minor collection only marks and sweeps the nursery,
+/// survivors are promoted to the old generation. Old objects stay marked between collections, so
+/// marking stops as soon as it reaches one. Pointers from old objects into the nursery are found
+/// through the remembered set, which contains every old object that was written to
+/// (see `write_barrier`) and every old object that is rooted.
+///
+/// Marking the entire heap can be done incrementally, see `start_marking`, `mark_step` and `finish_marking`.
+/// Minor collections can't happen while the heap is being marked incrementally.
 #[derive(Debug)]
 pub struct Heap {
-    objects: Vec<Box<Allocation<dyn Trace>>>,
+    nursery: Vec<Box<Allocation<dyn Trace>>>,
+    old: Vec<Box<Allocation<dyn Trace>>>,
+    remembered: Vec<NonNull<Allocation<dyn Trace>>>,
+    nursery_bytes: usize,
+    old_bytes: usize,
+    marking: bool,
+    poison: bool,
+    graveyard: Vec<Box<Allocation<dyn Trace>>>,
+    finalizable: Vec<Box<Allocation<dyn Trace>>>,
+}
+
+thread_local!(static GRAY: RefCell<Vec<NonNull<Allocation<dyn Trace>>>> = RefCell::new(vec![]));
+
+/// Turn a white object gray, it'll be scanned by `drain`.
+fn shade(ptr: NonNull<Allocation<dyn Trace>>) {
+    let allocation = unsafe { ptr.as_ref() };
+    allocation.check_poison();
+    if allocation.header.color.get() == Color::White {
+        allocation.header.color.set(Color::Gray);
+        GRAY.with(|gray| gray.borrow_mut().push(ptr));
+    }
+}
+
+/// Scan up to `budget` gray objects, returns true if there are no gray objects left.
+fn drain(budget: usize) -> bool {
+    for _ in 0..budget {
+        let next = GRAY.with(|gray| gray.borrow_mut().pop());
+        match next {
+            Some(ptr) => {
+                let allocation = unsafe { ptr.as_ref() };
+                allocation.header.color.set(Color::Black);
+                allocation.data.trace();
+            },
+            None => return true,
+        }
+    }
+    GRAY.with(|gray| gray.borrow().is_empty())
 }
 
 pub struct Gc<T: 'static + Trace + ?Sized> {
@@ -45,9 +121,52 @@ pub struct UniqueRoot<T: 'static + Trace + ?Sized> {
     ptr: NonNull<Allocation<T>>,
 }
 
+/// A pointer that doesn't keep its object alive, it can be upgraded to a `Root` as long as
+/// the object hasn't been collected.
+pub struct Weak<T: 'static + Trace + ?Sized> {
+    ptr: NonNull<Allocation<T>>,
+    alive: Rc<Cell<bool>>,
+}
+
 impl<T: 'static + Trace + ?Sized> Allocation<T> {
     fn unmark(&self) {
-        self.header.marked.set(false);
+        self.header.color.set(Color::White);
+    }
+
+    fn check_poison(&self) {
+        if self.header.poisoned.get() {
+            panic!("Use after free of a {}", self.data.type_name());
+        }
+    }
+
+    fn is_marked(&self) -> bool {
+        self.header.color.get() != Color::White
+    }
+
+    fn is_rooted(&self) -> bool {
+        self.header.roots.load(Ordering::Relaxed) > 0
+    }
+
+    fn promote(&self) {
+        self.header.old.set(true);
+    }
+
+    fn downgrade(&self, ptr: NonNull<Allocation<T>>) -> Weak<T> {
+        let alive = self.header.alive.borrow_mut().get_or_insert_with(|| Rc::new(Cell::new(true))).clone();
+        Weak { ptr, alive }
+    }
+
+    fn kill(&self) {
+        if let Some(alive) = self.header.alive.borrow().as_ref() {
+            alive.set(false);
+        }
+    }
+
+    fn finalize(&self) {
+        let finalizer = self.header.finalizer.borrow_mut().take();
+        if let Some(Finalizer(finalizer)) = finalizer {
+            finalizer();
+        }
     }
 
     fn root(&self) {
@@ -57,19 +176,16 @@ impl<T: 'static + Trace + ?Sized> Allocation<T> {
         self.header.roots.fetch_sub(1, Ordering::Relaxed);
     }
 }
-impl<T: 'static + Trace + ?Sized> Trace for Allocation<T> {
-    fn trace(&self) {
-        if !self.header.marked.replace(true) {
-            self.data.trace();
-        }
-    }
-}
-
 impl Default for Header {
     fn default() -> Self {
         Header {
             roots: AtomicUsize::new(0),
-            marked: Cell::new(false),
+            color: Cell::new(Color::White),
+            old: Cell::new(false),
+            remembered: Cell::new(false),
+            poisoned: Cell::new(false),
+            alive: RefCell::new(None),
+            finalizer: RefCell::new(None),
         }
     }
 }
@@ -77,14 +193,33 @@ impl Default for Header {
 impl Heap {
     pub fn new() -> Self {
         Heap{
-            objects: vec![],
+            nursery: vec![],
+            old: vec![],
+            remembered: vec![],
+            nursery_bytes: 0,
+            old_bytes: 0,
+            marking: false,
+            poison: false,
+            graveyard: vec![],
+            finalizable: vec![],
         }
     }
 
+    /// Instead of freeing unreachable objects keep them around, and panic when they are used again.
+    /// This is meant for finding rooting bugs, memory is never freed while this is enabled.
+    pub fn set_poison(&mut self, poison: bool) {
+        self.poison = poison;
+    }
+
     fn allocate<T: 'static + Trace>(&mut self, data: T) -> NonNull<Allocation<T>> {
         let mut alloc = Box::new(Allocation{header: Header::default(), data});
         let ptr = unsafe { NonNull::new_unchecked(&mut *alloc) };
-        self.objects.push(alloc);
+        self.nursery_bytes += std::mem::size_of::<T>();
+        self.nursery.push(alloc);
+        if self.marking {
+            // New objects can point to white objects, so they need to be scanned as well
+            shade(ptr);
+        }
         ptr
     }
 
@@ -107,26 +242,194 @@ impl Heap {
         root
     }
 
+    /// Like `manage`, but `finalizer` is called once the object has been collected.
+    /// Finalizers don't run during a collection, but afterwards when `run_finalizers` is called.
+    /// The object is still valid when the finalizer runs, but any objects it points to might not be.
+    pub fn manage_with_finalizer<T: 'static + Trace, F: FnOnce(&T) + 'static>(&mut self, data: T, finalizer: F) -> Root<T> {
+        let root = self.manage(data);
+        let ptr = root.ptr;
+        let finalizer = Finalizer(Box::new(move || finalizer(unsafe { &ptr.as_ref().data })));
+        *root.allocation().header.finalizer.borrow_mut() = Some(finalizer);
+        root
+    }
+
+    /// Run the finalizers of every object collected since the last call, and free those objects.
+    /// The heap must not be borrowed by the caller, because finalizers may use it.
+    fn run_finalizers(heap: &RefCell<Heap>) {
+        let objects = std::mem::take(&mut heap.borrow_mut().finalizable);
+        for object in &objects {
+            object.finalize();
+        }
+
+        let mut heap = heap.borrow_mut();
+        for object in objects {
+            heap.bury(object);
+        }
+    }
+
+    fn has_finalizable(&self) -> bool {
+        !self.finalizable.is_empty()
+    }
+
+    /// Record that `obj` has been written to. This must be called after storing a `Gc` inside an
+    /// object through interior mutability, otherwise a minor collection can miss the stored value.
+    /// Rooted objects that are written to through `UniqueRoot` don't need this, they are always remembered.
+    ///
+    /// While marking, an already scanned object that is written to is turned gray again so it'll be rescanned.
+    pub fn write_barrier<T: 'static + Trace>(&mut self, obj: Gc<T>) {
+        if obj.needs_remembering() {
+            obj.allocation().header.remembered.set(true);
+            self.remembered.push(obj.ptr);
+        }
+
+        let header = &obj.allocation().header;
+        if self.marking && header.color.get() == Color::Black {
+            header.color.set(Color::Gray);
+            GRAY.with(|gray| gray.borrow_mut().push(obj.ptr));
+        }
+    }
+
+    pub fn nursery_bytes(&self) -> usize { self.nursery_bytes }
+    pub fn old_bytes(&self) -> usize { self.old_bytes }
+    pub fn is_marking(&self) -> bool { self.marking }
+
+    /// Collect the entire heap, returns the amount of bytes freed.
     pub fn collect(&mut self) -> usize {
-        self.mark();
-        let bytes = self.bytes_marked();
-        self.sweep();
+        if !self.marking {
+            self.start_marking();
+        }
+        self.finish_marking()
+    }
+
+    /// Collect only the nursery, returns the amount of bytes freed.
+    pub fn collect_minor(&mut self) -> usize {
+        debug_assert!(!self.marking, "Can't do a minor collection while marking");
+        self.mark_minor();
+        let bytes = self.sweep_nursery();
+        self.forget_remembered();
         bytes
     }
 
-    fn mark(&mut self) {
-        for object in &self.objects { object.unmark(); }
-        self.objects.iter().filter(|o| o.header.roots.load(Ordering::Relaxed) > 0).for_each(|o| o.trace());
+    /// Start marking the entire heap. Roots are turned gray, everything else white.
+    pub fn start_marking(&mut self) {
+        for object in self.old.iter().chain(&self.nursery) { object.unmark(); }
+        self.old.iter().chain(&self.nursery).filter(|o| o.is_rooted()).for_each(|o| shade(NonNull::from(&**o)));
+        self.marking = true;
     }
 
-    fn sweep(&mut self) {
-        self.objects.retain(|o| o.header.marked.get());
+    /// Scan up to `budget` gray objects, returns true if there is nothing left to scan.
+    /// Call `finish_marking` afterwards to sweep the heap.
+    pub fn mark_step(&mut self, budget: usize) -> bool {
+        drain(budget)
     }
 
-    fn bytes_marked(&self) -> usize {
+    /// Finish marking the entire heap and sweep it, returns the amount of bytes freed.
+    pub fn finish_marking(&mut self) -> usize {
+        // Roots can change without a write barrier, so all of them need to be scanned again
+        for object in self.old.iter().chain(&self.nursery).filter(|o| o.is_rooted()) {
+            object.header.color.set(Color::Gray);
+            GRAY.with(|gray| gray.borrow_mut().push(NonNull::from(&**object)));
+        }
+        drain(usize::MAX);
+
+        self.marking = false;
+        let bytes = self.sweep();
+        self.remember_roots();
+        bytes
+    }
+
+    fn mark_minor(&mut self) {
+        for object in &self.nursery { object.unmark(); }
+        // Old objects are already black, so they have to be scanned directly
+        for object in &self.remembered {
+            unsafe { object.as_ref() }.data.trace();
+        }
+        self.nursery.iter().filter(|o| o.is_rooted()).for_each(|o| shade(NonNull::from(&**o)));
+        drain(usize::MAX);
+    }
+
+    fn sweep(&mut self) -> usize {
+        let mut bytes = Self::bytes_unmarked(&self.old);
+        let old = std::mem::take(&mut self.old);
+        self.old = self.sweep_objects(old);
+        self.old_bytes -= bytes;
+
+        bytes += self.sweep_nursery();
+        bytes
+    }
+
+    fn sweep_nursery(&mut self) -> usize {
+        let bytes = Self::bytes_unmarked(&self.nursery);
+        let nursery = std::mem::take(&mut self.nursery);
+        self.nursery = self.sweep_objects(nursery);
+
+        for object in &self.nursery {
+            object.promote();
+            if object.is_rooted() {
+                object.header.remembered.set(true);
+                self.remembered.push(NonNull::from(&**object));
+            }
+        }
+
+        self.old_bytes += self.nursery_bytes - bytes;
+        self.nursery_bytes = 0;
+        self.old.append(&mut self.nursery);
+        bytes
+    }
+
+    /// After a minor collection nothing in the old generation points into the nursery anymore,
+    /// only rooted objects need to stay remembered.
+    fn forget_remembered(&mut self) {
+        self.remembered.retain(|object| {
+            let object = unsafe { object.as_ref() };
+            object.header.remembered.set(object.is_rooted());
+            object.is_rooted()
+        });
+    }
+
+    fn remember_roots(&mut self) {
+        self.remembered.clear();
+        for object in &self.old {
+            object.header.remembered.set(object.is_rooted());
+            if object.is_rooted() {
+                self.remembered.push(NonNull::from(&**object));
+            }
+        }
+    }
+
+    pub fn objects_by_type(&self) -> HashMap<&'static str, usize> {
+        let mut objects = HashMap::new();
+        for object in self.old.iter().chain(&self.nursery) {
+            *objects.entry(object.data.type_name()).or_insert(0) += 1;
+        }
+        objects
+    }
+
+    /// Returns the marked objects, the others are freed unless they still need to be finalized.
+    fn sweep_objects(&mut self, objects: Vec<Box<Allocation<dyn Trace>>>) -> Vec<Box<Allocation<dyn Trace>>> {
+        let (live, dead): (Vec<_>, Vec<_>) = objects.into_iter().partition(|o| o.is_marked());
+        for object in dead {
+            object.kill();
+            if object.header.finalizer.borrow().is_some() {
+                self.finalizable.push(object);
+            } else {
+                self.bury(object);
+            }
+        }
+        live
+    }
+
+    fn bury(&mut self, object: Box<Allocation<dyn Trace>>) {
+        if self.poison {
+            object.header.poisoned.set(true);
+            self.graveyard.push(object);
+        }
+    }
+
+    fn bytes_unmarked(objects: &[Box<Allocation<dyn Trace>>]) -> usize {
         let mut bytes = 0;
-        for object in &self.objects {
-            if !object.header.marked.get() {
+        for object in objects {
+            if !object.is_marked() {
                 bytes += std::mem::size_of_val(&object.data);
             }
         }
@@ -134,9 +437,49 @@ impl Heap {
     }
 }
 
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<T: 'static + Trace + ?Sized> Gc<T> {
     fn allocation(&self) -> &Allocation<T> {
-        unsafe { &self.ptr.as_ref() }
+        let allocation = unsafe { self.ptr.as_ref() };
+        if cfg!(debug_assertions) {
+            allocation.check_poison();
+        }
+        allocation
+    }
+
+    fn needs_remembering(&self) -> bool {
+        let header = &self.allocation().header;
+        header.old.get() && !header.remembered.get()
+    }
+
+    fn is_black(&self) -> bool {
+        self.allocation().header.color.get() == Color::Black
+    }
+
+    pub fn downgrade(&self) -> Weak<T> {
+        self.allocation().downgrade(self.ptr)
+    }
+
+    /// True if both point to the same object.
+    pub fn ptr_eq(a: &Self, b: &Self) -> bool {
+        std::ptr::eq(a.ptr.as_ptr() as *const u8, b.ptr.as_ptr() as *const u8)
+    }
+}
+impl<T: 'static + Trace> Gc<T> {
+    /// The address of the object, which is aligned to at least 8 bytes.
+    pub fn into_raw(self) -> *const () {
+        self.ptr.as_ptr() as *const ()
+    }
+
+    /// # Safety
+    /// `ptr` must come from `into_raw` on a `Gc` of the same type.
+    pub unsafe fn from_raw(ptr: *const ()) -> Gc<T> {
+        Gc { ptr: NonNull::new_unchecked(ptr as *mut Allocation<T>) }
     }
 }
 impl<T: 'static + Trace + ?Sized> Copy for Gc<T> { }
@@ -150,19 +493,27 @@ impl<T: 'static + Trace + ?Sized> Deref for Gc<T> {
 }
 impl<T: fmt::Debug + 'static + Trace + ?Sized> fmt::Debug for Gc<T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let inner: &T = &*self;
+        let inner: &T = self;
         write!(f, "Gc({:?})", inner)
     }
 }
 impl<T: fmt::Display + 'static + Trace + ?Sized> fmt::Display for Gc<T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let inner: &T = &*self;
+        let inner: &T = self;
         inner.fmt(f)
     }
 }
-impl<T: 'static + Trace + ?Sized> Trace for Gc<T> { fn trace(&self) { self.allocation().trace(); } }
+impl<T: 'static + Trace> Trace for Gc<T> { fn trace(&self) { shade(self.ptr); } }
+// Equality and hashing use the identity of the object, not its contents
+impl<T: 'static + Trace + ?Sized> PartialEq for Gc<T> { fn eq(&self, other: &Gc<T>) -> bool { Gc::ptr_eq(self, other) } }
+impl<T: 'static + Trace + ?Sized> Eq for Gc<T> { }
+impl<T: 'static + Trace + ?Sized> std::hash::Hash for Gc<T> {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        (self.ptr.as_ptr() as *const u8).hash(state);
+    }
+}
 
-impl<T: 'static + Trace + ?Sized> Trace for Root<T> { fn trace(&self) { self.allocation().trace(); } }
+impl<T: 'static + Trace> Trace for Root<T> { fn trace(&self) { shade(self.ptr); } }
 impl<T: 'static + Trace + ?Sized> Clone for Root<T> {
     fn clone(&self) -> Root<T> {
         self.allocation().root();
@@ -171,12 +522,16 @@ impl<T: 'static + Trace + ?Sized> Clone for Root<T> {
 }
 impl<T: 'static + Trace + ?Sized> Root<T> {
     fn allocation(&self) -> &Allocation<T> {
-        unsafe { &self.ptr.as_ref() }
+        unsafe { self.ptr.as_ref() }
     }
 
     pub fn as_gc(&self) -> Gc<T> {
         Gc { ptr: self.ptr }
     }
+
+    pub fn downgrade(&self) -> Weak<T> {
+        self.allocation().downgrade(self.ptr)
+    }
 }
 impl<T: 'static + Trace + ?Sized> Drop for Root<T> {
     fn drop(&mut self) {
@@ -192,18 +547,18 @@ impl<T: 'static + Trace + ?Sized> Deref for Root<T> {
 }
 impl<T: fmt::Debug + 'static + Trace + ?Sized> fmt::Debug for Root<T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let inner: &T = &*self;
+        let inner: &T = self;
         write!(f, "Root({:?})", inner)
     }
 }
 
-impl<T: 'static + Trace + ?Sized> Trace for UniqueRoot<T> { fn trace(&self) { self.allocation().trace(); } }
+impl<T: 'static + Trace> Trace for UniqueRoot<T> { fn trace(&self) { shade(self.ptr); } }
 impl<T: 'static + Trace + ?Sized> UniqueRoot<T> {
     fn allocation_mut(&mut self) -> &mut Allocation<T> {
         unsafe { self.ptr.as_mut() }
     }
     fn allocation(&self) -> &Allocation<T> {
-        unsafe { &self.ptr.as_ref() }
+        unsafe { self.ptr.as_ref() }
     }
 }
 impl<T: 'static + Trace + ?Sized> Drop for UniqueRoot<T> {
@@ -225,16 +580,41 @@ impl<T: 'static + Trace + ?Sized> DerefMut for UniqueRoot<T> {
 }
 impl<T: fmt::Debug + 'static + Trace + ?Sized> fmt::Debug for UniqueRoot<T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let inner: &T = &*self;
+        let inner: &T = self;
         write!(f, "UniqueRoot({:?})", inner)
     }
 }
 
 
+impl<T: 'static + Trace + ?Sized> Weak<T> {
+    /// Returns a root to the object, or None if it has been collected.
+    pub fn upgrade(&self) -> Option<Root<T>> {
+        if self.alive.get() {
+            unsafe { self.ptr.as_ref() }.root();
+            Some(Root { ptr: self.ptr })
+        } else {
+            None
+        }
+    }
+
+    pub fn is_alive(&self) -> bool {
+        self.alive.get()
+    }
+}
+impl<T: 'static + Trace + ?Sized> Clone for Weak<T> {
+    fn clone(&self) -> Weak<T> {
+        Weak { ptr: self.ptr, alive: self.alive.clone() }
+    }
+}
+// Weak pointers don't keep their object alive, so there is nothing to trace
+impl<T: 'static + Trace + ?Sized> Trace for Weak<T> { fn trace(&self) {} }
+impl<T: 'static + Trace + ?Sized> fmt::Debug for Weak<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_alive() { write!(f, "Weak(<alive>)") } else { write!(f, "Weak(<collected>)") }
+    }
+}
 
-use std::cell::RefCell;
 use std::hash::Hash;
-use std::collections::HashMap;
 impl<T: Trace> Trace for RefCell<T> {
     fn trace(&self) {
         self.borrow().trace();
@@ -254,12 +634,23 @@ impl<T: Trace> Trace for &Vec<T> {
         }
     }
 }
-impl<K: Eq + Hash, T: Trace> Trace for HashMap<K, T> {
+impl<K: Eq + Hash + Trace, T: Trace> Trace for HashMap<K, T> {
     fn trace(&self) {
-        for val in self.values() {
+        for (key, val) in self {
+            key.trace();
             val.trace();
         }
     }
 }
 
-impl Trace for String { fn trace(&self) {} }
\ No newline at end of file
+impl<T: Trace> Trace for Option<T> {
+    fn trace(&self) {
+        if let Some(value) = self {
+            value.trace();
+        }
+    }
+}
+
+impl Trace for String { fn trace(&self) {} }
+#[cfg(test)]
+mod tests;
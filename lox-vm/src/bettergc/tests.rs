@@ -0,0 +1,264 @@
+use super::*;
+use std::cell::{Cell, RefCell};
+
+struct Node {
+    children: RefCell<Vec<Gc<Node>>>,
+}
+
+impl Trace for Node {
+    fn trace(&self) {
+        self.children.trace();
+    }
+}
+
+fn node() -> Node {
+    Node { children: RefCell::new(vec![]) }
+}
+
+#[test]
+fn test_minor_collection_frees_unrooted_nursery() {
+    let mut heap = Heap::new();
+    let root = heap.manage(node());
+    heap.manage(node());
+
+    heap.collect_minor();
+
+    assert_eq!(heap.nursery_bytes(), 0);
+    assert_eq!(heap.old_bytes(), std::mem::size_of::<Node>());
+    drop(root);
+}
+
+#[test]
+fn test_minor_collection_keeps_children_of_young_roots() {
+    let mut heap = Heap::new();
+    let root = heap.manage(node());
+    let child = heap.manage(node());
+    root.children.borrow_mut().push(child.as_gc());
+    drop(child);
+
+    heap.collect_minor();
+
+    assert_eq!(heap.old_bytes(), 2 * std::mem::size_of::<Node>());
+}
+
+#[test]
+fn test_write_barrier_keeps_young_object_alive() {
+    let mut heap = Heap::new();
+    let old = heap.manage(node());
+    heap.collect_minor();
+    // Old is now only reachable from a plain Gc, it must be remembered through the barrier
+    let old = old.as_gc();
+
+    let young = heap.manage(node());
+    old.children.borrow_mut().push(young.as_gc());
+    heap.write_barrier(old);
+    drop(young);
+
+    let freed = heap.collect_minor();
+
+    assert_eq!(freed, 0);
+    assert_eq!(heap.old_bytes(), 2 * std::mem::size_of::<Node>());
+}
+
+#[test]
+fn test_rooted_old_object_is_remembered() {
+    let mut heap = Heap::new();
+    let old = heap.unique(vec![]);
+    heap.collect_minor();
+
+    let mut old = old;
+    let young = heap.manage(node());
+    old.push(young.as_gc());
+    drop(young);
+
+    let freed = heap.collect_minor();
+
+    assert_eq!(freed, 0);
+    assert_eq!(old.len(), 1);
+}
+
+#[test]
+fn test_major_collection_frees_old_garbage() {
+    let mut heap = Heap::new();
+    let root = heap.manage(node());
+    heap.manage(node());
+    let garbage = heap.manage(node());
+    heap.collect_minor();
+    drop(garbage);
+
+    assert_eq!(heap.collect_minor(), 0);
+    assert_eq!(heap.collect(), std::mem::size_of::<Node>());
+    assert_eq!(heap.old_bytes(), std::mem::size_of::<Node>());
+    drop(root);
+}
+
+#[test]
+fn test_incremental_marking_frees_garbage() {
+    let mut heap = Heap::new();
+    let root = heap.manage(node());
+    let child = heap.manage(node());
+    root.children.borrow_mut().push(child.as_gc());
+    drop(child);
+    drop(heap.manage(node()));
+
+    heap.start_marking();
+    while !heap.mark_step(1) {}
+    let freed = heap.finish_marking();
+
+    assert_eq!(freed, std::mem::size_of::<Node>());
+    assert!(!heap.is_marking());
+}
+
+#[test]
+fn test_write_barrier_during_marking() {
+    let mut heap = Heap::new();
+    let root = heap.manage(node());
+    let gray = heap.manage(node());
+    let black = heap.manage(node());
+    let moved = heap.manage(node());
+    gray.children.borrow_mut().push(moved.as_gc());
+    // Children are scanned last to first, so `black` will be scanned before `gray`
+    root.children.borrow_mut().push(gray.as_gc());
+    root.children.borrow_mut().push(black.as_gc());
+    let (gray, black) = (gray.as_gc(), black.as_gc());
+    drop(moved);
+
+    heap.start_marking();
+    heap.mark_step(2);
+
+    // Move the only reference to `moved` from an unscanned object to a scanned one
+    let moved = gray.children.borrow_mut().pop().unwrap();
+    black.children.borrow_mut().push(moved);
+    heap.write_barrier(black);
+
+    while !heap.mark_step(1) {}
+    let freed = heap.finish_marking();
+
+    assert_eq!(freed, 0);
+    assert_eq!(black.children.borrow().len(), 1);
+}
+
+#[test]
+fn test_allocation_during_marking_survives() {
+    let mut heap = Heap::new();
+    let mut roots = heap.unique(vec![]);
+
+    heap.start_marking();
+    while !heap.mark_step(1) {}
+
+    // The stack-like root is changed without a write barrier, it is rescanned when marking finishes
+    let young = heap.manage(node());
+    roots.push(young.as_gc());
+    drop(young);
+
+    let freed = heap.finish_marking();
+
+    assert_eq!(freed, 0);
+}
+
+#[test]
+fn test_objects_by_type() {
+    let mut heap = Heap::new();
+    let _a = heap.manage(node());
+    let _b = heap.manage(node());
+    let _c = heap.manage(String::from("c"));
+
+    let objects = heap.objects_by_type();
+
+    assert_eq!(objects[std::any::type_name::<Node>()], 2);
+    assert_eq!(objects[std::any::type_name::<String>()], 1);
+}
+
+#[test]
+fn test_stats() {
+    gc::reset_stats();
+    let before = gc::stats();
+    let root = gc::manage(node());
+    drop(gc::manage(node()));
+
+    let freed = gc::force_collect();
+    let after = gc::stats();
+
+    assert_eq!(freed, std::mem::size_of::<Node>());
+    assert_eq!(after.bytes_allocated - before.bytes_allocated, 2 * std::mem::size_of::<Node>());
+    assert_eq!(after.bytes_freed - before.bytes_freed, freed);
+    assert_eq!(after.major_collections, before.major_collections + 1);
+    assert!(after.peak_heap_bytes >= after.heap_bytes);
+    assert!(after.pauses.count > before.pauses.count);
+    drop(root);
+}
+
+#[test]
+fn test_weak_is_cleared_when_collected() {
+    let mut heap = Heap::new();
+    let root = heap.manage(node());
+    let weak = root.downgrade();
+    let garbage = heap.manage(node()).downgrade();
+
+    heap.collect();
+
+    assert!(weak.is_alive());
+    assert!(!garbage.is_alive());
+    assert!(garbage.upgrade().is_none());
+
+    drop(root);
+    let upgraded = weak.upgrade().unwrap();
+    heap.collect();
+    assert!(weak.is_alive());
+
+    drop(upgraded);
+    heap.collect();
+    assert!(!weak.is_alive());
+}
+
+#[test]
+fn test_finalizer_runs_after_sweep() {
+    use std::rc::Rc;
+
+    let heap = RefCell::new(Heap::new());
+    let finalized = Rc::new(Cell::new(0));
+    let counter = finalized.clone();
+    let root = heap.borrow_mut().manage_with_finalizer(node(), move |node| {
+        assert!(node.children.borrow().is_empty());
+        counter.set(counter.get() + 1);
+    });
+
+    heap.borrow_mut().collect();
+    Heap::run_finalizers(&heap);
+    assert_eq!(finalized.get(), 0);
+
+    drop(root);
+    heap.borrow_mut().collect();
+    assert_eq!(finalized.get(), 0);
+    assert!(heap.borrow().has_finalizable());
+
+    Heap::run_finalizers(&heap);
+    assert_eq!(finalized.get(), 1);
+    assert!(!heap.borrow().has_finalizable());
+    assert_eq!(heap.borrow().old_bytes(), 0);
+}
+
+#[test]
+#[should_panic(expected = "Use after free")]
+fn test_poisoned_use_after_free() {
+    let mut heap = Heap::new();
+    heap.set_poison(true);
+    let unrooted = heap.manage(node()).as_gc();
+
+    heap.collect();
+
+    unrooted.children.borrow();
+}
+
+#[test]
+#[should_panic(expected = "Use after free")]
+fn test_poisoned_object_reachable_again() {
+    let mut heap = Heap::new();
+    heap.set_poison(true);
+    let root = heap.manage(node());
+    let unrooted = heap.manage(node()).as_gc();
+    heap.collect();
+
+    root.children.borrow_mut().push(unrooted);
+    heap.collect();
+}
@@ -0,0 +1,88 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+/// How far `Vm::step` runs before stopping again.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Step {
+    /// Stop at the next statement, also inside a function that is called.
+    Into,
+    /// Stop at the next statement in the same function or the one it returns to.
+    Over,
+    /// Stop at the next statement after the function returns.
+    Out,
+}
+
+/// Why the VM stopped running.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Stopped {
+    Breakpoint,
+    Step,
+    Finished,
+}
+
+/// A function that is being called, as seen from a debugger.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameInfo {
+    pub name: String,
+    pub chunk_index: usize,
+    /// The file the function is in, None without debug info.
+    pub file: Option<String>,
+    /// The line that is running, None without debug info.
+    pub line: Option<usize>,
+}
+
+/// Decides when to stop, it is asked before every instruction that starts a statement.
+#[derive(Default)]
+pub(crate) struct Debugger {
+    /// The lines with a breakpoint, by file.
+    breakpoints: BTreeMap<String, BTreeSet<usize>>,
+    /// What to step to, with the number of frames when stepping started.
+    step: Option<(Step, usize)>,
+    /// The VM stopped before the instruction it's about to run, it shouldn't stop there again when it continues.
+    stopped: bool,
+}
+
+impl Debugger {
+    pub fn add_breakpoint(&mut self, file: &str, line: usize) {
+        self.breakpoints.entry(file.to_string()).or_default().insert(line);
+    }
+
+    pub fn remove_breakpoint(&mut self, file: &str, line: usize) -> bool {
+        self.breakpoints.get_mut(file).is_some_and(|lines| lines.remove(&line))
+    }
+
+    pub fn clear_breakpoints(&mut self, file: &str) {
+        self.breakpoints.remove(file);
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item=(&str, usize)> + '_ {
+        self.breakpoints.iter().flat_map(|(file, lines)| lines.iter().map(move |&line| (file.as_str(), line)))
+    }
+
+    pub fn set_step(&mut self, step: Option<Step>, depth: usize) {
+        self.step = step.map(|step| (step, depth));
+    }
+
+    /// `line` is the line of the statement that starts at the next instruction, if one starts there, in `file`.
+    pub fn should_stop(&mut self, depth: usize, file: Option<&str>, line: Option<usize>) -> Option<Stopped> {
+        if std::mem::take(&mut self.stopped) {
+            return None;
+        }
+        let line = line?;
+
+        let breakpoint = file.and_then(|file| self.breakpoints.get(file)).is_some_and(|lines| lines.contains(&line));
+        let stopped = if breakpoint {
+            Stopped::Breakpoint
+        } else {
+            match self.step {
+                Some((Step::Into, _)) => Stopped::Step,
+                Some((Step::Over, from)) if depth <= from => Stopped::Step,
+                Some((Step::Out, from)) if depth < from => Stopped::Step,
+                _ => return None,
+            }
+        };
+
+        self.step = None;
+        self.stopped = true;
+        Some(stopped)
+    }
+}
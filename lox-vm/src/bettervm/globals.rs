@@ -0,0 +1,102 @@
+use super::value::Value;
+use super::vm::VmError;
+use crate::bettergc::Trace;
+use crate::bytecode::GlobalIndex;
+
+#[cfg(not(feature = "hashmap-globals"))]
+pub use self::slots::GlobalTable;
+#[cfg(feature = "hashmap-globals")]
+pub use self::hashmap::GlobalTable;
+
+/// The default table, the value of every global by the slot the compiler gave it.
+#[cfg(not(feature = "hashmap-globals"))]
+mod slots {
+    use super::*;
+
+    #[derive(Debug)]
+    pub struct GlobalTable {
+        /// A global that hasn't been defined yet is None.
+        values: Vec<Option<Value>>,
+    }
+
+    impl GlobalTable {
+        /// An undefined global for every name.
+        pub fn new(names: &[String]) -> Self {
+            GlobalTable { values: vec![None; names.len()] }
+        }
+
+        /// The value of the global in `slot`, None when it isn't defined yet.
+        pub fn get(&self, slot: GlobalIndex) -> Result<Option<Value>, VmError> {
+            self.values.get(slot).copied().ok_or(VmError::InvalidGlobal)
+        }
+
+        pub fn define(&mut self, slot: GlobalIndex, value: Value) -> Result<(), VmError> {
+            *self.values.get_mut(slot).ok_or(VmError::InvalidGlobal)? = Some(value);
+            Ok(())
+        }
+
+        /// Assign to a global that is defined already.
+        pub fn assign(&mut self, slot: GlobalIndex, value: Value) -> Result<(), VmError> {
+            match self.values.get_mut(slot).ok_or(VmError::InvalidGlobal)? {
+                Some(global) => *global = value,
+                None => return Err(VmError::GlobalNotDefined),
+            }
+            Ok(())
+        }
+    }
+
+    impl Trace for GlobalTable {
+        fn trace(&self) {
+            self.values.trace();
+        }
+    }
+}
+
+/// The globals in a map by interned name, the way they were looked up before they had slots. Only there for the
+/// `globals` benchmark to compare with.
+#[cfg(feature = "hashmap-globals")]
+mod hashmap {
+    use super::*;
+    use crate::bettergc::{Gc, Root};
+    use crate::bettervm::interner::intern;
+    use std::collections::HashMap;
+
+    #[derive(Debug)]
+    pub struct GlobalTable {
+        /// The interned name of every slot.
+        names: Vec<Root<String>>,
+        values: HashMap<Gc<String>, Value>,
+    }
+
+    impl GlobalTable {
+        pub fn new(names: &[String]) -> Self {
+            GlobalTable { names: names.iter().map(|name| intern(name)).collect(), values: HashMap::new() }
+        }
+
+        pub fn get(&self, slot: GlobalIndex) -> Result<Option<Value>, VmError> {
+            Ok(self.values.get(&self.name(slot)?).copied())
+        }
+
+        pub fn define(&mut self, slot: GlobalIndex, value: Value) -> Result<(), VmError> {
+            self.values.insert(self.name(slot)?, value);
+            Ok(())
+        }
+
+        pub fn assign(&mut self, slot: GlobalIndex, value: Value) -> Result<(), VmError> {
+            let name = self.name(slot)?;
+            *self.values.get_mut(&name).ok_or(VmError::GlobalNotDefined)? = value;
+            Ok(())
+        }
+
+        fn name(&self, slot: GlobalIndex) -> Result<Gc<String>, VmError> {
+            self.names.get(slot).map(Root::as_gc).ok_or(VmError::InvalidGlobal)
+        }
+    }
+
+    impl Trace for GlobalTable {
+        fn trace(&self) {
+            self.names.trace();
+            self.values.trace();
+        }
+    }
+}
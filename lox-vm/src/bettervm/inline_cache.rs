@@ -0,0 +1,66 @@
+use super::memory::Shape;
+use crate::bettergc::{Gc, Trace};
+
+/// How many shapes a single instruction remembers before it gives up on caching.
+const POLYMORPHIC_MAX: usize = 4;
+
+/// Where a property access found its property, for instances of one shape.
+#[derive(Debug, Copy, Clone)]
+pub struct CacheEntry {
+    pub shape: Gc<Shape>,
+    pub slot: usize,
+    /// Setting a property that doesn't exist yet adds a slot, and changes the shape of the instance to this.
+    pub transition: Option<Gc<Shape>>,
+}
+
+/// Every property access instruction has an inline cache, remembering the shapes of the instances it has seen.
+/// Most instructions only ever see a single shape, some see a few, and the rest aren't worth caching.
+#[derive(Debug, Clone)]
+pub enum InlineCache {
+    Empty,
+    Monomorphic(CacheEntry),
+    Polymorphic(Vec<CacheEntry>),
+    Megamorphic,
+}
+
+impl InlineCache {
+    pub fn lookup(&self, shape: Gc<Shape>) -> Option<CacheEntry> {
+        match self {
+            InlineCache::Monomorphic(entry) if entry.shape == shape => Some(*entry),
+            InlineCache::Polymorphic(entries) => entries.iter().find(|entry| entry.shape == shape).copied(),
+            _ => None,
+        }
+    }
+
+    pub fn insert(&mut self, entry: CacheEntry) {
+        *self = match std::mem::replace(self, InlineCache::Empty) {
+            InlineCache::Empty => InlineCache::Monomorphic(entry),
+            InlineCache::Monomorphic(first) => InlineCache::Polymorphic(vec![first, entry]),
+            InlineCache::Polymorphic(mut entries) if entries.len() < POLYMORPHIC_MAX => {
+                entries.push(entry);
+                InlineCache::Polymorphic(entries)
+            },
+            _ => InlineCache::Megamorphic,
+        }
+    }
+}
+
+impl Trace for CacheEntry {
+    fn trace(&self) {
+        self.shape.trace();
+        if let Some(transition) = self.transition {
+            transition.trace();
+        }
+    }
+}
+
+// Caches keep their shapes alive, a collected shape's address could be reused by another shape
+impl Trace for InlineCache {
+    fn trace(&self) {
+        match self {
+            InlineCache::Monomorphic(entry) => entry.trace(),
+            InlineCache::Polymorphic(entries) => entries.trace(),
+            InlineCache::Empty | InlineCache::Megamorphic => (),
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use crate::bettergc::{gc, Root, Weak};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+/// The table is only purged of collected strings once it has grown to this many entries.
+const PURGE_MIN: usize = 64;
+
+/// Every string on the heap is interned, so equal strings are the same object and can be compared and
+/// hashed by pointer. The table holds its strings weakly, strings that are no longer used are collected
+/// as usual and their entries are purged lazily.
+struct Interner {
+    strings: HashMap<String, Weak<String>>,
+    purge_at: usize,
+}
+
+impl Interner {
+    fn get(&self, string: &str) -> Option<Root<String>> {
+        self.strings.get(string).and_then(Weak::upgrade)
+    }
+
+    fn insert(&mut self, string: &str, root: &Root<String>) {
+        if self.strings.len() >= self.purge_at {
+            self.strings.retain(|_, weak| weak.is_alive());
+            self.purge_at = PURGE_MIN.max(self.strings.len() * 2);
+        }
+
+        self.strings.insert(string.to_string(), root.downgrade());
+    }
+}
+
+thread_local!(static INTERNER: RefCell<Interner> = RefCell::new(Interner {
+    strings: HashMap::new(),
+    purge_at: PURGE_MIN,
+}));
+
+/// Returns the string on the heap equal to `string`, allocating it if there is none yet.
+pub fn intern(string: &str) -> Root<String> {
+    if let Some(root) = INTERNER.with(|interner| interner.borrow().get(string)) {
+        return root;
+    }
+
+    // The table isn't borrowed while allocating, finalizers may intern strings too
+    let root = gc::manage(string.to_string());
+    INTERNER.with(|interner| interner.borrow_mut().insert(string, &root));
+    root
+}
+
+/// The amount of entries in the intern table, including strings that have been collected but not purged yet.
+pub fn interned_count() -> usize {
+    INTERNER.with(|interner| interner.borrow().strings.len())
+}
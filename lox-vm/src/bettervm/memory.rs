@@ -1,4 +1,5 @@
-use crate::bettergc::{Trace, Gc};
+use crate::bettergc::{Trace, Gc, Weak, gc};
+use super::value::{Value, Unpacked};
 use crate::bytecode::ChunkIndex;
 use std::cell::RefCell;
 use std::collections::HashMap;
@@ -13,7 +14,7 @@ impl Upvalue {
     pub fn is_open_with_index(&self, index: usize) -> bool {
         match self {
             Self::Open(i) => {
-                if *i == index { true } else { false }
+                *i == index
             },
             Self::Closed(_) => false,
         }
@@ -39,23 +40,96 @@ impl Trace for Upvalue {
 #[derive(Debug)]
 pub struct Instance {
     pub class: Gc<RefCell<Class>>,
-    pub fields: HashMap<String, Value>,
+    pub shape: Gc<Shape>,
+    /// Field values by the slot the shape gives them.
+    pub fields: Vec<Value>,
+}
+
+impl Instance {
+    pub fn new(class: Gc<RefCell<Class>>) -> Self {
+        let shape = class.borrow().shape;
+        Instance { class, shape, fields: vec![] }
+    }
+
+    pub fn field(&self, name: Gc<String>) -> Option<Value> {
+        self.shape.slot(name).map(|slot| self.fields[slot])
+    }
 }
 
 impl Trace for Instance {
     fn trace(&self) {
         self.class.trace();
+        self.shape.trace();
         self.fields.trace();
     }
 }
 
+/// A hidden class, the layout of the fields of an instance. Instances of a class that got the same
+/// fields in the same order share a shape, so where a field is stored only has to be looked up once per shape.
+pub struct Shape {
+    slots: HashMap<Gc<String>, usize>,
+    transitions: RefCell<HashMap<Gc<String>, Gc<Shape>>>,
+}
+
+impl Shape {
+    pub fn empty() -> Self {
+        Shape { slots: HashMap::new(), transitions: RefCell::new(HashMap::new()) }
+    }
+
+    pub fn slot(&self, name: Gc<String>) -> Option<usize> {
+        self.slots.get(&name).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    /// The shape of an instance with shape `shape` after the field `name` is added to it.
+    /// This allocates, so `shape` and `name` must be rooted.
+    pub fn with_field(shape: Gc<Shape>, name: Gc<String>) -> Gc<Shape> {
+        if let Some(next) = shape.transitions.borrow().get(&name) {
+            return *next;
+        }
+
+        let mut slots = shape.slots.clone();
+        slots.insert(name, slots.len());
+        let next = gc::manage(Shape { slots, transitions: RefCell::new(HashMap::new()) });
+
+        shape.transitions.borrow_mut().insert(name, next.as_gc());
+        gc::write_barrier(shape);
+        next.as_gc()
+    }
+}
+
+impl Trace for Shape {
+    fn trace(&self) {
+        for name in self.slots.keys() {
+            name.trace();
+        }
+        self.transitions.trace();
+    }
+}
+
+impl std::fmt::Debug for Shape {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<shape with {} fields>", self.len())
+    }
+}
+
 #[derive(Debug)]
 pub struct Class {
     pub name: String,
+    /// The shape of new instances.
+    pub shape: Gc<Shape>,
 }
 
 impl Trace for Class {
     fn trace(&self) {
+        self.shape.trace();
     }
 }
 
@@ -106,61 +180,51 @@ impl From<&crate::bytecode::Function> for Function {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
-pub enum Value {
-    Number(f64),
-    String(Gc<String>),
-    Closure(Gc<Closure>),
-    NativeFunction(Gc<NativeFunction>),
-    Boolean(bool),
-    Class(Gc<RefCell<Class>>),
-    Instance(Gc<RefCell<Instance>>),
-    Nil,
-}
-
-impl Trace for Value {
-    fn trace(&self) {
-        match self {
-            Value::String(string) => string.trace(),
-            Value::NativeFunction(function) => function.trace(),
-            Value::Closure(closure) => closure.trace(),
-            Value::Class(class) => class.trace(),
-            Value::Instance(instance) => instance.trace(),
-            Value::Number(_) => (),
-            Value::Nil => (),
-            Value::Boolean(_) => (),
-        }
-    }
-}
-
-impl Value {
-    pub fn is_falsey(&self) -> bool {
-        match self {
-            Value::Boolean(boolean) => !boolean,
-            Value::Nil => true,
-            _ => false,
+/// A reference to a value that doesn't keep it alive, values that aren't on the heap are simply copied.
+#[derive(Debug, Clone)]
+pub enum WeakRef {
+    String(Weak<String>),
+    Closure(Weak<Closure>),
+    NativeFunction(Weak<NativeFunction>),
+    Class(Weak<RefCell<Class>>),
+    Instance(Weak<RefCell<Instance>>),
+    Reference(Weak<WeakRef>),
+    Primitive(Value),
+}
+
+impl WeakRef {
+    pub fn new(value: Value) -> Self {
+        match value.unpack() {
+            Unpacked::String(string) => WeakRef::String(string.downgrade()),
+            Unpacked::Closure(closure) => WeakRef::Closure(closure.downgrade()),
+            Unpacked::NativeFunction(function) => WeakRef::NativeFunction(function.downgrade()),
+            Unpacked::Class(class) => WeakRef::Class(class.downgrade()),
+            Unpacked::Instance(instance) => WeakRef::Instance(instance.downgrade()),
+            Unpacked::WeakRef(weak) => WeakRef::Reference(weak.downgrade()),
+            Unpacked::Number(_) | Unpacked::Boolean(_) | Unpacked::Nil => WeakRef::Primitive(value),
         }
     }
 
-    pub fn is_same_type(a: &Value, b: &Value) -> bool {
-        match (b,a) {
-            (Value::Number(_), Value::Number(_)) => true,
-            (Value::Boolean(_), Value::Boolean(_)) => true,
-            (Value::String(_), Value::String(_)) => true,
-            (Value::NativeFunction(_), Value::NativeFunction(_)) => true,
-            (Value::Closure(_), Value::Closure(_)) => true,
-            (Value::Nil, Value::Nil) => true,
-            _ => false,
-        }
+    /// Returns the value, or nil if it has been collected.
+    /// The value must be rooted before anything else is allocated.
+    pub fn upgrade(&self) -> Value {
+        let value = match self {
+            WeakRef::String(weak) => weak.upgrade().map(|root| Value::string(root.as_gc())),
+            WeakRef::Closure(weak) => weak.upgrade().map(|root| Value::closure(root.as_gc())),
+            WeakRef::NativeFunction(weak) => weak.upgrade().map(|root| Value::native_function(root.as_gc())),
+            WeakRef::Class(weak) => weak.upgrade().map(|root| Value::class(root.as_gc())),
+            WeakRef::Instance(weak) => weak.upgrade().map(|root| Value::instance(root.as_gc())),
+            WeakRef::Reference(weak) => weak.upgrade().map(|root| Value::weak_ref(root.as_gc())),
+            WeakRef::Primitive(value) => Some(*value),
+        };
+        value.unwrap_or_else(Value::nil)
     }
 }
 
-impl From<bool> for Value {
-    fn from(value: bool) -> Self {
-        if value {
-            Value::Boolean(true)
-        } else {
-            Value::Boolean(false)
+impl Trace for WeakRef {
+    fn trace(&self) {
+        if let WeakRef::Primitive(value) = self {
+            value.trace();
         }
     }
-}
\ No newline at end of file
+}
@@ -1,24 +1,101 @@
-mod memory;
+pub(crate) mod inline_cache;
+mod debugger;
+mod globals;
+mod interner;
+pub(crate) mod memory;
+mod value;
 mod vm;
 
+#[cfg(test)]
+mod tests;
+
 use crate::bytecode::Module;
-use vm::Vm;
+use crate::bettergc::gc;
+use std::any::type_name;
+use std::cell::RefCell;
 
-pub use vm::VmError;
+pub use vm::{Vm, VmError, StackTrace, FRAMES_MAX, STACK_MAX};
+pub use debugger::{Step, Stopped, FrameInfo};
+pub use interner::{intern, interned_count};
+pub use value::{Value, Unpacked};
 
 pub fn execute(module: &Module) -> Result<(), VmError>{
     let mut vm = Vm::new(module);
-
-    //TODO Work on a way of initializing and executing the VM.
-    //     It should be possible to define your own native functions.
-    vm.set_native_fn("clock", |_args| {
-        use std::time::{UNIX_EPOCH, SystemTime};
-
-        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
-        memory::Value::Number(time)
-    });
+    define_natives(&mut vm);
 
     vm.interpret()?;
 
     Ok(())
-}
\ No newline at end of file
+}
+
+type NativeCode = fn(&[Value]) -> Value;
+
+/// The native functions every program can call, by name.
+pub(crate) const NATIVES: &[(&str, NativeCode)] = &[
+    ("clock", clock),
+    ("gc", collect),
+    ("weakref", weakref),
+    ("deref", deref),
+];
+
+//TODO Work on a way of initializing and executing the VM.
+//     It should be possible to define your own native functions.
+pub fn define_natives(vm: &mut Vm) {
+    for (name, code) in NATIVES {
+        vm.set_native_fn(name, *code);
+    }
+}
+
+fn clock(_args: &[Value]) -> Value {
+    use std::time::{UNIX_EPOCH, SystemTime};
+
+    let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+    Value::number(time)
+}
+
+/// Collects the entire heap and returns a description of it
+fn collect(_args: &[Value]) -> Value {
+    gc::force_collect();
+    let report = intern(&gc_report());
+    Value::string(report.as_gc())
+}
+
+/// Wraps a value in a reference that doesn't keep it alive
+fn weakref(args: &[Value]) -> Value {
+    let value = args.first().copied().unwrap_or_else(Value::nil);
+    let weak = gc::manage(memory::WeakRef::new(value));
+    Value::weak_ref(weak.as_gc())
+}
+
+/// Returns the value behind a weakref, or nil if it has been collected
+fn deref(args: &[Value]) -> Value {
+    match args.first().and_then(|value| value.as_weak_ref()) {
+        Some(weak) => weak.upgrade(),
+        None => Value::nil(),
+    }
+}
+
+/// Describe what the garbage collector has done, and what is on the heap right now.
+pub fn gc_report() -> String {
+    use memory::*;
+
+    let kinds = [
+        ("strings", type_name::<String>()),
+        ("closures", type_name::<Closure>()),
+        ("functions", type_name::<Function>()),
+        ("native functions", type_name::<NativeFunction>()),
+        ("classes", type_name::<RefCell<Class>>()),
+        ("instances", type_name::<RefCell<Instance>>()),
+        ("shapes", type_name::<Shape>()),
+        ("upvalues", type_name::<RefCell<Upvalue>>()),
+        ("weakrefs", type_name::<WeakRef>()),
+    ];
+
+    let mut objects = gc::objects_by_type();
+    let mut counts: Vec<String> = kinds.iter()
+        .map(|(kind, type_name)| format!("{} {}", objects.remove(type_name).unwrap_or(0), kind))
+        .collect();
+    counts.push(format!("{} other", objects.values().sum::<usize>()));
+
+    format!("{}\nobjects: {}", gc::stats(), counts.join(", "))
+}
@@ -0,0 +1,623 @@
+use super::*;
+
+fn run(data: &str) -> Result<(), VmError> {
+    let module = lox_compiler::compile(data).unwrap();
+    execute(&module)
+}
+
+/// Like `run`, but with an `assert` native that panics when its argument is falsey.
+fn run_with_assert(data: &str) -> Result<(), VmError> {
+    let module = lox_compiler::compile(data).unwrap();
+    let mut vm = Vm::new(&module);
+    define_natives(&mut vm);
+    vm.set_native_fn("assert", |args| {
+        assert!(!args[0].is_falsey(), "assertion failed");
+        Value::nil()
+    });
+    vm.interpret()
+}
+
+fn run_with_limits(data: &str, max_frames: usize, max_stack: usize) -> Result<(), VmError> {
+    let module = lox_compiler::compile(data).unwrap();
+    let mut vm = Vm::new(&module);
+    define_natives(&mut vm);
+    vm.set_max_frames(max_frames);
+    vm.set_max_stack(max_stack);
+    vm.interpret()
+}
+
+#[test]
+fn test_recursion_within_limits() {
+    assert!(run("fun f(n) { if (n > 0) f(n - 1); } f(50);").is_ok());
+}
+
+#[test]
+fn test_unbounded_recursion_overflows() {
+    match run("fun f() { f(); } f();") {
+        Err(VmError::StackOverflow(trace)) => {
+            assert_eq!(trace.innermost.len() + trace.omitted + trace.outermost.len(), FRAMES_MAX);
+            assert_eq!(trace.innermost[0], "f()");
+            assert_eq!(trace.outermost.last().unwrap(), "script");
+            assert!(trace.omitted > 0);
+        },
+        result => panic!("expected stack overflow, got {:?}", result),
+    }
+}
+
+#[test]
+fn test_configured_frame_limit() {
+    let code = "fun f(n) { if (n > 0) f(n - 1); } f(100);";
+    assert!(run_with_limits(code, 200, STACK_MAX).is_ok());
+    match run_with_limits(code, 10, STACK_MAX) {
+        Err(VmError::StackOverflow(trace)) => {
+            assert_eq!(trace.omitted, 0);
+            assert_eq!(trace.innermost.len(), 10);
+        },
+        result => panic!("expected stack overflow, got {:?}", result),
+    }
+}
+
+#[test]
+fn test_configured_stack_limit() {
+    let code = "fun f(a, b, c, d) { f(a, b, c, d); } f(1, 2, 3, 4);";
+    match run_with_limits(code, 1000, 20) {
+        Err(VmError::StackOverflow(trace)) => {
+            assert_eq!(trace.innermost.len(), 4);
+        },
+        result => panic!("expected stack overflow, got {:?}", result),
+    }
+}
+
+#[test]
+fn test_tail_calls_reuse_frames() {
+    let code = "
+        fun count(n, total) { if (n == 0) return total; return count(n - 1, total + 1); }
+        assert(count(10000, 0) == 10000);
+
+        fun even(n) { if (n == 0) return true; return odd(n - 1); }
+        fun odd(n) { if (n == 0) return false; return (even(n - 1)); }
+        assert(!even(1001));
+    ";
+    let module = lox_compiler::compile(code).unwrap();
+    let mut vm = Vm::new(&module);
+    define_natives(&mut vm);
+    vm.set_native_fn("assert", |args| {
+        assert!(!args[0].is_falsey(), "assertion failed");
+        Value::nil()
+    });
+    vm.set_max_frames(3);
+    assert!(vm.interpret().is_ok());
+}
+
+#[test]
+fn test_tail_calls_close_upvalues() {
+    let code = "
+        fun id(value) { return value; }
+        fun make(n) {
+            var captured = n;
+            fun get() { return captured; }
+            return id(get);
+        }
+        var get = make(5);
+        var other = make(6);
+        assert(get() == 5);
+        assert(other() == 6);
+    ";
+    assert!(run_with_assert(code).is_ok());
+}
+
+#[test]
+fn test_tail_calls_to_natives_and_classes() {
+    let code = "
+        class Point {}
+        fun now() { return clock(); }
+        fun point() { return Point(); }
+        assert(now() > 0);
+        var p = point();
+        p.x = 1;
+        assert(p.x == 1);
+    ";
+    assert!(run_with_assert(code).is_ok());
+}
+
+#[test]
+fn test_gc_native() {
+    assert!(run("var report = gc(); print report;").is_ok());
+    let report = gc_report();
+    assert!(report.contains("major"));
+    assert!(report.contains("strings"));
+}
+
+#[test]
+fn test_weakref_native() {
+    let code = "
+        class A {}
+        var a = A();
+        var w = weakref(a);
+        gc();
+        assert(deref(w) != nil);
+        a = nil;
+        gc();
+        assert(deref(w) == nil);
+        assert(deref(weakref(1)) == 1);
+    ";
+    assert!(run_with_assert(code).is_ok());
+}
+
+#[test]
+fn test_interned_strings_are_shared() {
+    let a = intern("interned");
+    let b = intern(&format!("{}{}", "inter", "ned"));
+    assert!(crate::bettergc::Gc::ptr_eq(&a.as_gc(), &b.as_gc()));
+    assert!(!crate::bettergc::Gc::ptr_eq(&a.as_gc(), &intern("other").as_gc()));
+}
+
+#[test]
+fn test_interned_strings_are_collected() {
+    for i in 0..1000 {
+        intern(&format!("garbage {}", i));
+    }
+    gc::force_collect();
+    for i in 0..1000 {
+        intern(&format!("garbage {}", i));
+    }
+    assert!(interned_count() < 2000);
+}
+
+#[test]
+fn test_string_equality() {
+    let code = "
+        var a = \"ab\";
+        assert(a == \"a\" + \"b\");
+        assert(\"a\" + \"b\" == a);
+        assert(!(a == \"ba\"));
+    ";
+    assert!(run_with_assert(code).is_ok());
+}
+
+#[test]
+fn test_fields_and_globals_by_interned_name() {
+    let code = "
+        class A {}
+        var a = A();
+        a.field = 1;
+        gc();
+        a.field = a.field + 1;
+        assert(a.field == 2);
+    ";
+    assert!(run_with_assert(code).is_ok());
+}
+
+#[test]
+fn test_undefined_globals() {
+    assert!(matches!(run("print undefined;"), Err(VmError::GlobalNotDefined)));
+    assert!(matches!(run("undefined = 1;"), Err(VmError::GlobalNotDefined)));
+    assert!(matches!(run("fun f() { return later; } f(); var later = 1;"), Err(VmError::GlobalNotDefined)));
+}
+
+#[test]
+fn test_late_bound_globals() {
+    let code = "
+        fun f() { return later; }
+        var later = 1;
+        assert(f() == 1);
+        later = 2;
+        assert(f() == 2);
+    ";
+    assert!(run_with_assert(code).is_ok());
+}
+
+#[test]
+fn test_value_round_trip() {
+    for number in &[0.0, -0.0, 1.5, -3.0, f64::INFINITY, f64::NEG_INFINITY, f64::MAX, f64::MIN_POSITIVE] {
+        assert_eq!(Value::number(*number).as_number().map(f64::to_bits), Some(number.to_bits()));
+    }
+    assert!(Value::number(f64::NAN).as_number().unwrap().is_nan());
+    assert!(Value::number(-f64::NAN).as_number().unwrap().is_nan());
+
+    assert!(Value::nil().is_nil());
+    assert!(Value::nil().as_number().is_none());
+    assert!(matches!(Value::boolean(true).unpack(), Unpacked::Boolean(true)));
+    assert!(matches!(Value::boolean(false).unpack(), Unpacked::Boolean(false)));
+
+    let string = intern("round trip");
+    let value = Value::string(string.as_gc());
+    assert!(crate::bettergc::Gc::ptr_eq(&value.as_string().unwrap(), &string.as_gc()));
+    assert!(value.as_number().is_none());
+    assert!(value.as_instance().is_none());
+}
+
+#[test]
+fn test_nan_is_a_number() {
+    assert!(run_with_assert("var nan = 0 / 0; assert(!(nan == nan)); assert(-nan != nil);").is_ok());
+}
+
+#[cfg(feature = "nan-boxing")]
+#[test]
+fn test_nan_boxed_value_size() {
+    assert_eq!(std::mem::size_of::<Value>(), 8);
+}
+
+#[test]
+fn test_shapes_are_shared() {
+    use memory::Shape;
+
+    let empty = gc::manage(Shape::empty());
+    let x = intern("x");
+    let y = intern("y");
+
+    let xy = Shape::with_field(Shape::with_field(empty.as_gc(), x.as_gc()), y.as_gc());
+    let yx = Shape::with_field(Shape::with_field(empty.as_gc(), y.as_gc()), x.as_gc());
+    assert!(xy == Shape::with_field(Shape::with_field(empty.as_gc(), x.as_gc()), y.as_gc()));
+    assert!(xy != yx);
+    assert_eq!(xy.slot(x.as_gc()), Some(0));
+    assert_eq!(yx.slot(x.as_gc()), Some(1));
+}
+
+#[test]
+fn test_inline_cache_states() {
+    use inline_cache::{InlineCache, CacheEntry};
+    use memory::Shape;
+
+    let shapes: Vec<_> = (0..6).map(|_| gc::manage(Shape::empty())).collect();
+    let mut cache = InlineCache::Empty;
+    for (slot, shape) in shapes.iter().enumerate() {
+        assert!(cache.lookup(shape.as_gc()).is_none());
+        cache.insert(CacheEntry { shape: shape.as_gc(), slot, transition: None });
+    }
+
+    assert!(matches!(cache, InlineCache::Megamorphic));
+    assert!(cache.lookup(shapes[0].as_gc()).is_none());
+
+    let mut cache = InlineCache::Empty;
+    cache.insert(CacheEntry { shape: shapes[0].as_gc(), slot: 0, transition: None });
+    assert!(matches!(cache, InlineCache::Monomorphic(_)));
+    cache.insert(CacheEntry { shape: shapes[1].as_gc(), slot: 1, transition: None });
+    assert!(matches!(cache, InlineCache::Polymorphic(_)));
+    assert_eq!(cache.lookup(shapes[1].as_gc()).map(|entry| entry.slot), Some(1));
+}
+
+#[test]
+fn test_polymorphic_property_access() {
+    let code = "
+        class A {}
+        fun make(order, x) {
+            var a = A();
+            if (order == 0) { a.x = x; a.y = 0; }
+            if (order == 1) { a.y = 0; a.x = x; }
+            if (order == 2) { a.z = 0; a.x = x; }
+            if (order == 3) { a.y = 0; a.z = 0; a.x = x; }
+            if (order == 4) { a.z = 0; a.y = 0; a.x = x; }
+            if (order == 5) { a.w = 0; a.x = x; }
+            return a;
+        }
+        fun getX(a) { return a.x; }
+        for (var round = 0; round < 3; round = round + 1) {
+            for (var order = 0; order < 6; order = order + 1) {
+                var a = make(order, order + round);
+                assert(getX(a) == order + round);
+                a.x = -1;
+                assert(getX(a) == -1);
+            }
+        }
+    ";
+    assert!(run_with_assert(code).is_ok());
+}
+
+#[test]
+fn test_undefined_property_with_cached_shape() {
+    let code = "
+        class A {}
+        fun get(a) { return a.x; }
+        var a = A();
+        a.x = 1;
+        get(a);
+        var b = A();
+        b.y = 1;
+        get(b);
+    ";
+    assert!(matches!(run(code), Err(VmError::UndefinedProperty)));
+}
+
+#[test]
+fn test_long_operands_and_jumps() {
+    let mut code = String::from("var sum = 0; if (true) {");
+    for i in 0..300 {
+        code.push_str(&format!("sum = sum + {};", i));
+    }
+    code.push_str("} assert(sum == 44850);");
+    assert!(run_with_assert(&code).is_ok());
+}
+
+const DEBUGGED: &str = "\
+var total = 0;
+fun add(n) {
+  var doubled = n * 2;
+  total = total + doubled;
+  return doubled;
+}
+fun counter() {
+  var count = 10;
+  fun inc() {
+    count = count + 1;
+    return count;
+  }
+  return inc;
+}
+add(1);
+var c = counter();
+c();
+";
+
+fn current_line(vm: &Vm) -> Option<usize> {
+    vm.call_stack()[0].line
+}
+
+#[test]
+fn test_breakpoints() {
+    let module = lox_compiler::compile_file(DEBUGGED, "debugged.lox").unwrap();
+    let mut vm = Vm::new(&module);
+    vm.start();
+
+    assert_eq!(vm.set_breakpoint("other.lox", 4), None);
+    assert_eq!(vm.set_breakpoint("debugged.lox", 4), Some(4));
+    // There is no statement on line 14, the next one is on line 15
+    assert_eq!(vm.set_breakpoint("debugged.lox", 14), Some(15));
+    assert_eq!(vm.breakpoints(), vec![("debugged.lox".to_string(), 4), ("debugged.lox".to_string(), 15)]);
+
+    assert_eq!(vm.resume().unwrap(), Stopped::Breakpoint);
+    assert_eq!(current_line(&vm), Some(15));
+    assert_eq!(vm.resume().unwrap(), Stopped::Breakpoint);
+    assert_eq!(current_line(&vm), Some(4));
+    assert!(!vm.clear_breakpoint("other.lox", 4));
+    assert!(vm.clear_breakpoint("debugged.lox", 4));
+    assert_eq!(vm.resume().unwrap(), Stopped::Finished);
+}
+
+#[test]
+fn test_stepping() {
+    let module = lox_compiler::compile_file(DEBUGGED, "debugged.lox").unwrap();
+    let mut vm = Vm::new(&module);
+    vm.start();
+
+    vm.set_breakpoint("debugged.lox", 15);
+    assert_eq!(vm.resume().unwrap(), Stopped::Breakpoint);
+
+    assert_eq!(vm.step(Step::Into).unwrap(), Stopped::Step);
+    assert_eq!(current_line(&vm), Some(3));
+    assert_eq!(vm.call_stack().len(), 2);
+
+    assert_eq!(vm.step(Step::Over).unwrap(), Stopped::Step);
+    assert_eq!(current_line(&vm), Some(4));
+
+    assert_eq!(vm.step(Step::Out).unwrap(), Stopped::Step);
+    assert_eq!(current_line(&vm), Some(16));
+    assert_eq!(vm.call_stack().len(), 1);
+
+    assert_eq!(vm.step(Step::Over).unwrap(), Stopped::Step);
+    assert_eq!(current_line(&vm), Some(17));
+}
+
+#[test]
+fn test_inspecting_frames() {
+    let module = lox_compiler::compile_file(DEBUGGED, "debugged.lox").unwrap();
+    let mut vm = Vm::new(&module);
+    vm.start();
+
+    vm.set_breakpoint("debugged.lox", 4);
+    vm.set_breakpoint("debugged.lox", 10);
+
+    assert_eq!(vm.resume().unwrap(), Stopped::Breakpoint);
+    let names: Vec<_> = vm.call_stack().into_iter().map(|frame| (frame.name, frame.line)).collect();
+    assert_eq!(names, vec![("add".to_string(), Some(4)), ("script".to_string(), Some(15))]);
+    assert_eq!(vm.local(0, "doubled").map(|value| value.to_string()), Some("2".to_string()));
+    assert!(vm.local(1, "doubled").is_none());
+    assert_eq!(vm.global("total").map(|value| value.to_string()), Some("0".to_string()));
+    assert!(vm.global("c").is_none());
+
+    assert_eq!(vm.resume().unwrap(), Stopped::Breakpoint);
+    let upvalues: Vec<_> = vm.upvalues(0).into_iter().map(|(name, value)| (name, value.to_string())).collect();
+    assert_eq!(upvalues, vec![("count".to_string(), "10".to_string())]);
+    let globals: Vec<_> = vm.globals().into_iter().map(|(name, _)| name).collect();
+    assert_eq!(globals, vec!["total", "add", "counter", "c"]);
+}
+
+#[test]
+fn test_interpret_ignores_breakpoints() {
+    let module = lox_compiler::compile_file(DEBUGGED, "debugged.lox").unwrap();
+    let mut vm = Vm::new(&module);
+    vm.set_breakpoint("debugged.lox", 4);
+    assert!(vm.interpret().is_ok());
+    assert_eq!(vm.global("total").map(|value| value.to_string()), Some("2".to_string()));
+}
+
+#[test]
+fn test_evaluate_in_frame() {
+    let module = lox_compiler::compile_file(DEBUGGED, "debugged.lox").unwrap();
+    let mut vm = Vm::new(&module);
+    vm.start();
+    vm.set_breakpoint("debugged.lox", 10);
+    assert_eq!(vm.resume().unwrap(), Stopped::Breakpoint);
+
+    let evaluate = |vm: &Vm, code: &str, depth: usize| {
+        let expression = lox_compiler::compile_expression(code).unwrap();
+        vm.evaluate(&expression, depth).map(|value| value.to_string())
+    };
+    assert_eq!(evaluate(&vm, "count * 2 + total", 0).unwrap(), "22");
+    assert!(matches!(evaluate(&vm, "count", 1), Err(VmError::GlobalNotDefined)));
+
+    // Assignments only change the copy
+    assert_eq!(evaluate(&vm, "total = 5", 0).unwrap(), "5");
+    assert_eq!(vm.global("total").map(|value| value.to_string()), Some("2".to_string()));
+
+    // Functions of the program can be called, with the globals they use copied as well
+    assert_eq!(evaluate(&vm, "add(3) + total", 0).unwrap(), "14");
+    assert_eq!(vm.global("total").map(|value| value.to_string()), Some("2".to_string()));
+}
+
+#[test]
+fn test_evaluate_step_limit() {
+    let code = "fun spin() { while (true) {} }
+var a = 1;
+print a;";
+    let module = lox_compiler::compile_file(code, "spin.lox").unwrap();
+    let mut vm = Vm::new(&module);
+    vm.start();
+    vm.set_breakpoint("spin.lox", 3);
+    assert_eq!(vm.resume().unwrap(), Stopped::Breakpoint);
+
+    let expression = lox_compiler::compile_expression("spin()").unwrap();
+    assert!(matches!(vm.evaluate(&expression, 0), Err(VmError::EvaluateStepLimit)));
+    let expression = lox_compiler::compile_expression("a + 1").unwrap();
+    assert_eq!(vm.evaluate(&expression, 0).unwrap().to_string(), "2");
+}
+
+#[test]
+fn test_print_hook() {
+    use std::{cell::RefCell, rc::Rc};
+
+    let module = lox_compiler::compile("print 1; print \"two\";").unwrap();
+    let mut vm = Vm::new(&module);
+    let printed = Rc::new(RefCell::new(vec![]));
+    let lines = printed.clone();
+    vm.set_print(move |line| lines.borrow_mut().push(line.to_string()));
+    vm.interpret().unwrap();
+    assert_eq!(*printed.borrow(), vec!["1", "two"]);
+}
+
+#[test]
+fn test_imported_modules() {
+    use std::{cell::RefCell, rc::Rc};
+
+    let mut loader = lox_compiler::MemoryLoader::new();
+    loader.insert("lib/counter.lox", "
+        print \"counter runs once\";
+        var count = 0;
+        export fun increment() { count = count + 1; return count; }
+        export var started = clock() >= 0;
+    ");
+    loader.insert("lib/double.lox", "
+        import { increment } from \"counter.lox\";
+        var count = 100;
+        export fun double() { return increment() * 2; }
+    ");
+    let main = "
+        import \"lib/counter.lox\";
+        import { double } from \"lib/double.lox\";
+        var count = \"mine\";
+        print increment();
+        print double();
+        print started;
+        print count;
+    ";
+
+    let module = lox_compiler::compile_with_loader(main, "main.lox", &loader).unwrap();
+    let mut vm = Vm::new(&module);
+    define_natives(&mut vm);
+    let printed = Rc::new(RefCell::new(vec![]));
+    let lines = printed.clone();
+    vm.set_print(move |line| lines.borrow_mut().push(line.to_string()));
+    vm.interpret().unwrap();
+    assert_eq!(*printed.borrow(), vec!["counter runs once", "1", "4", "true", "mine"]);
+}
+
+#[test]
+fn test_module_namespaces() {
+    let mut loader = lox_compiler::MemoryLoader::new();
+    loader.insert("lib/b.lox", "export fun peek() { return secret; }");
+    let error = lox_compiler::compile_with_loader("var secret = 42;\nimport \"lib/b.lox\";\nprint peek();", "main.lox", &loader)
+        .map(|_| ())
+        .unwrap_err();
+    assert_eq!(error.to_string(), "lib/b.lox: [line 1:21] 'secret' is declared in another module, import it to use it.");
+    // Every error of the script names its path too
+    loader.insert("lib/d.lox", "export var shown = 1;\nvar hidden = 2;");
+    let error = lox_compiler::compile_with_loader("import { shown } from \"lib/d.lox\";\nprint hidden;", "main.lox", &loader)
+        .map(|_| ())
+        .unwrap_err();
+    assert_eq!(error.to_string(), "main.lox: [line 2:1] 'hidden' is declared in another module, import it to use it.");
+    let error = lox_compiler::compile_with_loader("return 1;\nprint this;", "main.lox", &loader).map(|_| ()).unwrap_err();
+    assert_eq!(error.to_string(), "main.lox: [line 1:1] Can't return from top-level code.\nmain.lox: [line 2:1] Can't use 'this' outside of a class.");
+
+    // Natives aren't declared in a module
+    loader.insert("lib/c.lox", "export fun now() { return clock(); }");
+    assert!(lox_compiler::compile_with_loader("import \"lib/c.lox\";\nprint now() >= 0;", "main.lox", &loader).is_ok());
+}
+
+#[test]
+fn test_breakpoints_in_imported_modules() {
+    let mut loader = lox_compiler::MemoryLoader::new();
+    loader.insert("lib/b.lox", "export fun peek() {\n  return 42;\n}");
+    let module = lox_compiler::compile_with_loader("import \"lib/b.lox\";\nvar answer = peek();\nprint answer;", "main.lox", &loader).unwrap();
+    let mut vm = Vm::new(&module);
+    define_natives(&mut vm);
+    vm.start();
+    let frames = |vm: &Vm| vm.call_stack().into_iter().map(|frame| (frame.file.unwrap(), frame.line.unwrap())).collect::<Vec<_>>();
+
+    // Line 2 of the script isn't a breakpoint, only line 2 of the module
+    assert_eq!(vm.set_breakpoint("b.lox", 2), Some(2));
+    assert_eq!(vm.resume().unwrap(), Stopped::Breakpoint);
+    assert_eq!(frames(&vm), vec![("lib/b.lox".to_string(), 2), ("main.lox".to_string(), 2)]);
+
+    assert_eq!(vm.set_breakpoint("main.lox", 3), Some(3));
+    vm.clear_breakpoints("b.lox");
+    assert_eq!(vm.breakpoints(), vec![("main.lox".to_string(), 3)]);
+    assert_eq!(vm.resume().unwrap(), Stopped::Breakpoint);
+    assert_eq!(frames(&vm), vec![("main.lox".to_string(), 3)]);
+    assert_eq!(vm.resume().unwrap(), Stopped::Finished);
+}
+
+#[test]
+fn test_linked_modules() {
+    use std::{cell::RefCell, rc::Rc};
+    use lox_bytecode::link::{link, LinkError};
+
+    // Enough constants that the ones of the application need the long form of their instructions once linked
+    let numbers: Vec<String> = (0..300).map(|number| number.to_string()).collect();
+    let library = format!("export var total = {}; export fun greet(name) {{ return prefix + name; }} var prefix = \"hello \";", numbers.join(" + "));
+    // The library doesn't export its prefix, the application has one of its own
+    let application = "
+        var i = 0;
+        var prefix = \"bye \";
+        while (i < 3) { if (i == 1) print greet(\"world\"); i = i + 1; }
+        print total;
+    ";
+
+    let library = lox_compiler::compile(&library).unwrap();
+    let module = link(lox_compiler::compile_file(application, "app.lox").unwrap(), vec![library]).unwrap();
+    // Only the lines of the application are in the debug info
+    assert_eq!(module.debug_info().unwrap().statement_line_from("app.lox", 1), Some(2));
+    let mut vm = Vm::new(&module);
+    define_natives(&mut vm);
+    let printed = Rc::new(RefCell::new(vec![]));
+    let lines = printed.clone();
+    vm.set_print(move |line| lines.borrow_mut().push(line.to_string()));
+    vm.interpret().unwrap();
+    assert_eq!(*printed.borrow(), vec!["hello world", "44850"]);
+
+    let application = lox_compiler::compile("var total = 1;").unwrap();
+    let result = link(application, vec![lox_compiler::compile("export var total = 1;").unwrap()]);
+    assert_eq!(result.err(), Some(LinkError::DuplicateGlobal("total".to_string())));
+}
+
+#[test]
+fn test_property_names_must_be_strings() {
+    use crate::bytecode::{Constant, Instruction};
+
+    for instruction in [Instruction::GetProperty(0), Instruction::SetProperty(0)] {
+        let mut module = Module::new();
+        module.add_constant(Constant::Number(1.0));
+        let chunk = module.add_chunk();
+        module.chunk_mut(chunk).add_instruction(instruction);
+        assert!(matches!(Vm::new(&module).interpret(), Err(VmError::StringConstantExpected)));
+    }
+}
+
+#[test]
+fn test_invalid_global_index() {
+    use crate::bytecode::Instruction;
+
+    let mut module = Module::new();
+    let chunk = module.add_chunk();
+    module.chunk_mut(chunk).add_instruction(Instruction::GetGlobal(3));
+    assert!(matches!(Vm::new(&module).interpret(), Err(VmError::InvalidGlobal)));
+}
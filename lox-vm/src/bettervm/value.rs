@@ -0,0 +1,288 @@
+use super::memory::*;
+use crate::bettergc::{Trace, Gc};
+use std::cell::RefCell;
+use std::fmt;
+
+/// The contents of a `Value`, with its type spelled out. Values are unpacked to inspect them.
+#[derive(Debug, Copy, Clone)]
+pub enum Unpacked {
+    Number(f64),
+    String(Gc<String>),
+    Closure(Gc<Closure>),
+    NativeFunction(Gc<NativeFunction>),
+    Boolean(bool),
+    Class(Gc<RefCell<Class>>),
+    Instance(Gc<RefCell<Instance>>),
+    WeakRef(Gc<WeakRef>),
+    Nil,
+}
+
+impl Trace for Unpacked {
+    fn trace(&self) {
+        match self {
+            Unpacked::String(string) => string.trace(),
+            Unpacked::NativeFunction(function) => function.trace(),
+            Unpacked::Closure(closure) => closure.trace(),
+            Unpacked::Class(class) => class.trace(),
+            Unpacked::Instance(instance) => instance.trace(),
+            Unpacked::WeakRef(weak) => weak.trace(),
+            Unpacked::Number(_) => (),
+            Unpacked::Nil => (),
+            Unpacked::Boolean(_) => (),
+        }
+    }
+}
+
+#[cfg(not(feature = "nan-boxing"))]
+pub use self::tagged::Value;
+#[cfg(feature = "nan-boxing")]
+pub use self::nan_boxed::Value;
+
+/// The default representation, a plain enum.
+#[cfg(not(feature = "nan-boxing"))]
+mod tagged {
+    use super::Unpacked;
+
+    #[derive(Copy, Clone)]
+    pub struct Value(Unpacked);
+
+    impl Value {
+        pub fn unpack(self) -> Unpacked {
+            self.0
+        }
+
+        pub fn number(number: f64) -> Value {
+            Value(Unpacked::Number(number))
+        }
+
+        pub fn as_number(self) -> Option<f64> {
+            match self.0 {
+                Unpacked::Number(number) => Some(number),
+                _ => None,
+            }
+        }
+
+        pub fn nil() -> Value {
+            Value(Unpacked::Nil)
+        }
+
+        pub fn is_nil(self) -> bool {
+            matches!(self.0, Unpacked::Nil)
+        }
+    }
+
+    impl From<Unpacked> for Value {
+        fn from(unpacked: Unpacked) -> Self {
+            Value(unpacked)
+        }
+    }
+}
+
+/// Every value fits in 64 bits. Numbers are stored as is, everything else is hidden in the payload
+/// of a quiet NaN: nil and booleans are small constants, and heap objects are their address with
+/// the sign bit set and the type of the object in the lowest three bits.
+#[cfg(feature = "nan-boxing")]
+mod nan_boxed {
+    use super::Unpacked;
+    use crate::bettergc::Gc;
+
+    const SIGN: u64 = 0x8000_0000_0000_0000;
+    const QNAN: u64 = 0x7ffc_0000_0000_0000;
+
+    const NIL: u64 = QNAN | 1;
+    const FALSE: u64 = QNAN | 2;
+    const TRUE: u64 = QNAN | 3;
+
+    // Objects are aligned to 8 bytes, which leaves the lowest 3 bits of their address for the type
+    const TYPE_MASK: u64 = 0b111;
+    const ADDRESS_MASK: u64 = !(SIGN | QNAN | TYPE_MASK);
+
+    const STRING: u64 = 0;
+    const CLOSURE: u64 = 1;
+    const NATIVE_FUNCTION: u64 = 2;
+    const CLASS: u64 = 3;
+    const INSTANCE: u64 = 4;
+    const WEAK_REF: u64 = 5;
+
+    #[derive(Copy, Clone)]
+    pub struct Value(u64);
+
+    impl Value {
+        pub fn unpack(self) -> Unpacked {
+            if self.0 & QNAN != QNAN {
+                return Unpacked::Number(f64::from_bits(self.0));
+            }
+
+            if self.0 & SIGN == 0 {
+                return match self.0 {
+                    NIL => Unpacked::Nil,
+                    FALSE => Unpacked::Boolean(false),
+                    TRUE => Unpacked::Boolean(true),
+                    bits => unreachable!("Invalid NaN-boxed value {:#x}", bits),
+                };
+            }
+
+            let address = (self.0 & ADDRESS_MASK) as *const ();
+            unsafe {
+                match self.0 & TYPE_MASK {
+                    STRING => Unpacked::String(Gc::from_raw(address)),
+                    CLOSURE => Unpacked::Closure(Gc::from_raw(address)),
+                    NATIVE_FUNCTION => Unpacked::NativeFunction(Gc::from_raw(address)),
+                    CLASS => Unpacked::Class(Gc::from_raw(address)),
+                    INSTANCE => Unpacked::Instance(Gc::from_raw(address)),
+                    WEAK_REF => Unpacked::WeakRef(Gc::from_raw(address)),
+                    tag => unreachable!("Invalid NaN-boxed object type {}", tag),
+                }
+            }
+        }
+
+        pub fn number(number: f64) -> Value {
+            // Every NaN is made the same, so a NaN resulting from arithmetic can't be mistaken for a boxed value
+            if number.is_nan() {
+                Value(f64::NAN.to_bits())
+            } else {
+                Value(number.to_bits())
+            }
+        }
+
+        pub fn as_number(self) -> Option<f64> {
+            if self.0 & QNAN != QNAN { Some(f64::from_bits(self.0)) } else { None }
+        }
+
+        pub fn nil() -> Value {
+            Value(NIL)
+        }
+
+        pub fn is_nil(self) -> bool {
+            self.0 == NIL
+        }
+
+        fn object(address: *const (), tag: u64) -> Value {
+            let address = address as u64;
+            debug_assert_eq!(address & !ADDRESS_MASK, 0, "Address {:#x} doesn't fit in a NaN-boxed value", address);
+            Value(SIGN | QNAN | address | tag)
+        }
+    }
+
+    impl From<Unpacked> for Value {
+        fn from(unpacked: Unpacked) -> Self {
+            match unpacked {
+                Unpacked::Number(number) => Value::number(number),
+                Unpacked::Boolean(true) => Value(TRUE),
+                Unpacked::Boolean(false) => Value(FALSE),
+                Unpacked::Nil => Value(NIL),
+                Unpacked::String(string) => Value::object(string.into_raw(), STRING),
+                Unpacked::Closure(closure) => Value::object(closure.into_raw(), CLOSURE),
+                Unpacked::NativeFunction(function) => Value::object(function.into_raw(), NATIVE_FUNCTION),
+                Unpacked::Class(class) => Value::object(class.into_raw(), CLASS),
+                Unpacked::Instance(instance) => Value::object(instance.into_raw(), INSTANCE),
+                Unpacked::WeakRef(weak) => Value::object(weak.into_raw(), WEAK_REF),
+            }
+        }
+    }
+}
+
+impl Value {
+    pub fn boolean(boolean: bool) -> Value {
+        Unpacked::Boolean(boolean).into()
+    }
+
+    pub fn string(string: Gc<String>) -> Value {
+        Unpacked::String(string).into()
+    }
+
+    pub fn closure(closure: Gc<Closure>) -> Value {
+        Unpacked::Closure(closure).into()
+    }
+
+    pub fn native_function(function: Gc<NativeFunction>) -> Value {
+        Unpacked::NativeFunction(function).into()
+    }
+
+    pub fn class(class: Gc<RefCell<Class>>) -> Value {
+        Unpacked::Class(class).into()
+    }
+
+    pub fn instance(instance: Gc<RefCell<Instance>>) -> Value {
+        Unpacked::Instance(instance).into()
+    }
+
+    pub fn weak_ref(weak: Gc<WeakRef>) -> Value {
+        Unpacked::WeakRef(weak).into()
+    }
+
+    pub fn as_string(self) -> Option<Gc<String>> {
+        match self.unpack() {
+            Unpacked::String(string) => Some(string),
+            _ => None,
+        }
+    }
+
+    pub fn as_instance(self) -> Option<Gc<RefCell<Instance>>> {
+        match self.unpack() {
+            Unpacked::Instance(instance) => Some(instance),
+            _ => None,
+        }
+    }
+
+    pub fn as_weak_ref(self) -> Option<Gc<WeakRef>> {
+        match self.unpack() {
+            Unpacked::WeakRef(weak) => Some(weak),
+            _ => None,
+        }
+    }
+
+    pub fn is_falsey(&self) -> bool {
+        match self.unpack() {
+            Unpacked::Boolean(boolean) => !boolean,
+            Unpacked::Nil => true,
+            _ => false,
+        }
+    }
+
+    pub fn is_same_type(a: &Value, b: &Value) -> bool {
+        matches!((b.unpack(), a.unpack()),
+            (Unpacked::Number(_), Unpacked::Number(_)) |
+            (Unpacked::Boolean(_), Unpacked::Boolean(_)) |
+            (Unpacked::String(_), Unpacked::String(_)) |
+            (Unpacked::NativeFunction(_), Unpacked::NativeFunction(_)) |
+            (Unpacked::Closure(_), Unpacked::Closure(_)) |
+            (Unpacked::Nil, Unpacked::Nil)
+        )
+    }
+}
+
+impl Trace for Value {
+    fn trace(&self) {
+        self.unpack().trace();
+    }
+}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.unpack().fmt(f)
+    }
+}
+
+/// How `print` shows a value.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.unpack() {
+            Unpacked::Number(n) => write!(f, "{}", n),
+            Unpacked::Nil => write!(f, "nil"),
+            Unpacked::Boolean(boolean) => write!(f, "{}", boolean),
+            Unpacked::String(string) => write!(f, "{}", string),
+            Unpacked::NativeFunction(function) => write!(f, "<native fun {}>", function.name),
+            Unpacked::Closure(closure) => write!(f, "<fun {}({}) @ {}>", closure.function.name, closure.function.arity, closure.function.chunk_index),
+            Unpacked::Class(class) => write!(f, "{}", class.borrow().name),
+            Unpacked::Instance(instance) => write!(f, "{} instance", instance.borrow().class.borrow().name),
+            Unpacked::WeakRef(_) => write!(f, "<weakref>"),
+        }
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::boolean(value)
+    }
+}
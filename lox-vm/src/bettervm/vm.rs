@@ -1,13 +1,35 @@
 use super::memory::*;
-use std::collections::HashMap;
-use crate::bytecode::{Module, Chunk};
+use super::globals::GlobalTable;
+use super::value::{Value, Unpacked};
+use super::inline_cache::{InlineCache, CacheEntry};
+use super::interner::intern;
+use super::debugger::{Debugger, FrameInfo, Step, Stopped};
+use crate::bytecode::{Module, Chunk, ChunkIndex, Instruction, InstructionIndex};
+use lox_bytecode::debug::ChunkDebugInfo;
 use crate::bettergc::{UniqueRoot, Gc, Root, gc};
 use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+
+/// Default maximum number of call frames, the same as clox.
+pub const FRAMES_MAX: usize = 64;
+/// Default maximum number of values on the stack, the same as clox.
+pub const STACK_MAX: usize = FRAMES_MAX * 256;
+
+/// How many frames to show at either end of a stack trace before eliding the middle.
+const STACK_TRACE_EDGE: usize = 8;
+
+/// How many instructions `evaluate` runs before giving up on the expression, so a call that never returns doesn't
+/// hang the debugger.
+pub const EVALUATE_STEPS: usize = 1_000_000;
+
+type Print = Box<dyn FnMut(&str)>;
 
-#[derive(PartialEq)]
 enum InterpretResult {
-    Done,
+    /// The script returned, with the value it returned.
+    Done(Value),
     More,
+    Stopped(Stopped),
 }
 
 #[derive(Debug)]
@@ -16,16 +38,72 @@ pub enum VmError {
     FrameEmpty,
     StringConstantExpected,
     GlobalNotDefined,
+    /// An instruction refers to a global the module doesn't have.
+    InvalidGlobal,
     InvalidCallee,
     IncorrectArity,
     UnexpectedConstant,
     ClosureConstantExpected,
     UnexpectedValue,
     UndefinedProperty,
+    StackOverflow(StackTrace),
+    /// An evaluated expression didn't finish within `EVALUATE_STEPS` instructions.
+    EvaluateStepLimit,
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmError::StackOverflow(trace) => write!(f, "Stack overflow.\n{}", trace),
+            VmError::EvaluateStepLimit => write!(f, "Evaluating didn't finish within {} instructions.", EVALUATE_STEPS),
+            error => write!(f, "{:?}", error),
+        }
+    }
+}
+
+/// A (possibly truncated) list of the active call frames, innermost first.
+#[derive(Debug)]
+pub struct StackTrace {
+    pub innermost: Vec<String>,
+    pub omitted: usize,
+    pub outermost: Vec<String>,
+}
+
+impl StackTrace {
+    /// Build a trace from the names of the frames, innermost first.
+    pub(crate) fn new(names: Vec<String>) -> Self {
+        if names.len() <= STACK_TRACE_EDGE * 2 {
+            return StackTrace { innermost: names, omitted: 0, outermost: vec![] };
+        }
+
+        StackTrace {
+            innermost: names[..STACK_TRACE_EDGE].to_vec(),
+            omitted: names.len() - STACK_TRACE_EDGE * 2,
+            outermost: names[names.len() - STACK_TRACE_EDGE..].to_vec(),
+        }
+    }
+}
+
+impl fmt::Display for StackTrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for frame in &self.innermost {
+            writeln!(f, "  in {}", frame)?;
+        }
+        if self.omitted > 0 {
+            writeln!(f, "  ... {} more frames", self.omitted)?;
+        }
+        for frame in &self.outermost {
+            writeln!(f, "  in {}", frame)?;
+        }
+        Ok(())
+    }
 }
 
 struct CallFrame<'a> {
+    /// The index of the next instruction.
     program_counter: usize,
+    /// The index of the instruction being executed.
+    instruction_index: usize,
     base_counter: usize,
     chunk: &'a Chunk,
     closure: Root<Closure>,
@@ -35,72 +113,162 @@ pub struct Vm<'a> {
     module: &'a Module,
     frames: Vec<CallFrame<'a>>,
     stack: UniqueRoot<Vec<Value>>,
-    globals: UniqueRoot<HashMap<String, Value>>,
+    globals: UniqueRoot<GlobalTable>,
+    /// The interned string for every string constant in the module, by constant index.
+    strings: Vec<Option<Root<String>>>,
+    /// An inline cache for every property instruction, `cache_slots` has the index of the cache of each of them by
+    /// chunk and instruction index.
+    caches: UniqueRoot<Vec<InlineCache>>,
+    cache_slots: Vec<HashMap<InstructionIndex, usize>>,
     upvalues: Vec<Root<RefCell<Upvalue>>>,
+    max_frames: usize,
+    max_stack: usize,
+    /// Only there once a debugger has been used, so running without one doesn't pay for it.
+    debugger: Option<Debugger>,
+    /// Where `print` writes to instead of stdout.
+    print: Option<Print>,
 }
 
 impl<'a> Vm<'a> {
     pub fn new(module: &'a Module) -> Self {
+        use crate::bytecode::Constant;
+
+        let strings = module.constants().iter().map(|constant| {
+            match constant {
+                Constant::String(string) => Some(intern(string)),
+                _ => None,
+            }
+        }).collect();
+
+        let mut cache_count = 0;
+        let cache_slots = module.chunks().iter().map(|chunk| {
+            chunk.decoded()
+                .filter(|(_, instruction)| matches!(instruction, Instruction::GetProperty(_) | Instruction::SetProperty(_)))
+                .map(|(index, _)| {
+                    cache_count += 1;
+                    (index, cache_count - 1)
+                })
+                .collect()
+        }).collect();
+
         Vm {
             module,
             frames: vec![],
             stack: gc::unique(vec![]),
-            globals: gc::unique(HashMap::new()),
+            globals: gc::unique(GlobalTable::new(module.globals().names())),
             upvalues: vec![],
+            strings,
+            caches: gc::unique(vec![InlineCache::Empty; cache_count]),
+            cache_slots,
+            max_frames: FRAMES_MAX,
+            max_stack: STACK_MAX,
+            debugger: None,
+            print: None,
         }
     }
 
+    pub fn set_max_frames(&mut self, max_frames: usize) {
+        self.max_frames = max_frames;
+    }
+
+    pub fn set_max_stack(&mut self, max_stack: usize) {
+        self.max_stack = max_stack;
+    }
+
+    /// Send everything the program prints to `print`, one line at a time, instead of to stdout.
+    pub fn set_print(&mut self, print: impl FnMut(&str) + 'static) {
+        self.print = Some(Box::new(print));
+    }
+
     pub fn interpret(&mut self) -> Result<(), VmError> {
-        let function = gc::manage(Function{ arity: 0, chunk_index: 0, name: "top".into() });
+        self.start();
+        while self.run()? != Stopped::Finished {
+            // Breakpoints are ignored
+        }
+
+        Ok(())
+    }
+
+    /// Get ready to run the script, without running any of it. Then `resume` or `step` run it under the debugger.
+    pub fn start(&mut self) {
+        self.start_at(0);
+    }
+
+    /// Like `start`, with the chunk at `chunk_index` as the script. It starts above what is on the stack already.
+    fn start_at(&mut self, chunk_index: ChunkIndex) {
+        let function = gc::manage(Function{ arity: 0, chunk_index, name: "top".into() });
         let closure = gc::manage(Closure { upvalues: vec![], function: function.as_gc() });
-        self.push(Value::Closure(closure.as_gc()));
+        self.push(Value::closure(closure.as_gc()));
 
         self.frames.push(CallFrame { //TODO Use begin/end_frame because it needs to do cleanup of the stack
             program_counter: 0,
-            base_counter: 0,
-            chunk: self.module.chunk(0),
-            closure: closure,
+            instruction_index: 0,
+            base_counter: self.stack.len() - 1,
+            chunk: self.module.chunk(chunk_index),
+            closure,
         });
+    }
 
-        while self.interpret_next()? == InterpretResult::More {};
-
-        Ok(())
+    fn run(&mut self) -> Result<Stopped, VmError> {
+        loop {
+            match self.interpret_next()? {
+                // Everything is rooted in between instructions, so this is a safe point for incremental marking
+                InterpretResult::More => gc::step(),
+                InterpretResult::Done(_) => return Ok(Stopped::Finished),
+                InterpretResult::Stopped(stopped) => return Ok(stopped),
+            }
+        }
     }
 
     pub fn set_native_fn(&mut self, identifier: &str, code: fn(&[Value]) -> Value) {
         let native_function = NativeFunction {
             name: identifier.to_string(),
-            code: code,
+            code,
         };
 
-        let root = gc::manage(native_function);
-        self.globals.insert(identifier.to_string(), Value::NativeFunction(root.as_gc()));
+        // A native the module never refers to doesn't have a slot, and can't be used anyway
+        if let Some(index) = self.module.globals().index(identifier) {
+            let root = gc::manage(native_function);
+            self.globals.define(index, Value::native_function(root.as_gc())).expect("the module has the slot");
+        }
     }
 
     fn interpret_next(&mut self) -> Result<InterpretResult, VmError> {
         use crate::bytecode::{Instruction, Constant};
 
-        
-        self.current_frame_mut()?.program_counter += 1;
+        if let Some(debugger) = &mut self.debugger {
+            let frame = self.frames.last().ok_or(VmError::FrameEmpty)?;
+            let line = chunk_debug_info(self.module, frame).and_then(|info| info.statement_line(frame.program_counter));
+            let file = self.module.debug_info().and_then(|info| info.source_file(frame.closure.function.chunk_index));
+            if let Some(stopped) = debugger.should_stop(self.frames.len(), file, line) {
+                return Ok(InterpretResult::Stopped(stopped));
+            }
+        }
 
         let instr = {
-            let frame = self.current_frame()?;
-            frame.chunk.instructions()[frame.program_counter-1]
+            let frame = self.current_frame_mut()?;
+            let (instruction, next) = frame.chunk.read(frame.program_counter);
+            frame.instruction_index = frame.program_counter;
+            frame.program_counter = next;
+            instruction
         };
 
         if false { // DEBUG
             println!("stack: {:?}", self.stack);
-            println!("");
+            println!();
             println!("globals: {:?}", self.globals);
             println!("{:?}", instr);
-            println!("");
+            println!();
         }
 
         match instr {
             Instruction::Constant(index) => {
                 match self.module.constant(index) {
-                    Constant::Number(n) => self.push(Value::Number(*n)),
-                    Constant::String(string) => self.push_string(string),
+                    Constant::Number(n) => self.push(Value::number(*n)),
+                    Constant::String(_) => {
+                        let string = self.string_constant(index)?;
+                        self.push(Value::string(string));
+                    },
                     Constant::Class(_) => unimplemented!(),
                     Constant::Closure(_) => unimplemented!(),
                 }
@@ -129,102 +297,78 @@ impl<'a> Vm<'a> {
                     let function_root = gc::manage(Function::from(&closure.function));
                     let closure_root = gc::manage(Closure {
                         function: function_root.as_gc(),
-                        upvalues: upvalues,
+                        upvalues,
                     });
-                    self.push(Value::Closure(closure_root.as_gc()));
+                    self.push(Value::closure(closure_root.as_gc()));
                 } else {
                     return Err(VmError::ClosureConstantExpected);
                 }
             },
             Instruction::Class(index) => {
                 if let Constant::Class(class) = self.module.constant(index) {
-                    let class = gc::manage(RefCell::new(Class { name: class.name.clone() }));
-                    self.push(Value::Class(class.as_gc()));
+                    let shape = gc::manage(Shape::empty());
+                    let class = gc::manage(RefCell::new(Class { name: class.name.clone(), shape: shape.as_gc() }));
+                    self.push(Value::class(class.as_gc()));
                 } else {
                     return Err(VmError::UnexpectedConstant);
                 }
             },
             Instruction::SetProperty(index) => {
-                if let Constant::String(property) = self.module.constant(index) {
-                    if let Value::Instance(instance) = self.peek_n(1)? {
-                        instance.borrow_mut().fields.insert(property.clone(), *self.peek()?);
-
-                        let value = self.pop()?;
-                        self.pop()?;
-                        self.push(value);
-                    } else {
-                        return Err(VmError::UnexpectedValue);
-                    }
+                let property = self.string_constant(index)?;
+                if let Some(instance) = self.peek_n(1)?.as_instance() {
+                    self.set_property(instance, property, *self.peek()?)?;
+                    gc::write_barrier(instance);
+
+                    let value = self.pop()?;
+                    self.pop()?;
+                    self.push(value);
                 } else {
-                    return Err(VmError::UnexpectedConstant);
+                    return Err(VmError::UnexpectedValue);
                 }
             },
             Instruction::GetProperty(index) => {
-                if let Constant::String(property) = self.module.constant(index) {
-                    if let Value::Instance(instance) = self.pop()? {
-                        let instance = gc::root(instance);
-                        if let Some(value) = instance.borrow().fields.get(property) {
-                            self.push(*value);
-                        } else {
-                            return Err(VmError::UndefinedProperty);
-                        };
-                    }
+                let property = self.string_constant(index)?;
+                if let Some(instance) = self.pop()?.as_instance() {
+                    let instance = gc::root(instance);
+                    let value = self.get_property(instance.as_gc(), property)?;
+                    self.push(value);
                 }
             },
             Instruction::Print => {
-                match self.pop()? {
-                    Value::Number(n) => println!("{}", n),
-                    Value::Nil => println!("nil"),
-                    Value::Boolean(boolean) => println!("{}", boolean),
-                    Value::String(string) => println!("{}", string),
-                    Value::NativeFunction(function) => println!("<native fun {}>", function.name),
-                    Value::Closure(closure) => println!("<fun {}({}) @ {}>", closure.function.name, closure.function.arity, closure.function.chunk_index),
-                    Value::Class(class) => println!("{}", class.borrow().name),
-                    Value::Instance(instance) => println!("{} instance", instance.borrow().class.borrow().name),
+                let value = self.pop()?;
+                match &mut self.print {
+                    Some(print) => print(&value.to_string()),
+                    None => println!("{}", value),
                 }
             },
             Instruction::Nil => {
-                self.push(Value::Nil)
+                self.push(Value::nil())
             },
             Instruction::Return => {
-                let result = self.pop()?;
-                let frame = self.frames.pop().ok_or(VmError::FrameEmpty)?;
-
-                for i in frame.base_counter..self.stack.len() {
-                    self.close_upvalues(i);
-                }
-                
-                self.stack.split_off(frame.base_counter);
-
-                if self.frames.len() == 0 { 
-                    // We are done interpreting, don't push a result as it'll be nil
-                    return Ok(InterpretResult::Done); 
-                } 
-
-                self.push(result);
+                return self.end_frame();
             },
             Instruction::Add => {
-                match (self.pop()?, self.pop()?) {
-                    (Value::Number(b), Value::Number(a)) => self.push(Value::Number(a+b)),
-                    (Value::String(b), Value::String(a)) => self.push_string(&format!("{}{}", a, b)),
+                match (self.pop()?.unpack(), self.pop()?.unpack()) {
+                    (Unpacked::Number(b), Unpacked::Number(a)) => self.push(Value::number(a+b)),
+                    (Unpacked::String(b), Unpacked::String(a)) => self.push_string(&format!("{}{}", a, b)),
                     (b, a) => unimplemented!("{:?} + {:?}", a, b),
                 }
             },
             Instruction::Subtract => {
-                match (self.pop()?, self.pop()?) {
-                    (Value::Number(b), Value::Number(a)) => self.push(Value::Number(a-b)),
+                match (self.pop()?.unpack(), self.pop()?.unpack()) {
+                    (Unpacked::Number(b), Unpacked::Number(a)) => self.push(Value::number(a-b)),
                     (b, a) => unimplemented!("{:?} - {:?}", a, b),
                 }
             },
             Instruction::Multiply => {
-                match (self.pop()?, self.pop()?) {
-                    (Value::Number(b), Value::Number(a)) => self.push(Value::Number(a*b)),
+                match (self.pop()?.unpack(), self.pop()?.unpack()) {
+                    (Unpacked::Number(b), Unpacked::Number(a)) => self.push(Value::number(a*b)),
                     (b, a) => unimplemented!("{:?} * {:?}", a, b),
                 }
             },
             Instruction::Divide => {
-                match (self.pop()?, self.pop()?) {
-                    (Value::Number(b), Value::Number(a)) => self.push(Value::Number(a/b)),
+                match (self.pop()?.unpack(), self.pop()?.unpack()) {
+                    (Unpacked::Number(b), Unpacked::Number(a)) => self.push(Value::number(a/b)),
                     (b, a) => unimplemented!("{:?} / {:?}", a, b),
                 }
             },
@@ -232,36 +376,16 @@ impl<'a> Vm<'a> {
                 self.pop()?;
             },
             Instruction::DefineGlobal(index) => {
-                if let Constant::String(identifier) = self.module.constant(index) {
-                    let value = self.pop()?;
-                    self.globals.insert(identifier.to_string(), value);
-                } else {
-                    return Err(VmError::StringConstantExpected);
-                }
+                let value = self.pop()?;
+                self.globals.define(index, value)?;
             },
             Instruction::GetGlobal(index) => {
-                if let Constant::String(identifier) = self.module.constant(index) {
-                    let value = self.globals.get(identifier).cloned();
-                    if let Some(value) = value {
-                        self.push(value);
-                    } else {
-                        return Err(VmError::GlobalNotDefined);
-                    }
-                } else {
-                    return Err(VmError::StringConstantExpected);
-                }
+                let value = self.globals.get(index)?.ok_or(VmError::GlobalNotDefined)?;
+                self.push(value);
             },
             Instruction::SetGlobal(index) => {
-                if let Constant::String(identifier) = self.module.constant(index) {
-                    let value = *self.peek()?;
-                    if self.globals.contains_key(identifier) { 
-                        self.globals.insert(identifier.to_string(), value);
-                    } else {
-                        return Err(VmError::GlobalNotDefined); 
-                    }
-                } else {
-                    return Err(VmError::StringConstantExpected);
-                }
+                let value = *self.peek()?;
+                self.globals.assign(index, value)?;
             },
             Instruction::GetLocal(index) => {
                 let index = self.current_frame()?.base_counter+index;
@@ -273,10 +397,10 @@ impl<'a> Vm<'a> {
                 self.stack[index] = value;
             },
             Instruction::True => {
-                self.push(Value::Boolean(true))
+                self.push(Value::boolean(true))
             },
             Instruction::False => {
-                self.push(Value::Boolean(false))
+                self.push(Value::boolean(false))
             },
             Instruction::JumpIfFalse(to) => {
                 if self.peek()?.is_falsey() {
@@ -287,14 +411,14 @@ impl<'a> Vm<'a> {
                 self.current_frame_mut()?.program_counter = to;
             },
             Instruction::Less => {
-                match (self.pop()?, self.pop()?) {
-                    (Value::Number(b), Value::Number(a)) => self.push((a < b).into()),
+                match (self.pop()?.unpack(), self.pop()?.unpack()) {
+                    (Unpacked::Number(b), Unpacked::Number(a)) => self.push((a < b).into()),
                     (b, a) => unimplemented!("{:?} < {:?}", a, b),
                 }
             },
             Instruction::Greater => {
-                match (self.pop()?, self.pop()?) {
-                    (Value::Number(b), Value::Number(a)) => self.push((a > b).into()),
+                match (self.pop()?.unpack(), self.pop()?.unpack()) {
+                    (Unpacked::Number(b), Unpacked::Number(a)) => self.push((a > b).into()),
                     (b, a) => unimplemented!("{:?} > {:?}", a, b),
                 }
             },
@@ -303,13 +427,13 @@ impl<'a> Vm<'a> {
                 let a = self.pop()?;
 
                 if Value::is_same_type(&a, &b) {
-                    match (b,a) {
-                        (Value::Number(b), Value::Number(a)) => self.push((a == b).into()),
-                        (Value::Boolean(b), Value::Boolean(a)) => self.push((a == b).into()),
-                        (Value::String(b), Value::String(a)) => self.push((*a == *b).into()),
-                        (Value::Closure(_), Value::Closure(_)) => unimplemented!(),
-                        (Value::NativeFunction(_), Value::NativeFunction(_)) => unimplemented!(),
-                        (Value::Nil, Value::Nil) => self.push(true.into()),
+                    match (b.unpack(), a.unpack()) {
+                        (Unpacked::Number(b), Unpacked::Number(a)) => self.push((a == b).into()),
+                        (Unpacked::Boolean(b), Unpacked::Boolean(a)) => self.push((a == b).into()),
+                        (Unpacked::String(b), Unpacked::String(a)) => self.push(Gc::ptr_eq(&a, &b).into()),
+                        (Unpacked::Closure(_), Unpacked::Closure(_)) => unimplemented!(),
+                        (Unpacked::NativeFunction(_), Unpacked::NativeFunction(_)) => unimplemented!(),
+                        (Unpacked::Nil, Unpacked::Nil) => self.push(true.into()),
                         _ => (),
                     };
                 } else {
@@ -319,9 +443,19 @@ impl<'a> Vm<'a> {
             Instruction::Call(arity) => {
                 self.call(arity)?;
             },
+            Instruction::TailCall(arity) => {
+                match self.peek_n(arity)?.unpack() {
+                    Unpacked::Closure(callee) => self.tail_call(callee, arity)?,
+                    _ => {
+                        // Natives and classes don't get a frame, so there's nothing to reuse
+                        self.call(arity)?;
+                        return self.end_frame();
+                    },
+                }
+            },
             Instruction::Negate => {
-                match self.pop()? {
-                    Value::Number(n) => self.push(Value::Number(-n)),
+                match self.pop()?.unpack() {
+                    Unpacked::Number(n) => self.push(Value::number(-n)),
                     x => unimplemented!("{:?}", x),
                 }
             },
@@ -331,12 +465,13 @@ impl<'a> Vm<'a> {
             },
             Instruction::GetUpvalue(index) => {
                 let upvalue = self.current_frame()?.closure.upvalues[index];
-                self.push(self.resolve_upvalue_into_value(&*upvalue.borrow()));
+                self.push(self.resolve_upvalue_into_value(&upvalue.borrow()));
             },
             Instruction::SetUpvalue(index) => {
                 let value = *self.peek()?;
                 let upvalue = self.current_frame()?.closure.upvalues[index];
-                self.set_upvalue(&mut *upvalue.borrow_mut(), value);
+                self.set_upvalue(&mut upvalue.borrow_mut(), value);
+                gc::write_barrier(upvalue);
             },
             Instruction::CloseUpvalue => {
                 let index = self.stack.len() - 1;
@@ -348,11 +483,59 @@ impl<'a> Vm<'a> {
         Ok(InterpretResult::More)
     }
 
+    /// The inline cache of the instruction that is being executed.
+    fn current_cache(&mut self) -> Result<&mut InlineCache, VmError> {
+        let frame = self.current_frame()?;
+        let index = self.cache_slots[frame.closure.function.chunk_index][&frame.instruction_index];
+        Ok(&mut self.caches[index])
+    }
+
+    fn get_property(&mut self, instance: Gc<RefCell<Instance>>, property: Gc<String>) -> Result<Value, VmError> {
+        let shape = instance.borrow().shape;
+        let slot = match self.current_cache()?.lookup(shape) {
+            Some(entry) => entry.slot,
+            None => {
+                let slot = shape.slot(property).ok_or(VmError::UndefinedProperty)?;
+                self.current_cache()?.insert(CacheEntry { shape, slot, transition: None });
+                slot
+            },
+        };
+
+        let value = instance.borrow().fields[slot];
+        Ok(value)
+    }
+
+    /// The instance must be rooted, adding a property allocates a new shape.
+    fn set_property(&mut self, instance: Gc<RefCell<Instance>>, property: Gc<String>, value: Value) -> Result<(), VmError> {
+        let shape = instance.borrow().shape;
+        let entry = match self.current_cache()?.lookup(shape) {
+            Some(entry) => entry,
+            None => {
+                let entry = match shape.slot(property) {
+                    Some(slot) => CacheEntry { shape, slot, transition: None },
+                    None => CacheEntry { shape, slot: shape.len(), transition: Some(Shape::with_field(shape, property)) },
+                };
+                self.current_cache()?.insert(entry);
+                entry
+            },
+        };
+
+        let mut instance = instance.borrow_mut();
+        if let Some(transition) = entry.transition {
+            instance.shape = transition;
+            instance.fields.push(value);
+        } else {
+            instance.fields[entry.slot] = value;
+        }
+        Ok(())
+    }
+
     fn close_upvalues(&mut self, index: usize) {
         let value = self.stack[index]; //TODO Result
         for root in &self.upvalues {
             if root.borrow().is_open_with_index(index) {
                 root.replace(Upvalue::Closed(value));
+                gc::write_barrier(root.as_gc());
             }
         }
 
@@ -390,24 +573,25 @@ impl<'a> Vm<'a> {
 
     fn call(&mut self, arity: usize) -> Result<(), VmError> {
         let callee = *self.peek_n(arity)?;
-        match callee {
-            Value::Closure(callee) => {
+        match callee.unpack() {
+            Unpacked::Closure(callee) => {
                 if callee.function.arity != arity { return Err(VmError::IncorrectArity); }
-                self.begin_frame(callee);
+                self.begin_frame(callee)?;
             },
-            Value::NativeFunction(callee) => {
-                let mut args = self.pop_n(arity)?;
-                args.reverse();
-                self.pop()?; // discard callee
+            Unpacked::NativeFunction(callee) => {
+                // Arguments stay on the stack during the call, so they stay rooted if the native allocates
+                let args = self.stack[self.stack.len() - arity..].to_vec();
                 let result = (callee.code)(&args);
+                self.pop_n(arity + 1)?; // discard arguments and callee
                 self.push(result);
             },
-            Value::Class(class) => {
+            Unpacked::Class(class) => {
                 if arity > 0 { unimplemented!("Calling a class with arguments is not yet supported"); }
-                self.pop()?; //TODO Temporary, remove when arguments are supported
 
-                let instance = gc::manage(RefCell::new(Instance{ class, fields: HashMap::new()}));
-                self.push(Value::Instance(instance.as_gc()));
+                // The class is only popped after allocating, so it can't be collected in between
+                let instance = gc::manage(RefCell::new(Instance::new(class)));
+                self.pop()?; //TODO Temporary, remove when arguments are supported
+                self.push(Value::instance(instance.as_gc()));
             },
             _ => return Err(VmError::InvalidCallee),
         }
@@ -415,7 +599,49 @@ impl<'a> Vm<'a> {
         Ok(())
     }
 
-    fn current_frame(&self) -> Result<&CallFrame, VmError> {
+    /// Call a closure in place of the function that is running, its frame is reused so deep recursion doesn't overflow.
+    fn tail_call(&mut self, callee: Gc<Closure>, arity: usize) -> Result<(), VmError> {
+        if callee.function.arity != arity { return Err(VmError::IncorrectArity); }
+
+        let base = self.current_frame()?.base_counter;
+        let callee_index = self.stack.len() - arity - 1;
+        for i in base..callee_index {
+            self.close_upvalues(i);
+        }
+
+        // Move the callee and its arguments down to where the function that is returning started
+        self.stack.drain(base..callee_index);
+
+        let chunk = self.module.chunk(callee.function.chunk_index);
+        let frame = self.current_frame_mut()?;
+        frame.program_counter = 0;
+        frame.instruction_index = 0;
+        frame.chunk = chunk;
+        frame.closure = gc::root(callee);
+
+        Ok(())
+    }
+
+    fn end_frame(&mut self) -> Result<InterpretResult, VmError> {
+        let result = self.pop()?;
+        let frame = self.frames.pop().ok_or(VmError::FrameEmpty)?;
+
+        for i in frame.base_counter..self.stack.len() {
+            self.close_upvalues(i);
+        }
+
+        self.stack.truncate(frame.base_counter);
+
+        if self.frames.is_empty() {
+            // We are done interpreting, only `evaluate` uses what the script returns
+            return Ok(InterpretResult::Done(result));
+        }
+
+        self.push(result);
+        Ok(InterpretResult::More)
+    }
+
+    fn current_frame(&self) -> Result<&CallFrame<'_>, VmError> {
         self.frames.last().ok_or(VmError::FrameEmpty)
     }
 
@@ -428,8 +654,15 @@ impl<'a> Vm<'a> {
     }
 
     fn push_string(&mut self, string: &str) {
-        let root = gc::manage(string.to_string());
-        self.push(Value::String(root.as_gc()));
+        let root = intern(string);
+        self.push(Value::string(root.as_gc()));
+    }
+
+    fn string_constant(&self, index: usize) -> Result<Gc<String>, VmError> {
+        match self.strings.get(index) {
+            Some(Some(string)) => Ok(string.as_gc()),
+            _ => Err(VmError::StringConstantExpected),
+        }
     }
 
     fn pop(&mut self) -> Result<Value, VmError> {
@@ -453,12 +686,190 @@ impl<'a> Vm<'a> {
         self.stack.get(self.stack.len() - n - 1).ok_or(VmError::StackEmpty)
     }
 
-    fn begin_frame(&mut self, closure: Gc<Closure>) {
+    fn begin_frame(&mut self, closure: Gc<Closure>) -> Result<(), VmError> {
+        if self.frames.len() >= self.max_frames || self.stack.len() >= self.max_stack {
+            return Err(VmError::StackOverflow(self.stack_trace()));
+        }
+
         self.frames.push(CallFrame {
             program_counter: 0,
+            instruction_index: 0,
             base_counter: self.stack.len() - closure.function.arity - 1,
             chunk: self.module.chunk(closure.function.chunk_index),
             closure: gc::root(closure),
         });
+
+        Ok(())
+    }
+
+    fn stack_trace(&self) -> StackTrace {
+        let names: Vec<String> = self.frames.iter().enumerate().rev().map(|(depth, frame)| {
+            if depth == 0 {
+                "script".to_string()
+            } else {
+                format!("{}()", frame.closure.function.name)
+            }
+        }).collect();
+
+        StackTrace::new(names)
+    }
+}
+
+/// Debugging, the line numbers and names all come from the debug info of the module.
+impl<'a> Vm<'a> {
+    /// Stop when a statement on `line` of `file` is about to run. Returns the line the breakpoint ended up on, the
+    /// first one with a statement, or None if there is no such line in `file`.
+    pub fn set_breakpoint(&mut self, file: &str, line: usize) -> Option<usize> {
+        let debug_info = self.module.debug_info()?;
+        let (file, line) = (debug_info.find_source_file(file)?, debug_info.statement_line_from(file, line)?);
+        self.debugger().add_breakpoint(file, line);
+        Some(line)
+    }
+
+    pub fn clear_breakpoint(&mut self, file: &str, line: usize) -> bool {
+        match self.module.debug_info().and_then(|info| info.find_source_file(file)) {
+            Some(file) => self.debugger().remove_breakpoint(file, line),
+            None => false,
+        }
+    }
+
+    /// Remove every breakpoint in `file`.
+    pub fn clear_breakpoints(&mut self, file: &str) {
+        if let Some(file) = self.module.debug_info().and_then(|info| info.find_source_file(file)) {
+            self.debugger().clear_breakpoints(file);
+        }
     }
-}
\ No newline at end of file
+
+    /// The breakpoints with the files they are in, by file and line.
+    pub fn breakpoints(&self) -> Vec<(String, usize)> {
+        let breakpoints = self.debugger.as_ref().map(|debugger| debugger.breakpoints().map(|(file, line)| (file.to_string(), line)).collect());
+        breakpoints.unwrap_or_default()
+    }
+
+    /// Run until a breakpoint is hit or the script is done.
+    pub fn resume(&mut self) -> Result<Stopped, VmError> {
+        self.debugger().set_step(None, 0);
+        self.run()
+    }
+
+    pub fn step(&mut self, step: Step) -> Result<Stopped, VmError> {
+        let depth = self.frames.len();
+        self.debugger().set_step(Some(step), depth);
+        self.run()
+    }
+
+    /// The functions that are running, innermost first.
+    pub fn call_stack(&self) -> Vec<FrameInfo> {
+        (0..self.frames.len()).map(|depth| {
+            let (frame, index) = self.frame_at(depth).expect("frame exists");
+            let chunk_index = frame.closure.function.chunk_index;
+            FrameInfo {
+                name: if depth == self.frames.len() - 1 { "script".to_string() } else { frame.closure.function.name.clone() },
+                chunk_index,
+                file: self.module.debug_info().and_then(|info| info.source_file(chunk_index)).map(String::from),
+                line: chunk_debug_info(self.module, frame).and_then(|info| info.line(index)),
+            }
+        }).collect()
+    }
+
+    /// The locals of the frame at `depth` that hold a value, outermost first. The innermost frame is at depth 0.
+    pub fn locals(&self, depth: usize) -> Vec<(String, Value)> {
+        let (frame, index) = match self.frame_at(depth) {
+            Some(found) => found,
+            None => return vec![],
+        };
+        let info = match chunk_debug_info(self.module, frame) {
+            Some(info) => info,
+            None => return vec![],
+        };
+
+        info.locals_at(index)
+            .filter_map(|local| {
+                let value = self.stack.get(frame.base_counter + local.slot)?;
+                Some((local.name.clone(), *value))
+            })
+            .collect()
+    }
+
+    /// The value of a local by name, as the code in the frame at `depth` would see it.
+    pub fn local(&self, depth: usize, name: &str) -> Option<Value> {
+        self.locals(depth).into_iter().rev().find(|(local, _)| local == name).map(|(_, value)| value)
+    }
+
+    pub fn upvalues(&self, depth: usize) -> Vec<(String, Value)> {
+        let (frame, _) = match self.frame_at(depth) {
+            Some(found) => found,
+            None => return vec![],
+        };
+        let names = chunk_debug_info(self.module, frame).map(|info| info.upvalues.as_slice()).unwrap_or_default();
+
+        names.iter().zip(&frame.closure.upvalues)
+            .map(|(name, upvalue)| (name.clone(), self.resolve_upvalue_into_value(&upvalue.borrow())))
+            .collect()
+    }
+
+    /// Every global that has been defined.
+    pub fn globals(&self) -> Vec<(String, Value)> {
+        self.module.globals().names().iter().enumerate()
+            .filter_map(|(index, name)| Some((name.clone(), self.globals.get(index).ok()??)))
+            .collect()
+    }
+
+    pub fn global(&self, name: &str) -> Option<Value> {
+        self.globals.get(self.module.globals().index(name)?).ok()?
+    }
+
+    /// Run a module compiled by `lox_compiler::compile_expression` as if it was in the frame at `depth`, returning
+    /// the value of the expression. The locals, upvalues and globals the frame can see are copied in, assigning to
+    /// them doesn't change the program. It can call the functions of the program, but gives up after
+    /// `EVALUATE_STEPS` instructions.
+    pub fn evaluate(&self, expression: &Module, depth: usize) -> Result<Value, VmError> {
+        // The closures of the program refer to its chunks and constants, those keep their index
+        let mut module = self.module.clone();
+        let script = lox_bytecode::link::append(&mut module, expression);
+        let mut vm = Vm::new(&module);
+        super::define_natives(&mut vm);
+        for index in 0..self.module.globals().names().len() {
+            if let Some(value) = self.globals.get(index)? {
+                vm.globals.define(index, value)?;
+            }
+        }
+        // Open upvalues of the closures refer to slots of the stack
+        vm.stack.extend_from_slice(&self.stack);
+
+        let upvalues = self.upvalues(depth);
+        for name in expression.globals().names() {
+            let value = self.local(depth, name)
+                .or_else(|| upvalues.iter().find(|(upvalue, _)| upvalue == name).map(|(_, value)| *value));
+            if let (Some(value), Some(index)) = (value, module.globals().index(name)) {
+                vm.globals.define(index, value)?;
+            }
+        }
+
+        // A new VM doesn't have breakpoints, it runs until the expression is done
+        vm.start_at(script);
+        for _ in 0..EVALUATE_STEPS {
+            match vm.interpret_next()? {
+                InterpretResult::Done(value) => return Ok(value),
+                InterpretResult::More | InterpretResult::Stopped(_) => gc::step(),
+            }
+        }
+        Err(VmError::EvaluateStepLimit)
+    }
+
+    fn debugger(&mut self) -> &mut Debugger {
+        self.debugger.get_or_insert_with(Debugger::default)
+    }
+
+    /// The frame at `depth` from the innermost one, with the index of the instruction it's at.
+    fn frame_at(&self, depth: usize) -> Option<(&CallFrame<'a>, InstructionIndex)> {
+        let frame = self.frames.iter().rev().nth(depth)?;
+        // The innermost frame stopped before running the next instruction, the others are calling a function.
+        let index = if depth == 0 { frame.program_counter } else { frame.instruction_index };
+        Some((frame, index))
+    }
+}
+
+fn chunk_debug_info<'a>(module: &'a Module, frame: &CallFrame) -> Option<&'a ChunkDebugInfo> {
+    module.debug_info()?.chunk(frame.closure.function.chunk_index)
+}
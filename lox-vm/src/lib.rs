@@ -1,4 +1,5 @@
 pub mod bettervm;
-mod bettergc;
+pub mod bettergc;
+pub mod registervm;
 
-use lox_bytecode::bytecode;
\ No newline at end of file
+use lox_bytecode::{bytecode, register};
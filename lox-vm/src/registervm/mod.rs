@@ -0,0 +1,27 @@
+//! An executor for the register based instruction set. It shares its values, heap objects and
+//! natives with the stack based VM in `bettervm`, only the way instructions are run differs.
+
+mod vm;
+
+#[cfg(test)]
+mod tests;
+
+use crate::register::Module;
+use crate::bettervm::{VmError, NATIVES};
+
+pub use vm::Vm;
+
+pub fn execute(module: &Module) -> Result<(), VmError> {
+    let mut vm = Vm::new(module);
+    define_natives(&mut vm);
+
+    vm.interpret()?;
+
+    Ok(())
+}
+
+pub fn define_natives(vm: &mut Vm) {
+    for (name, code) in NATIVES {
+        vm.set_native_fn(name, *code);
+    }
+}
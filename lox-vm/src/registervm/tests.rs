@@ -0,0 +1,142 @@
+use super::*;
+use crate::bettervm::Value;
+
+fn run(data: &str) -> Result<(), VmError> {
+    let module = lox_compiler::compile_registers(data).unwrap();
+    execute(&module)
+}
+
+/// Like `run`, but with an `assert` native that panics when its argument is falsey.
+fn run_with_assert(data: &str) -> Result<(), VmError> {
+    let module = lox_compiler::compile_registers(data).unwrap();
+    let mut vm = Vm::new(&module);
+    define_natives(&mut vm);
+    vm.set_native_fn("assert", |args| {
+        assert!(!args[0].is_falsey(), "assertion failed");
+        Value::nil()
+    });
+    vm.interpret()
+}
+
+#[test]
+fn test_arithmetic_and_comparison() {
+    let code = "
+        assert(1 + 2 * 3 == 7);
+        assert((1 + 2) * 3 == 9);
+        assert(-(4 / 2) == -2);
+        assert(1 <= 1 and 1 >= 1 and 1 != 2);
+        assert(!(1 > 2) and !nil);
+        assert(\"a\" + \"b\" == \"ab\");
+    ";
+    assert!(run_with_assert(code).is_ok());
+}
+
+#[test]
+fn test_operands_are_read_in_order() {
+    let code = "
+        {
+            var a = 1;
+            assert(a + (a = 2) == 3);
+            assert(a == 2);
+            var b = 3;
+            a = b or a;
+            assert(a == 3);
+            a = nil and b;
+            assert(a == nil);
+        }
+    ";
+    assert!(run_with_assert(code).is_ok());
+}
+
+#[test]
+fn test_calls_and_recursion() {
+    let code = "
+        fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }
+        assert(fib(15) == 610);
+        {
+            fun add(a, b) { return a + b; }
+            var total = 0;
+            for (var i = 0; i < 10; i = i + 1) total = add(total, i);
+            assert(total == 45);
+        }
+    ";
+    assert!(run_with_assert(code).is_ok());
+}
+
+#[test]
+fn test_closures() {
+    let code = "
+        fun counter() {
+            var count = 0;
+            fun increment() { count = count + 1; return count; }
+            return increment;
+        }
+        var a = counter();
+        var b = counter();
+        a(); a();
+        assert(a() == 3);
+        assert(b() == 1);
+
+        var closures = nil;
+        {
+            var shared = 1;
+            fun get() { return shared; }
+            fun set(value) { shared = value; }
+            set(5);
+            assert(shared == 5);
+            closures = get;
+        }
+        assert(closures() == 5);
+    ";
+    assert!(run_with_assert(code).is_ok());
+}
+
+#[test]
+fn test_classes_and_properties() {
+    let code = "
+        class Point {}
+        var p = Point();
+        p.x = 1;
+        p.y = p.x + 1;
+        assert(p.x + p.y == 3);
+        gc();
+        assert(p.y == 2);
+    ";
+    assert!(run_with_assert(code).is_ok());
+}
+
+#[test]
+fn test_unbounded_recursion_overflows() {
+    match run("fun f() { f(); } f();") {
+        Err(VmError::StackOverflow(trace)) => {
+            assert_eq!(trace.innermost[0], "f()");
+            assert_eq!(trace.outermost.last().unwrap(), "script");
+        },
+        result => panic!("expected stack overflow, got {:?}", result),
+    }
+}
+
+#[test]
+fn test_undefined_globals() {
+    assert!(matches!(run("print undefined;"), Err(VmError::GlobalNotDefined)));
+    assert!(matches!(run("undefined = 1;"), Err(VmError::GlobalNotDefined)));
+}
+
+#[test]
+fn test_function_equality() {
+    let code = "
+        fun f() {}
+        fun g() {}
+        assert(f == f and f != g);
+        assert(clock == clock and clock != gc);
+    ";
+    assert!(run_with_assert(code).is_ok());
+}
+
+#[test]
+fn test_type_errors() {
+    assert!(matches!(run("print \"a\" + 1;"), Err(VmError::UnexpectedValue)));
+    assert!(matches!(run("print -\"a\";"), Err(VmError::UnexpectedValue)));
+    assert!(matches!(run("print nil < 1;"), Err(VmError::UnexpectedValue)));
+    assert!(matches!(run("class A {} A(1);"), Err(VmError::IncorrectArity)));
+}
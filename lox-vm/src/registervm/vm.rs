@@ -0,0 +1,488 @@
+use crate::bettervm::memory::*;
+use crate::bettervm::inline_cache::{InlineCache, CacheEntry};
+use crate::bettervm::{intern, Value, Unpacked, VmError, StackTrace, FRAMES_MAX, STACK_MAX};
+use crate::register::{Module, Chunk, Register};
+use crate::bettergc::{UniqueRoot, Gc, Root, gc};
+use std::cell::RefCell;
+
+type Print = Box<dyn FnMut(&str)>;
+
+#[derive(PartialEq)]
+enum InterpretResult {
+    Done,
+    More,
+}
+
+struct CallFrame<'a> {
+    /// The index of the next instruction.
+    program_counter: usize,
+    /// The index of the instruction being executed.
+    instruction_index: usize,
+    /// The index of register 0 of this frame, in the register file.
+    base: usize,
+    chunk: &'a Chunk,
+    closure: Root<Closure>,
+}
+
+/// Runs modules compiled to register instructions. Frames are windows in a single register file,
+/// a call starts the window of the callee at the register holding it.
+pub struct Vm<'a> {
+    module: &'a Module,
+    frames: Vec<CallFrame<'a>>,
+    registers: UniqueRoot<Vec<Value>>,
+    /// Globals by slot, a global that hasn't been defined yet is None.
+    globals: UniqueRoot<Vec<Option<Value>>>,
+    /// The interned string for every string constant in the module, by constant index.
+    strings: Vec<Option<Root<String>>>,
+    /// An inline cache for every instruction by its index, `cache_offsets` has the index of the first one of each chunk.
+    caches: UniqueRoot<Vec<InlineCache>>,
+    cache_offsets: Vec<usize>,
+    upvalues: Vec<Root<RefCell<Upvalue>>>,
+    max_frames: usize,
+    max_registers: usize,
+    /// Where `print` writes to instead of stdout.
+    print: Option<Print>,
+}
+
+impl<'a> Vm<'a> {
+    pub fn new(module: &'a Module) -> Self {
+        use crate::bytecode::Constant;
+
+        let strings = module.constants().iter().map(|constant| {
+            match constant {
+                Constant::String(string) => Some(intern(string)),
+                _ => None,
+            }
+        }).collect();
+
+        let mut cache_offsets = vec![];
+        let mut cache_count = 0;
+        for chunk in module.chunks() {
+            cache_offsets.push(cache_count);
+            cache_count += chunk.instructions().len();
+        }
+
+        Vm {
+            module,
+            frames: vec![],
+            registers: gc::unique(vec![]),
+            globals: gc::unique(vec![None; module.globals().names().len()]),
+            strings,
+            caches: gc::unique(vec![InlineCache::Empty; cache_count]),
+            cache_offsets,
+            upvalues: vec![],
+            max_frames: FRAMES_MAX,
+            max_registers: STACK_MAX,
+            print: None,
+        }
+    }
+
+    pub fn set_max_frames(&mut self, max_frames: usize) {
+        self.max_frames = max_frames;
+    }
+
+    pub fn set_max_registers(&mut self, max_registers: usize) {
+        self.max_registers = max_registers;
+    }
+
+    /// Send everything the program prints to `print`, one line at a time, instead of to stdout.
+    pub fn set_print(&mut self, print: impl FnMut(&str) + 'static) {
+        self.print = Some(Box::new(print));
+    }
+
+    pub fn interpret(&mut self) -> Result<(), VmError> {
+        let function = gc::manage(Function{ arity: 0, chunk_index: 0, name: "top".into() });
+        let closure = gc::manage(Closure { upvalues: vec![], function: function.as_gc() });
+        self.registers.push(Value::closure(closure.as_gc()));
+        self.begin_frame(closure.as_gc(), 0)?;
+
+        while self.interpret_next()? == InterpretResult::More {
+            // Everything is rooted in between instructions, so this is a safe point for incremental marking
+            gc::step();
+        }
+
+        Ok(())
+    }
+
+    pub fn set_native_fn(&mut self, identifier: &str, code: fn(&[Value]) -> Value) {
+        let native_function = NativeFunction {
+            name: identifier.to_string(),
+            code,
+        };
+
+        // A native the module never refers to doesn't have a slot, and can't be used anyway
+        if let Some(index) = self.module.globals().index(identifier) {
+            let root = gc::manage(native_function);
+            self.globals[index] = Some(Value::native_function(root.as_gc()));
+        }
+    }
+
+    fn interpret_next(&mut self) -> Result<InterpretResult, VmError> {
+        use crate::register::Instruction;
+        use crate::bytecode::Constant;
+
+        let (instr, base) = {
+            let frame = self.current_frame_mut()?;
+            let instruction = frame.chunk.instruction(frame.program_counter);
+            frame.instruction_index = frame.program_counter;
+            frame.program_counter += 1;
+            (instruction, frame.base)
+        };
+
+        match instr {
+            Instruction::LoadConstant(dst, index) => {
+                let value = match self.module.constant(index) {
+                    Constant::Number(n) => Value::number(*n),
+                    Constant::String(_) => Value::string(self.string_constant(index)?),
+                    _ => return Err(VmError::UnexpectedConstant),
+                };
+                self.registers[base + dst] = value;
+            },
+            Instruction::LoadNil(dst) => self.registers[base + dst] = Value::nil(),
+            Instruction::LoadTrue(dst) => self.registers[base + dst] = Value::boolean(true),
+            Instruction::LoadFalse(dst) => self.registers[base + dst] = Value::boolean(false),
+            Instruction::Move(dst, src) => self.registers[base + dst] = self.registers[base + src],
+            Instruction::Negate(dst, src) => {
+                let n = self.registers[base + src].as_number().ok_or(VmError::UnexpectedValue)?;
+                self.registers[base + dst] = Value::number(-n);
+            },
+            Instruction::Not(dst, src) => {
+                self.registers[base + dst] = self.registers[base + src].is_falsey().into();
+            },
+            Instruction::Add(dst, a, b) => {
+                let value = match (self.registers[base + a].unpack(), self.registers[base + b].unpack()) {
+                    (Unpacked::Number(a), Unpacked::Number(b)) => Value::number(a + b),
+                    (Unpacked::String(a), Unpacked::String(b)) => {
+                        // Stored right away, so the new string is rooted by the registers
+                        let root = intern(&format!("{}{}", a, b));
+                        Value::string(root.as_gc())
+                    },
+                    _ => return Err(VmError::UnexpectedValue),
+                };
+                self.registers[base + dst] = value;
+            },
+            Instruction::Subtract(dst, a, b) => {
+                let (a, b) = self.numbers(base, a, b)?;
+                self.registers[base + dst] = Value::number(a - b);
+            },
+            Instruction::Multiply(dst, a, b) => {
+                let (a, b) = self.numbers(base, a, b)?;
+                self.registers[base + dst] = Value::number(a * b);
+            },
+            Instruction::Divide(dst, a, b) => {
+                let (a, b) = self.numbers(base, a, b)?;
+                self.registers[base + dst] = Value::number(a / b);
+            },
+            Instruction::Less(dst, a, b) => {
+                let (a, b) = self.numbers(base, a, b)?;
+                self.registers[base + dst] = (a < b).into();
+            },
+            Instruction::Greater(dst, a, b) => {
+                let (a, b) = self.numbers(base, a, b)?;
+                self.registers[base + dst] = (a > b).into();
+            },
+            Instruction::Equal(dst, a, b) => {
+                let a = self.registers[base + a];
+                let b = self.registers[base + b];
+
+                let equal = Value::is_same_type(&a, &b) && match (a.unpack(), b.unpack()) {
+                    (Unpacked::Number(a), Unpacked::Number(b)) => a == b,
+                    (Unpacked::Boolean(a), Unpacked::Boolean(b)) => a == b,
+                    (Unpacked::String(a), Unpacked::String(b)) => Gc::ptr_eq(&a, &b),
+                    // A function is only equal to itself
+                    (Unpacked::Closure(a), Unpacked::Closure(b)) => Gc::ptr_eq(&a, &b),
+                    (Unpacked::NativeFunction(a), Unpacked::NativeFunction(b)) => Gc::ptr_eq(&a, &b),
+                    (Unpacked::Nil, Unpacked::Nil) => true,
+                    _ => false,
+                };
+                self.registers[base + dst] = equal.into();
+            },
+            Instruction::Print(src) => {
+                let value = self.registers[base + src];
+                match &mut self.print {
+                    Some(print) => print(&value.to_string()),
+                    None => println!("{}", value),
+                }
+            },
+            Instruction::DefineGlobal(index, src) => {
+                *self.global_slot(index)? = Some(self.registers[base + src]);
+            },
+            Instruction::GetGlobal(dst, index) => {
+                let value = self.global_slot(index)?.ok_or(VmError::GlobalNotDefined)?;
+                self.registers[base + dst] = value;
+            },
+            Instruction::SetGlobal(index, src) => {
+                let value = self.registers[base + src];
+                match self.global_slot(index)? {
+                    Some(global) => *global = value,
+                    None => return Err(VmError::GlobalNotDefined),
+                }
+            },
+            Instruction::GetUpvalue(dst, index) => {
+                let upvalue = self.current_frame()?.closure.upvalues[index];
+                let value = match *upvalue.borrow() {
+                    Upvalue::Closed(value) => value,
+                    Upvalue::Open(index) => self.registers[index],
+                };
+                self.registers[base + dst] = value;
+            },
+            Instruction::SetUpvalue(index, src) => {
+                let value = self.registers[base + src];
+                let upvalue = self.current_frame()?.closure.upvalues[index];
+                let open = match &mut *upvalue.borrow_mut() {
+                    Upvalue::Closed(closed) => { *closed = value; None },
+                    Upvalue::Open(index) => Some(*index),
+                };
+                if let Some(index) = open {
+                    self.registers[index] = value;
+                }
+                gc::write_barrier(upvalue);
+            },
+            Instruction::GetProperty(dst, instance, index) => {
+                let property = self.string_constant(index)?;
+                let instance = self.registers[base + instance].as_instance().ok_or(VmError::UnexpectedValue)?;
+                let value = self.get_property(instance, property)?;
+                self.registers[base + dst] = value;
+            },
+            Instruction::SetProperty(instance, index, src) => {
+                let property = self.string_constant(index)?;
+                let instance = self.registers[base + instance].as_instance().ok_or(VmError::UnexpectedValue)?;
+                self.set_property(instance, property, self.registers[base + src])?;
+                gc::write_barrier(instance);
+            },
+            Instruction::Jump(to) => {
+                self.current_frame_mut()?.program_counter = to;
+            },
+            Instruction::JumpIfFalse(condition, to) => {
+                if self.registers[base + condition].is_falsey() {
+                    self.current_frame_mut()?.program_counter = to;
+                }
+            },
+            Instruction::JumpIfTrue(condition, to) => {
+                if !self.registers[base + condition].is_falsey() {
+                    self.current_frame_mut()?.program_counter = to;
+                }
+            },
+            Instruction::Call(callee, arity) => {
+                self.call(base + callee, arity)?;
+            },
+            Instruction::Return(src) => {
+                let result = self.registers[base + src];
+                self.close_upvalues(base);
+                self.frames.pop().ok_or(VmError::FrameEmpty)?;
+
+                if let Some(frame) = self.frames.last() {
+                    // The result replaces the callee. Everything above it was only used by the call and
+                    // is cleared, so it doesn't keep anything alive.
+                    let top = frame.base + frame.chunk.registers();
+                    self.registers[base] = result;
+                    self.registers.truncate(base + 1);
+                    self.registers.resize(top, Value::nil());
+                } else {
+                    self.registers.clear();
+                    return Ok(InterpretResult::Done);
+                }
+            },
+            Instruction::CloseUpvalues(from) => {
+                self.close_upvalues(base + from);
+            },
+            Instruction::Class(dst, index) => {
+                if let Constant::Class(class) = self.module.constant(index) {
+                    let shape = gc::manage(Shape::empty());
+                    let class = gc::manage(RefCell::new(Class { name: class.name.clone(), shape: shape.as_gc() }));
+                    self.registers[base + dst] = Value::class(class.as_gc());
+                } else {
+                    return Err(VmError::UnexpectedConstant);
+                }
+            },
+            Instruction::Closure(dst, index) => {
+                if let Constant::Closure(closure) = self.module.constant(index) {
+                    let upvalues = closure.upvalues.iter().map(|upvalue| {
+                        match upvalue {
+                            crate::bytecode::Upvalue::Local(index) => Ok(self.capture_upvalue(base + *index)),
+                            crate::bytecode::Upvalue::Upvalue(index) => self.find_upvalue_by_index(*index),
+                        }
+                    }).collect::<Result<_, _>>()?;
+
+                    let function_root = gc::manage(Function::from(&closure.function));
+                    let closure_root = gc::manage(Closure {
+                        function: function_root.as_gc(),
+                        upvalues,
+                    });
+                    self.registers[base + dst] = Value::closure(closure_root.as_gc());
+                } else {
+                    return Err(VmError::ClosureConstantExpected);
+                }
+            },
+        }
+
+        Ok(InterpretResult::More)
+    }
+
+    /// The operands of an arithmetic or comparison instruction, which have to be numbers.
+    fn numbers(&self, base: usize, a: Register, b: Register) -> Result<(f64, f64), VmError> {
+        match (self.registers[base + a].unpack(), self.registers[base + b].unpack()) {
+            (Unpacked::Number(a), Unpacked::Number(b)) => Ok((a, b)),
+            _ => Err(VmError::UnexpectedValue),
+        }
+    }
+
+    /// The inline cache of the instruction that is being executed.
+    fn current_cache(&mut self) -> Result<&mut InlineCache, VmError> {
+        let frame = self.current_frame()?;
+        let index = self.cache_offsets[frame.closure.function.chunk_index] + frame.instruction_index;
+        Ok(&mut self.caches[index])
+    }
+
+    fn get_property(&mut self, instance: Gc<RefCell<Instance>>, property: Gc<String>) -> Result<Value, VmError> {
+        let shape = instance.borrow().shape;
+        let slot = match self.current_cache()?.lookup(shape) {
+            Some(entry) => entry.slot,
+            None => {
+                let slot = shape.slot(property).ok_or(VmError::UndefinedProperty)?;
+                self.current_cache()?.insert(CacheEntry { shape, slot, transition: None });
+                slot
+            },
+        };
+
+        let value = instance.borrow().fields[slot];
+        Ok(value)
+    }
+
+    /// The instance must be rooted, adding a property allocates a new shape.
+    fn set_property(&mut self, instance: Gc<RefCell<Instance>>, property: Gc<String>, value: Value) -> Result<(), VmError> {
+        let shape = instance.borrow().shape;
+        let entry = match self.current_cache()?.lookup(shape) {
+            Some(entry) => entry,
+            None => {
+                let entry = match shape.slot(property) {
+                    Some(slot) => CacheEntry { shape, slot, transition: None },
+                    None => CacheEntry { shape, slot: shape.len(), transition: Some(Shape::with_field(shape, property)) },
+                };
+                self.current_cache()?.insert(entry);
+                entry
+            },
+        };
+
+        let mut instance = instance.borrow_mut();
+        if let Some(transition) = entry.transition {
+            instance.shape = transition;
+            instance.fields.push(value);
+        } else {
+            instance.fields[entry.slot] = value;
+        }
+        Ok(())
+    }
+
+    /// The open upvalue for a register, shared by every closure that captures it.
+    fn capture_upvalue(&mut self, index: usize) -> Gc<RefCell<Upvalue>> {
+        if let Some(upvalue) = self.upvalues.iter().rev().find(|root| root.borrow().is_open_with_index(index)) {
+            return upvalue.as_gc();
+        }
+
+        let root = gc::manage(RefCell::new(Upvalue::Open(index)));
+        let upvalue = root.as_gc();
+        self.upvalues.push(root);
+        upvalue
+    }
+
+    fn find_upvalue_by_index(&self, index: usize) -> Result<Gc<RefCell<Upvalue>>, VmError> {
+        Ok(self.current_frame()?.closure.upvalues[index])
+    }
+
+    /// Close every open upvalue of a register at or above `from`.
+    fn close_upvalues(&mut self, from: usize) {
+        let registers = &self.registers;
+        self.upvalues.retain(|root| {
+            let index = match *root.borrow() {
+                Upvalue::Open(index) if index >= from => index,
+                _ => return true,
+            };
+            root.replace(Upvalue::Closed(registers[index]));
+            gc::write_barrier(root.as_gc());
+            false
+        });
+    }
+
+    /// Call the value in register `callee`, an absolute index, with the arguments that follow it.
+    fn call(&mut self, callee: usize, arity: usize) -> Result<(), VmError> {
+        match self.registers[callee].unpack() {
+            Unpacked::Closure(closure) => {
+                if closure.function.arity != arity { return Err(VmError::IncorrectArity); }
+                self.begin_frame(closure, callee)?;
+            },
+            Unpacked::NativeFunction(function) => {
+                // Arguments stay in their registers during the call, so they stay rooted if the native allocates
+                let args = self.registers[callee + 1..callee + 1 + arity].to_vec();
+                self.registers[callee] = (function.code)(&args);
+                for register in &mut self.registers[callee + 1..callee + 1 + arity] {
+                    *register = Value::nil();
+                }
+            },
+            Unpacked::Class(class) => {
+                // Without an initializer a class takes no arguments
+                if arity > 0 { return Err(VmError::IncorrectArity); }
+
+                // The class stays in its register while allocating, so it can't be collected in between
+                let instance = gc::manage(RefCell::new(Instance::new(class)));
+                self.registers[callee] = Value::instance(instance.as_gc());
+            },
+            _ => return Err(VmError::InvalidCallee),
+        }
+
+        Ok(())
+    }
+
+    fn begin_frame(&mut self, closure: Gc<Closure>, base: usize) -> Result<(), VmError> {
+        let chunk = self.module.chunk(closure.function.chunk_index);
+        let top = base + chunk.registers();
+        if self.frames.len() >= self.max_frames || top > self.max_registers {
+            return Err(VmError::StackOverflow(self.stack_trace()));
+        }
+
+        if self.registers.len() < top {
+            self.registers.resize(top, Value::nil());
+        }
+
+        self.frames.push(CallFrame {
+            program_counter: 0,
+            instruction_index: 0,
+            base,
+            chunk,
+            closure: gc::root(closure),
+        });
+
+        Ok(())
+    }
+
+    fn current_frame(&self) -> Result<&CallFrame<'a>, VmError> {
+        self.frames.last().ok_or(VmError::FrameEmpty)
+    }
+
+    fn current_frame_mut(&mut self) -> Result<&mut CallFrame<'a>, VmError> {
+        self.frames.last_mut().ok_or(VmError::FrameEmpty)
+    }
+
+    fn global_slot(&mut self, index: usize) -> Result<&mut Option<Value>, VmError> {
+        self.globals.get_mut(index).ok_or(VmError::InvalidGlobal)
+    }
+
+    fn string_constant(&self, index: usize) -> Result<Gc<String>, VmError> {
+        match self.strings.get(index) {
+            Some(Some(string)) => Ok(string.as_gc()),
+            _ => Err(VmError::StringConstantExpected),
+        }
+    }
+
+    fn stack_trace(&self) -> StackTrace {
+        let names: Vec<String> = self.frames.iter().enumerate().rev().map(|(depth, frame)| {
+            if depth == 0 {
+                "script".to_string()
+            } else {
+                format!("{}()", frame.closure.function.name)
+            }
+        }).collect();
+
+        StackTrace::new(names)
+    }
+}
@@ -0,0 +1,107 @@
+//! Runs every Lox program in `tests/programs` while collecting the entire heap on every allocation,
+//! on both the stack and the register VM.
+//! Objects that are collected while still in use are poisoned, so rooting bugs in the VM cause a panic.
+//! What a program prints has to match its `// expect: ` comments, so a value that is corrupted fails too.
+
+use lox_vm::bettergc::gc;
+use std::cell::RefCell;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+fn programs() -> Vec<PathBuf> {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let mut programs: Vec<PathBuf> = fs::read_dir(root.join("tests/programs"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "lox"))
+        .collect();
+    programs.push(root.join("../test.lox"));
+    programs.sort();
+    programs
+}
+
+/// The lines the program should print, from its `// expect: ` comments in order.
+fn expected(source: &str) -> Vec<String> {
+    source.lines()
+        .filter_map(|line| line.split_once("// expect: ").map(|(_, expected)| expected.to_string()))
+        .collect()
+}
+
+type Printed = Rc<RefCell<Vec<String>>>;
+
+fn printer() -> (Printed, impl FnMut(&str) + 'static) {
+    let printed = Printed::default();
+    let lines = printed.clone();
+    (printed, move |line: &str| lines.borrow_mut().push(line.to_string()))
+}
+
+fn run(path: &Path, stress: bool) {
+    use lox_vm::bettervm::{define_natives, Vm};
+
+    let source = fs::read_to_string(path).unwrap();
+    let module = lox_compiler::compile(&source).unwrap();
+    let (printed, print) = printer();
+
+    gc::set_stress(stress);
+    let mut vm = Vm::new(&module);
+    define_natives(&mut vm);
+    vm.set_print(print);
+    let result = vm.interpret();
+    drop(vm);
+    gc::set_stress(false);
+
+    if let Err(error) = result {
+        panic!("{} failed: {}", path.display(), error);
+    }
+    assert_eq!(*printed.borrow(), expected(&source), "{} printed something else", path.display());
+}
+
+fn run_registers(path: &Path, stress: bool) {
+    use lox_vm::registervm::{define_natives, Vm};
+
+    let source = fs::read_to_string(path).unwrap();
+    let module = lox_compiler::compile_registers(&source).unwrap();
+    let (printed, print) = printer();
+
+    gc::set_stress(stress);
+    let mut vm = Vm::new(&module);
+    define_natives(&mut vm);
+    vm.set_print(print);
+    let result = vm.interpret();
+    drop(vm);
+    gc::set_stress(false);
+
+    if let Err(error) = result {
+        panic!("{} failed: {}", path.display(), error);
+    }
+    assert_eq!(*printed.borrow(), expected(&source), "{} printed something else", path.display());
+}
+
+#[test]
+fn test_programs_under_gc_stress() {
+    for program in programs() {
+        run(&program, true);
+    }
+}
+
+#[test]
+fn test_programs_on_registers_under_gc_stress() {
+    for program in programs() {
+        run_registers(&program, true);
+    }
+}
+
+#[test]
+fn test_programs_without_gc_stress() {
+    for program in programs() {
+        run(&program, false);
+    }
+}
+
+#[test]
+fn test_programs_on_registers_without_gc_stress() {
+    for program in programs() {
+        run_registers(&program, false);
+    }
+}
@@ -0,0 +1,556 @@
+//! A Debug Adapter Protocol server over stdin and stdout, for debugging from editors.
+//! Only one program with a single thread is debugged, so the thread is always the same.
+
+use lox_bytecode::bytecode::Module;
+use lox_vm::bettervm::{self, Step, Stopped, Vm, VmError};
+use crate::framing::{read_message, write_message};
+use serde_json::{json, Value};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::rc::Rc;
+
+const THREAD_ID: i64 = 1;
+
+/// Every frame has a scope for each of these, `variablesReference` is `frame * SCOPES.len() + scope + 1`.
+const SCOPES: &[&str] = &["Locals", "Upvalues", "Globals"];
+
+pub fn serve() -> io::Result<()> {
+    let stdin = io::stdin();
+    serve_connection(Connection::new(stdin.lock(), io::stdout()))
+}
+
+/// Debug the program the client launches, until the client disconnects.
+fn serve_connection<R: BufRead, W: Write>(mut connection: Connection<R, W>) -> io::Result<()> {
+    // Breakpoints can be set before the program is launched, they are kept by file with their id
+    let mut breakpoints: HashMap<String, Vec<(i64, usize)>> = HashMap::new();
+    let mut next_breakpoint = 1;
+    // The client can be done configuring before it launches the program
+    let mut configured = false;
+    let (path, module, stop_on_entry) = loop {
+        let request = match connection.read()? {
+            Some(request) => request,
+            None => return Ok(()),
+        };
+
+        match command(&request) {
+            "initialize" => {
+                connection.respond(&request, json!({
+                    "supportsConfigurationDoneRequest": true,
+                    "supportsEvaluateForHovers": true,
+                }))?;
+                connection.event("initialized", json!({}))?;
+            },
+            "launch" => {
+                let arguments = &request["arguments"];
+                let path = arguments["program"].as_str().unwrap_or_default().to_string();
+                let module = std::fs::read_to_string(&path)
+                    .map_err(|error| error.to_string())
+                    .and_then(|data| lox_compiler::compile_file(&data, &path).map_err(|error| error.to_string()));
+                match module {
+                    Ok(module) => {
+                        connection.respond(&request, json!({}))?;
+                        break (path, module, arguments["stopOnEntry"].as_bool().unwrap_or(false));
+                    },
+                    Err(error) => connection.fail(&request, &error)?,
+                }
+            },
+            "setBreakpoints" => {
+                // They are verified with a `breakpoint` event once the program is compiled
+                let lines: Vec<_> = (next_breakpoint..).zip(requested_lines(&request)).collect();
+                next_breakpoint += lines.len() as i64;
+                let unverified: Vec<_> = lines.iter().map(|&(id, line)| json!({ "id": id, "verified": false, "line": line })).collect();
+                let path = request["arguments"]["source"]["path"].as_str().unwrap_or_default();
+                breakpoints.insert(path.to_string(), lines);
+                connection.respond(&request, json!({ "breakpoints": unverified }))?;
+            },
+            "configurationDone" => {
+                configured = true;
+                connection.respond(&request, json!({}))?;
+            },
+            "disconnect" => return connection.respond(&request, json!({})),
+            _ => connection.respond(&request, json!({}))?,
+        }
+    };
+
+    let mut session = Session::new(connection, &path, &module, next_breakpoint);
+    session.verify_breakpoints(&breakpoints)?;
+    if configured {
+        session.launch(stop_on_entry)?;
+    }
+    session.serve(stop_on_entry)
+}
+
+fn command(request: &Value) -> &str {
+    request["command"].as_str().unwrap_or_default()
+}
+
+fn requested_lines(request: &Value) -> Vec<usize> {
+    request["arguments"]["breakpoints"].as_array()
+        .map(|breakpoints| breakpoints.iter().filter_map(|breakpoint| breakpoint["line"].as_u64()).map(|line| line as usize).collect())
+        .unwrap_or_default()
+}
+
+/// Reads requests and writes responses and events, numbering everything it sends.
+struct Connection<R, W> {
+    reader: R,
+    writer: W,
+    seq: i64,
+}
+
+impl<R: BufRead, W: Write> Connection<R, W> {
+    fn new(reader: R, writer: W) -> Self {
+        Connection { reader, writer, seq: 1 }
+    }
+
+    /// The next message, None when the client has gone away. A message that doesn't parse is answered with an
+    /// error, and the one after it is read.
+    fn read(&mut self) -> io::Result<Option<Value>> {
+        loop {
+            match read_message(&mut self.reader) {
+                Err(error) if error.kind() == io::ErrorKind::InvalidData => self.send(json!({
+                    "type": "response",
+                    "request_seq": 0,
+                    "command": "",
+                    "success": false,
+                    "message": format!("invalid message: {}", error),
+                }))?,
+                message => return message,
+            }
+        }
+    }
+
+    fn send(&mut self, mut message: Value) -> io::Result<()> {
+        message["seq"] = json!(self.seq);
+        self.seq += 1;
+        write_message(&mut self.writer, &message)
+    }
+
+    fn respond(&mut self, request: &Value, body: Value) -> io::Result<()> {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": true,
+            "body": body,
+        }))
+    }
+
+    fn fail(&mut self, request: &Value, message: &str) -> io::Result<()> {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": false,
+            "message": message,
+        }))
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> io::Result<()> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+}
+
+struct Session<'a, R, W> {
+    connection: Connection<R, W>,
+    path: &'a str,
+    vm: Vm<'a>,
+    /// What the program printed since the last time it stopped.
+    output: Rc<RefCell<Vec<String>>>,
+    finished: bool,
+    /// The id of the next breakpoint that is set.
+    next_breakpoint: i64,
+}
+
+impl<'a, R: BufRead, W: Write> Session<'a, R, W> {
+    fn new(connection: Connection<R, W>, path: &'a str, module: &'a Module, next_breakpoint: i64) -> Self {
+        let output = Rc::new(RefCell::new(vec![]));
+        let mut vm = Vm::new(module);
+        bettervm::define_natives(&mut vm);
+        let printed = output.clone();
+        vm.set_print(move |line| printed.borrow_mut().push(line.to_string()));
+        vm.start();
+
+        Session { connection, path, vm, output, finished: false, next_breakpoint }
+    }
+
+    /// Set the breakpoints that were set before the program was launched, and tell the client which of them are
+    /// verified and on which line.
+    fn verify_breakpoints(&mut self, breakpoints: &HashMap<String, Vec<(i64, usize)>>) -> io::Result<()> {
+        for (file, lines) in breakpoints {
+            for &(id, line) in lines {
+                let breakpoint = match self.vm.set_breakpoint(file, line) {
+                    Some(line) => json!({ "id": id, "verified": true, "line": line }),
+                    None => json!({ "id": id, "verified": false, "line": line }),
+                };
+                self.connection.event("breakpoint", json!({ "reason": "changed", "breakpoint": breakpoint }))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn serve(mut self, stop_on_entry: bool) -> io::Result<()> {
+        while let Some(request) = self.connection.read()? {
+            match command(&request) {
+                "configurationDone" => {
+                    self.connection.respond(&request, json!({}))?;
+                    self.launch(stop_on_entry)?;
+                },
+                "setBreakpoints" => self.set_breakpoints(&request)?,
+                "threads" => self.connection.respond(&request, json!({
+                    "threads": [{ "id": THREAD_ID, "name": "main" }],
+                }))?,
+                "stackTrace" => self.stack_trace(&request)?,
+                "scopes" => self.scopes(&request)?,
+                "variables" => self.variables(&request)?,
+                "evaluate" => self.evaluate(&request)?,
+                "continue" => {
+                    self.connection.respond(&request, json!({ "allThreadsContinued": true }))?;
+                    self.run(None)?;
+                },
+                "next" | "stepIn" | "stepOut" => {
+                    self.connection.respond(&request, json!({}))?;
+                    let step = match command(&request) {
+                        "next" => Step::Over,
+                        "stepIn" => Step::Into,
+                        _ => Step::Out,
+                    };
+                    self.run(Some(step))?;
+                },
+                "disconnect" => return self.connection.respond(&request, json!({})),
+                command => self.connection.fail(&request, &format!("{} is not supported", command))?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Start running the program, stopped before its first statement with `stop_on_entry`.
+    fn launch(&mut self, stop_on_entry: bool) -> io::Result<()> {
+        if !stop_on_entry {
+            return self.run(None);
+        }
+        let result = self.vm.step(Step::Into);
+        self.report(result, "entry")
+    }
+
+    /// Step, or run until a breakpoint without `step`, and tell the client where the program stopped.
+    fn run(&mut self, step: Option<Step>) -> io::Result<()> {
+        if self.finished {
+            return self.connection.event("terminated", json!({}));
+        }
+
+        let result = match step {
+            Some(step) => self.vm.step(step),
+            None => self.vm.resume(),
+        };
+        self.report(result, "step")
+    }
+
+    /// Send what the program printed and why it stopped, with `step_reason` when a step finished.
+    fn report(&mut self, result: Result<Stopped, VmError>, step_reason: &str) -> io::Result<()> {
+        let output: Vec<String> = self.output.borrow_mut().drain(..).collect();
+        for line in output {
+            self.connection.event("output", json!({ "category": "stdout", "output": format!("{}\n", line) }))?;
+        }
+
+        match result {
+            Ok(Stopped::Breakpoint) => self.stopped("breakpoint"),
+            Ok(Stopped::Step) => self.stopped(step_reason),
+            Ok(Stopped::Finished) => self.terminate(0),
+            Err(error) => {
+                self.connection.event("output", json!({ "category": "stderr", "output": format!("{}\n", error) }))?;
+                self.terminate(70)
+            },
+        }
+    }
+
+    fn stopped(&mut self, reason: &str) -> io::Result<()> {
+        self.connection.event("stopped", json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true }))
+    }
+
+    fn terminate(&mut self, exit_code: i32) -> io::Result<()> {
+        self.finished = true;
+        self.connection.event("exited", json!({ "exitCode": exit_code }))?;
+        self.connection.event("terminated", json!({}))
+    }
+
+    fn set_breakpoints(&mut self, request: &Value) -> io::Result<()> {
+        let path = request["arguments"]["source"]["path"].as_str().unwrap_or_default();
+        // The breakpoints of a file replace the ones it had, breakpoints in other files stay
+        self.vm.clear_breakpoints(path);
+
+        let mut verified = vec![];
+        for line in requested_lines(request) {
+            let id = self.next_breakpoint;
+            self.next_breakpoint += 1;
+            verified.push(match self.vm.set_breakpoint(path, line) {
+                Some(line) => json!({ "id": id, "verified": true, "line": line }),
+                None => json!({ "id": id, "verified": false, "line": line }),
+            });
+        }
+        self.connection.respond(request, json!({ "breakpoints": verified }))
+    }
+
+    fn stack_trace(&mut self, request: &Value) -> io::Result<()> {
+        let frames: Vec<_> = self.vm.call_stack().into_iter().enumerate()
+            .map(|(depth, frame)| json!({
+                "id": depth,
+                "name": frame.name,
+                "source": { "path": frame.file.as_deref().unwrap_or(self.path) },
+                "line": frame.line.unwrap_or(0),
+                "column": 1,
+            }))
+            .collect();
+        let total = frames.len();
+        self.connection.respond(request, json!({ "stackFrames": frames, "totalFrames": total }))
+    }
+
+    fn scopes(&mut self, request: &Value) -> io::Result<()> {
+        let frame = request["arguments"]["frameId"].as_u64().unwrap_or(0) as usize;
+        let scopes: Vec<_> = SCOPES.iter().enumerate()
+            .map(|(scope, name)| json!({
+                "name": name,
+                "variablesReference": frame * SCOPES.len() + scope + 1,
+                "expensive": false,
+            }))
+            .collect();
+        self.connection.respond(request, json!({ "scopes": scopes }))
+    }
+
+    fn variables(&mut self, request: &Value) -> io::Result<()> {
+        let reference = request["arguments"]["variablesReference"].as_u64().unwrap_or(0) as usize;
+        let (frame, scope) = (reference.saturating_sub(1) / SCOPES.len(), reference.saturating_sub(1) % SCOPES.len());
+        let values = match scope {
+            0 => self.vm.locals(frame),
+            1 => self.vm.upvalues(frame),
+            _ => self.vm.globals(),
+        };
+
+        let variables: Vec<_> = values.into_iter()
+            .map(|(name, value)| json!({ "name": name, "value": value.to_string(), "variablesReference": 0 }))
+            .collect();
+        self.connection.respond(request, json!({ "variables": variables }))
+    }
+
+    fn evaluate(&mut self, request: &Value) -> io::Result<()> {
+        let arguments = &request["arguments"];
+        let expression = arguments["expression"].as_str().unwrap_or_default();
+        let frame = arguments["frameId"].as_u64().unwrap_or(0) as usize;
+
+        let result = lox_compiler::compile_expression(expression)
+            .map_err(|error| error.to_string())
+            .and_then(|module| self.vm.evaluate(&module, frame).map_err(|error: VmError| error.to_string()));
+
+        match result {
+            Ok(value) => self.connection.respond(request, json!({ "result": value.to_string(), "variablesReference": 0 })),
+            Err(error) => self.connection.fail(request, &error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROGRAM: &str = "\
+fun add(a, b) {
+  var sum = a + b;
+  return sum;
+}
+var total = add(1, 2);
+print total;
+";
+
+    /// Writes the program to a file of its own, the adapter launches programs from files.
+    fn program(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("lox-dap-{}-{}.lox", std::process::id(), name));
+        std::fs::write(&path, PROGRAM).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    /// Runs the adapter on the requests, numbered in order, and returns everything it sent back.
+    fn exchange(requests: Vec<(&str, Value)>) -> Vec<Value> {
+        let mut input = vec![];
+        for (seq, (command, arguments)) in requests.into_iter().enumerate() {
+            let request = json!({ "seq": seq + 1, "type": "request", "command": command, "arguments": arguments });
+            write_message(&mut input, &request).unwrap();
+        }
+
+        let mut output = vec![];
+        serve_connection(Connection::new(io::Cursor::new(input), &mut output)).unwrap();
+
+        let mut reader = io::Cursor::new(output);
+        let mut sent = vec![];
+        while let Some(message) = read_message(&mut reader).unwrap() {
+            sent.push(message);
+        }
+        sent
+    }
+
+    fn response<'a>(sent: &'a [Value], command: &str) -> &'a Value {
+        sent.iter().find(|message| message["type"] == "response" && message["command"] == command).expect("no response")
+    }
+
+    /// The events in the order they were sent, with their bodies.
+    fn events(sent: &[Value]) -> Vec<(&str, &Value)> {
+        sent.iter()
+            .filter(|message| message["type"] == "event")
+            .map(|message| (message["event"].as_str().unwrap(), &message["body"]))
+            .collect()
+    }
+
+    fn lines(response: &Value) -> Vec<(&str, u64)> {
+        response["body"]["stackFrames"].as_array().unwrap().iter()
+            .map(|frame| (frame["name"].as_str().unwrap(), frame["line"].as_u64().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_breakpoint_session() {
+        let path = program("breakpoint");
+        let sent = exchange(vec![
+            ("initialize", json!({ "adapterID": "lox" })),
+            ("setBreakpoints", json!({ "source": { "path": path }, "breakpoints": [{ "line": 3 }] })),
+            ("launch", json!({ "program": path })),
+            ("configurationDone", json!({})),
+            ("threads", json!({})),
+            ("stackTrace", json!({ "threadId": THREAD_ID })),
+            ("scopes", json!({ "frameId": 0 })),
+            ("variables", json!({ "variablesReference": 1 })),
+            ("evaluate", json!({ "expression": "sum * 10", "frameId": 0 })),
+            ("evaluate", json!({ "expression": "sum *", "frameId": 0 })),
+            // Hovering over a string that is still being typed
+            ("evaluate", json!({ "expression": "\"oops", "frameId": 0 })),
+            ("continue", json!({ "threadId": THREAD_ID })),
+            ("disconnect", json!({})),
+        ]);
+
+        assert_eq!(response(&sent, "initialize")["body"]["supportsConfigurationDoneRequest"], true);
+        // Breakpoints are only verified once the program is compiled
+        assert_eq!(response(&sent, "setBreakpoints")["body"]["breakpoints"], json!([{ "id": 1, "verified": false, "line": 3 }]));
+        assert_eq!(response(&sent, "launch")["success"], true);
+        assert_eq!(response(&sent, "threads")["body"]["threads"][0]["id"], THREAD_ID);
+
+        let stack_trace = response(&sent, "stackTrace");
+        assert_eq!(lines(stack_trace), vec![("add", 3), ("script", 5)]);
+        assert_eq!(stack_trace["body"]["stackFrames"][0]["source"]["path"], path.as_str());
+        assert_eq!(response(&sent, "scopes")["body"]["scopes"].as_array().unwrap().len(), SCOPES.len());
+        let variables: Vec<_> = response(&sent, "variables")["body"]["variables"].as_array().unwrap().iter()
+            .map(|variable| format!("{} = {}", variable["name"].as_str().unwrap(), variable["value"].as_str().unwrap()))
+            .collect();
+        assert_eq!(variables, vec!["a = 1", "b = 2", "sum = 3"]);
+
+        let evaluated: Vec<_> = sent.iter().filter(|message| message["command"] == "evaluate").collect();
+        assert_eq!(evaluated[0]["body"]["result"], "30");
+        assert_eq!(evaluated[1]["success"], false);
+        assert_eq!(evaluated[2]["success"], false);
+        assert_eq!(evaluated[2]["message"], "[line 1:1] Unterminated string.");
+
+        // The client is told it can configure right after it is initialized
+        let initialized = sent.iter().position(|message| message["event"] == "initialized").unwrap();
+        assert_eq!(sent[initialized - 1]["command"], "initialize");
+        assert_eq!(events(&sent), vec![
+            ("initialized", &json!({})),
+            ("breakpoint", &json!({ "reason": "changed", "breakpoint": { "id": 1, "verified": true, "line": 3 } })),
+            ("stopped", &json!({ "reason": "breakpoint", "threadId": THREAD_ID, "allThreadsStopped": true })),
+            ("output", &json!({ "category": "stdout", "output": "3\n" })),
+            ("exited", &json!({ "exitCode": 0 })),
+            ("terminated", &json!({})),
+        ]);
+        assert_eq!(sent.last().unwrap()["command"], "disconnect");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_stepping_from_entry() {
+        let path = program("stepping");
+        let sent = exchange(vec![
+            ("launch", json!({ "program": path, "stopOnEntry": true })),
+            ("configurationDone", json!({})),
+            ("next", json!({ "threadId": THREAD_ID })),
+            ("stepIn", json!({ "threadId": THREAD_ID })),
+            ("stackTrace", json!({ "threadId": THREAD_ID })),
+            ("stepOut", json!({ "threadId": THREAD_ID })),
+            ("stackTrace", json!({ "threadId": THREAD_ID })),
+            ("disconnect", json!({})),
+        ]);
+
+        let stack_traces: Vec<_> = sent.iter().filter(|message| message["command"] == "stackTrace").collect();
+        assert_eq!(lines(stack_traces[0]), vec![("add", 2), ("script", 5)]);
+        assert_eq!(lines(stack_traces[1]), vec![("script", 6)]);
+        let stopped: Vec<_> = events(&sent).into_iter()
+            .filter(|(event, _)| *event == "stopped")
+            .map(|(_, body)| body["reason"].as_str().unwrap())
+            .collect();
+        assert_eq!(stopped, vec!["entry", "step", "step", "step"]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_configured_before_launch() {
+        let path = program("configured");
+        let sent = exchange(vec![
+            ("initialize", json!({ "adapterID": "lox" })),
+            ("setBreakpoints", json!({ "source": { "path": path }, "breakpoints": [{ "line": 2 }, { "line": 40 }] })),
+            ("configurationDone", json!({})),
+            ("launch", json!({ "program": path })),
+            ("setBreakpoints", json!({ "source": { "path": path }, "breakpoints": [{ "line": 6 }] })),
+            ("continue", json!({ "threadId": THREAD_ID })),
+            ("continue", json!({ "threadId": THREAD_ID })),
+            ("disconnect", json!({})),
+        ]);
+
+        let events: Vec<_> = events(&sent).into_iter().map(|(event, body)| match event {
+            "breakpoint" => format!("breakpoint {} {} {}", body["breakpoint"]["id"], body["breakpoint"]["verified"], body["breakpoint"]["line"]),
+            "stopped" => format!("stopped {}", body["reason"].as_str().unwrap()),
+            event => event.to_string(),
+        }).collect();
+        assert_eq!(events, vec![
+            "initialized",
+            "breakpoint 1 true 2",
+            "breakpoint 2 false 40",
+            "stopped breakpoint",
+            "stopped breakpoint",
+            "output",
+            "exited",
+            "terminated",
+        ]);
+        let set: Vec<_> = sent.iter().filter(|message| message["command"] == "setBreakpoints").collect();
+        assert_eq!(set[1]["body"]["breakpoints"], json!([{ "id": 3, "verified": true, "line": 6 }]));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_messages() {
+        let mut input = b"Content-Length: 7\r\n\r\n{oops}\n".to_vec();
+        write_message(&mut input, &json!({ "seq": 1, "type": "request", "command": "initialize", "arguments": {} })).unwrap();
+        input.extend_from_slice(b"Content-Type: text\r\n\r\n");
+        write_message(&mut input, &json!({ "seq": 2, "type": "request", "command": "disconnect", "arguments": {} })).unwrap();
+
+        let mut output = vec![];
+        serve_connection(Connection::new(io::Cursor::new(input), &mut output)).unwrap();
+        let mut reader = io::Cursor::new(output);
+        let mut sent = vec![];
+        while let Some(message) = read_message(&mut reader).unwrap() {
+            sent.push(message);
+        }
+
+        let responses: Vec<_> = sent.iter()
+            .filter(|message| message["type"] == "response")
+            .map(|message| (message["command"].as_str().unwrap(), message["success"].as_bool().unwrap()))
+            .collect();
+        assert_eq!(responses, vec![("", false), ("initialize", true), ("", false), ("disconnect", true)]);
+        assert!(sent[0]["message"].as_str().unwrap().starts_with("invalid message"));
+    }
+
+    #[test]
+    fn test_launch_errors() {
+        let sent = exchange(vec![
+            ("launch", json!({ "program": "no/such/program.lox" })),
+            ("disconnect", json!({})),
+        ]);
+        assert_eq!(response(&sent, "launch")["success"], false);
+        assert_eq!(response(&sent, "disconnect")["success"], true);
+    }
+}
@@ -0,0 +1,187 @@
+use lox_vm::bettervm::{self, Step, Stopped, Vm, VmError};
+use std::io::{self, BufRead, Write};
+
+const HELP: &str = "\
+commands:
+  break [file:]line    stop when a statement on that line is about to run (b)
+  delete [file:]line   remove the breakpoint on that line (d)
+  breakpoints          list the breakpoints
+  continue             run until the next breakpoint (c)
+  step                 run to the next statement, into functions (s)
+  next                 run to the next statement in this function (n)
+  finish               run until this function returns (f)
+  backtrace            show the functions that are running (bt)
+  frame n              show locals and upvalues of the frame n levels up
+  locals               show the locals of the selected frame
+  upvalues             show the upvalues of the selected frame
+  globals              show every defined global
+  print name           show a local, upvalue or global (p)
+  quit                 stop debugging (q)";
+
+/// Runs the script at `path` under the debugger, stopped before its first statement.
+pub fn debug(path: &str, data: &str) -> Result<(), VmError> {
+    let module = match lox_compiler::compile_file(data, path) {
+        Ok(module) => module,
+        Err(error) => {
+            eprintln!("{}", error);
+            std::process::exit(65);
+        },
+    };
+    let source: Vec<&str> = data.lines().collect();
+
+    let mut vm = Vm::new(&module);
+    bettervm::define_natives(&mut vm);
+    vm.start();
+
+    let mut session = Session { vm, path, source, frame: 0 };
+    let stopped = session.vm.step(Step::Into)?;
+    session.stopped(stopped);
+    if stopped == Stopped::Finished {
+        return Ok(());
+    }
+
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("(lox) ");
+        io::stdout().flush().unwrap();
+        let line = match lines.next() {
+            Some(line) => line.unwrap(),
+            None => return Ok(()),
+        };
+
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or("");
+        let argument = words.next();
+
+        let stopped = match command {
+            "c" | "continue" => session.vm.resume()?,
+            "s" | "step" => session.vm.step(Step::Into)?,
+            "n" | "next" => session.vm.step(Step::Over)?,
+            "f" | "finish" => session.vm.step(Step::Out)?,
+            "q" | "quit" => return Ok(()),
+            "" => continue,
+            _ => {
+                session.command(command, argument);
+                continue;
+            },
+        };
+
+        session.stopped(stopped);
+        if stopped == Stopped::Finished {
+            return Ok(());
+        }
+    }
+}
+
+struct Session<'a> {
+    vm: Vm<'a>,
+    path: &'a str,
+    source: Vec<&'a str>,
+    /// The frame that is being looked at, 0 is the innermost one.
+    frame: usize,
+}
+
+impl<'a> Session<'a> {
+    fn stopped(&mut self, stopped: Stopped) {
+        self.frame = 0;
+        match stopped {
+            Stopped::Finished => println!("finished"),
+            Stopped::Breakpoint | Stopped::Step => {
+                if stopped == Stopped::Breakpoint {
+                    println!("breakpoint");
+                }
+                self.show_frame();
+            },
+        }
+    }
+
+    fn command(&mut self, command: &str, argument: Option<&str>) {
+        match (command, argument) {
+            ("b", Some(location)) | ("break", Some(location)) => self.set_breakpoint(location),
+            ("d", Some(location)) | ("delete", Some(location)) => {
+                let (file, line) = self.location_of(location);
+                match line.parse() {
+                    Ok(line) if self.vm.clear_breakpoint(file, line) => println!("deleted breakpoint at {}:{}", file, line),
+                    _ => println!("no breakpoint at {}", location),
+                }
+            },
+            ("breakpoints", None) => for (file, line) in self.vm.breakpoints() {
+                println!("{}:{}", file, line);
+            },
+            ("bt", None) | ("backtrace", None) => for (depth, frame) in self.vm.call_stack().iter().enumerate() {
+                let marker = if depth == self.frame { ">" } else { " " };
+                println!("{} #{} {} at {}", marker, depth, frame.name, self.location(frame.file.as_deref(), frame.line));
+            },
+            ("frame", Some(depth)) => match depth.parse() {
+                Ok(depth) if depth < self.vm.call_stack().len() => {
+                    self.frame = depth;
+                    self.show_frame();
+                },
+                _ => println!("no frame {}", depth),
+            },
+            ("locals", None) => show_values(self.vm.locals(self.frame)),
+            ("upvalues", None) => show_values(self.vm.upvalues(self.frame)),
+            ("globals", None) => show_values(self.vm.globals()),
+            ("p", Some(name)) | ("print", Some(name)) => {
+                let value = self.vm.local(self.frame, name)
+                    .or_else(|| self.vm.upvalues(self.frame).into_iter().find(|(upvalue, _)| upvalue == name).map(|(_, value)| value))
+                    .or_else(|| self.vm.global(name));
+                match value {
+                    Some(value) => println!("{} = {}", name, value),
+                    None => println!("{} is not defined here", name),
+                }
+            },
+            ("h", None) | ("help", None) => println!("{}", HELP),
+            _ => println!("unknown command, try help"),
+        }
+    }
+
+    /// The file and line of a `[file:]line` location, the file is the script without one.
+    fn location_of<'b>(&self, location: &'b str) -> (&'b str, &'b str) where 'a: 'b {
+        match location.rsplit_once(':') {
+            Some((file, line)) => (file, line),
+            None => (self.path, location),
+        }
+    }
+
+    fn set_breakpoint(&mut self, location: &str) {
+        let (file, line) = self.location_of(location);
+        match line.parse().ok().and_then(|line| self.vm.set_breakpoint(file, line)) {
+            Some(line) => println!("breakpoint at {}:{}", file, line),
+            None => println!("no code at {}", location),
+        }
+    }
+
+    fn show_frame(&self) {
+        let frame = self.vm.call_stack().into_iter().nth(self.frame);
+        let file = frame.as_ref().and_then(|frame| frame.file.clone());
+        let line = frame.and_then(|frame| frame.line);
+        match line.and_then(|line| self.source_line(file.as_deref(), line)) {
+            Some(source) => println!("{}: {}", self.location(file.as_deref(), line), source.trim()),
+            None => println!("{}", self.location(file.as_deref(), line)),
+        }
+    }
+
+    /// The line of the file, imported modules are read when they are shown.
+    fn source_line(&self, file: Option<&str>, line: usize) -> Option<String> {
+        match file {
+            Some(file) if file != self.path => std::fs::read_to_string(file).ok()?.lines().nth(line - 1).map(String::from),
+            _ => self.source.get(line - 1).map(|source| source.to_string()),
+        }
+    }
+
+    fn location(&self, file: Option<&str>, line: Option<usize>) -> String {
+        let file = file.unwrap_or(self.path);
+        match line {
+            Some(line) => format!("{}:{}", file, line),
+            None => format!("{}:?", file),
+        }
+    }
+}
+
+fn show_values(values: Vec<(String, bettervm::Value)>) {
+    for (name, value) in values {
+        println!("{} = {}", name, value);
+    }
+}
@@ -0,0 +1,485 @@
+//! A Language Server Protocol server over stdin and stdout. Documents are synced in full, every change analyzes
+//! the whole document again.
+
+use crate::framing::{read_message, write_message};
+use lox_compiler::analysis::{analyze, Analysis, Symbol, SymbolKind, TokenKind};
+use lox_compiler::{Position, Span};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+/// The semantic token types, in the order of `TokenKind`.
+const TOKEN_TYPES: &[&str] = &[
+    "keyword", "variable", "parameter", "function", "class", "method", "property", "string", "number", "operator",
+];
+
+const METHOD_NOT_FOUND: i64 = -32601;
+
+pub fn serve() -> io::Result<()> {
+    let stdin = io::stdin();
+    Server::new(stdin.lock(), io::stdout()).serve()
+}
+
+struct Document {
+    text: String,
+    analysis: Analysis,
+}
+
+impl Document {
+    fn lines(&self) -> Vec<&str> {
+        self.text.lines().collect()
+    }
+}
+
+struct Server<R, W> {
+    reader: R,
+    writer: W,
+    /// Open documents by URI.
+    documents: HashMap<String, Document>,
+}
+
+impl<R: BufRead, W: Write> Server<R, W> {
+    fn new(reader: R, writer: W) -> Self {
+        Server { reader, writer, documents: HashMap::new() }
+    }
+
+    fn serve(mut self) -> io::Result<()> {
+        while let Some(message) = read_message(&mut self.reader)? {
+            let method = message["method"].as_str().unwrap_or_default();
+            let params = &message["params"];
+
+            let result = match method {
+                "initialize" => Some(json!({
+                    "capabilities": {
+                        "textDocumentSync": 1,
+                        "definitionProvider": true,
+                        "referencesProvider": true,
+                        "hoverProvider": true,
+                        "documentSymbolProvider": true,
+                        "semanticTokensProvider": {
+                            "legend": { "tokenTypes": TOKEN_TYPES, "tokenModifiers": [] },
+                            "full": true,
+                        },
+                    },
+                    "serverInfo": { "name": "lox" },
+                })),
+                "textDocument/didOpen" => {
+                    let document = &params["textDocument"];
+                    self.update(document["uri"].as_str().unwrap_or_default(), document["text"].as_str().unwrap_or_default())?;
+                    None
+                },
+                "textDocument/didChange" => {
+                    // Every change has the whole document
+                    let text = params["contentChanges"].as_array().and_then(|changes| changes.last()).map(|change| &change["text"]);
+                    self.update(uri(params), text.and_then(Value::as_str).unwrap_or_default())?;
+                    None
+                },
+                "textDocument/didClose" => {
+                    self.documents.remove(uri(params));
+                    self.notify("textDocument/publishDiagnostics", json!({ "uri": uri(params), "diagnostics": [] }))?;
+                    None
+                },
+                "textDocument/definition" => Some(self.definition(params)),
+                "textDocument/references" => Some(self.references(params)),
+                "textDocument/hover" => Some(self.hover(params)),
+                "textDocument/documentSymbol" => Some(self.document_symbols(params)),
+                "textDocument/semanticTokens/full" => Some(self.semantic_tokens(params)),
+                "shutdown" => Some(Value::Null),
+                "exit" => return Ok(()),
+                _ => None,
+            };
+
+            // Notifications don't have an id and don't get a response
+            let id = &message["id"];
+            if id.is_null() {
+                continue;
+            }
+            match result {
+                Some(result) => write_message(&mut self.writer, &json!({ "jsonrpc": "2.0", "id": id, "result": result }))?,
+                None => write_message(&mut self.writer, &json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "error": { "code": METHOD_NOT_FOUND, "message": format!("{} is not supported", method) },
+                }))?,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn notify(&mut self, method: &str, params: Value) -> io::Result<()> {
+        write_message(&mut self.writer, &json!({ "jsonrpc": "2.0", "method": method, "params": params }))
+    }
+
+    fn update(&mut self, uri: &str, text: &str) -> io::Result<()> {
+        let analysis = analyze(text);
+        let lines: Vec<&str> = text.lines().collect();
+        let diagnostics: Vec<_> = analysis.diagnostics.iter()
+            .map(|diagnostic| json!({
+                "range": range(&lines, diagnostic.span),
+                "severity": 1,
+                "source": "lox",
+                "message": diagnostic.message,
+            }))
+            .collect();
+
+        self.documents.insert(uri.to_string(), Document { text: text.to_string(), analysis });
+        self.notify("textDocument/publishDiagnostics", json!({ "uri": uri, "diagnostics": diagnostics }))
+    }
+
+    /// The document and the symbol at the position in a request.
+    fn symbol_at(&self, params: &Value) -> Option<(&Document, &Symbol)> {
+        let document = self.documents.get(uri(params))?;
+        let analysis = &document.analysis;
+        let line = params["position"]["line"].as_u64()? as usize + 1;
+        let character = params["position"]["character"].as_u64()? as usize;
+        let column = character_column(document.lines().get(line - 1).copied().unwrap_or_default(), character);
+
+        // Right after a name counts as on it too
+        let symbol = analysis.symbol_at(Position { line, column: column + 1 })
+            .or_else(|| analysis.symbol_at(Position { line, column }))?;
+        Some((document, &analysis.symbols[symbol]))
+    }
+
+    fn definition(&self, params: &Value) -> Value {
+        match self.symbol_at(params) {
+            Some((document, symbol)) => json!({ "uri": uri(params), "range": range(&document.lines(), symbol.declaration) }),
+            None => Value::Null,
+        }
+    }
+
+    fn references(&self, params: &Value) -> Value {
+        let (document, symbol) = match self.symbol_at(params) {
+            Some(found) => found,
+            None => return json!([]),
+        };
+        let lines = document.lines();
+
+        let include_declaration = params["context"]["includeDeclaration"].as_bool().unwrap_or(true);
+        let declaration = if include_declaration { Some(&symbol.declaration) } else { None };
+        let locations: Vec<_> = declaration.into_iter().chain(&symbol.references)
+            .map(|span| json!({ "uri": uri(params), "range": range(&lines, *span) }))
+            .collect();
+        json!(locations)
+    }
+
+    fn hover(&self, params: &Value) -> Value {
+        let (document, symbol) = match self.symbol_at(params) {
+            Some(found) => found,
+            None => return Value::Null,
+        };
+        let analysis = &document.analysis;
+
+        let description = match symbol.kind {
+            SymbolKind::Function | SymbolKind::Method => {
+                let index = analysis.symbols.iter().position(|other| other == symbol);
+                let params: Vec<_> = analysis.symbols.iter()
+                    .filter(|param| param.kind == SymbolKind::Parameter && param.parent == index)
+                    .map(|param| param.name.as_str())
+                    .collect();
+                let arity = symbol.arity.unwrap_or(0);
+                let kind = if symbol.kind == SymbolKind::Method { "method" } else { "fun" };
+                format!("{} {}({})\n\ntakes {} argument{}", kind, symbol.name, params.join(", "), arity, if arity == 1 { "" } else { "s" })
+            },
+            SymbolKind::Class => format!("class {}", symbol.name),
+            SymbolKind::Global => format!("global {}", symbol.name),
+            SymbolKind::Local => format!("local {}", symbol.name),
+            SymbolKind::Parameter => format!("parameter {}", symbol.name),
+        };
+        json!({ "contents": { "kind": "plaintext", "value": description } })
+    }
+
+    fn document_symbols(&self, params: &Value) -> Value {
+        match self.documents.get(uri(params)) {
+            Some(document) => json!(document_symbols(&document.analysis, &document.lines(), None)),
+            None => json!([]),
+        }
+    }
+
+    fn semantic_tokens(&self, params: &Value) -> Value {
+        let document = match self.documents.get(uri(params)) {
+            Some(document) => document,
+            None => return json!({ "data": [] }),
+        };
+        let lines = document.lines();
+
+        // Every token is relative to the one before it
+        let mut data = vec![];
+        let mut previous = (0, 0);
+        for (span, kind) in &document.analysis.tokens {
+            // Tokens can't span lines
+            if span.start.line != span.end.line {
+                continue;
+            }
+            let line = span.start.line - 1;
+            let start = utf16_column(lines.get(line).copied().unwrap_or_default(), span.start.column - 1);
+            let end = utf16_column(lines.get(line).copied().unwrap_or_default(), span.end.column);
+            let delta_start = if line == previous.0 { start - previous.1 } else { start };
+            data.extend_from_slice(&[line - previous.0, delta_start, end - start, token_type(*kind), 0]);
+            previous = (line, start);
+        }
+        json!({ "data": data })
+    }
+}
+
+fn uri(params: &Value) -> &str {
+    params["textDocument"]["uri"].as_str().unwrap_or_default()
+}
+
+/// The symbols that are declared directly in `parent`, with the ones declared in them as children. Locals and
+/// parameters are left out.
+fn document_symbols(analysis: &Analysis, lines: &[&str], parent: Option<usize>) -> Vec<Value> {
+    analysis.symbols.iter().enumerate()
+        .filter(|(_, symbol)| symbol.parent == parent)
+        .filter_map(|(index, symbol)| {
+            let kind = match symbol.kind {
+                SymbolKind::Class => 5,
+                SymbolKind::Method => 6,
+                SymbolKind::Function => 12,
+                SymbolKind::Global => 13,
+                SymbolKind::Local | SymbolKind::Parameter => return None,
+            };
+            Some(json!({
+                "name": symbol.name,
+                "kind": kind,
+                "range": range(lines, symbol.span),
+                "selectionRange": range(lines, symbol.declaration),
+                "children": document_symbols(analysis, lines, Some(index)),
+            }))
+        })
+        .collect()
+}
+
+fn token_type(kind: TokenKind) -> usize {
+    match kind {
+        TokenKind::Keyword => 0,
+        TokenKind::Variable => 1,
+        TokenKind::Parameter => 2,
+        TokenKind::Function => 3,
+        TokenKind::Class => 4,
+        TokenKind::Method => 5,
+        TokenKind::Property => 6,
+        TokenKind::String => 7,
+        TokenKind::Number => 8,
+        TokenKind::Operator => 9,
+    }
+}
+
+/// Editors count columns in UTF-16 code units, the tokenizer counts characters. Past the end of the line every
+/// character is one unit.
+fn utf16_column(line: &str, characters: usize) -> usize {
+    let units: usize = line.chars().take(characters).map(char::len_utf16).sum();
+    units + characters.saturating_sub(line.chars().count())
+}
+
+/// The other way around, the number of characters before `utf16` code units into the line.
+fn character_column(line: &str, utf16: usize) -> usize {
+    let mut units = 0;
+    let mut characters = 0;
+    for c in line.chars() {
+        if units + c.len_utf16() > utf16 {
+            return characters;
+        }
+        units += c.len_utf16();
+        characters += 1;
+    }
+    characters + (utf16 - units)
+}
+
+/// Spans include their last character and start at line 1 and column 1, ranges in the protocol don't.
+fn range(lines: &[&str], span: Span) -> Value {
+    let character = |line: usize, characters: usize| utf16_column(lines.get(line - 1).copied().unwrap_or_default(), characters);
+    json!({
+        "start": { "line": span.start.line - 1, "character": character(span.start.line, span.start.column - 1) },
+        "end": { "line": span.end.line - 1, "character": character(span.end.line, span.end.column) },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URI: &str = "file:///test.lox";
+    const CODE: &str = "\
+fun add(a, b) {
+  return a + b;
+}
+class Point {
+  sum() { return add(1, 2); }
+}
+print add(1, 2);
+";
+
+    /// Runs the server on the messages and returns everything it sent back.
+    fn exchange(messages: Vec<Value>) -> Vec<Value> {
+        let mut input = vec![];
+        for message in &messages {
+            write_message(&mut input, message).unwrap();
+        }
+
+        let mut output = vec![];
+        Server::new(io::Cursor::new(input), &mut output).serve().unwrap();
+
+        let mut reader = io::Cursor::new(output);
+        let mut sent = vec![];
+        while let Some(message) = read_message(&mut reader).unwrap() {
+            sent.push(message);
+        }
+        sent
+    }
+
+    fn open(text: &str) -> Value {
+        json!({ "jsonrpc": "2.0", "method": "textDocument/didOpen", "params": {
+            "textDocument": { "uri": URI, "languageId": "lox", "version": 1, "text": text },
+        }})
+    }
+
+    fn request(id: i64, method: &str, params: Value) -> Value {
+        json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params })
+    }
+
+    fn at(line: usize, character: usize) -> Value {
+        json!({ "textDocument": { "uri": URI }, "position": { "line": line, "character": character } })
+    }
+
+    fn result(responses: &[Value], id: i64) -> &Value {
+        &responses.iter().find(|response| response["id"] == id).expect("no response")["result"]
+    }
+
+    #[test]
+    fn test_initialize_and_shutdown() {
+        let responses = exchange(vec![
+            request(1, "initialize", json!({ "capabilities": {} })),
+            json!({ "jsonrpc": "2.0", "method": "initialized", "params": {} }),
+            request(2, "workspace/symbol", json!({ "query": "" })),
+            request(3, "shutdown", Value::Null),
+            json!({ "jsonrpc": "2.0", "method": "exit" }),
+        ]);
+
+        assert_eq!(responses.len(), 3);
+        assert_eq!(result(&responses, 1)["capabilities"]["definitionProvider"], true);
+        assert_eq!(responses[1]["error"]["code"], METHOD_NOT_FOUND);
+        assert_eq!(result(&responses, 3), &Value::Null);
+    }
+
+    #[test]
+    fn test_diagnostics_on_change() {
+        let responses = exchange(vec![
+            open("print 1;"),
+            json!({ "jsonrpc": "2.0", "method": "textDocument/didChange", "params": {
+                "textDocument": { "uri": URI, "version": 2 },
+                "contentChanges": [{ "text": "print 1;\nprint ;" }],
+            }}),
+        ]);
+
+        assert_eq!(responses[0]["params"]["diagnostics"], json!([]));
+        let diagnostics = responses[1]["params"]["diagnostics"].as_array().unwrap();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0]["range"]["start"], json!({ "line": 1, "character": 6 }));
+    }
+
+    #[test]
+    fn test_diagnostics_for_text_that_does_not_lex() {
+        let responses = exchange(vec![
+            open("var a = \"unterminated;"),
+            json!({ "jsonrpc": "2.0", "method": "textDocument/didChange", "params": {
+                "textDocument": { "uri": URI, "version": 2 },
+                "contentChanges": [{ "text": "var a = 1 @ 2;" }],
+            }}),
+        ]);
+
+        let messages = |index: usize| -> Vec<String> {
+            responses[index]["params"]["diagnostics"].as_array().unwrap().iter()
+                .map(|diagnostic| diagnostic["message"].as_str().unwrap().to_string())
+                .collect()
+        };
+        assert_eq!(responses.len(), 2);
+        assert_eq!(messages(0)[0], "Unterminated string.");
+        assert_eq!(messages(1)[0], "Unexpected character '@'.");
+        assert_eq!(responses[1]["params"]["diagnostics"][0]["range"]["start"], json!({ "line": 0, "character": 10 }));
+    }
+
+    #[test]
+    fn test_definition_and_references() {
+        let responses = exchange(vec![
+            open(CODE),
+            request(1, "textDocument/definition", at(6, 7)),
+            request(2, "textDocument/references", json!({
+                "textDocument": { "uri": URI },
+                "position": { "line": 0, "character": 4 },
+                "context": { "includeDeclaration": false },
+            })),
+            request(3, "textDocument/definition", at(1, 13)),
+            request(4, "textDocument/definition", at(1, 2)),
+        ]);
+
+        assert_eq!(result(&responses, 1)["range"], json!({
+            "start": { "line": 0, "character": 4 },
+            "end": { "line": 0, "character": 7 },
+        }));
+        let references: Vec<_> = result(&responses, 2).as_array().unwrap().iter()
+            .map(|location| location["range"]["start"]["line"].as_u64().unwrap())
+            .collect();
+        assert_eq!(references, vec![4, 6]);
+        assert_eq!(result(&responses, 3)["range"]["start"], json!({ "line": 0, "character": 11 }));
+        assert_eq!(result(&responses, 4), &Value::Null);
+    }
+
+    #[test]
+    fn test_positions_count_utf16() {
+        // The emoji is two UTF-16 code units but one character
+        let responses = exchange(vec![
+            open("var a = 1;\nprint \"é😀\" + a;\nprint \"😀\" + b @;"),
+            // Right after `a`
+            request(1, "textDocument/definition", at(1, 15)),
+            request(2, "textDocument/references", json!({
+                "textDocument": { "uri": URI },
+                "position": { "line": 0, "character": 4 },
+                "context": { "includeDeclaration": false },
+            })),
+        ]);
+
+        let diagnostics = responses[0]["params"]["diagnostics"].as_array().unwrap();
+        assert_eq!(diagnostics[0]["range"], json!({
+            "start": { "line": 2, "character": 15 },
+            "end": { "line": 2, "character": 16 },
+        }));
+        assert_eq!(result(&responses, 1)["range"]["start"], json!({ "line": 0, "character": 4 }));
+        assert_eq!(result(&responses, 2)[0]["range"], json!({
+            "start": { "line": 1, "character": 14 },
+            "end": { "line": 1, "character": 15 },
+        }));
+    }
+
+    #[test]
+    fn test_hover_shows_arity() {
+        let responses = exchange(vec![open(CODE), request(1, "textDocument/hover", at(6, 7))]);
+        assert_eq!(result(&responses, 1)["contents"]["value"], "fun add(a, b)\n\ntakes 2 arguments");
+    }
+
+    #[test]
+    fn test_document_symbols() {
+        let responses = exchange(vec![open(CODE), request(1, "textDocument/documentSymbol", json!({ "textDocument": { "uri": URI } }))]);
+        let symbols = result(&responses, 1).as_array().unwrap();
+        let names: Vec<_> = symbols.iter().map(|symbol| symbol["name"].as_str().unwrap()).collect();
+        assert_eq!(names, vec!["add", "Point"]);
+        assert_eq!(symbols[1]["children"][0]["name"], "sum");
+        assert_eq!(symbols[1]["children"][0]["kind"], 6);
+    }
+
+    #[test]
+    fn test_semantic_tokens() {
+        let responses = exchange(vec![
+            open("var a = 1;\nprint a;"),
+            request(1, "textDocument/semanticTokens/full", json!({ "textDocument": { "uri": URI } })),
+        ]);
+        assert_eq!(result(&responses, 1)["data"], json!([
+            0, 0, 3, 0, 0,
+            0, 4, 1, 1, 0,
+            0, 2, 1, 9, 0,
+            0, 2, 1, 8, 0,
+            1, 0, 5, 0, 0,
+            0, 6, 1, 1, 0,
+        ]));
+    }
+}
@@ -1,3 +1,8 @@
+mod dap;
+mod debugger;
+mod framing;
+mod lsp;
+
 fn main() {
     // let data = "print 3;";
     // let data = "print 1 + 2;1+2;print 3;";
@@ -56,21 +61,250 @@ fn main() {
     // globalGet();
     // ";
 
-    let data = std::fs::read_to_string("test.lox").unwrap();
+    let server = match std::env::args().nth(1).as_deref() {
+        Some("dap") => Some(dap::serve as fn() -> std::io::Result<()>),
+        Some("lsp") => Some(lsp::serve as fn() -> std::io::Result<()>),
+        _ => None,
+    };
+    if let Some(serve) = server {
+        if let Err(error) = serve() {
+            eprintln!("{}", error);
+            std::process::exit(74);
+        }
+        return;
+    }
+
+    if std::env::args().nth(1).as_deref() == Some("fmt") {
+        let check = std::env::args().any(|arg| arg == "--check");
+        let paths: Vec<_> = std::env::args().skip(2).filter(|arg| arg != "--check").collect();
+        std::process::exit(fmt(&paths, check));
+    }
+
+    if std::env::args().nth(1).as_deref() == Some("lint") {
+        std::process::exit(lint(&std::env::args().skip(2).collect::<Vec<_>>()));
+    }
+
+    if std::env::args().nth(1).as_deref() == Some("check") {
+        let types = std::env::args().any(|arg| arg == "--types");
+        let paths: Vec<_> = std::env::args().skip(2).filter(|arg| arg != "--types").collect();
+        std::process::exit(check(&paths, types));
+    }
+
+    let mut path = "test.lox".to_string();
+    let mut gc_stats = false;
+    let mut registers = false;
+    let mut ir = false;
+    let mut dump = false;
+    let debug = std::env::args().nth(1).as_deref() == Some("debug");
+    for arg in std::env::args().skip(if debug { 2 } else { 1 }) {
+        match arg.as_str() {
+            "--gc-stats" => gc_stats = true,
+            "--registers" => registers = true,
+            "--ir" => ir = true,
+            "--dump" => dump = true,
+            _ => path = arg,
+        }
+    }
+
+    let data = std::fs::read_to_string(&path).unwrap();
+
+    if debug {
+        if let Err(error) = debugger::debug(&path, &data) {
+            eprintln!("{}", error);
+            std::process::exit(70);
+        }
+        return;
+    }
+
+    if ir {
+        match lox_compiler::dump_ir(&data) {
+            Ok(ir) => println!("{}", ir),
+            Err(error) => {
+                eprintln!("{}", error);
+                std::process::exit(65);
+            },
+        }
+    }
+
+    let result = if registers {
+        run_registers(&data)
+    } else {
+        run(&data, &path, dump)
+    };
 
-    let module = lox_compiler::compile(&data).unwrap();
+    if gc_stats {
+        eprintln!("{}", lox_vm::bettervm::gc_report());
+    }
+
+    if let Err(error) = result {
+        eprintln!("{}", error);
+        std::process::exit(70);
+    }
+}
+
+/// Format the files in place, or only list the ones that aren't formatted with `check`. Returns the exit code.
+fn fmt(paths: &[String], check: bool) -> i32 {
+    let mut code = 0;
+    for path in paths {
+        let data = match std::fs::read_to_string(path) {
+            Ok(data) => data,
+            Err(error) => {
+                eprintln!("{}: {}", path, error);
+                return 74;
+            },
+        };
+        let formatted = match lox_compiler::format(&data) {
+            Ok(formatted) => formatted,
+            Err(error) => {
+                eprintln!("{}: {}", path, error);
+                code = 65;
+                continue;
+            },
+        };
+
+        if formatted == data {
+            continue;
+        }
+        if check {
+            println!("{}", path);
+            code = code.max(1);
+        } else if let Err(error) = std::fs::write(path, formatted) {
+            eprintln!("{}: {}", path, error);
+            return 74;
+        }
+    }
+    code
+}
+
+/// Print the warnings in the files, with the rules turned on or off in `.loxlint` and by `--enable` and `--disable`.
+/// Returns the exit code.
+fn lint(args: &[String]) -> i32 {
+    use lox_compiler::lint::{Config, Rule};
+
+    let mut config = match std::fs::read_to_string(".loxlint") {
+        Ok(config) => match Config::parse(&config) {
+            Ok(config) => config,
+            Err(error) => {
+                eprintln!(".loxlint: {}", error);
+                return 64;
+            },
+        },
+        Err(_) => Config::default(),
+    };
 
-    // Temporary to test serde out
-    let data = serde_json::to_string_pretty(&module).unwrap();
-    println!("{}", data);
-    let module: lox_bytecode::bytecode::Module = serde_json::from_str(&data).unwrap();
+    let mut paths = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let enable = match arg.as_str() {
+            "--enable" => true,
+            "--disable" => false,
+            _ => {
+                paths.push(arg);
+                continue;
+            },
+        };
+        match args.next().and_then(|id| Rule::from_id(id)) {
+            Some(rule) if enable => config.enable(rule),
+            Some(rule) => config.disable(rule),
+            None => {
+                eprintln!("{} expects one of: {}", arg, Rule::ALL.iter().map(|rule| rule.id()).collect::<Vec<_>>().join(", "));
+                return 64;
+            },
+        }
+    }
+
+    let mut code = 0;
+    for path in paths {
+        let data = match std::fs::read_to_string(path) {
+            Ok(data) => data,
+            Err(error) => {
+                eprintln!("{}: {}", path, error);
+                return 74;
+            },
+        };
+        match lox_compiler::lint::lint(&data, &config) {
+            Ok(lints) => for lint in lints {
+                println!("{}:{}:{}: warning[{}]: {}", path, lint.span.start.line, lint.span.start.column, lint.rule.id(), lint.message);
+                code = code.max(1);
+            },
+            Err(diagnostics) => {
+                for diagnostic in diagnostics {
+                    eprintln!("{}:{}:{}: error: {}", path, diagnostic.span.start.line, diagnostic.span.start.column, diagnostic.message);
+                }
+                code = 65;
+            },
+        }
+    }
+    code
+}
+
+/// Print the errors in the files without running them, with `types` also the type errors. Returns the exit code.
+fn check(paths: &[String], types: bool) -> i32 {
+    let mut code = 0;
+    for path in paths {
+        let data = match std::fs::read_to_string(path) {
+            Ok(data) => data,
+            Err(error) => {
+                eprintln!("{}: {}", path, error);
+                return 74;
+            },
+        };
 
-    println!("constants: {:?}", module.constants());
-    for chunk in module.chunks() {
-        println!("chunk: {:?}", chunk.instructions());
+        let diagnostics = if types {
+            match lox_compiler::types::check(&data) {
+                Ok(errors) => {
+                    for error in errors {
+                        println!("{}:{}:{}: error: {}", path, error.span.start.line, error.span.start.column, error.message);
+                        code = code.max(1);
+                    }
+                    continue;
+                },
+                Err(diagnostics) => diagnostics,
+            }
+        } else {
+            lox_compiler::analysis::analyze(&data).diagnostics
+        };
+        for diagnostic in &diagnostics {
+            eprintln!("{}:{}:{}: error: {}", path, diagnostic.span.start.line, diagnostic.span.start.column, diagnostic.message);
+        }
+        if !diagnostics.is_empty() {
+            code = 65;
+        }
     }
+    code
+}
+
+/// Compile and run the script, with `dump` first print the serialized module and the instructions of every chunk.
+fn run(data: &str, path: &str, dump: bool) -> Result<(), lox_vm::bettervm::VmError> {
+    let module = match lox_compiler::compile_file(data, path) {
+        Ok(module) => module,
+        Err(error) => {
+            eprintln!("{}", error);
+            std::process::exit(65);
+        },
+    };
+
+    if dump {
+        println!("{}", serde_json::to_string_pretty(&module).unwrap());
+        println!("constants: {:?}", module.constants());
+        for chunk in module.chunks() {
+            println!("chunk: {:?}", chunk.instructions());
+        }
+        println!();
+    }
+
+    lox_vm::bettervm::execute(&module)
+}
 
-    println!();
+/// Compile to register instructions and run them on the register VM instead.
+fn run_registers(data: &str) -> Result<(), lox_vm::bettervm::VmError> {
+    let module = match lox_compiler::compile_registers(data) {
+        Ok(module) => module,
+        Err(error) => {
+            eprintln!("{}", error);
+            std::process::exit(65);
+        },
+    };
 
-    lox_vm::bettervm::execute(&module).unwrap();
-}
\ No newline at end of file
+    lox_vm::registervm::execute(&module)
+}